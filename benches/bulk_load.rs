@@ -0,0 +1,45 @@
+//! Times building a store from a million sorted pairs through `set` in a loop, and
+//! through `bulk_load`, which writes them straight into a sealed generation.
+//!
+//! Run it with `cargo bench --bench bulk_load`.
+
+use std::path::Path;
+use std::time::Instant;
+
+use kvs::{KvStore, Result};
+use tempfile::TempDir;
+
+const PAIRS: u64 = 1_000_000;
+
+fn main() -> Result<()> {
+    run("looped set", |path| {
+        let mut store = KvStore::open(path)?;
+        for (key, value) in pairs() {
+            store.set(key, value)?;
+        }
+        Ok(())
+    })?;
+    run("bulk_load", |path| {
+        KvStore::bulk_load(path, pairs()).map(drop)
+    })
+}
+
+/// Returns the pairs to load, in key order.
+fn pairs() -> impl Iterator<Item = (String, String)> {
+    (0..PAIRS).map(|i| (format!("key{:08}", i), format!("value{}", i)))
+}
+
+fn run(name: &str, build: impl FnOnce(&Path) -> Result<()>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let start = Instant::now();
+    build(temp_dir.path())?;
+    let elapsed = start.elapsed();
+    println!(
+        "{}: {} pairs in {:?}, {:?} per pair",
+        name,
+        PAIRS,
+        elapsed,
+        elapsed / PAIRS as u32
+    );
+    Ok(())
+}
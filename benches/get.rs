@@ -0,0 +1,51 @@
+//! Times random gets against a store whose values all sit in sealed logs.
+//!
+//! Run it with `cargo bench --bench get`, and with `--features mmap` to compare
+//! buffered reads with reads through memory maps.
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use kvs::{KvStore, Result};
+use tempfile::TempDir;
+
+const KEYS: u64 = 10_000;
+const GETS: u64 = 200_000;
+
+fn main() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder(temp_dir.path())
+        .max_segment_size(64 * 1024)
+        .open()?;
+    for i in 0..KEYS {
+        store.set(format!("key{}", i), format!("value{}", i).repeat(10))?;
+    }
+    drop(store);
+
+    run("buffered", KvStore::builder(temp_dir.path()).open()?)?;
+    #[cfg(all(feature = "mmap", unix))]
+    run(
+        "mmap",
+        KvStore::builder(temp_dir.path()).mmap_reads(true).open()?,
+    )?;
+    Ok(())
+}
+
+fn run(name: &str, mut store: KvStore) -> Result<()> {
+    // A fixed linear congruential sequence, so both runs read the same keys.
+    let mut seed: u64 = 1;
+    let start = Instant::now();
+    for _ in 0..GETS {
+        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+        black_box(store.get(format!("key{}", (seed >> 33) % KEYS))?);
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{}: {} gets in {:?}, {:?} per get",
+        name,
+        GETS,
+        elapsed,
+        elapsed / GETS as u32
+    );
+    Ok(())
+}
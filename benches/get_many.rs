@@ -0,0 +1,71 @@
+//! Times fetching batches of random keys through `get_many`, which sorts the reads by
+//! their position in the logs, against a loop of `get` calls reading in request order,
+//! on a store holding a few hundred MB of logs over many generations.
+//!
+//! Run it with `cargo bench --bench get_many`.
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use kvs::{KvStore, Result};
+use tempfile::TempDir;
+
+const KEYS: u64 = 300_000;
+const VALUE_LEN: usize = 1024;
+const BATCHES: u64 = 200;
+const BATCH_LEN: u64 = 500;
+
+fn main() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder(temp_dir.path())
+        .max_segment_size(32 * 1024 * 1024)
+        .open()?;
+    let mut batched = store.batched()?;
+    for i in 0..KEYS {
+        batched.set(format!("key{}", i), "v".repeat(VALUE_LEN))?;
+    }
+    batched.finish()?;
+    drop(store);
+
+    // The value cache is off, so every run reads from the logs.
+    let open = || KvStore::builder(temp_dir.path()).read_cache_bytes(0).open();
+    run("get loop", open()?, |store, keys| {
+        for key in keys {
+            black_box(store.get(key)?);
+        }
+        Ok(())
+    })?;
+    run("get_many", open()?, |store, keys| {
+        black_box(store.get_many(keys)?);
+        Ok(())
+    })
+}
+
+fn run(
+    name: &str,
+    mut store: KvStore,
+    mut fetch: impl FnMut(&mut KvStore, Vec<String>) -> Result<()>,
+) -> Result<()> {
+    // A fixed linear congruential sequence, so both runs read the same keys.
+    let mut seed: u64 = 1;
+    let start = Instant::now();
+    for _ in 0..BATCHES {
+        let keys = (0..BATCH_LEN)
+            .map(|_| {
+                seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+                format!("key{}", (seed >> 33) % KEYS)
+            })
+            .collect();
+        fetch(&mut store, keys)?;
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{}: {} batches of {} keys in {:?}, {:?} per batch",
+        name,
+        BATCHES,
+        BATCH_LEN,
+        elapsed,
+        elapsed / BATCHES as u32
+    );
+    Ok(())
+}
@@ -0,0 +1,43 @@
+//! Times a bulk load of a store through `set`, which flushes every write, and
+//! through a `batched` guard, which flushes once the buffer of the log fills up.
+//!
+//! Run it with `cargo bench --bench set`.
+
+use std::time::Instant;
+
+use kvs::{KvStore, Result};
+use tempfile::TempDir;
+
+const KEYS: u64 = 200_000;
+
+fn main() -> Result<()> {
+    run("flushed", |store| {
+        for i in 0..KEYS {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        Ok(())
+    })?;
+    run("batched", |store| {
+        let mut batched = store.batched()?;
+        for i in 0..KEYS {
+            batched.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        batched.finish()
+    })
+}
+
+fn run(name: &str, load: impl FnOnce(&mut KvStore) -> Result<()>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let start = Instant::now();
+    load(&mut store)?;
+    let elapsed = start.elapsed();
+    println!(
+        "{}: {} sets in {:?}, {:?} per set",
+        name,
+        KEYS,
+        elapsed,
+        elapsed / KEYS as u32
+    );
+    Ok(())
+}
@@ -0,0 +1,109 @@
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::hint::{hint_path, remove_hint};
+use crate::kv::log_path;
+use crate::{Result, Retention};
+
+/// Name of the subdirectory holding the logs replaced by compactions, if they are retained.
+pub(crate) const ARCHIVE_DIR: &str = "archive";
+
+/// Returns the archive directory of a store directory.
+pub(crate) fn archive_dir(dir: &Path) -> PathBuf {
+    dir.join(ARCHIVE_DIR)
+}
+
+/// Removes a log replaced by a compaction and its hint, or moves them to the archive.
+///
+/// Returns the sizes of the log and of its hint.
+pub(crate) fn retire_log(dir: &Path, gen: u64, archive: bool) -> Result<(u64, u64)> {
+    retire_log_from(dir, dir, gen, archive)
+}
+
+/// Removes a log of a store directory and its hint, kept in `from`, or moves them to
+/// the archive of the store.
+///
+/// Returns the sizes of the log and of its hint.
+pub(crate) fn retire_log_from(
+    from: &Path,
+    dir: &Path,
+    gen: u64,
+    archive: bool,
+) -> Result<(u64, u64)> {
+    let path = log_path(from, gen);
+    let log_len = fs::metadata(&path)?.len();
+    if !archive {
+        fs::remove_file(path)?;
+        return Ok((log_len, remove_hint(from, gen)?));
+    }
+    let archive = archive_dir(dir);
+    fs::create_dir_all(&archive)?;
+    let archived = log_path(&archive, gen);
+    fs::rename(path, &archived)?;
+    // The age of an archived log counts from when it was archived.
+    File::options()
+        .write(true)
+        .open(archived)?
+        .set_modified(SystemTime::now())?;
+    let hint = hint_path(from, gen);
+    let hint_len = match fs::metadata(&hint) {
+        Ok(metadata) => metadata.len(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((log_len, 0)),
+        Err(e) => return Err(e.into()),
+    };
+    // The hint marks the log as compacted, which tells restores where to start.
+    fs::rename(hint, hint_path(&archive, gen))?;
+    Ok((log_len, hint_len))
+}
+
+/// Returns the generations in the archive of a store directory, in order.
+pub(crate) fn archived_gens(dir: &Path) -> Result<Vec<u64>> {
+    let archive = archive_dir(dir);
+    if !archive.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut gens: Vec<u64> = fs::read_dir(archive)?
+        .flat_map(|entry| -> Result<_> { Ok(entry?.path()) })
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .filter_map(|path| {
+            path.file_stem()
+                .and_then(OsStr::to_str)
+                .and_then(|stem| stem.parse().ok())
+        })
+        .collect();
+    gens.sort_unstable();
+    Ok(gens)
+}
+
+/// Removes the archived logs the retention no longer keeps.
+pub(crate) fn prune_archive(dir: &Path, retention: Retention) -> Result<()> {
+    let archive = archive_dir(dir);
+    let gens = archived_gens(dir)?;
+    let mut expired: BTreeMap<u64, PathBuf> = BTreeMap::new();
+    match retention {
+        Retention::Count(count) => {
+            for &gen in &gens[..gens.len().saturating_sub(count)] {
+                expired.insert(gen, log_path(&archive, gen));
+            }
+        }
+        Retention::Age(age) => {
+            let now = SystemTime::now();
+            for &gen in &gens {
+                let path = log_path(&archive, gen);
+                let archived_at = fs::metadata(&path)?.modified()?;
+                if now.duration_since(archived_at).unwrap_or_default() >= age {
+                    expired.insert(gen, path);
+                }
+            }
+        }
+    }
+    for (gen, path) in expired {
+        fs::remove_file(path)?;
+        remove_hint(&archive, gen)?;
+    }
+    Ok(())
+}
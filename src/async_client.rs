@@ -0,0 +1,129 @@
+use std::fmt::Display;
+use std::io;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+use crate::client::connect_error;
+use crate::protocol::{negotiate, Frame, Hello, Request, Response, MAX_VERSION};
+use crate::{KvsError, Result};
+
+/// An async client talking to a `kvs-server`, like `KvsClient` does, over a tokio `TcpStream`.
+///
+/// Dropping a request future before it completes leaves the connection out of step with
+/// the server, so later requests on it fail and a new client has to connect.
+///
+/// Available with the `async` feature.
+pub struct AsyncKvsClient {
+    stream: TcpStream,
+    // Bytes received but not yet decoded.
+    buf: Vec<u8>,
+    // Protocol version agreed on with the server.
+    version: u32,
+    // Set while a request is in flight, so it stays set if the request is cancelled.
+    in_flight: bool,
+    // ID of the next request.
+    next_id: u64,
+}
+
+impl AsyncKvsClient {
+    /// Connects to the server at the given address.
+    ///
+    /// Returns `KvsError::ConnectionRefused` or `KvsError::Connect` naming the address
+    /// if it cannot be reached, and `KvsError::ProtocolMismatch` if it speaks no
+    /// protocol version this client does.
+    pub async fn connect<A: ToSocketAddrs + Display>(addr: A) -> Result<Self> {
+        let stream = TcpStream::connect(&addr)
+            .await
+            .map_err(|cause| connect_error(addr.to_string(), cause))?;
+        let mut client = Self {
+            stream,
+            buf: Vec::new(),
+            version: MAX_VERSION,
+            in_flight: false,
+            next_id: 1,
+        };
+        client.send(&Hello::new()).await?;
+        let hello: Hello = client.receive().await?;
+        hello.check_magic()?;
+        client.version = negotiate(MAX_VERSION, hello.version)?;
+        Ok(client)
+    }
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.request(Request::Get { key }).await
+    }
+    /// Sets the value of a string key to a string.
+    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.request(Request::Set { key, value }).await.map(|_| ())
+    }
+    /// Removes a given key.
+    ///
+    /// Returns `KvsError::KeyNotFound` if the given key is not found.
+    pub async fn remove(&mut self, key: String) -> Result<()> {
+        self.request(Request::Remove { key }).await.map(|_| ())
+    }
+
+    async fn request(&mut self, request: Request) -> Result<Option<String>> {
+        if self.in_flight {
+            return Err(KvsError::Protocol(
+                "a cancelled request left the connection out of step".to_owned(),
+            ));
+        }
+        self.in_flight = true;
+        let id = self.next_id;
+        self.next_id += 1;
+        self.send(&Frame::new(self.version, id, request)).await?;
+        let response: Frame<Response> = self.receive().await?;
+        self.in_flight = false;
+        let (answered, response) = response.into_message(self.version)?;
+        // Version 1 responses carry no ID.
+        if self.version >= 2 && answered != id {
+            return Err(KvsError::Protocol(format!(
+                "the response to request {} came for request {}",
+                answered, id
+            )));
+        }
+        match response {
+            Response::Ok(value) => Ok(value),
+            Response::KeyNotFound => Err(KvsError::KeyNotFound),
+            Response::Err(message) => Err(KvsError::Server(message)),
+            Response::Unsupported(name) => Err(KvsError::UnsupportedRequest(name)),
+            _ => Err(KvsError::Protocol(
+                "unexpected response from the server".to_owned(),
+            )),
+        }
+    }
+
+    async fn send<T: Serialize>(&mut self, message: &T) -> Result<()> {
+        let bytes = serde_json::to_vec(message)?;
+        self.stream.write_all(&bytes).await?;
+        Ok(())
+    }
+
+    /// Reads the next message, which may arrive in any number of pieces.
+    async fn receive<T: DeserializeOwned>(&mut self) -> Result<T> {
+        loop {
+            let mut messages = serde_json::Deserializer::from_slice(&self.buf).into_iter();
+            match messages.next() {
+                Some(Ok(message)) => {
+                    let len = messages.byte_offset();
+                    self.buf.drain(..len);
+                    return Ok(message);
+                }
+                Some(Err(e)) if !e.is_eof() => return Err(e.into()),
+                _ => {}
+            }
+            let mut chunk = [0; 4096];
+            let n = self.stream.read(&mut chunk).await?;
+            if n == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
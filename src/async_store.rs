@@ -0,0 +1,82 @@
+use std::path::PathBuf;
+use std::thread;
+
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{KvStore, KvsError, Result};
+
+type Job = Box<dyn FnOnce(&mut KvStore) + Send + 'static>;
+
+/// An async handle to a `KvStore`, for use on a tokio runtime.
+///
+/// The store is owned by a dedicated thread, which runs the operations of all clones
+/// of the handle in the order they are issued. An operation runs to the end once it is
+/// issued, even if its future is dropped, so cancelling one never leaves the store half
+/// written. The thread closes the store once every handle is dropped.
+///
+/// Available with the `async` feature.
+#[derive(Clone)]
+pub struct AsyncKvStore {
+    sender: mpsc::UnboundedSender<Job>,
+}
+
+impl AsyncKvStore {
+    /// Opens a `KvStore` with the given path on a new thread.
+    pub async fn open<P: Into<PathBuf>>(path: P) -> Result<Self> {
+        let path = path.into();
+        let (opened, result) = oneshot::channel();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        thread::Builder::new()
+            .name("kvs-async".to_owned())
+            .spawn(move || match KvStore::open(path) {
+                Ok(store) => {
+                    let _ = opened.send(Ok(()));
+                    run_jobs(store, receiver);
+                }
+                Err(e) => {
+                    let _ = opened.send(Err(e));
+                }
+            })?;
+        result.await.map_err(|_| KvsError::WorkerStopped)??;
+        Ok(Self { sender })
+    }
+    /// Sets the value of a string key to a string.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    pub async fn set(&self, key: String, value: String) -> Result<()> {
+        self.call(move |store| store.set(key, value)).await
+    }
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist or has expired.
+    pub async fn get(&self, key: String) -> Result<Option<String>> {
+        self.call(move |store| store.get(key)).await
+    }
+    /// Removes a given key.
+    ///
+    /// Returns `KvsError::KeyNotFound` if the given key is not found.
+    pub async fn remove(&self, key: String) -> Result<()> {
+        self.call(move |store| store.remove(key)).await
+    }
+
+    /// Runs an operation on the store thread and waits for its result.
+    async fn call<T, F>(&self, op: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut KvStore) -> Result<T> + Send + 'static,
+    {
+        let (done, result) = oneshot::channel();
+        self.sender
+            .send(Box::new(move |store| {
+                let _ = done.send(op(store));
+            }))
+            .map_err(|_| KvsError::WorkerStopped)?;
+        result.await.map_err(|_| KvsError::WorkerStopped)?
+    }
+}
+
+fn run_jobs(mut store: KvStore, mut receiver: mpsc::UnboundedReceiver<Job>) {
+    while let Some(job) = receiver.blocking_recv() {
+        job(&mut store);
+    }
+}
@@ -0,0 +1,50 @@
+//! The shared token a server can require its clients to authenticate with.
+
+use std::fmt;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// How long a server waits before rejecting a connection that failed to authenticate,
+/// and closing it, to slow down guessing the token.
+pub(crate) const AUTH_FAILURE_DELAY: Duration = Duration::from_secs(1);
+
+/// A token shared by a server and its clients.
+///
+/// It is not printed by `Debug`.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub(crate) struct AuthToken(String);
+
+impl AuthToken {
+    /// # Panics
+    ///
+    /// Panics if the token is empty.
+    pub(crate) fn new(token: String) -> Self {
+        assert!(!token.is_empty(), "an auth token must not be empty");
+        Self(token)
+    }
+
+    /// Returns `true` if the given token is this one.
+    ///
+    /// The time taken depends on the length of the given token only, not on how much of
+    /// it matches, so it tells nothing about this token.
+    pub(crate) fn matches(&self, given: &[u8]) -> bool {
+        let expected = self.0.as_bytes();
+        let mut diff = (expected.len() != given.len()) as u8;
+        for (i, byte) in given.iter().enumerate() {
+            diff |= byte ^ expected[i % expected.len()];
+        }
+        diff == 0
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+}
+
+impl fmt::Debug for AuthToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("AuthToken(..)")
+    }
+}
@@ -0,0 +1,34 @@
+use crate::kv::Command;
+
+/// A batch of writes applied atomically by [`KvStore::write_batch`].
+///
+/// [`KvStore::write_batch`]: crate::KvStore::write_batch
+#[derive(Debug, Default)]
+pub struct WriteBatch {
+    pub(crate) commands: Vec<Command>,
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Sets the value of a string key to a string.
+    pub fn put(&mut self, key: String, value: String) {
+        self.commands.push(Command::Set(key, value));
+    }
+    /// Removes a given key.
+    ///
+    /// Removing a key that does not exist is a no-op.
+    pub fn delete(&mut self, key: String) {
+        self.commands.push(Command::Remove(key));
+    }
+    /// Returns the number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+    /// Returns `true` if the batch contains no writes.
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+}
@@ -0,0 +1,67 @@
+use std::io::Seek;
+
+use log::warn;
+
+use crate::kv::Command;
+use crate::{KvStore, KvsError, Result};
+
+/// A guard writing to a store without flushing every write, for bulk loads.
+///
+/// This is created by [`KvStore::batched`]. Its writes are applied to the index at once
+/// but left in the buffer of the active log, which is flushed when it fills up, when
+/// the log rolls over or is compacted, before a read, and once more when the guard is
+/// finished or dropped. Watchers are sent the events of the writes once they are
+/// flushed. The durability mode still applies, and a sync flushes the buffer first.
+///
+/// [`KvStore::batched`]: crate::KvStore::batched
+pub struct Batched<'a> {
+    store: &'a mut KvStore,
+    // Where the active log ends, past the writes still buffered.
+    end: u64,
+}
+
+impl<'a> Batched<'a> {
+    pub(crate) fn new(store: &'a mut KvStore) -> Result<Self> {
+        let end = store.writer()?.stream_position()?;
+        Ok(Self { store, end })
+    }
+    /// Sets the value of a string key to a string.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.end = self
+            .store
+            .append_deferred(Command::Set(key, value), self.end)?;
+        Ok(())
+    }
+    /// Gets the string value of a given string key, flushing the writes buffered first.
+    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
+        self.store.flush_deferred()?;
+        self.store.get(key)
+    }
+    /// Removes a given key.
+    ///
+    /// Returns `KvsError::KeyNotFound` if the given key is not found.
+    pub fn remove(&mut self, key: &str) -> Result<()> {
+        if !self.store.contains_key(key) {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.end = self
+            .store
+            .append_deferred(Command::Remove(key.to_owned()), self.end)?;
+        Ok(())
+    }
+    /// Flushes the writes buffered, returning the error that dropping the guard can only
+    /// log.
+    pub fn finish(self) -> Result<()> {
+        self.store.flush_deferred()
+    }
+}
+
+impl Drop for Batched<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.store.flush_deferred() {
+            warn!("Failed to flush the batched writes: {}", e);
+        }
+    }
+}
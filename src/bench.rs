@@ -0,0 +1,604 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Barrier;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Serialize, Serializer};
+
+use crate::{KvsClient, KvsError, Result, SharedKvStore};
+
+/// Distribution of the sizes in bytes of the keys or values of a [`Workload`].
+///
+/// It parses from a size, like `16`, or an inclusive range of sizes drawn uniformly,
+/// like `8-64`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum SizeDistribution {
+    /// Every size is the same.
+    Fixed(usize),
+    /// Sizes are drawn uniformly from `min` to `max`, both included.
+    Uniform {
+        /// Smallest size.
+        min: usize,
+        /// Largest size.
+        max: usize,
+    },
+}
+
+impl SizeDistribution {
+    /// Returns the size a random number stands for.
+    fn sample(self, random: u64) -> usize {
+        match self {
+            SizeDistribution::Fixed(size) => size,
+            SizeDistribution::Uniform { min, max } => {
+                min + (random % (max - min + 1) as u64) as usize
+            }
+        }
+    }
+}
+
+impl fmt::Display for SizeDistribution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SizeDistribution::Fixed(size) => write!(f, "{}", size),
+            SizeDistribution::Uniform { min, max } => write!(f, "{}-{}", min, max),
+        }
+    }
+}
+
+impl FromStr for SizeDistribution {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let size = |size: &str| {
+            size.trim()
+                .parse::<usize>()
+                .map_err(|_| KvsError::InvalidWorkload(format!("{:?} is not a size", s)))
+        };
+        match s.split_once('-') {
+            None => Ok(SizeDistribution::Fixed(size(s)?)),
+            Some((min, max)) => {
+                let (min, max) = (size(min)?, size(max)?);
+                if min > max {
+                    return Err(KvsError::InvalidWorkload(format!(
+                        "sizes {:?} range from more to less",
+                        s
+                    )));
+                }
+                Ok(SizeDistribution::Uniform { min, max })
+            }
+        }
+    }
+}
+
+/// Order in which the operations of a [`Workload`] pick their keys.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyOrder {
+    /// Every key in turn, from the first one, wrapping around.
+    Sequential,
+    /// Keys drawn uniformly at random.
+    #[default]
+    Random,
+}
+
+impl FromStr for KeyOrder {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sequential" => Ok(KeyOrder::Sequential),
+            "random" => Ok(KeyOrder::Random),
+            _ => Err(KvsError::InvalidWorkload(format!(
+                "unknown key order {:?}",
+                s
+            ))),
+        }
+    }
+}
+
+/// A workload run by [`Workload::run`] to benchmark a store.
+///
+/// Every key is set once before the benchmark starts, so reads find their keys. The
+/// operations are then split between the threads, and each reads or writes a key as
+/// the read ratio has it. The same workload and seed always generate the same
+/// operations.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Workload {
+    /// Number of distinct keys.
+    pub keys: u64,
+    /// Number of operations timed, over all threads.
+    pub operations: u64,
+    /// Sizes of the keys, each of which is its number padded with zeros. A key is
+    /// never shorter than its number, so it can be longer than its size.
+    pub key_size: SizeDistribution,
+    /// Sizes of the values written.
+    pub value_size: SizeDistribution,
+    /// Share of the operations that are reads, from `0.0` to `1.0`.
+    pub read_ratio: f64,
+    /// Order in which the operations pick their keys.
+    pub order: KeyOrder,
+    /// Number of threads running operations at once, each with its own connection when
+    /// benchmarking a server.
+    pub threads: usize,
+    /// Seed of the random numbers drawing sizes, keys and operations.
+    pub seed: u64,
+}
+
+impl Default for Workload {
+    fn default() -> Self {
+        Self {
+            keys: 10_000,
+            operations: 100_000,
+            key_size: SizeDistribution::Fixed(16),
+            value_size: SizeDistribution::Fixed(100),
+            read_ratio: 0.5,
+            order: KeyOrder::Random,
+            threads: 1,
+            seed: 0,
+        }
+    }
+}
+
+/// An operation generated by a [`Workload`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    /// Reads a key.
+    Get(String),
+    /// Writes the value of a key.
+    Set(String, String),
+}
+
+impl Workload {
+    /// Returns `KvsError::InvalidWorkload` if the workload cannot run.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |reason: &str| Err(KvsError::InvalidWorkload(reason.to_owned()));
+        if self.keys == 0 {
+            return invalid("there must be at least one key");
+        }
+        if self.threads == 0 {
+            return invalid("there must be at least one thread");
+        }
+        if !(0.0..=1.0).contains(&self.read_ratio) {
+            return invalid("the read ratio must be from 0 to 1");
+        }
+        for sizes in [self.key_size, self.value_size] {
+            if let SizeDistribution::Uniform { min, max } = sizes {
+                if min > max {
+                    return invalid("sizes cannot range from more to less");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the key of the given number, from `0`.
+    pub fn key(&self, n: u64) -> String {
+        let size = self
+            .key_size
+            .sample(mix(self.seed ^ n.wrapping_mul(KEY_SALT)));
+        format!("{:0>size$}", n, size = size)
+    }
+
+    /// Returns the operations setting the keys before the benchmark that the given
+    /// thread runs, from `0`.
+    pub fn load_ops(&self, thread: usize) -> Ops<'_> {
+        let threads = self.threads.max(1) as u64;
+        Ops {
+            workload: self,
+            load: true,
+            rng: Rng::new(self.seed, thread as u64, LOAD_SALT),
+            next: thread as u64,
+            step: threads,
+            end: self.keys,
+        }
+    }
+
+    /// Returns the operations timed that the given thread runs, from `0`.
+    ///
+    /// Operation `n` of the workload is run by thread `n` modulo the number of
+    /// threads, and picks key `n` modulo the number of keys in sequential order.
+    pub fn ops(&self, thread: usize) -> Ops<'_> {
+        let threads = self.threads.max(1) as u64;
+        Ops {
+            workload: self,
+            load: false,
+            rng: Rng::new(self.seed, thread as u64, OPS_SALT),
+            next: thread as u64,
+            step: threads,
+            end: self.operations,
+        }
+    }
+
+    /// Runs the workload against a target, and reports how it went.
+    ///
+    /// The keys are loaded first with as many threads as the workload runs, then the
+    /// operations are timed from when every thread is ready to when the last one is
+    /// done. The store directory is synced before its size is measured.
+    pub fn run(&self, target: &BenchTarget) -> Result<BenchReport> {
+        self.validate()?;
+        match target {
+            BenchTarget::Local(dir) => {
+                let store = SharedKvStore::open(dir)?;
+                let before = store.compactions();
+                let (elapsed, reads, writes) = self.run_threads(
+                    |_| Ok(store.clone()),
+                    |store, op| match op {
+                        Op::Get(key) => store.get(key).map(|_| ()),
+                        Op::Set(key, value) => store.set(key, value),
+                    },
+                )?;
+                store.sync()?;
+                let compactions = store.compactions() - before;
+                drop(store);
+                Ok(self.report(
+                    format!("kvs in {}", dir.display()),
+                    elapsed,
+                    reads,
+                    writes,
+                    Some(dir_size(dir)?),
+                    Some(compactions),
+                ))
+            }
+            BenchTarget::Server { addr, dir } => {
+                let (elapsed, reads, writes) = self.run_threads(
+                    |_| KvsClient::connect(addr.as_str()),
+                    |client, op| match op {
+                        Op::Get(key) => client.get(key).map(|_| ()),
+                        Op::Set(key, value) => client.set(key, value),
+                    },
+                )?;
+                let dir_bytes = dir.as_deref().map(dir_size).transpose()?;
+                Ok(self.report(
+                    format!("kvs-server at {}", addr),
+                    elapsed,
+                    reads,
+                    writes,
+                    dir_bytes,
+                    None,
+                ))
+            }
+        }
+    }
+
+    /// Loads the keys then times the operations on as many threads as the workload
+    /// runs, each with its own handle from `open`, returning how long the operations
+    /// took and the latencies of the reads and writes.
+    fn run_threads<H, O, A>(&self, open: O, apply: A) -> Result<(Duration, Latencies, Latencies)>
+    where
+        O: Fn(usize) -> Result<H> + Sync,
+        A: Fn(&mut H, Op) -> Result<()> + Sync,
+    {
+        let ready = Barrier::new(self.threads + 1);
+        thread::scope(|scope| {
+            let workers: Vec<_> = (0..self.threads)
+                .map(|thread| {
+                    let (open, apply, ready) = (&open, &apply, &ready);
+                    scope.spawn(move || {
+                        // The barrier is waited for even if loading fails, so the
+                        // other threads are not left waiting.
+                        let loaded = open(thread).and_then(|mut handle| {
+                            for op in self.load_ops(thread) {
+                                apply(&mut handle, op)?;
+                            }
+                            Ok(handle)
+                        });
+                        ready.wait();
+                        let mut handle = loaded?;
+                        let mut reads = Latencies::new();
+                        let mut writes = Latencies::new();
+                        for op in self.ops(thread) {
+                            let latencies = match op {
+                                Op::Get(_) => &mut reads,
+                                Op::Set(..) => &mut writes,
+                            };
+                            let start = Instant::now();
+                            apply(&mut handle, op)?;
+                            latencies.record(start.elapsed());
+                        }
+                        Ok::<_, KvsError>((reads, writes, Instant::now()))
+                    })
+                })
+                .collect();
+            ready.wait();
+            let start = Instant::now();
+            let mut reads = Latencies::new();
+            let mut writes = Latencies::new();
+            let mut end = start;
+            for worker in workers {
+                let (thread_reads, thread_writes, done) = worker.join().unwrap()?;
+                reads.merge(thread_reads);
+                writes.merge(thread_writes);
+                end = end.max(done);
+            }
+            Ok((end - start, reads, writes))
+        })
+    }
+
+    fn report(
+        &self,
+        target: String,
+        elapsed: Duration,
+        mut reads: Latencies,
+        mut writes: Latencies,
+        dir_bytes: Option<u64>,
+        compactions: Option<u64>,
+    ) -> BenchReport {
+        let read_latency = reads.summary();
+        let write_latency = writes.summary();
+        reads.merge(writes);
+        let operations = reads.len() as u64;
+        BenchReport {
+            target,
+            workload: self.clone(),
+            elapsed,
+            throughput: operations as f64 / elapsed.as_secs_f64().max(f64::MIN_POSITIVE),
+            latency: reads.summary(),
+            read_latency,
+            write_latency,
+            dir_bytes,
+            compactions,
+        }
+    }
+}
+
+/// What [`Workload::run`] benchmarks.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BenchTarget {
+    /// A `SharedKvStore` opened in the given directory, shared by the threads.
+    Local(PathBuf),
+    /// A `kvs-server` at the given address, speaking the JSON protocol. The size of
+    /// the store directory is reported if it is given.
+    ///
+    /// Every thread keeps a connection open for the whole benchmark, so the server
+    /// must serve as many connections at once as the workload runs threads.
+    Server {
+        /// Address of the server.
+        addr: String,
+        /// Directory of the store of the server, if it can be measured.
+        dir: Option<PathBuf>,
+    },
+}
+
+/// Salts of the random numbers drawn for the sizes of keys, values loaded and values
+/// of operations, so they do not follow each other.
+const KEY_SALT: u64 = 0x9e37_79b9_7f4a_7c15;
+const LOAD_SALT: u64 = 0xbf58_476d_1ce4_e5b9;
+const OPS_SALT: u64 = 0x94d0_49bb_1331_11eb;
+
+/// The operations of a thread of a [`Workload`], returned by [`Workload::ops`] and
+/// [`Workload::load_ops`].
+pub struct Ops<'a> {
+    workload: &'a Workload,
+    // Whether these are the writes loading every key.
+    load: bool,
+    rng: Rng,
+    // Number of the next operation, or key when loading, in the whole workload.
+    next: u64,
+    step: u64,
+    end: u64,
+}
+
+impl Iterator for Ops<'_> {
+    type Item = Op;
+
+    fn next(&mut self) -> Option<Op> {
+        if self.next >= self.end {
+            return None;
+        }
+        let n = self.next;
+        self.next += self.step;
+        let workload = self.workload;
+        if self.load {
+            return Some(Op::Set(workload.key(n), self.value()));
+        }
+        let key = match workload.order {
+            KeyOrder::Sequential => n % workload.keys,
+            KeyOrder::Random => self.rng.next() % workload.keys,
+        };
+        let key = workload.key(key);
+        if self.rng.fraction() < workload.read_ratio {
+            Some(Op::Get(key))
+        } else {
+            Some(Op::Set(key, self.value()))
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let left = self.end.saturating_sub(self.next).div_ceil(self.step) as usize;
+        (left, Some(left))
+    }
+}
+
+impl ExactSizeIterator for Ops<'_> {}
+
+impl Ops<'_> {
+    /// Returns a value of a size drawn from the workload's, of letters.
+    fn value(&mut self) -> String {
+        let size = self.workload.value_size.sample(self.rng.next());
+        let mut value = String::with_capacity(size);
+        let mut letters = self.rng.next();
+        for i in 0..size {
+            if i % 12 == 0 {
+                letters = self.rng.next();
+            }
+            value.push((b'a' + (letters % 26) as u8) as char);
+            letters /= 26;
+        }
+        value
+    }
+}
+
+/// SplitMix64, which is enough to draw a workload and needs no dependency.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64, thread: u64, salt: u64) -> Self {
+        Rng(mix(seed ^ salt ^ thread.wrapping_mul(KEY_SALT)))
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        mix(self.0)
+    }
+
+    /// Returns a number from `0.0` included to `1.0` excluded.
+    fn fraction(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Latencies of the operations of a benchmark, kept in full so the percentiles are
+/// exact.
+#[derive(Clone, Debug, Default)]
+pub struct Latencies {
+    // In nanoseconds.
+    samples: Vec<u64>,
+    sorted: bool,
+}
+
+impl Latencies {
+    /// Creates an empty set of latencies.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Adds the latency of an operation.
+    pub fn record(&mut self, latency: Duration) {
+        self.samples
+            .push(latency.as_nanos().min(u64::MAX as u128) as u64);
+        self.sorted = false;
+    }
+    /// Adds the latencies of another set, like the one of another thread.
+    pub fn merge(&mut self, other: Latencies) {
+        self.samples.extend(other.samples);
+        self.sorted = false;
+    }
+    /// Returns the number of latencies.
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+    /// Returns `true` if there are no latencies.
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+    /// Returns the latency at a percentile from `0.0` to `100.0`, the smallest one that
+    /// at least that share of the latencies is no more than, or zero if there are none.
+    pub fn percentile(&mut self, percentile: f64) -> Duration {
+        if self.samples.is_empty() {
+            return Duration::ZERO;
+        }
+        if !self.sorted {
+            self.samples.sort_unstable();
+            self.sorted = true;
+        }
+        let len = self.samples.len();
+        let rank = (percentile.clamp(0.0, 100.0) / 100.0 * len as f64).ceil() as usize;
+        Duration::from_nanos(self.samples[rank.clamp(1, len) - 1])
+    }
+    /// Returns the mean latency, or zero if there are none.
+    pub fn mean(&self) -> Duration {
+        if self.samples.is_empty() {
+            return Duration::ZERO;
+        }
+        let total: u128 = self.samples.iter().map(|&nanos| nanos as u128).sum();
+        Duration::from_nanos((total / self.samples.len() as u128) as u64)
+    }
+    /// Summarizes the latencies.
+    pub fn summary(&mut self) -> LatencySummary {
+        LatencySummary {
+            count: self.len() as u64,
+            mean: self.mean(),
+            p50: self.percentile(50.0),
+            p90: self.percentile(90.0),
+            p99: self.percentile(99.0),
+            p999: self.percentile(99.9),
+            max: self.percentile(100.0),
+        }
+    }
+}
+
+/// Summary of a set of [`Latencies`]. The latencies are in microseconds in JSON.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct LatencySummary {
+    /// Number of operations.
+    pub count: u64,
+    /// Mean latency.
+    #[serde(rename = "mean_us", serialize_with = "micros")]
+    pub mean: Duration,
+    /// Median latency.
+    #[serde(rename = "p50_us", serialize_with = "micros")]
+    pub p50: Duration,
+    /// 90th percentile latency.
+    #[serde(rename = "p90_us", serialize_with = "micros")]
+    pub p90: Duration,
+    /// 99th percentile latency.
+    #[serde(rename = "p99_us", serialize_with = "micros")]
+    pub p99: Duration,
+    /// 99.9th percentile latency.
+    #[serde(rename = "p999_us", serialize_with = "micros")]
+    pub p999: Duration,
+    /// Largest latency.
+    #[serde(rename = "max_us", serialize_with = "micros")]
+    pub max: Duration,
+}
+
+/// What a benchmark measured, returned by [`Workload::run`].
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct BenchReport {
+    /// What was benchmarked.
+    pub target: String,
+    /// The workload run.
+    pub workload: Workload,
+    /// Time the operations took, from when every thread was ready to when the last
+    /// one was done. It is in seconds in JSON.
+    #[serde(rename = "elapsed_secs", serialize_with = "secs")]
+    pub elapsed: Duration,
+    /// Operations per second.
+    pub throughput: f64,
+    /// Latencies of every operation.
+    pub latency: LatencySummary,
+    /// Latencies of the reads.
+    pub read_latency: LatencySummary,
+    /// Latencies of the writes.
+    pub write_latency: LatencySummary,
+    /// Size of the store directory afterwards, if it was measured.
+    pub dir_bytes: Option<u64>,
+    /// Number of compactions the benchmark triggered, loading the keys included, if
+    /// they can be counted, which they cannot through a server.
+    pub compactions: Option<u64>,
+}
+
+fn micros<S: Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64() * 1e6)
+}
+
+fn secs<S: Serializer>(duration: &Duration, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+/// Returns the total size of the files in a directory and its subdirectories.
+fn dir_size(dir: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            metadata.len()
+        };
+    }
+    Ok(size)
+}
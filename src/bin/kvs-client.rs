@@ -0,0 +1,197 @@
+use std::env;
+use std::process::exit;
+use std::time::Duration;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+#[cfg(feature = "tls")]
+use kvs::ClientTlsConfig;
+use kvs::{Health, KvsClient, KvsClientBuilder, KvsError, Result};
+
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+
+/// Variable holding the token to authenticate to the server with, if it requires one.
+const AUTH_TOKEN_VAR: &str = "KVS_AUTH_TOKEN";
+
+/// How long a ping waits to connect and for the server to answer.
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn main() {
+    let app = App::new("kvs-client")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about("Talk to a kvs-server")
+        .after_help(
+            "The token to authenticate with, if the server requires one, is read from the \
+             KVS_AUTH_TOKEN environment variable.",
+        )
+        .setting(AppSettings::DisableHelpSubcommand)
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(
+            Arg::with_name("ADDR")
+                .long("addr")
+                .value_name("IP:PORT")
+                .help("The address of the server")
+                .default_value(DEFAULT_ADDR)
+                .global(true),
+        )
+        .subcommand(
+            SubCommand::with_name("set")
+                .about("Set the value of a string key to a string")
+                .arg(Arg::with_name("KEY").help("A string key").required(true))
+                .arg(
+                    Arg::with_name("VALUE")
+                        .help("The string value of the key")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("get")
+                .about("Get the string value of a given string key")
+                .arg(Arg::with_name("KEY").help("A string key").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("rm")
+                .about("Remove a given key")
+                .arg(Arg::with_name("KEY").help("A string key").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("ping")
+                .about(
+                    "Check that the server is up, exiting with a non-zero code if it is not \
+                     or does not answer in time",
+                )
+                .arg(
+                    Arg::with_name("HEALTH")
+                        .long("health")
+                        .help("Check that the engine of the server can serve requests too"),
+                ),
+        );
+    #[cfg(feature = "tls")]
+    let app = app
+        .arg(
+            Arg::with_name("TLS")
+                .long("tls")
+                .help("Connect over TLS, trusting the certificate authorities of the system")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("TLS_CA")
+                .long("tls-ca")
+                .value_name("PATH")
+                .help(
+                    "Connect over TLS, trusting the certificate authorities in the PEM file \
+                     instead, like the one of a self-signed certificate",
+                )
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("TLS_SERVER_NAME")
+                .long("tls-server-name")
+                .value_name("NAME")
+                .help(
+                    "Connect over TLS, checking the certificate of the server is valid for \
+                     NAME instead of the host of its address",
+                )
+                .global(true),
+        );
+    let matches = app.get_matches();
+
+    if let Err(e) = run(&matches) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(matches: &ArgMatches) -> Result<()> {
+    let (command, matches) = matches.subcommand();
+    let matches = matches.unwrap();
+    let addr = matches.value_of("ADDR").unwrap();
+    let builder = builder(addr, matches)?;
+    if command == "ping" {
+        return ping(builder, matches.is_present("HEALTH"));
+    }
+    let mut client = builder.connect()?;
+    let key = matches.value_of("KEY").unwrap().to_string();
+    match command {
+        "set" => {
+            let value = matches.value_of("VALUE").unwrap();
+            client.set(key, value.to_string())?;
+        }
+        "get" => {
+            if let Some(value) = client.get(key)? {
+                println!("{}", value);
+            } else {
+                println!("Key not found");
+            }
+        }
+        "rm" => match client.remove(key) {
+            Ok(()) => {}
+            Err(KvsError::KeyNotFound) => {
+                println!("Key not found");
+                exit(1);
+            }
+            Err(e) => return Err(e),
+        },
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+/// Returns a builder of a client of the server, with the token from the environment
+/// and the TLS options given.
+fn builder(addr: &str, matches: &ArgMatches) -> Result<KvsClientBuilder> {
+    let builder = KvsClient::builder(addr);
+    let builder = match env::var(AUTH_TOKEN_VAR) {
+        Ok(token) if !token.is_empty() => builder.auth_token(token),
+        _ => builder,
+    };
+    tls(builder, matches)
+}
+
+/// Sets the client to connect over TLS if any TLS option is given.
+#[cfg(feature = "tls")]
+fn tls(builder: KvsClientBuilder, matches: &ArgMatches) -> Result<KvsClientBuilder> {
+    if !["TLS", "TLS_CA", "TLS_SERVER_NAME"]
+        .iter()
+        .any(|arg| matches.is_present(arg))
+    {
+        return Ok(builder);
+    }
+    let ca_cert = match matches.value_of("TLS_CA") {
+        Some(path) => Some(
+            std::fs::read(path)
+                .map_err(|e| KvsError::TlsConfig(format!("cannot read {}: {}", path, e)))?,
+        ),
+        None => None,
+    };
+    Ok(builder.tls(ClientTlsConfig {
+        ca_cert,
+        server_name: matches.value_of("TLS_SERVER_NAME").map(str::to_owned),
+    }))
+}
+
+#[cfg(not(feature = "tls"))]
+fn tls(builder: KvsClientBuilder, _: &ArgMatches) -> Result<KvsClientBuilder> {
+    Ok(builder)
+}
+
+/// Pings the server, or checks its health, exiting with a non-zero code if the engine
+/// is degraded.
+fn ping(builder: KvsClientBuilder, health: bool) -> Result<()> {
+    let mut client = builder.timeout(PING_TIMEOUT).connect()?;
+    if !health {
+        client.ping()?;
+        println!("PONG");
+        return Ok(());
+    }
+    match client.health()? {
+        Health::Ok => println!("ok"),
+        Health::Degraded(reason) => {
+            println!("degraded: {}", reason);
+            exit(1);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,397 @@
+use std::env::current_dir;
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, available_parallelism};
+use std::time::Duration;
+
+use clap::{App, Arg};
+use log::info;
+
+#[cfg(feature = "tls")]
+use kvs::ServerTlsConfig;
+#[cfg(feature = "sled")]
+use kvs::SledKvsEngine;
+use kvs::{
+    engine_of, KvsEngine, KvsError, KvsServer, Protocol, SharedKvStore, SharedQueueThreadPool,
+    ThreadPool,
+};
+
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+const DEFAULT_ENGINE: &str = "kvs";
+
+/// Set once the process is asked to stop by a signal.
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+fn main() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let app = App::new("kvs-server")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about("Serve a key-value store over TCP")
+        .arg(
+            Arg::with_name("ADDR")
+                .long("addr")
+                .value_name("IP:PORT")
+                .help("The address to listen on")
+                .default_value(DEFAULT_ADDR)
+                .validator(|addr| match addr.parse::<SocketAddr>() {
+                    Ok(_) => Ok(()),
+                    Err(_) => {
+                        Err("must be an IP address and a port, like 127.0.0.1:4000".to_owned())
+                    }
+                }),
+        )
+        .arg(
+            Arg::with_name("ENGINE")
+                .long("engine")
+                .value_name("ENGINE")
+                .help(
+                    "The storage engine, which must be the one the store directory was \
+                     created with, and is by default",
+                )
+                .possible_values(&["kvs", "sled"]),
+        )
+        .arg(
+            Arg::with_name("DIR")
+                .long("dir")
+                .value_name("PATH")
+                .help("The directory of the store, the current directory by default"),
+        )
+        .arg(
+            Arg::with_name("PROTOCOL")
+                .long("protocol")
+                .value_name("PROTOCOL")
+                .help("The wire protocol to speak")
+                .possible_values(&["json", "resp"])
+                .default_value("json"),
+        )
+        .arg(
+            Arg::with_name("THREADS")
+                .long("threads")
+                .value_name("N")
+                .help("The number of threads serving clients, one per CPU by default")
+                .validator(|n| match n.parse::<u32>() {
+                    Ok(n) if n > 0 => Ok(()),
+                    _ => Err("must be a positive number".to_owned()),
+                }),
+        )
+        .arg(
+            Arg::with_name("GRACE_PERIOD")
+                .long("grace-period")
+                .value_name("SECONDS")
+                .help(
+                    "How long a shutdown waits for the requests in flight before closing \
+                     the connections left",
+                )
+                .default_value("10")
+                .validator(|secs| parse_seconds(&secs).map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("AUTH_TOKEN_FILE")
+                .long("auth-token-file")
+                .value_name("PATH")
+                .env("KVS_AUTH_TOKEN_FILE")
+                .help(
+                    "A file holding the token every client must authenticate with before \
+                     any request, which kvs-client reads from KVS_AUTH_TOKEN",
+                ),
+        );
+    #[cfg(feature = "metrics")]
+    let app = app.arg(
+        Arg::with_name("METRICS_ADDR")
+            .long("metrics-addr")
+            .value_name("IP:PORT")
+            .help("The address to serve Prometheus metrics on at /metrics, like 127.0.0.1:9184")
+            .validator(|addr| match addr.parse::<SocketAddr>() {
+                Ok(_) => Ok(()),
+                Err(_) => Err("must be an IP address and a port, like 127.0.0.1:9184".to_owned()),
+            }),
+    );
+    #[cfg(feature = "tls")]
+    let app = app
+        .arg(
+            Arg::with_name("TLS_CERT")
+                .long("tls-cert")
+                .value_name("PATH")
+                .requires("TLS_KEY")
+                .help(
+                    "A PEM file holding the certificate chain to serve TLS with, starting with \
+                     the certificate of the server",
+                ),
+        )
+        .arg(
+            Arg::with_name("TLS_KEY")
+                .long("tls-key")
+                .value_name("PATH")
+                .requires("TLS_CERT")
+                .help("A PEM file holding the private key of the TLS certificate"),
+        );
+    let matches = app.get_matches();
+    let addr: SocketAddr = matches.value_of("ADDR").unwrap().parse().unwrap();
+    let protocol = matches.value_of("PROTOCOL").unwrap().parse().unwrap();
+    let threads = match matches.value_of("THREADS") {
+        Some(threads) => threads.parse().unwrap(),
+        None => available_parallelism().map_or(1, |n| n.get() as u32),
+    };
+    let grace_period = parse_seconds(matches.value_of("GRACE_PERIOD").unwrap()).unwrap();
+    let dir = match matches.value_of("DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => current_dir().unwrap_or_else(|e| fail(format!("No current directory: {}", e))),
+    };
+    let server = Server {
+        addr,
+        dir,
+        protocol,
+        threads,
+        grace_period,
+        auth_token_file: matches.value_of("AUTH_TOKEN_FILE").map(PathBuf::from),
+        #[cfg(feature = "tls")]
+        tls: matches.value_of("TLS_CERT").map(|cert| {
+            let key = matches.value_of("TLS_KEY").unwrap();
+            (PathBuf::from(cert), PathBuf::from(key))
+        }),
+        #[cfg(feature = "metrics")]
+        metrics_addr: matches
+            .value_of("METRICS_ADDR")
+            .map(|addr| addr.parse().unwrap()),
+    };
+
+    if let Err(message) = server.start(matches.value_of("ENGINE")) {
+        fail(message);
+    }
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    exit(1);
+}
+
+/// The configuration of the server.
+struct Server {
+    addr: SocketAddr,
+    dir: PathBuf,
+    protocol: Protocol,
+    threads: u32,
+    grace_period: Duration,
+    auth_token_file: Option<PathBuf>,
+    // The certificate chain and private key files.
+    #[cfg(feature = "tls")]
+    tls: Option<(PathBuf, PathBuf)>,
+    #[cfg(feature = "metrics")]
+    metrics_addr: Option<SocketAddr>,
+}
+
+impl Server {
+    /// Opens the store with the given engine, or the one owning the store directory,
+    /// and serves it until the listener fails or the process is asked to stop.
+    ///
+    /// Every step is checked before the listener binds, and a failure is returned as the
+    /// message to show.
+    fn start(self, engine: Option<&str>) -> Result<(), String> {
+        let recorded = engine_of(&self.dir).map_err(|e| dir_error(&self.dir, e))?;
+        let engine = match (engine, recorded.as_deref()) {
+            (Some(requested), Some(recorded)) if requested != recorded => {
+                let e = KvsError::EngineMismatch {
+                    recorded: recorded.to_owned(),
+                    requested: requested.to_owned(),
+                };
+                return Err(format!(
+                    "{}: {}, start it with --engine {}",
+                    self.dir.display(),
+                    e,
+                    recorded
+                ));
+            }
+            (Some(engine), _) | (None, Some(engine)) => engine.to_owned(),
+            (None, None) => DEFAULT_ENGINE.to_owned(),
+        };
+        match engine.as_str() {
+            "kvs" => {
+                let store = SharedKvStore::open(&self.dir).map_err(|e| dir_error(&self.dir, e))?;
+                self.serve(store, &engine)
+            }
+            #[cfg(feature = "sled")]
+            "sled" => {
+                let db = SledKvsEngine::open(&self.dir).map_err(|e| dir_error(&self.dir, e))?;
+                self.serve(db, &engine)
+            }
+            #[cfg(not(feature = "sled"))]
+            "sled" => Err(
+                "This kvs-server is built without the sled engine, rebuild it with \
+                 `--features sled`"
+                    .to_owned(),
+            ),
+            engine => Err(format!(
+                "{}: Store belongs to the unknown {} engine",
+                self.dir.display(),
+                engine
+            )),
+        }
+    }
+
+    fn serve<E: KvsEngine + Clone + Send + 'static>(
+        &self,
+        engine: E,
+        name: &str,
+    ) -> Result<(), String> {
+        let auth_token = match &self.auth_token_file {
+            Some(path) => Some(read_auth_token(path)?),
+            None => None,
+        };
+        #[cfg(feature = "tls")]
+        let tls = match &self.tls {
+            Some((cert, key)) => Some(read_tls_config(cert, key)?),
+            None => None,
+        };
+        let pool = SharedQueueThreadPool::new(self.threads)
+            .map_err(|e| format!("Cannot start the threads serving clients: {}", e))?;
+        let listener = TcpListener::bind(self.addr)
+            .map_err(|e| format!("Cannot listen on {}: {}", self.addr, e))?;
+        let addr = listener.local_addr().map_err(|e| e.to_string())?;
+        info!(
+            "kvs-server {} serving the {} engine in {} on {}",
+            env!("CARGO_PKG_VERSION"),
+            name,
+            self.dir.display(),
+            addr
+        );
+        let server = KvsServer::new(engine, pool)
+            .protocol(self.protocol)
+            .grace_period(self.grace_period);
+        let server = match auth_token {
+            Some(token) => {
+                info!("Requiring clients to authenticate with the token");
+                server.auth_token(token)
+            }
+            None => server,
+        };
+        #[cfg(feature = "tls")]
+        let server = match tls {
+            Some(tls) => {
+                info!("Serving clients over TLS");
+                server.tls(tls)
+            }
+            None => server,
+        };
+        #[cfg(feature = "metrics")]
+        let server = match self.metrics_addr {
+            Some(metrics_addr) => {
+                let metrics = TcpListener::bind(metrics_addr)
+                    .map_err(|e| format!("Cannot serve metrics on {}: {}", metrics_addr, e))?;
+                let metrics_addr = metrics.local_addr().map_err(|e| e.to_string())?;
+                info!("Serving metrics on http://{}/metrics", metrics_addr);
+                server.metrics_listener(metrics)
+            }
+            None => server,
+        };
+        let shutdown = server.shutdown_handle();
+        on_stop_signal(move || shutdown.shutdown())
+            .map_err(|e| format!("Cannot handle the signals stopping the server: {}", e))?;
+        server
+            .serve(listener)
+            .map_err(|e| format!("Stopped serving on {}: {}", addr, e))?;
+        info!("kvs-server stopped");
+        Ok(())
+    }
+}
+
+/// Parses a number of seconds, which must not be negative nor too large for a `Duration`.
+fn parse_seconds(secs: &str) -> Result<Duration, String> {
+    secs.parse()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .ok_or_else(|| "must be a number of seconds".to_owned())
+}
+
+/// Reads the auth token from its file, ignoring the whitespace around it, like the
+/// newline ending the file.
+fn read_auth_token(path: &Path) -> Result<String, String> {
+    let token = fs::read_to_string(path)
+        .map_err(|e| format!("Cannot read the auth token in {}: {}", path.display(), e))?;
+    let token = token.trim();
+    if token.is_empty() {
+        return Err(format!("The auth token file {} is empty", path.display()));
+    }
+    Ok(token.to_owned())
+}
+
+/// Reads the TLS certificate chain and private key of the server from their files.
+#[cfg(feature = "tls")]
+fn read_tls_config(cert: &Path, key: &Path) -> Result<ServerTlsConfig, String> {
+    let read =
+        |path: &Path| fs::read(path).map_err(|e| format!("Cannot read {}: {}", path.display(), e));
+    ServerTlsConfig::from_pem(&read(cert)?, &read(key)?).map_err(|e| {
+        format!(
+            "Cannot serve TLS with {} and {}: {}",
+            cert.display(),
+            key.display(),
+            e
+        )
+    })
+}
+
+/// Returns the message to show for a store directory that cannot be used.
+fn dir_error(dir: &Path, e: KvsError) -> String {
+    match e {
+        KvsError::Io(e) if e.kind() == std::io::ErrorKind::PermissionDenied => format!(
+            "Cannot use {} as the store directory, it is not writable: {}",
+            dir.display(),
+            e
+        ),
+        e => format!("Cannot open the store in {}: {}", dir.display(), e),
+    }
+}
+
+/// Calls `stop` on a thread of its own once the process is asked to stop, by SIGINT or
+/// SIGTERM on Unix, or by ctrl-C or the console closing on Windows.
+///
+/// A signal handler can do next to nothing safely, so it only sets a flag the thread
+/// polls.
+fn on_stop_signal<F: FnOnce() + Send + 'static>(stop: F) -> io::Result<()> {
+    handle_stop_signals()?;
+    thread::Builder::new()
+        .name("signals".to_owned())
+        .spawn(move || {
+            while !STOP_REQUESTED.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(50));
+            }
+            info!("Asked to stop, shutting down");
+            stop();
+        })?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn handle_stop_signals() -> io::Result<()> {
+    extern "C" fn handler(_: libc::c_int) {
+        STOP_REQUESTED.store(true, Ordering::SeqCst);
+    }
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        let handler = handler as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        // SAFETY: the handler only stores to an atomic, which is async-signal-safe.
+        if unsafe { libc::signal(signal, handler) } == libc::SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn handle_stop_signals() -> io::Result<()> {
+    use windows_sys::core::BOOL;
+    use windows_sys::Win32::System::Console::SetConsoleCtrlHandler;
+
+    unsafe extern "system" fn handler(_: u32) -> BOOL {
+        STOP_REQUESTED.store(true, Ordering::SeqCst);
+        1
+    }
+    // SAFETY: the handler only stores to an atomic.
+    if unsafe { SetConsoleCtrlHandler(Some(handler), 1) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -1,11 +1,18 @@
 use std::env::current_dir;
+use std::fmt::Display;
+use std::path::PathBuf;
 use std::process::exit;
+use std::str::FromStr;
+use std::time::Duration;
 
-use clap::{App, AppSettings, Arg, SubCommand};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 
-use kvs::{KvStore, KvsError, Result};
+use kvs::{
+    BenchReport, BenchTarget, KeyOrder, KvStore, KvsError, LatencySummary, Result,
+    SizeDistribution, Workload,
+};
 
-fn main() -> Result<()> {
+fn main() {
     let matches = App::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
         .author(env!("CARGO_PKG_AUTHORS"))
@@ -13,6 +20,14 @@ fn main() -> Result<()> {
         .setting(AppSettings::DisableHelpSubcommand)
         .setting(AppSettings::SubcommandRequiredElseHelp)
         .setting(AppSettings::VersionlessSubcommands)
+        .arg(
+            Arg::with_name("PATH")
+                .long("path")
+                .value_name("DIR")
+                .help("The store directory, the current directory by default")
+                .takes_value(true)
+                .global(true),
+        )
         .subcommand(
             SubCommand::with_name("set")
                 .about("Set the value of a string key to a string")
@@ -33,21 +48,135 @@ fn main() -> Result<()> {
                 .about("Remove a given key")
                 .arg(Arg::with_name("KEY").help("A string key").required(true)),
         )
+        .subcommand(
+            SubCommand::with_name("bench")
+                .about(
+                    "Benchmark a workload against the store in the store directory, whose \
+                     keys it overwrites, or against a server",
+                )
+                .arg(
+                    Arg::with_name("ADDR")
+                        .long("addr")
+                        .value_name("IP:PORT")
+                        .help(
+                            "Benchmark the server at this address instead, measuring the \
+                             store directory only if --path is given",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("KEYS")
+                        .long("keys")
+                        .value_name("N")
+                        .help("The number of keys, all set before the benchmark")
+                        .default_value("10000")
+                        .validator(|n| parsed::<u64>(&n)),
+                )
+                .arg(
+                    Arg::with_name("OPS")
+                        .long("ops")
+                        .value_name("N")
+                        .help("The number of operations timed")
+                        .default_value("100000")
+                        .validator(|n| parsed::<u64>(&n)),
+                )
+                .arg(
+                    Arg::with_name("KEY_SIZE")
+                        .long("key-size")
+                        .value_name("SIZE")
+                        .help("The size of the keys in bytes, or a range like 8-64")
+                        .default_value("16")
+                        .validator(|size| parsed::<SizeDistribution>(&size)),
+                )
+                .arg(
+                    Arg::with_name("VALUE_SIZE")
+                        .long("value-size")
+                        .value_name("SIZE")
+                        .help("The size of the values in bytes, or a range like 10-1000")
+                        .default_value("100")
+                        .validator(|size| parsed::<SizeDistribution>(&size)),
+                )
+                .arg(
+                    Arg::with_name("READ_RATIO")
+                        .long("read-ratio")
+                        .value_name("RATIO")
+                        .help("The share of the operations that are reads, from 0 to 1")
+                        .default_value("0.5")
+                        .validator(|ratio| match ratio.parse::<f64>() {
+                            Ok(ratio) if (0.0..=1.0).contains(&ratio) => Ok(()),
+                            _ => Err("must be a number from 0 to 1".to_owned()),
+                        }),
+                )
+                .arg(
+                    Arg::with_name("ORDER")
+                        .long("order")
+                        .value_name("ORDER")
+                        .help("The order the operations pick their keys in")
+                        .possible_values(&["sequential", "random"])
+                        .default_value("random"),
+                )
+                .arg(
+                    Arg::with_name("THREADS")
+                        .long("threads")
+                        .value_name("N")
+                        .help(
+                            "The number of threads running operations, each with a \
+                             connection a server must serve at the same time",
+                        )
+                        .default_value("1")
+                        .validator(|n| match n.parse::<usize>() {
+                            Ok(n) if n > 0 => Ok(()),
+                            _ => Err("must be a positive number".to_owned()),
+                        }),
+                )
+                .arg(
+                    Arg::with_name("SEED")
+                        .long("seed")
+                        .value_name("N")
+                        .help("The seed of the random sizes, keys and operations")
+                        .default_value("0")
+                        .validator(|n| parsed::<u64>(&n)),
+                )
+                .arg(
+                    Arg::with_name("JSON")
+                        .long("json")
+                        .help("Print the report as JSON"),
+                ),
+        )
         .get_matches();
 
+    if let Err(e) = run(&matches) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+/// Validates an argument that parses as a `T`.
+fn parsed<T: FromStr>(value: &str) -> std::result::Result<(), String>
+where
+    T::Err: Display,
+{
+    value.parse::<T>().map(|_| ()).map_err(|e| e.to_string())
+}
+
+fn run(matches: &ArgMatches) -> Result<()> {
+    let given_path = matches.value_of("PATH").map(PathBuf::from);
+    let path = match &given_path {
+        Some(path) => path.clone(),
+        None => current_dir()?,
+    };
     match matches.subcommand() {
         ("set", Some(matches)) => {
             let key = matches.value_of("KEY").unwrap();
             let value = matches.value_of("VALUE").unwrap();
 
-            let mut store = KvStore::open(current_dir()?)?;
+            let mut store = KvStore::open(&path)?;
             store.set(key.to_string(), value.to_string())?;
         }
         ("get", Some(matches)) => {
             let key = matches.value_of("KEY").unwrap();
 
-            let mut store = KvStore::open(current_dir()?)?;
-            if let Some(value) = store.get(key.to_string())? {
+            let mut store = KvStore::open(&path)?;
+            if let Some(value) = store.get(key)? {
                 println!("{}", value);
             } else {
                 println!("Key not found");
@@ -56,8 +185,8 @@ fn main() -> Result<()> {
         ("rm", Some(matches)) => {
             let key = matches.value_of("KEY").unwrap();
 
-            let mut store = KvStore::open(current_dir()?)?;
-            match store.remove(key.to_string()) {
+            let mut store = KvStore::open(&path)?;
+            match store.remove(key) {
                 Ok(()) => {}
                 Err(KvsError::KeyNotFound) => {
                     println!("Key not found");
@@ -66,7 +195,90 @@ fn main() -> Result<()> {
                 Err(e) => return Err(e),
             }
         }
+        ("bench", Some(matches)) => {
+            // Every value was validated by its argument.
+            let value = |name| matches.value_of(name).unwrap();
+            let workload = Workload {
+                keys: value("KEYS").parse().unwrap(),
+                operations: value("OPS").parse().unwrap(),
+                key_size: value("KEY_SIZE").parse()?,
+                value_size: value("VALUE_SIZE").parse()?,
+                read_ratio: value("READ_RATIO").parse().unwrap(),
+                order: value("ORDER").parse::<KeyOrder>()?,
+                threads: value("THREADS").parse().unwrap(),
+                seed: value("SEED").parse().unwrap(),
+            };
+            let target = match matches.value_of("ADDR") {
+                Some(addr) => BenchTarget::Server {
+                    addr: addr.to_owned(),
+                    dir: given_path,
+                },
+                None => BenchTarget::Local(path),
+            };
+            let report = workload.run(&target)?;
+            if matches.is_present("JSON") {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                print_report(&report);
+            }
+        }
         _ => unreachable!(),
     }
     Ok(())
 }
+
+/// Prints a benchmark report as a table.
+fn print_report(report: &BenchReport) {
+    let workload = &report.workload;
+    println!("target       {}", report.target);
+    let order = match workload.order {
+        KeyOrder::Sequential => "sequential",
+        KeyOrder::Random => "random",
+    };
+    println!(
+        "workload     {} operations, {} of them reads, on {} threads",
+        workload.operations, workload.read_ratio, workload.threads
+    );
+    println!(
+        "             {} keys of {} bytes in {} order, values of {} bytes",
+        workload.keys, workload.key_size, order, workload.value_size
+    );
+    println!("elapsed      {:.3} s", report.elapsed.as_secs_f64());
+    println!("throughput   {:.0} ops/s", report.throughput);
+    println!();
+    println!(
+        "{:<8} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        "latency", "count", "mean", "p50", "p90", "p99", "p99.9", "max"
+    );
+    for (name, latency) in [
+        ("all", &report.latency),
+        ("reads", &report.read_latency),
+        ("writes", &report.write_latency),
+    ] {
+        print_latency(name, latency);
+    }
+    println!();
+    match report.dir_bytes {
+        Some(bytes) => println!("directory    {} bytes", bytes),
+        None => println!("directory    not measured"),
+    }
+    match report.compactions {
+        Some(compactions) => println!("compactions  {}", compactions),
+        None => println!("compactions  not counted through a server"),
+    }
+}
+
+fn print_latency(name: &str, latency: &LatencySummary) {
+    let micros = |duration: Duration| format!("{:.1}us", duration.as_secs_f64() * 1e6);
+    println!(
+        "{:<8} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        name,
+        latency.count,
+        micros(latency.mean),
+        micros(latency.p50),
+        micros(latency.p90),
+        micros(latency.p99),
+        micros(latency.p999),
+        micros(latency.max)
+    );
+}
@@ -0,0 +1,141 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::kv::{Command, CommandPos};
+
+/// Bytes counted for every cached value on top of its key and value, for the
+/// bookkeeping that comes with it.
+const ENTRY_OVERHEAD: u64 = 64;
+
+/// A cache of the latest values read from the logs, evicting the least recently used
+/// values to stay within its budget.
+///
+/// The store drops the value of a key whenever it writes the key, so a cached value
+/// is always the one the index points to. Compactions only move records, so they
+/// leave the cache as it is.
+pub(crate) struct ValueCache {
+    values: HashMap<String, Cached>,
+    // Keys by the time of their last use, oldest first.
+    recency: BTreeMap<u64, String>,
+    clock: u64,
+    bytes: u64,
+    max_bytes: u64,
+    max_entries: usize,
+    pub(crate) hits: u64,
+    pub(crate) misses: u64,
+}
+
+/// A cached value and what it counts for.
+struct Cached {
+    cmd: Command,
+    // Where the value was read from, which tells when it expires.
+    pos: CommandPos,
+    cost: u64,
+    used: u64,
+}
+
+impl ValueCache {
+    /// Creates a cache holding up to `max_bytes` of values and up to `max_entries` of
+    /// them. A budget of `0` caches nothing.
+    pub(crate) fn new(max_bytes: u64, max_entries: usize) -> Self {
+        Self {
+            values: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+            bytes: 0,
+            max_bytes,
+            max_entries,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Returns whether the cache holds any values at all.
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.max_bytes > 0 && self.max_entries > 0
+    }
+
+    /// Returns the bytes the cached values count for.
+    pub(crate) fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Returns the cached value of a key, counting a hit or a miss.
+    ///
+    /// A value that has expired by `now` is dropped, and counts as a miss.
+    pub(crate) fn get(&mut self, key: &str, now: u64) -> Option<Command> {
+        if !self.is_enabled() {
+            return None;
+        }
+        let cached = match self.values.get_mut(key) {
+            Some(cached) if !cached.pos.is_expired(now) => cached,
+            Some(_) => {
+                self.remove(key);
+                self.misses += 1;
+                return None;
+            }
+            None => {
+                self.misses += 1;
+                return None;
+            }
+        };
+        self.clock += 1;
+        let key = self.recency.remove(&cached.used).unwrap();
+        cached.used = self.clock;
+        self.recency.insert(self.clock, key);
+        self.hits += 1;
+        Some(cached.cmd.clone())
+    }
+
+    /// Caches the value of a key read from the given position, evicting the least
+    /// recently used values to make room for it.
+    ///
+    /// A value that is larger than the whole budget is not cached.
+    pub(crate) fn insert(&mut self, key: &str, cmd: &Command, pos: &CommandPos) {
+        let cost = key.len() as u64 + value_len(cmd, pos) + ENTRY_OVERHEAD;
+        if !self.is_enabled() || cost > self.max_bytes {
+            return;
+        }
+        self.remove(key);
+        while self.bytes + cost > self.max_bytes || self.values.len() >= self.max_entries {
+            let (_, oldest) = self.recency.pop_first().unwrap();
+            let evicted = self.values.remove(&oldest).unwrap();
+            self.bytes -= evicted.cost;
+        }
+        self.clock += 1;
+        self.recency.insert(self.clock, key.to_owned());
+        self.values.insert(
+            key.to_owned(),
+            Cached {
+                cmd: cmd.clone(),
+                pos: *pos,
+                cost,
+                used: self.clock,
+            },
+        );
+        self.bytes += cost;
+    }
+
+    /// Drops the cached value of a key, if any.
+    pub(crate) fn remove(&mut self, key: &str) {
+        if let Some(cached) = self.values.remove(key) {
+            self.recency.remove(&cached.used);
+            self.bytes -= cached.cost;
+        }
+    }
+
+    /// Drops every cached value.
+    pub(crate) fn clear(&mut self) {
+        self.values.clear();
+        self.recency.clear();
+        self.bytes = 0;
+    }
+}
+
+/// Returns the length of a value, or of its record for a typed value.
+fn value_len(cmd: &Command, pos: &CommandPos) -> u64 {
+    match cmd {
+        Command::Set(_, value) | Command::SetWithExpiry(_, value, _) => value.len() as u64,
+        Command::SetBytes(_, value) => value.len() as u64,
+        _ => pos.len(),
+    }
+}
@@ -0,0 +1,274 @@
+use std::collections::{btree_map, BTreeMap};
+use std::io::{BufReader, Seek, SeekFrom};
+
+use crate::format::Record;
+use crate::kv::{at_record, Command, CommandPos, LogReaders};
+use crate::{KvsError, Result};
+
+/// A change written to a store, with its sequence number.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChangeEvent {
+    /// Sequence number of the change, one more than the change before it.
+    pub seq: u64,
+    /// What the change did.
+    pub change: Change,
+}
+
+/// What a change did to the keys of a store.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    /// The key was set to the value, given as UTF-8 with invalid bytes replaced.
+    Set(String, String),
+    /// The key was removed.
+    Remove(String),
+    /// Every key was removed.
+    Clear,
+}
+
+/// A change found in the logs, whose value is read once it is yielded.
+enum Found {
+    Set(String, CommandPos),
+    Remove(String),
+    Clear,
+}
+
+/// An iterator over the changes written to a store after a sequence number, in order.
+///
+/// This is created by [`KvStore::changes_since`]. The changes are found in the logs
+/// when it is created, and their values read as it advances.
+///
+/// [`KvStore::changes_since`]: crate::KvStore::changes_since
+pub struct Changes<'a> {
+    readers: &'a mut LogReaders,
+    // The error finding the changes, yielded first.
+    error: Option<KvsError>,
+    changes: btree_map::IntoIter<u64, Found>,
+}
+
+impl<'a> Changes<'a> {
+    /// Finds the changes after `since` up to the change of sequence number `last`.
+    ///
+    /// The logs are read from the newest on, until every change is found, and a change
+    /// missing from all of them was dropped by a compaction.
+    pub(crate) fn new(readers: &'a mut LogReaders, since: u64, last: u64) -> Self {
+        let mut found = BTreeMap::new();
+        let gens: Vec<u64> = readers.gens().rev().collect();
+        let mut error = None;
+        for gen in gens {
+            if found.len() as u64 >= last.saturating_sub(since) {
+                break;
+            }
+            if let Err(e) = changes_in_log(readers, gen, since, last, &mut found) {
+                error = Some(e);
+                break;
+            }
+        }
+        if error.is_none() && (found.len() as u64) < last.saturating_sub(since) {
+            let horizon = (since + 1..=last)
+                .rev()
+                .find(|seq| !found.contains_key(seq))
+                .unwrap();
+            error = Some(KvsError::CompactedAway { since, horizon });
+        }
+        if error.is_some() {
+            found.clear();
+        }
+        Self {
+            readers,
+            error,
+            changes: found.into_iter(),
+        }
+    }
+}
+
+impl Iterator for Changes<'_> {
+    type Item = Result<ChangeEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.error.take() {
+            return Some(Err(e));
+        }
+        let (seq, found) = self.changes.next()?;
+        let change = match found {
+            Found::Set(key, pos) => {
+                let mut value = Vec::new();
+                if let Err(e) = self.readers.copy_value(&key, &pos, &mut value) {
+                    return Some(Err(e));
+                }
+                Change::Set(key, String::from_utf8_lossy(&value).into_owned())
+            }
+            Found::Remove(key) => Change::Remove(key),
+            Found::Clear => Change::Clear,
+        };
+        Some(Ok(ChangeEvent { seq, change }))
+    }
+}
+
+/// Adds the changes in the log of a generation after `since` up to `last` to `found`.
+///
+/// Changes written before sequence numbers were are not numbered, and left out.
+fn changes_in_log(
+    readers: &mut LogReaders,
+    gen: u64,
+    since: u64,
+    last: u64,
+    found: &mut BTreeMap<u64, Found>,
+) -> Result<()> {
+    let format = readers.format;
+    let reader = readers.reader(gen)?;
+    reader.seek(SeekFrom::Start(0)).map_err(at_record(gen, 0))?;
+    // Sequence number of the next record, and where its checksum record starts.
+    let mut next = None;
+    // Key, sequence number, start and bytes still to read of a streamed value being read.
+    let mut stream: Option<(String, u64, u64, u64)> = None;
+    for record in format.records(&mut *reader) {
+        let (cmd, start, end) = match record? {
+            Record::Command {
+                cmd, start, end, ..
+            } => (cmd, start, end),
+            Record::Broken(offset) => return Err(KvsError::Corruption { gen, offset }),
+        };
+        let cmd = match cmd {
+            Command::Sequenced(_, seq) => {
+                next = Some((seq, start));
+                continue;
+            }
+            Command::Checksum(_) => continue,
+            Command::Chunk(chunk) => {
+                if let Some((_, _, _, remaining)) = &mut stream {
+                    *remaining = remaining.saturating_sub(chunk.len() as u64);
+                    if *remaining == 0 {
+                        let (key, seq, start, _) = stream.take().unwrap();
+                        let pos = CommandPos {
+                            gen,
+                            start,
+                            end,
+                            expires_at: None,
+                        };
+                        found.insert(seq, Found::Set(key, pos));
+                    }
+                }
+                continue;
+            }
+            cmd => cmd,
+        };
+        let (seq, start) = match next.take() {
+            Some((seq, start)) if seq > since && seq <= last => (seq, start),
+            _ => continue,
+        };
+        let pos = CommandPos {
+            gen,
+            start,
+            end,
+            expires_at: None,
+        };
+        match cmd {
+            Command::SetStream(key, len) if len > 0 => stream = Some((key, seq, start, len)),
+            Command::Remove(key) => {
+                found.insert(seq, Found::Remove(key));
+            }
+            Command::Clear => {
+                found.insert(seq, Found::Clear);
+            }
+            cmd => {
+                if let Some(key) = cmd.key() {
+                    found.insert(seq, Found::Set(key.to_owned(), pos));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The records of a log, one at a time.
+type Records = Box<dyn Iterator<Item = Result<Record>>>;
+
+/// An iterator over the changes in the logs of a range of generations, as commands.
+///
+/// This is created by [`KvStore::raw_commands`]. The logs are read in order, one
+/// record at a time, and every change is yielded as it was written, but for a
+/// streamed value, which is yielded whole as a `SetBytes` command, and the commands of
+/// a batch, which are yielded one by one. The iterator ends after its first error.
+///
+/// [`KvStore::raw_commands`]: crate::KvStore::raw_commands
+pub struct RawCommands<'a> {
+    readers: &'a LogReaders,
+    gens: std::vec::IntoIter<u64>,
+    // The generation and the records of the log being read.
+    log: Option<(u64, Records)>,
+}
+
+impl<'a> RawCommands<'a> {
+    pub(crate) fn new(readers: &'a LogReaders, gens: Vec<u64>) -> Self {
+        Self {
+            readers,
+            gens: gens.into_iter(),
+            log: None,
+        }
+    }
+
+    fn next_command(&mut self) -> Result<Option<Command>> {
+        let format = self.readers.format;
+        loop {
+            let (gen, records) = match &mut self.log {
+                Some((gen, records)) => (*gen, records),
+                None => match self.gens.next() {
+                    Some(gen) => {
+                        let file = BufReader::new(self.readers.open(gen)?);
+                        self.log = Some((gen, format.records(file)));
+                        continue;
+                    }
+                    None => return Ok(None),
+                },
+            };
+            let (cmd, start) = match next_record(records, gen)? {
+                Some(record) => record,
+                None => {
+                    self.log = None;
+                    continue;
+                }
+            };
+            match cmd.decompress(format).map_err(at_record(gen, start))? {
+                Command::Checksum(_) | Command::Sequenced(..) | Command::Batch(_) => {}
+                Command::SetStream(key, len) => {
+                    let mut value = Vec::with_capacity(len as usize);
+                    while (value.len() as u64) < len {
+                        match next_record(records, gen)? {
+                            Some((Command::Checksum(_), _)) => {}
+                            Some((Command::Chunk(chunk), _)) => value.extend_from_slice(&chunk),
+                            _ => return Err(KvsError::Corruption { gen, offset: start }),
+                        }
+                    }
+                    return Ok(Some(Command::SetBytes(key, value)));
+                }
+                Command::Chunk(_) => return Err(KvsError::Corruption { gen, offset: start }),
+                cmd => return Ok(Some(cmd)),
+            }
+        }
+    }
+}
+
+impl Iterator for RawCommands<'_> {
+    type Item = Result<Command>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.next_command();
+        if next.is_err() {
+            self.gens = Vec::new().into_iter();
+            self.log = None;
+        }
+        next.transpose()
+    }
+}
+
+/// Returns the next command of the records of a log and where its record starts.
+fn next_record(
+    records: &mut dyn Iterator<Item = Result<Record>>,
+    gen: u64,
+) -> Result<Option<(Command, u64)>> {
+    match records.next().transpose()? {
+        Some(Record::Command { cmd, start, .. }) => Ok(Some((cmd, start))),
+        Some(Record::Broken(offset)) => Err(KvsError::Corruption { gen, offset }),
+        None => Ok(None),
+    }
+}
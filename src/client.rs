@@ -0,0 +1,642 @@
+use std::fmt::Display;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+use log::debug;
+use serde::Deserialize;
+use serde_json::de::{Deserializer, IoRead};
+
+use crate::auth::AuthToken;
+use crate::protocol::{negotiate, Frame, Health, Hello, Request, Response, MAX_VERSION};
+use crate::stream::Stream;
+#[cfg(feature = "tls")]
+use crate::tls::ClientTls;
+#[cfg(feature = "tls")]
+use crate::ClientTlsConfig;
+use crate::{KvsClientPoolBuilder, KvsError, Result};
+
+/// Number of keys a scan asks the server for at a time.
+const SCAN_COUNT: usize = 100;
+/// Time waited before the first retry of a request, doubled for every retry after it.
+const RETRY_BACKOFF: Duration = Duration::from_millis(50);
+
+/// A client talking to a `kvs-server`.
+///
+/// A request failing to reach the server, or to get its response back, closes the
+/// connection, and the next request opens a new one. Requests that only read are retried
+/// on a new connection as many times as the builder allows, and writes only if it says
+/// so.
+pub struct KvsClient {
+    settings: KvsClientBuilder,
+    // `None` until connected again after a failure.
+    connection: Option<Connection>,
+}
+
+/// A connection to the server, once the handshake is done.
+struct Connection {
+    reader: Deserializer<IoRead<BufReader<Stream>>>,
+    writer: BufWriter<Stream>,
+    // Protocol version agreed on with the server.
+    version: u32,
+    // ID of the next request.
+    next_id: u64,
+}
+
+/// Builder of a [`KvsClient`], created by [`KvsClient::builder`].
+///
+/// By default nothing times out and failed requests are not retried.
+#[derive(Clone, Debug)]
+pub struct KvsClientBuilder {
+    addr: String,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    retries: u32,
+    retry_writes: bool,
+    auth_token: Option<AuthToken>,
+    #[cfg(feature = "tls")]
+    tls: Option<ClientTls>,
+}
+
+impl KvsClientBuilder {
+    /// Sets the connect, read and write timeouts at once.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the timeout is zero, like the ones it sets.
+    pub fn timeout(self, timeout: Duration) -> Self {
+        self.connect_timeout(timeout)
+            .read_timeout(timeout)
+            .write_timeout(timeout)
+    }
+    /// Sets how long connecting to every address of the server may take.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the timeout is zero.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        assert!(!timeout.is_zero(), "a timeout must not be zero");
+        self.connect_timeout = Some(timeout);
+        self
+    }
+    /// Sets how long waiting for the server to send anything may take, from the
+    /// handshake on.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the timeout is zero.
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        assert!(!timeout.is_zero(), "a timeout must not be zero");
+        self.read_timeout = Some(timeout);
+        self
+    }
+    /// Sets how long waiting for the server to take a request may take.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the timeout is zero.
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        assert!(!timeout.is_zero(), "a timeout must not be zero");
+        self.write_timeout = Some(timeout);
+        self
+    }
+    /// Sets how many times connecting, and a request that only reads, is retried on a
+    /// new connection when it fails to reach the server or to get its response back.
+    ///
+    /// The first retry waits 50 milliseconds, and every one after it twice as long as
+    /// the last. An error answered by the server is never retried.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+    /// Sets whether sets and removes are retried too, `false` by default.
+    ///
+    /// A write whose response is lost may have been made, so retrying it can make it
+    /// twice: a remove made twice fails with `KvsError::KeyNotFound`, and a set made
+    /// again may overwrite a value set in between by another client.
+    pub fn retry_writes(mut self, retry_writes: bool) -> Self {
+        self.retry_writes = retry_writes;
+        self
+    }
+    /// Sets the token to authenticate with to a server that requires one, sent before
+    /// any request on every connection.
+    ///
+    /// Connecting fails with `KvsError::AuthFailed` if the server rejects it, and a
+    /// request to such a server without a token with `KvsError::AuthRequired`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the token is empty.
+    pub fn auth_token(mut self, token: String) -> Self {
+        self.auth_token = Some(AuthToken::new(token));
+        self
+    }
+    /// Connects to the server over TLS.
+    ///
+    /// Connecting fails with `KvsError::Tls` if the TLS handshake does, like for a
+    /// certificate of the server that is not trusted or not valid for its name, and
+    /// with `KvsError::TlsConfig` if the certificates of the config cannot be read.
+    /// Available with the `tls` feature.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, config: ClientTlsConfig) -> Self {
+        self.tls = Some(ClientTls::new(config));
+        self
+    }
+    /// Returns a builder of a pool of up to `max_connections` clients connecting like
+    /// this builder.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_connections` is zero.
+    pub fn pool(self, max_connections: usize) -> KvsClientPoolBuilder {
+        KvsClientPoolBuilder::new(self, max_connections)
+    }
+    /// Connects to the server.
+    ///
+    /// Returns `KvsError::ConnectionRefused` or `KvsError::Connect` naming the address
+    /// if it cannot be reached, `KvsError::TimedOut` if it does not answer in time,
+    /// and `KvsError::ProtocolMismatch` if it speaks no protocol version this client
+    /// does.
+    pub fn connect(self) -> Result<KvsClient> {
+        let retries = self.retries;
+        let mut client = KvsClient {
+            settings: self,
+            connection: None,
+        };
+        client.retrying(retries, |client| client.connection().map(|_| ()))?;
+        Ok(client)
+    }
+
+    fn open(&self) -> Result<Connection> {
+        let stream = self.open_stream()?;
+        // Requests are flushed whole, so holding them back only delays them.
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(self.read_timeout)?;
+        stream.set_write_timeout(self.write_timeout)?;
+        let handshake = || {
+            let stream = self.secure(stream)?;
+            let mut reader = Deserializer::from_reader(BufReader::new(stream.try_clone()?));
+            let mut writer = BufWriter::new(stream);
+            serde_json::to_writer(&mut writer, &Hello::new())?;
+            writer.flush()?;
+            let hello = Hello::deserialize(&mut reader)?;
+            hello.check_magic()?;
+            let version = negotiate(MAX_VERSION, hello.version)?;
+            let mut connection = Connection {
+                reader,
+                writer,
+                version,
+                next_id: 1,
+            };
+            if let Some(token) = &self.auth_token {
+                let auth = Request::Auth {
+                    token: token.clone(),
+                };
+                let response = connection.exchange(std::slice::from_ref(&auth))?.pop();
+                response_result(response.ok_or_else(unexpected_response)?)?;
+            }
+            Ok(connection)
+        };
+        handshake().map_err(|e| self.transport_error(e))
+    }
+
+    /// Starts TLS on a new connection if the client is set to.
+    #[cfg(feature = "tls")]
+    fn secure(&self, stream: TcpStream) -> Result<Stream> {
+        match &self.tls {
+            Some(tls) => Ok(Stream::Tls(tls.connect(stream, &self.addr)?)),
+            None => Ok(Stream::Tcp(stream)),
+        }
+    }
+
+    #[cfg(not(feature = "tls"))]
+    fn secure(&self, stream: TcpStream) -> Result<Stream> {
+        Ok(Stream::Tcp(stream))
+    }
+
+    fn open_stream(&self) -> Result<TcpStream> {
+        let connect_error = |cause| connect_error(self.addr.clone(), cause);
+        let Some(timeout) = self.connect_timeout else {
+            return TcpStream::connect(&self.addr).map_err(connect_error);
+        };
+        let mut last = None;
+        for addr in self.addr.to_socket_addrs().map_err(connect_error)? {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last = Some(e),
+            }
+        }
+        let cause = last.unwrap_or_else(|| {
+            io::Error::new(ErrorKind::InvalidInput, "the address resolves to nothing")
+        });
+        Err(connect_error(cause))
+    }
+
+    /// Returns the error to report for a failure talking to the server, telling a
+    /// timeout and the server closing the connection apart from other I/O errors.
+    fn transport_error(&self, e: KvsError) -> KvsError {
+        let e = match e {
+            KvsError::Serde(e) if e.is_eof() => {
+                io::Error::new(ErrorKind::UnexpectedEof, "the server closed the connection").into()
+            }
+            KvsError::Serde(e) if e.is_io() => KvsError::Io(e.into()),
+            e => e,
+        };
+        match e {
+            KvsError::Io(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                KvsError::TimedOut(self.addr.clone())
+            }
+            e => e,
+        }
+    }
+}
+
+/// Returns the error to report for a failure connecting to a server.
+pub(crate) fn connect_error(addr: String, cause: io::Error) -> KvsError {
+    match cause.kind() {
+        ErrorKind::ConnectionRefused => KvsError::ConnectionRefused(addr),
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => KvsError::TimedOut(addr),
+        _ => KvsError::Connect { addr, cause },
+    }
+}
+
+/// Returns `true` for the errors of a request that failed to reach the server or to
+/// get its response back.
+fn is_transport_error(e: &KvsError) -> bool {
+    matches!(
+        e,
+        KvsError::Io(_)
+            | KvsError::TimedOut(_)
+            | KvsError::ConnectionRefused(_)
+            | KvsError::Connect { .. }
+    )
+}
+
+impl KvsClient {
+    /// Connects to the server at the given address, without timeouts or retries.
+    ///
+    /// Returns `KvsError::ConnectionRefused` or `KvsError::Connect` naming the address
+    /// if it cannot be reached, and `KvsError::ProtocolMismatch` if it speaks no
+    /// protocol version this client does.
+    pub fn connect<A: ToSocketAddrs + Display>(addr: A) -> Result<Self> {
+        Self::builder(addr).connect()
+    }
+    /// Returns a builder of a client of the server at the given address, setting its
+    /// timeouts and retries.
+    pub fn builder<A: ToSocketAddrs + Display>(addr: A) -> KvsClientBuilder {
+        KvsClientBuilder {
+            addr: addr.to_string(),
+            connect_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
+            retries: 0,
+            retry_writes: false,
+            auth_token: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.request(Request::Get { key })
+    }
+    /// Sets the value of a string key to a string.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.request(Request::Set { key, value }).map(|_| ())
+    }
+    /// Removes a given key.
+    ///
+    /// Returns `KvsError::KeyNotFound` if the given key is not found.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.request(Request::Remove { key }).map(|_| ())
+    }
+
+    /// Returns `true` if the given key exists, without fetching its value.
+    pub fn exists(&mut self, key: String) -> Result<bool> {
+        match self.call(Request::Exists { key })? {
+            Response::Bool(exists) => Ok(exists),
+            _ => Err(unexpected_response()),
+        }
+    }
+    /// Removes every given key that exists in a single request, returning how many did.
+    pub fn del(&mut self, keys: Vec<String>) -> Result<u64> {
+        match self.call(Request::Del { keys })? {
+            Response::Count(removed) => Ok(removed),
+            _ => Err(unexpected_response()),
+        }
+    }
+    /// Returns every key starting with `prefix` in sorted order.
+    ///
+    /// The server lists at most 10,000 keys at once, and fails with a
+    /// `KvsError::Server` error if more match; [`scan`] pages through any number.
+    ///
+    /// [`scan`]: KvsClient::scan
+    pub fn keys(&mut self, prefix: &str) -> Result<Vec<String>> {
+        let prefix = (!prefix.is_empty()).then(|| prefix.to_owned());
+        match self.call(Request::Keys { prefix })? {
+            Response::Keys { keys, .. } => Ok(keys),
+            _ => Err(unexpected_response()),
+        }
+    }
+    /// Returns the number of keys on the server.
+    pub fn dbsize(&mut self) -> Result<u64> {
+        match self.call(Request::DbSize)? {
+            Response::Count(len) => Ok(len),
+            _ => Err(unexpected_response()),
+        }
+    }
+    /// Checks that the server is up, without it touching its engine.
+    pub fn ping(&mut self) -> Result<()> {
+        match self.call(Request::Ping)? {
+            Response::Pong => Ok(()),
+            _ => Err(unexpected_response()),
+        }
+    }
+    /// Asks the server whether its engine can serve requests, which it checks by
+    /// reading a reserved key.
+    pub fn health(&mut self) -> Result<Health> {
+        match self.call(Request::Health)? {
+            Response::Health(health) => Ok(health),
+            _ => Err(unexpected_response()),
+        }
+    }
+    /// Returns an iterator over the keys starting with `prefix` in sorted order, which
+    /// asks the server for them a batch at a time.
+    ///
+    /// Writes made while the iterator is in use do not make it fail: a key present the
+    /// whole time is returned exactly once, and a key added or removed meanwhile may or
+    /// may not be.
+    pub fn scan(&mut self, prefix: &str) -> KeyScan<'_> {
+        KeyScan {
+            client: self,
+            prefix: (!prefix.is_empty()).then(|| prefix.to_owned()),
+            cursor: Some(String::new()),
+            batch: Vec::new().into_iter(),
+        }
+    }
+
+    /// Returns a pipeline of requests, which sends every request queued on it to the
+    /// server at once, without waiting for the response of one to send the next.
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline {
+            client: self,
+            requests: Vec::new(),
+        }
+    }
+
+    fn request(&mut self, request: Request) -> Result<Option<String>> {
+        match self.call(request)? {
+            Response::Ok(value) => Ok(value),
+            _ => Err(unexpected_response()),
+        }
+    }
+
+    /// Sends a request and reads back its response, turning error responses into errors.
+    fn call(&mut self, request: Request) -> Result<Response> {
+        let response = self.call_all(std::slice::from_ref(&request))?.pop();
+        response_result(response.ok_or_else(unexpected_response)?)
+    }
+
+    /// Sends requests back to back and reads back their responses, in order.
+    ///
+    /// The requests are retried together, if they all only read or writes are
+    /// retried.
+    fn call_all(&mut self, requests: &[Request]) -> Result<Vec<Response>> {
+        let read_only = requests.iter().all(Request::is_read_only);
+        let retries = if read_only || self.settings.retry_writes {
+            self.settings.retries
+        } else {
+            0
+        };
+        self.retrying(retries, |client| {
+            let connection = client.connection()?;
+            match connection.exchange(requests) {
+                Ok(responses) => {
+                    // The server closes a connection it answers this on.
+                    if let Some(Response::AuthRequired) = responses.first() {
+                        client.connection = None;
+                    }
+                    Ok(responses)
+                }
+                // The stream may be left mid-message.
+                Err(e) => {
+                    client.connection = None;
+                    Err(client.settings.transport_error(e))
+                }
+            }
+        })
+    }
+
+    /// Returns `true` if the client is connected, and the server has not closed the
+    /// connection or sent anything unasked since.
+    pub(crate) fn is_connected(&self) -> bool {
+        let Some(connection) = &self.connection else {
+            return false;
+        };
+        let stream = connection.writer.get_ref().tcp();
+        if stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let pending = stream.peek(&mut [0]);
+        let idle = matches!(pending, Err(e) if e.kind() == ErrorKind::WouldBlock);
+        stream.set_nonblocking(false).is_ok() && idle
+    }
+
+    /// Returns the connection to the server, opening a new one if the last one failed.
+    fn connection(&mut self) -> Result<&mut Connection> {
+        if self.connection.is_none() {
+            self.connection = Some(self.settings.open()?);
+        }
+        Ok(self.connection.as_mut().unwrap())
+    }
+
+    /// Runs `attempt` until it succeeds, fails with an error other than a transport
+    /// error, or has been retried `retries` times.
+    fn retrying<T, F>(&mut self, retries: u32, mut attempt: F) -> Result<T>
+    where
+        F: FnMut(&mut Self) -> Result<T>,
+    {
+        let mut backoff = RETRY_BACKOFF;
+        for _ in 0..retries {
+            match attempt(self) {
+                Err(e) if is_transport_error(&e) => {
+                    debug!("Retrying in {:?} after: {}", backoff, e);
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                }
+                result => return result,
+            }
+        }
+        attempt(self)
+    }
+}
+
+impl Connection {
+    /// Sends requests and reads back their responses, checking each answers its request.
+    ///
+    /// More than one request is written on a thread of its own while the responses are
+    /// read, so neither side waits for the other to read what it wrote.
+    fn exchange(&mut self, requests: &[Request]) -> Result<Vec<Response>> {
+        let first = self.next_id;
+        self.next_id += requests.len() as u64;
+        let version = self.version;
+        // A handle to stop the writing thread with, if there is one.
+        let stream = match requests.len() {
+            1 => None,
+            _ => Some(self.writer.get_ref().try_clone()?),
+        };
+        let writer = &mut self.writer;
+        let reader = &mut self.reader;
+        let mut send = move || -> Result<()> {
+            for (id, request) in (first..).zip(requests) {
+                serde_json::to_writer(&mut *writer, &Frame::new(version, id, request))?;
+            }
+            writer.flush()?;
+            Ok(())
+        };
+        let mut receive = || -> Result<Vec<Response>> {
+            (first..first + requests.len() as u64)
+                .map(|id| {
+                    let frame = Frame::<Response>::deserialize(&mut *reader)?;
+                    let (answered, response) = frame.into_message(version)?;
+                    // Version 1 responses carry no ID.
+                    if version >= 2 && answered != id {
+                        return Err(KvsError::Protocol(format!(
+                            "the response to request {} came for request {}",
+                            answered, id
+                        )));
+                    }
+                    Ok(response)
+                })
+                .collect()
+        };
+        let Some(stream) = stream else {
+            send()?;
+            return receive();
+        };
+        thread::scope(|scope| {
+            let sent = scope.spawn(send);
+            let received = receive();
+            // Unblock the writing thread if the server stopped reading.
+            if received.is_err() {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            let sent = sent.join().unwrap();
+            received.and_then(|responses| sent.map(|()| responses))
+        })
+    }
+}
+
+/// Turns an error response into the error it stands for.
+fn response_result(response: Response) -> Result<Response> {
+    match response {
+        Response::KeyNotFound => Err(KvsError::KeyNotFound),
+        Response::Err(message) => Err(KvsError::Server(message)),
+        Response::Unsupported(name) => Err(KvsError::UnsupportedRequest(name)),
+        Response::AuthRequired => Err(KvsError::AuthRequired),
+        Response::AuthFailed => Err(KvsError::AuthFailed),
+        response => Ok(response),
+    }
+}
+
+fn unexpected_response() -> KvsError {
+    KvsError::Protocol("unexpected response from the server".to_owned())
+}
+
+/// An iterator over the keys of a server, created by [`KvsClient::scan`].
+///
+/// A failed request is returned as the next item, after which the iterator ends.
+pub struct KeyScan<'a> {
+    client: &'a mut KvsClient,
+    prefix: Option<String>,
+    // Cursor of the next batch, or `None` once the last one is read.
+    cursor: Option<String>,
+    batch: std::vec::IntoIter<String>,
+}
+
+impl Iterator for KeyScan<'_> {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Result<String>> {
+        loop {
+            if let Some(key) = self.batch.next() {
+                return Some(Ok(key));
+            }
+            let request = Request::Scan {
+                cursor: self.cursor.take()?,
+                prefix: self.prefix.clone(),
+                count: SCAN_COUNT,
+            };
+            match self.client.call(request) {
+                Ok(Response::Keys { keys, cursor }) => {
+                    self.batch = keys.into_iter();
+                    self.cursor = (!cursor.is_empty()).then_some(cursor);
+                }
+                Ok(_) => return Some(Err(unexpected_response())),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// Requests queued to be sent to the server at once, created by [`KvsClient::pipeline`].
+///
+/// Pipelining saves a round trip to the server for every request after the first.
+/// The requests are sent in the order they are queued, and the server handles them in
+/// that order too.
+pub struct Pipeline<'a> {
+    client: &'a mut KvsClient,
+    requests: Vec<Request>,
+}
+
+impl Pipeline<'_> {
+    /// Queues getting the string value of a given string key.
+    pub fn get(&mut self, key: String) -> &mut Self {
+        self.requests.push(Request::Get { key });
+        self
+    }
+    /// Queues setting the value of a string key to a string.
+    pub fn set(&mut self, key: String, value: String) -> &mut Self {
+        self.requests.push(Request::Set { key, value });
+        self
+    }
+    /// Queues removing a given key.
+    pub fn remove(&mut self, key: String) -> &mut Self {
+        self.requests.push(Request::Remove { key });
+        self
+    }
+    /// Returns the number of requests queued.
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+    /// Returns `true` if no request is queued.
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+    /// Sends the requests queued, and returns their results in the same order: the
+    /// value of the key for a get, and `None` for a set or a remove.
+    ///
+    /// A request failing on the server, like a remove of a missing key with
+    /// `KvsError::KeyNotFound`, only fails its own result, and the requests after it
+    /// are still made. Failing to reach the server or to read the responses fails the
+    /// whole pipeline, after which any of the requests may or may not have been made.
+    /// The pipeline is retried like a single request, as a write if it holds sets or
+    /// removes.
+    pub fn execute(self) -> Result<Vec<Result<Option<String>>>> {
+        if self.requests.is_empty() {
+            return Ok(Vec::new());
+        }
+        let responses = self.client.call_all(&self.requests)?;
+        Ok(responses
+            .into_iter()
+            .map(|response| match response_result(response)? {
+                Response::Ok(value) => Ok(value),
+                _ => Err(unexpected_response()),
+            })
+            .collect())
+    }
+}
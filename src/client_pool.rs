@@ -0,0 +1,262 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::debug;
+
+use crate::{KvsClient, KvsClientBuilder, KvsError, Result};
+
+/// How long a connection of a pool stays idle by default before it is closed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// What checking a client out of an exhausted [`KvsClientPool`] does.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Exhausted {
+    /// Wait until a client is returned to the pool.
+    #[default]
+    Block,
+    /// Wait up to the given time, then fail with `KvsError::PoolExhausted`.
+    Wait(Duration),
+    /// Fail with `KvsError::PoolExhausted` at once.
+    Fail,
+}
+
+/// Builder of a [`KvsClientPool`], created by [`KvsClientBuilder::pool`].
+#[derive(Clone, Debug)]
+pub struct KvsClientPoolBuilder {
+    client: KvsClientBuilder,
+    max_connections: usize,
+    min_idle: usize,
+    idle_timeout: Option<Duration>,
+    exhausted: Exhausted,
+}
+
+impl KvsClientPoolBuilder {
+    pub(crate) fn new(client: KvsClientBuilder, max_connections: usize) -> Self {
+        assert!(max_connections > 0, "a pool needs at least one connection");
+        Self {
+            client,
+            max_connections,
+            min_idle: 0,
+            idle_timeout: Some(IDLE_TIMEOUT),
+            exhausted: Exhausted::default(),
+        }
+    }
+    /// Sets how many connections are opened with the pool and kept open when idle, no
+    /// more than the largest number of connections. None by default.
+    pub fn min_idle(mut self, min_idle: usize) -> Self {
+        self.min_idle = min_idle.min(self.max_connections);
+        self
+    }
+    /// Sets how long a connection beyond the minimum stays idle before it is closed, a
+    /// minute by default, or `None` to keep idle connections open.
+    pub fn idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+    /// Sets what checking a client out does once every connection is in use.
+    pub fn when_exhausted(mut self, exhausted: Exhausted) -> Self {
+        self.exhausted = exhausted;
+        self
+    }
+    /// Creates the pool, opening its minimum of idle connections.
+    ///
+    /// Fails like [`KvsClientBuilder::connect`] if one of them cannot be opened.
+    pub fn build(self) -> Result<KvsClientPool> {
+        let mut idle = Vec::with_capacity(self.max_connections);
+        for _ in 0..self.min_idle {
+            idle.push(Idle {
+                client: self.client.clone().connect()?,
+                since: Instant::now(),
+            });
+        }
+        let pool = Arc::new(Pool {
+            state: Mutex::new(State {
+                open: idle.len(),
+                idle,
+            }),
+            returned: Condvar::new(),
+            settings: self,
+        });
+        if let Some(idle_timeout) = pool.settings.idle_timeout {
+            let reaped = Arc::downgrade(&pool);
+            thread::Builder::new()
+                .name("kvs-client-pool".to_owned())
+                .spawn(move || reap_idle(reaped, idle_timeout))?;
+        }
+        Ok(KvsClientPool { pool })
+    }
+}
+
+/// A pool of clients of a `kvs-server`, shared between threads.
+///
+/// Clients are checked out with [`get`], and returned to the pool when the
+/// [`PooledClient`] is dropped, unless their connection broke. Up to the largest number
+/// of connections are open at once, each used by one thread at a time. Clones of the
+/// pool share its connections.
+///
+/// A client is checked for its connection being closed by the server before it is
+/// handed out, and replaced if it is.
+///
+/// [`get`]: KvsClientPool::get
+#[derive(Clone)]
+pub struct KvsClientPool {
+    pool: Arc<Pool>,
+}
+
+/// The state of a pool shared by its clones and its checked out clients.
+struct Pool {
+    settings: KvsClientPoolBuilder,
+    state: Mutex<State>,
+    // Notified when a client is returned, or a connection closed.
+    returned: Condvar,
+}
+
+struct State {
+    idle: Vec<Idle>,
+    // Connections open, idle or checked out.
+    open: usize,
+}
+
+/// A client waiting in the pool, with when it was returned.
+struct Idle {
+    client: KvsClient,
+    since: Instant,
+}
+
+impl KvsClientPool {
+    /// Checks a client out of the pool, connecting a new one if none is idle and the
+    /// pool is not exhausted.
+    ///
+    /// Returns `KvsError::PoolExhausted` if every connection is in use and the pool is
+    /// set not to wait for one, or not any longer.
+    pub fn get(&self) -> Result<PooledClient> {
+        let deadline = match self.pool.settings.exhausted {
+            Exhausted::Wait(wait) => Some(Instant::now() + wait),
+            _ => None,
+        };
+        let mut state = self.pool.state.lock().unwrap();
+        loop {
+            while let Some(Idle { client, .. }) = state.idle.pop() {
+                if client.is_connected() {
+                    return Ok(self.checked_out(client));
+                }
+                debug!("Replacing a pooled connection closed by the server");
+                state.open -= 1;
+            }
+            if state.open < self.pool.settings.max_connections {
+                state.open += 1;
+                drop(state);
+                return match self.pool.settings.client.clone().connect() {
+                    Ok(client) => Ok(self.checked_out(client)),
+                    Err(e) => {
+                        self.pool.closed(self.pool.state.lock().unwrap());
+                        Err(e)
+                    }
+                };
+            }
+            state = match (self.pool.settings.exhausted, deadline) {
+                (Exhausted::Block, _) => self.pool.returned.wait(state).unwrap(),
+                (Exhausted::Wait(_), Some(deadline)) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(self.exhausted());
+                    }
+                    self.pool
+                        .returned
+                        .wait_timeout(state, deadline - now)
+                        .unwrap()
+                        .0
+                }
+                _ => return Err(self.exhausted()),
+            };
+        }
+    }
+    /// Returns the number of connections open, idle or checked out.
+    pub fn connections(&self) -> usize {
+        self.pool.state.lock().unwrap().open
+    }
+    /// Returns the number of idle connections.
+    pub fn idle_connections(&self) -> usize {
+        self.pool.state.lock().unwrap().idle.len()
+    }
+
+    fn checked_out(&self, client: KvsClient) -> PooledClient {
+        PooledClient {
+            client: Some(client),
+            pool: Arc::clone(&self.pool),
+        }
+    }
+
+    fn exhausted(&self) -> KvsError {
+        KvsError::PoolExhausted(self.pool.settings.max_connections)
+    }
+}
+
+impl Pool {
+    /// Counts a connection as closed, letting a waiting checkout open another one.
+    fn closed(&self, mut state: MutexGuard<'_, State>) {
+        state.open -= 1;
+        self.returned.notify_one();
+    }
+}
+
+/// Closes the connections idle for longer than the idle timeout beyond the pool's
+/// minimum, until the pool is dropped.
+fn reap_idle(pool: Weak<Pool>, idle_timeout: Duration) {
+    loop {
+        thread::sleep(idle_timeout.min(Duration::from_secs(1)));
+        let Some(pool) = pool.upgrade() else {
+            return;
+        };
+        let mut state = pool.state.lock().unwrap();
+        let min_idle = pool.settings.min_idle;
+        // The oldest idle clients come first, since the latest returned are reused.
+        while state.idle.len() > min_idle && state.idle[0].since.elapsed() >= idle_timeout {
+            state.idle.remove(0);
+            state.open -= 1;
+            debug!("Closed a pooled connection idle for {:?}", idle_timeout);
+        }
+    }
+}
+
+/// A client checked out of a [`KvsClientPool`], returned to it when dropped.
+pub struct PooledClient {
+    // Only `None` once dropped.
+    client: Option<KvsClient>,
+    pool: Arc<Pool>,
+}
+
+impl Deref for PooledClient {
+    type Target = KvsClient;
+
+    fn deref(&self) -> &KvsClient {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledClient {
+    fn deref_mut(&mut self) -> &mut KvsClient {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledClient {
+    fn drop(&mut self) {
+        let client = self.client.take().unwrap();
+        // A client that lost its connection would open a new one not counted by the pool.
+        let connected = client.is_connected();
+        let mut state = self.pool.state.lock().unwrap();
+        if connected {
+            state.idle.push(Idle {
+                client,
+                since: Instant::now(),
+            });
+            self.pool.returned.notify_one();
+        } else {
+            debug!("Dropping a pooled client whose connection broke");
+            self.pool.closed(state);
+        }
+    }
+}
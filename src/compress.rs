@@ -0,0 +1,40 @@
+//! Compression of the records written by compactions.
+//!
+//! A compressed record is a `Compressed` command holding the deflated encoding of
+//! the command it stands for, so compressed and plain records can share a log.
+
+use crate::{KvsError, Result};
+
+/// Compression level of deflate, from 0 to 10.
+#[cfg(feature = "compression")]
+const LEVEL: u8 = 6;
+
+/// Deflates the encoding of a record.
+///
+/// Returns `None` if compression is not enabled.
+#[cfg(feature = "compression")]
+pub(crate) fn compress(bytes: &[u8]) -> Option<Vec<u8>> {
+    Some(miniz_oxide::deflate::compress_to_vec(bytes, LEVEL))
+}
+
+/// Deflates the encoding of a record.
+///
+/// Returns `None` if compression is not enabled.
+#[cfg(not(feature = "compression"))]
+pub(crate) fn compress(_bytes: &[u8]) -> Option<Vec<u8>> {
+    None
+}
+
+/// Inflates the encoding of a record deflated by `compress`.
+#[cfg(feature = "compression")]
+pub(crate) fn decompress(bytes: &[u8]) -> Result<Vec<u8>> {
+    miniz_oxide::inflate::decompress_to_vec(bytes).map_err(|e| KvsError::Decompress(e.to_string()))
+}
+
+/// Inflates the encoding of a record deflated by `compress`.
+#[cfg(not(feature = "compression"))]
+pub(crate) fn decompress(_bytes: &[u8]) -> Result<Vec<u8>> {
+    Err(KvsError::Decompress(
+        "the store has compressed logs, which need the `compression` feature".to_owned(),
+    ))
+}
@@ -0,0 +1,207 @@
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+/// Options of a CSV import by [`KvStore::import_csv`].
+///
+/// The defaults read two columns, the key then the value, separated by commas, with no
+/// header row, which is what [`KvStore::export_csv`] writes.
+///
+/// [`KvStore::import_csv`]: crate::KvStore::import_csv
+/// [`KvStore::export_csv`]: crate::KvStore::export_csv
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CsvOptions {
+    /// Whether the first row is a header, which is skipped.
+    pub has_header: bool,
+    /// Byte separating the fields of a row. It cannot be a double quote or a line break.
+    pub delimiter: u8,
+    /// Index of the column holding the key, from `0`.
+    pub key_column: usize,
+    /// Index of the column holding the value, from `0`.
+    pub value_column: usize,
+    /// Whether the first malformed row fails the import, instead of being reported and
+    /// skipped.
+    pub strict: bool,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        Self {
+            has_header: false,
+            delimiter: b',',
+            key_column: 0,
+            value_column: 1,
+            strict: false,
+        }
+    }
+}
+
+/// A row of a CSV import that did not read, reported by [`KvStore::import_csv`].
+///
+/// [`KvStore::import_csv`]: crate::KvStore::import_csv
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CsvRowError {
+    /// Line the row starts on, from `1`.
+    pub line: u64,
+    /// Why the row does not read.
+    pub reason: String,
+}
+
+impl fmt::Display for CsvRowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Row at line {} is malformed: {}", self.line, self.reason)
+    }
+}
+
+/// Where the reader is within a row.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    FieldStart,
+    Unquoted,
+    Quoted,
+    // A quote in a quoted field, closing it unless another quote follows.
+    QuoteSeen,
+    // A carriage return after a quoted field, which only a line feed may follow.
+    ReturnSeen,
+}
+
+/// A row read, with the line it starts on, holding its fields or why it is malformed.
+pub(crate) type Row = (u64, Result<Vec<String>, String>);
+
+/// Reads the rows of CSV text as in RFC 4180, one at a time.
+///
+/// Fields may be quoted with double quotes, within which delimiters and line breaks
+/// are kept and a doubled quote stands for one. Rows end with a line feed or a carriage
+/// return and line feed, and blank lines are skipped. A malformed row is skipped up to
+/// the end of the line it goes wrong on.
+pub(crate) struct CsvReader<R> {
+    reader: R,
+    delimiter: u8,
+    // Lines read so far.
+    line: u64,
+    buf: Vec<u8>,
+}
+
+impl<R: BufRead> CsvReader<R> {
+    pub(crate) fn new(reader: R, delimiter: u8) -> Self {
+        Self {
+            reader,
+            delimiter,
+            line: 0,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Reads the next row, or returns `None` at the end of the text.
+    pub(crate) fn read_row(&mut self) -> io::Result<Option<Row>> {
+        let mut fields = Vec::new();
+        let mut field = Vec::new();
+        let mut state = State::FieldStart;
+        let mut start = None;
+        loop {
+            self.buf.clear();
+            if self.reader.read_until(b'\n', &mut self.buf)? == 0 {
+                return Ok(start.map(|start| {
+                    let row = match state {
+                        State::Quoted => Err("unterminated quoted field".to_owned()),
+                        _ => {
+                            fields.push(field);
+                            into_strings(fields)
+                        }
+                    };
+                    (start, row)
+                }));
+            }
+            self.line += 1;
+            if start.is_none() {
+                if self.buf == b"\n" || self.buf == b"\r\n" {
+                    continue;
+                }
+                start = Some(self.line);
+            }
+            let start = start.unwrap();
+            for &byte in &self.buf {
+                state = match (state, byte) {
+                    (State::FieldStart, b'"') => State::Quoted,
+                    (State::Quoted, b'"') => State::QuoteSeen,
+                    (State::Quoted, byte) => {
+                        field.push(byte);
+                        State::Quoted
+                    }
+                    (State::QuoteSeen, b'"') => {
+                        field.push(b'"');
+                        State::Quoted
+                    }
+                    (State::QuoteSeen, b'\r') => State::ReturnSeen,
+                    (State::FieldStart | State::Unquoted | State::QuoteSeen, byte)
+                        if byte == self.delimiter =>
+                    {
+                        fields.push(std::mem::take(&mut field));
+                        State::FieldStart
+                    }
+                    (State::FieldStart | State::Unquoted, b'\n') => {
+                        if field.last() == Some(&b'\r') {
+                            field.pop();
+                        }
+                        fields.push(field);
+                        return Ok(Some((start, into_strings(fields))));
+                    }
+                    (State::QuoteSeen | State::ReturnSeen, b'\n') => {
+                        fields.push(field);
+                        return Ok(Some((start, into_strings(fields))));
+                    }
+                    (State::Unquoted, b'"') => {
+                        return self.malformed(start, "quote in an unquoted field");
+                    }
+                    (State::FieldStart | State::Unquoted, byte) => {
+                        field.push(byte);
+                        State::Unquoted
+                    }
+                    (State::QuoteSeen | State::ReturnSeen, _) => {
+                        return self.malformed(start, "text after a closing quote");
+                    }
+                };
+            }
+        }
+    }
+
+    /// Skips the rest of a malformed row, which is the rest of the line read.
+    fn malformed(&mut self, start: u64, reason: &str) -> io::Result<Option<Row>> {
+        if self.buf.last() != Some(&b'\n') {
+            self.buf.clear();
+            self.reader.read_until(b'\n', &mut self.buf)?;
+        }
+        Ok(Some((start, Err(reason.to_owned()))))
+    }
+}
+
+fn into_strings(fields: Vec<Vec<u8>>) -> Result<Vec<String>, String> {
+    fields
+        .into_iter()
+        .map(|field| String::from_utf8(field).map_err(|_| "invalid UTF-8".to_owned()))
+        .collect()
+}
+
+/// Writes a row of CSV fields, quoting the fields that hold a delimiter, a quote or a
+/// line break.
+pub(crate) fn write_row<W: Write>(
+    writer: &mut W,
+    fields: &[&str],
+    delimiter: u8,
+) -> io::Result<()> {
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            writer.write_all(&[delimiter])?;
+        }
+        if field
+            .bytes()
+            .any(|byte| matches!(byte, b'"' | b'\n' | b'\r') || byte == delimiter)
+        {
+            writer.write_all(b"\"")?;
+            writer.write_all(field.replace('"', "\"\"").as_bytes())?;
+            writer.write_all(b"\"")?;
+        } else {
+            writer.write_all(field.as_bytes())?;
+        }
+    }
+    writer.write_all(b"\n")
+}
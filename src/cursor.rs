@@ -0,0 +1,146 @@
+use std::ops::Bound;
+
+use crate::index::{Entries, KeyIndex};
+use crate::kv::{now_millis, CommandPos, LogReaders};
+use crate::Result;
+
+/// A cursor moving over the keys of a store in either direction.
+///
+/// This is created by [`KvStore::cursor`], before the first key. Every move looks the
+/// next key up in the index and returns whether the cursor is at a key afterwards, and
+/// the value of the key is only read from the log when asked for, so a record that fails
+/// to read fails its own [`value`] call and the cursor can still move past it. The cursor
+/// borrows the store mutably, so the store cannot be written to while it is in use.
+///
+/// [`KvStore::cursor`]: crate::KvStore::cursor
+/// [`value`]: Cursor::value
+pub struct Cursor<'a> {
+    index: &'a dyn KeyIndex,
+    readers: &'a mut LogReaders,
+    position: Position,
+    // The value of the key the cursor is at, once read.
+    value: Option<String>,
+    // Keys expired at the time the cursor was created are skipped.
+    now: u64,
+}
+
+/// Where a cursor is.
+enum Position {
+    BeforeFirst,
+    At(String, CommandPos),
+    AfterLast,
+}
+
+impl<'a> Cursor<'a> {
+    pub(crate) fn new(index: &'a dyn KeyIndex, readers: &'a mut LogReaders) -> Self {
+        Self {
+            index,
+            readers,
+            position: Position::BeforeFirst,
+            value: None,
+            now: now_millis(),
+        }
+    }
+    /// Moves the cursor to the first key greater than or equal to `key`.
+    ///
+    /// Returns `false` and leaves the cursor after the last key if there is none.
+    pub fn seek(&mut self, key: &str) -> Result<bool> {
+        self.move_forward(Bound::Included(key), Position::AfterLast)
+    }
+    /// Moves the cursor to the last key less than or equal to `key`.
+    ///
+    /// Returns `false` and leaves the cursor before the first key if there is none.
+    pub fn seek_for_prev(&mut self, key: &str) -> Result<bool> {
+        self.move_back(Bound::Included(key), Position::BeforeFirst)
+    }
+    /// Moves the cursor to the first key.
+    ///
+    /// Returns `false` if the store has no keys.
+    pub fn seek_to_first(&mut self) -> Result<bool> {
+        self.move_forward(Bound::Unbounded, Position::AfterLast)
+    }
+    /// Moves the cursor to the last key.
+    ///
+    /// Returns `false` if the store has no keys.
+    pub fn seek_to_last(&mut self) -> Result<bool> {
+        self.move_back(Bound::Unbounded, Position::BeforeFirst)
+    }
+    /// Moves the cursor to the next key, or to the first key if it is before the first.
+    ///
+    /// Returns `false` and leaves the cursor after the last key if there is none.
+    // The cursor moves in place rather than yielding keys, so it is not an `Iterator`.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<bool> {
+        let start = match &self.position {
+            Position::BeforeFirst => Bound::Unbounded,
+            Position::At(key, _) => Bound::Excluded(key.clone()),
+            Position::AfterLast => return Ok(false),
+        };
+        self.move_forward(start.as_ref().map(String::as_str), Position::AfterLast)
+    }
+    /// Moves the cursor to the previous key, or to the last key if it is after the last.
+    ///
+    /// Returns `false` and leaves the cursor before the first key if there is none.
+    pub fn prev(&mut self) -> Result<bool> {
+        let end = match &self.position {
+            Position::BeforeFirst => return Ok(false),
+            Position::At(key, _) => Bound::Excluded(key.clone()),
+            Position::AfterLast => Bound::Unbounded,
+        };
+        self.move_back(end.as_ref().map(String::as_str), Position::BeforeFirst)
+    }
+    /// Returns `true` if the cursor is at a key.
+    pub fn valid(&self) -> bool {
+        matches!(self.position, Position::At(..))
+    }
+    /// Returns the key the cursor is at, `None` if it is before the first key or after the
+    /// last.
+    pub fn key(&self) -> Option<&str> {
+        match &self.position {
+            Position::At(key, _) => Some(key),
+            _ => None,
+        }
+    }
+    /// Returns the value of the key the cursor is at, `None` if it is not at a key.
+    ///
+    /// The value is read from its log the first time it is asked for at a key.
+    pub fn value(&mut self) -> Result<Option<&str>> {
+        let Position::At(key, pos) = &self.position else {
+            return Ok(None);
+        };
+        if self.value.is_none() {
+            let cmd = self.readers.read_value(key, pos)?;
+            self.value = Some(cmd.into_value()?.unwrap_or_default());
+        }
+        Ok(self.value.as_deref())
+    }
+    fn move_forward(&mut self, start: Bound<&str>, past: Position) -> Result<bool> {
+        let entries = self.index.range(start, Bound::Unbounded);
+        let found = first_live(entries, self.now)?;
+        Ok(self.move_to(found, past))
+    }
+    fn move_back(&mut self, end: Bound<&str>, past: Position) -> Result<bool> {
+        let entries = self.index.range_rev(Bound::Unbounded, end);
+        let found = first_live(entries, self.now)?;
+        Ok(self.move_to(found, past))
+    }
+    fn move_to(&mut self, found: Option<(String, CommandPos)>, past: Position) -> bool {
+        self.value = None;
+        self.position = match found {
+            Some((key, pos)) => Position::At(key, pos),
+            None => past,
+        };
+        self.valid()
+    }
+}
+
+/// Returns the first entry whose key has not expired.
+fn first_live(entries: Entries<'_>, now: u64) -> Result<Option<(String, CommandPos)>> {
+    for entry in entries {
+        let (key, pos) = entry?;
+        if !pos.is_expired(now) {
+            return Ok(Some((key.into_owned(), pos)));
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,842 @@
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::iter::Peekable;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use log::warn;
+
+use crate::index::{Checkpoint, Entries, KeyIndex, StaleRecords};
+use crate::kv::{log_path, CommandPos, RecordCopier};
+use crate::Result;
+
+/// Name of the file holding the entries of a disk-resident index.
+pub(crate) const INDEX_FILE: &str = "index.sorted";
+
+/// Number of entries in each block of the index file. Only the first key of every
+/// block is kept in memory, and a lookup reads the one block its key can be in.
+const BLOCK_ENTRIES: usize = 64;
+
+/// Length of the position of an entry, following its key.
+const ENTRY_POS_LEN: usize = 33;
+
+/// Length of the footer at the end of the index file.
+const FOOTER_LEN: u64 = 80;
+
+/// An index keeping its entries in a sorted file in the store directory.
+///
+/// Entries changed since the file was written are kept in memory, and merged into a
+/// new file once there are `max_entries` of them, after a compaction, and when the
+/// store is closed. Up to `max_entries` entries read from the file are cached.
+///
+/// The file records how far into the logs it reflects them, so opening the store only
+/// replays the records written after it.
+pub(crate) struct DiskIndex {
+    dir: PathBuf,
+    // `None` until the first file is written, and after the index is cleared.
+    file: Option<IndexFile>,
+    // Entries changed since the file was written, `None` for a removed key.
+    changes: BTreeMap<String, Option<CommandPos>>,
+    cache: Mutex<Cache>,
+    max_entries: usize,
+    // How far into the logs the index reflects them.
+    at: Checkpoint,
+    // A read-only store never writes the file, so the changes stay in memory.
+    read_only: bool,
+}
+
+/// The index file, as it was last written.
+struct IndexFile {
+    file: File,
+    // First key and offset of every block of entries.
+    blocks: Vec<(String, u64)>,
+    // Where the entries end and the list of blocks starts.
+    entries_end: u64,
+    // Checksum of the entries and the list of blocks.
+    body_crc: u32,
+    // How far into the logs the file reflects them.
+    at: Checkpoint,
+}
+
+impl DiskIndex {
+    /// Opens the disk-resident index of a store whose logs are the given generations.
+    ///
+    /// An index file that is missing or damaged, or that reflects a log that is gone or
+    /// has shrunk since, is dropped, and the index starts out empty.
+    pub(crate) fn open(
+        dir: &Path,
+        gens: &[u64],
+        max_entries: usize,
+        read_only: bool,
+    ) -> Result<Self> {
+        let file = read_index_file(dir, gens);
+        if file.is_none() && !read_only {
+            remove_index_file(dir)?;
+        }
+        Ok(Self {
+            dir: dir.to_owned(),
+            at: file
+                .as_ref()
+                .map_or_else(Checkpoint::default, |file| file.at),
+            file,
+            changes: BTreeMap::new(),
+            cache: Mutex::new(Cache::default()),
+            max_entries: max_entries.max(1),
+            read_only,
+        })
+    }
+
+    /// Returns the point in the logs the index reflects them up to on open, where
+    /// replaying them has to resume.
+    pub(crate) fn replay_from(&self) -> Checkpoint {
+        self.at
+    }
+
+    fn lookup(&self, key: &str) -> Result<Option<CommandPos>> {
+        if let Some(&change) = self.changes.get(key) {
+            return Ok(change);
+        }
+        let file = match &self.file {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(pos) = cache.get(key) {
+            return Ok(pos);
+        }
+        let pos = file.get(key)?;
+        cache.insert(key.to_owned(), pos, self.max_entries);
+        Ok(pos)
+    }
+
+    /// Writes every entry of the index, mapped through `map`, to a temporary index file
+    /// that reflects the logs up to `at`.
+    fn write_file(
+        &self,
+        at: Checkpoint,
+        map: &mut dyn FnMut(&str, CommandPos) -> Result<CommandPos>,
+    ) -> Result<NewFile> {
+        let path = self.dir.join(format!("{INDEX_FILE}.tmp"));
+        let mut writer = BufWriter::new(File::create(&path)?);
+        let mut hasher = crc32fast::Hasher::new();
+        let mut blocks = Vec::new();
+        let mut offset = 0;
+        let mut buf = Vec::new();
+        for (i, entry) in self.iter().enumerate() {
+            let (key, pos) = entry?;
+            let pos = map(&key, pos)?;
+            if i % BLOCK_ENTRIES == 0 {
+                blocks.push((key.clone().into_owned(), offset));
+            }
+            buf.clear();
+            encode_entry(&mut buf, &key, &pos);
+            hasher.update(&buf);
+            writer.write_all(&buf)?;
+            offset += buf.len() as u64;
+        }
+        let entries_end = offset;
+        for (key, offset) in &blocks {
+            buf.clear();
+            encode_key(&mut buf, key);
+            buf.extend_from_slice(&offset.to_le_bytes());
+            hasher.update(&buf);
+            writer.write_all(&buf)?;
+        }
+        let body_crc = hasher.finalize();
+        writer.write_all(&encode_footer(entries_end, blocks.len(), body_crc, at))?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        Ok(NewFile {
+            path,
+            blocks,
+            entries_end,
+            body_crc,
+            at,
+        })
+    }
+
+    /// Replaces the index file with a new one holding every entry of the index.
+    fn replace_file(&mut self, new: NewFile) -> Result<()> {
+        let path = index_path(&self.dir);
+        // Close the previous file before replacing it, which Windows requires.
+        self.file = None;
+        fs::rename(&new.path, &path)?;
+        self.file = Some(IndexFile {
+            file: File::open(path)?,
+            blocks: new.blocks,
+            entries_end: new.entries_end,
+            body_crc: new.body_crc,
+            at: new.at,
+        });
+        self.at = new.at;
+        self.changes.clear();
+        self.cache.get_mut().unwrap().clear();
+        Ok(())
+    }
+
+    /// Writes the changes to a new index file that reflects the logs up to `at`.
+    fn merge_changes(&mut self, at: Checkpoint) -> Result<()> {
+        let new = self.write_file(at, &mut |_, pos| Ok(pos))?;
+        self.replace_file(new)
+    }
+}
+
+impl KeyIndex for DiskIndex {
+    fn get(&self, key: &str) -> Result<Option<CommandPos>> {
+        self.lookup(key)
+    }
+
+    fn insert(&mut self, key: String, pos: CommandPos) -> Result<Option<CommandPos>> {
+        let old = self.lookup(&key)?;
+        self.changes.insert(key, Some(pos));
+        Ok(old)
+    }
+
+    fn remove(&mut self, key: &str) -> Result<Option<CommandPos>> {
+        let old = self.lookup(key)?;
+        if old.is_some() {
+            self.changes.insert(key.to_owned(), None);
+        }
+        Ok(old)
+    }
+
+    fn clear(&mut self) -> Result<(u64, u64)> {
+        let (mut count, mut stale) = (0, 0);
+        for entry in self.iter() {
+            count += 1;
+            stale += entry?.1.len();
+        }
+        self.file = None;
+        self.changes.clear();
+        self.cache.get_mut().unwrap().clear();
+        remove_index_file(&self.dir)?;
+        Ok((count, stale))
+    }
+
+    fn range(&self, start: Bound<&str>, end: Bound<&str>) -> Entries<'_> {
+        Box::new(Merged {
+            file: self
+                .file
+                .as_ref()
+                .map(|file| file.entries(start, end).peekable()),
+            changes: self.changes.range::<str, _>((start, end)).peekable(),
+            rev: false,
+        })
+    }
+
+    fn range_rev(&self, start: Bound<&str>, end: Bound<&str>) -> Entries<'_> {
+        Box::new(Merged {
+            file: self
+                .file
+                .as_ref()
+                .map(|file| file.entries_rev(start, end).peekable()),
+            changes: self.changes.range::<str, _>((start, end)).rev().peekable(),
+            rev: true,
+        })
+    }
+
+    fn retain(&mut self, keep: &mut dyn FnMut(&str, &CommandPos) -> bool) -> Result<()> {
+        let mut dropped = Vec::new();
+        for entry in self.iter() {
+            let (key, pos) = entry?;
+            if !keep(&key, &pos) {
+                dropped.push(key.into_owned());
+            }
+        }
+        for key in dropped {
+            self.changes.insert(key, None);
+        }
+        let at = self.at;
+        self.checkpoint(at)
+    }
+
+    fn relocate(
+        &mut self,
+        at: Checkpoint,
+        start: Bound<&str>,
+        budget: u64,
+        copier: &mut RecordCopier<'_>,
+    ) -> Result<Option<String>> {
+        let gen = copier.gen();
+        let mut last: Option<String> = None;
+        let mut left = false;
+        // The whole file is rewritten, with only the entries copied pointing elsewhere.
+        let new = self.write_file(at, &mut |key, pos| {
+            let after_start = match start {
+                Bound::Included(start) => key >= start,
+                Bound::Excluded(start) => key > start,
+                Bound::Unbounded => true,
+            };
+            if !after_start || pos.gen >= gen || left {
+                return Ok(pos);
+            }
+            if copier.copied() >= budget && last.is_some() {
+                left = true;
+                return Ok(pos);
+            }
+            let copy = copier.copy(key, &pos)?;
+            last = Some(key.to_owned());
+            Ok(copy)
+        })?;
+        // The copies must be on disk before the index points at them.
+        copier.finish()?;
+        self.replace_file(new)?;
+        Ok(last.filter(|_| left))
+    }
+
+    fn checkpoint(&mut self, at: Checkpoint) -> Result<()> {
+        self.at = at;
+        if !self.read_only && self.changes.len() >= self.max_entries {
+            self.merge_changes(at)?;
+        }
+        Ok(())
+    }
+
+    fn save(&mut self, _dir: &Path, _logs: Vec<(u64, u64)>, at: Checkpoint) -> Result<()> {
+        self.persist(at)
+    }
+
+    fn persist(&mut self, at: Checkpoint) -> Result<()> {
+        match &mut self.file {
+            // Only the footer changes, so it is all that is rewritten.
+            Some(file) if self.changes.is_empty() => {
+                if file.at != at {
+                    file.rewrite_footer(&self.dir, at)?;
+                }
+                self.at = at;
+                Ok(())
+            }
+            _ => self.merge_changes(at),
+        }
+    }
+}
+
+impl IndexFile {
+    /// Returns the position of a key in the file.
+    fn get(&self, key: &str) -> Result<Option<CommandPos>> {
+        let block = match self
+            .blocks
+            .partition_point(|(first, _)| first.as_str() <= key)
+        {
+            0 => return Ok(None),
+            i => i - 1,
+        };
+        let start = self.blocks[block].1;
+        let end = self
+            .blocks
+            .get(block + 1)
+            .map_or(self.entries_end, |&(_, offset)| offset);
+        let mut bytes = vec![0; (end - start) as usize];
+        self.section(start, end).read_exact(&mut bytes)?;
+        // Keys compare as bytes the way they do as strings, so only the matching entry
+        // is decoded.
+        let mut entries = &bytes[..];
+        while entries.len() >= 4 {
+            let key_len = u32::from_le_bytes(entries[..4].try_into().unwrap()) as usize;
+            let entry_key = entries
+                .get(4..4 + key_len)
+                .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+            match entry_key.cmp(key.as_bytes()) {
+                Ordering::Less => {}
+                Ordering::Equal => return Ok(Some(read_entry(&mut entries)?.1)),
+                Ordering::Greater => break,
+            }
+            entries = entries
+                .get(4 + key_len + ENTRY_POS_LEN..)
+                .unwrap_or_default();
+        }
+        Ok(None)
+    }
+
+    /// Returns the entries of the file within the given key range, in key order.
+    fn entries(&self, start: Bound<&str>, end: Bound<&str>) -> FileEntries<'_> {
+        let block = match start {
+            Bound::Included(key) | Bound::Excluded(key) => self
+                .blocks
+                .partition_point(|(first, _)| first.as_str() <= key)
+                .saturating_sub(1),
+            Bound::Unbounded => 0,
+        };
+        let offset = self
+            .blocks
+            .get(block)
+            .map_or(self.entries_end, |&(_, offset)| offset);
+        FileEntries {
+            reader: BufReader::new(self.section(offset, self.entries_end)),
+            start: start.map(str::to_owned),
+            end: end.map(str::to_owned),
+            done: false,
+        }
+    }
+
+    /// Returns the entries of the file within the given key range, in reverse key order.
+    fn entries_rev(&self, start: Bound<&str>, end: Bound<&str>) -> RevFileEntries<'_> {
+        let blocks = match end {
+            Bound::Included(key) | Bound::Excluded(key) => self
+                .blocks
+                .partition_point(|(first, _)| first.as_str() <= key),
+            Bound::Unbounded => self.blocks.len(),
+        };
+        RevFileEntries {
+            file: self,
+            blocks,
+            block: Vec::new(),
+            start: start.map(str::to_owned),
+            end: end.map(str::to_owned),
+            done: false,
+        }
+    }
+
+    /// Reads the entries of a block, in key order.
+    fn read_block(&self, block: usize) -> io::Result<Vec<(String, CommandPos)>> {
+        let start = self.blocks[block].1;
+        let end = self
+            .blocks
+            .get(block + 1)
+            .map_or(self.entries_end, |&(_, offset)| offset);
+        let mut reader = BufReader::new(self.section(start, end));
+        let mut entries = Vec::with_capacity(BLOCK_ENTRIES);
+        while !reader.fill_buf()?.is_empty() {
+            entries.push(read_entry(&mut reader)?);
+        }
+        Ok(entries)
+    }
+
+    fn section(&self, offset: u64, end: u64) -> Section<'_> {
+        Section {
+            file: &self.file,
+            offset,
+            end,
+        }
+    }
+
+    /// Records in the file that it reflects the logs up to `at`.
+    ///
+    /// A crash midway leaves a damaged footer, which makes the next open drop the file.
+    fn rewrite_footer(&mut self, dir: &Path, at: Checkpoint) -> Result<()> {
+        let footer = encode_footer(self.entries_end, self.blocks.len(), self.body_crc, at);
+        let mut file = OpenOptions::new().write(true).open(index_path(dir))?;
+        file.seek(SeekFrom::End(-(FOOTER_LEN as i64)))?;
+        file.write_all(&footer)?;
+        file.sync_all()?;
+        self.at = at;
+        Ok(())
+    }
+}
+
+/// An index file written next to the one it replaces.
+struct NewFile {
+    path: PathBuf,
+    blocks: Vec<(String, u64)>,
+    entries_end: u64,
+    body_crc: u32,
+    at: Checkpoint,
+}
+
+/// Entries recently read from the index file, `None` for keys it does not have.
+#[derive(Default)]
+struct Cache {
+    entries: HashMap<String, (Option<CommandPos>, u64)>,
+    // Counts the uses of the cache, to tell which entries were used least recently.
+    clock: u64,
+}
+
+impl Cache {
+    fn get(&mut self, key: &str) -> Option<Option<CommandPos>> {
+        self.clock += 1;
+        let clock = self.clock;
+        self.entries.get_mut(key).map(|(pos, used)| {
+            *used = clock;
+            *pos
+        })
+    }
+
+    /// Caches an entry, first evicting the least recently used half of the entries if
+    /// there are `max` of them.
+    fn insert(&mut self, key: String, pos: Option<CommandPos>, max: usize) {
+        if self.entries.len() >= max {
+            let mut used: Vec<u64> = self.entries.values().map(|&(_, used)| used).collect();
+            let middle = used.len() / 2;
+            let median = *used.select_nth_unstable(middle).1;
+            self.entries.retain(|_, &mut (_, used)| used > median);
+        }
+        self.clock += 1;
+        self.entries.insert(key, (pos, self.clock));
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// Entries of the index file with the changes since it was written applied, in key order,
+/// or in reverse key order if `rev` is set.
+struct Merged<F: Iterator, C: Iterator> {
+    // `None` once reading the file has failed.
+    file: Option<Peekable<F>>,
+    changes: Peekable<C>,
+    rev: bool,
+}
+
+impl<'a, F, C> Iterator for Merged<F, C>
+where
+    F: Iterator<Item = Result<(String, CommandPos)>>,
+    C: Iterator<Item = (&'a String, &'a Option<CommandPos>)>,
+{
+    type Item = Result<(Cow<'a, str>, CommandPos)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if matches!(self.file.as_mut().and_then(Peekable::peek), Some(Err(_))) {
+                if let Some(Err(e)) = self.file.take().and_then(|mut file| file.next()) {
+                    return Some(Err(e));
+                }
+            }
+            let in_file = match self.file.as_mut().and_then(Peekable::peek) {
+                Some(Ok((key, _))) => Some(key.as_str()),
+                _ => None,
+            };
+            let changed = self.changes.peek().map(|(key, _)| key.as_str());
+            let (from_file, from_changes) = match (in_file, changed) {
+                (None, None) => return None,
+                (Some(_), None) => (true, false),
+                (None, Some(_)) => (false, true),
+                (Some(in_file), Some(changed)) if self.rev => {
+                    (in_file >= changed, changed >= in_file)
+                }
+                (Some(in_file), Some(changed)) => (in_file <= changed, changed <= in_file),
+            };
+            if !from_changes {
+                let (key, pos) = self.file.as_mut()?.next()?.ok()?;
+                return Some(Ok((Cow::Owned(key), pos)));
+            }
+            // A change replaces the entry of the file for the same key.
+            if from_file {
+                self.file.as_mut()?.next();
+            }
+            if let Some((key, Some(pos))) = self.changes.next() {
+                return Some(Ok((Cow::Borrowed(key.as_str()), *pos)));
+            }
+        }
+    }
+}
+
+/// Entries of the index file within a key range, in key order.
+struct FileEntries<'a> {
+    reader: BufReader<Section<'a>>,
+    start: Bound<String>,
+    end: Bound<String>,
+    done: bool,
+}
+
+impl Iterator for FileEntries<'_> {
+    type Item = Result<(String, CommandPos)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let entry = match self.reader.fill_buf() {
+                Ok([]) => None,
+                Ok(_) => Some(read_entry(&mut self.reader)),
+                Err(e) => Some(Err(e)),
+            };
+            let (key, pos) = match entry {
+                Some(Ok(entry)) => entry,
+                Some(Err(e)) => {
+                    self.done = true;
+                    return Some(Err(e.into()));
+                }
+                None => break,
+            };
+            let before_start = match &self.start {
+                Bound::Included(start) => key < *start,
+                Bound::Excluded(start) => key <= *start,
+                Bound::Unbounded => false,
+            };
+            let after_end = match &self.end {
+                Bound::Included(end) => key > *end,
+                Bound::Excluded(end) => key >= *end,
+                Bound::Unbounded => false,
+            };
+            if after_end {
+                break;
+            } else if !before_start {
+                return Some(Ok((key, pos)));
+            }
+        }
+        self.done = true;
+        None
+    }
+}
+
+/// Entries of the index file within a key range, in reverse key order.
+///
+/// The file is read backwards one block at a time.
+struct RevFileEntries<'a> {
+    file: &'a IndexFile,
+    // Number of blocks left to read, the last of them first.
+    blocks: usize,
+    // Entries of the block being read, the next one last.
+    block: Vec<(String, CommandPos)>,
+    start: Bound<String>,
+    end: Bound<String>,
+    done: bool,
+}
+
+impl Iterator for RevFileEntries<'_> {
+    type Item = Result<(String, CommandPos)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let (key, pos) = match self.block.pop() {
+                Some(entry) => entry,
+                None if self.blocks == 0 => break,
+                None => {
+                    self.blocks -= 1;
+                    match self.file.read_block(self.blocks) {
+                        Ok(block) => self.block = block,
+                        Err(e) => {
+                            self.done = true;
+                            return Some(Err(e.into()));
+                        }
+                    }
+                    continue;
+                }
+            };
+            let after_end = match &self.end {
+                Bound::Included(end) => key > *end,
+                Bound::Excluded(end) => key >= *end,
+                Bound::Unbounded => false,
+            };
+            let before_start = match &self.start {
+                Bound::Included(start) => key < *start,
+                Bound::Excluded(start) => key <= *start,
+                Bound::Unbounded => false,
+            };
+            if before_start {
+                break;
+            } else if !after_end {
+                return Some(Ok((key, pos)));
+            }
+        }
+        self.done = true;
+        None
+    }
+}
+
+/// Reads a range of a file through offsets, without moving its cursor.
+struct Section<'a> {
+    file: &'a File,
+    offset: u64,
+    end: u64,
+}
+
+impl Read for Section<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = (self.end - self.offset).min(buf.len() as u64) as usize;
+        if len == 0 {
+            return Ok(0);
+        }
+        let read = read_at(self.file, &mut buf[..len], self.offset)?;
+        self.offset += read as u64;
+        Ok(read)
+    }
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+fn index_path(dir: &Path) -> PathBuf {
+    dir.join(INDEX_FILE)
+}
+
+/// Reads the index file of a store whose logs are the given generations.
+///
+/// Returns `None` if there is no index file, or if it is damaged or does not match the logs.
+fn read_index_file(dir: &Path, gens: &[u64]) -> Option<IndexFile> {
+    match try_read_index_file(dir, gens) {
+        Ok(file) => file,
+        Err(e) => {
+            warn!("Ignoring the index file: {}", e);
+            None
+        }
+    }
+}
+
+fn try_read_index_file(dir: &Path, gens: &[u64]) -> Result<Option<IndexFile>> {
+    let file = match File::open(index_path(dir)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let len = file.metadata()?.len();
+    if len < FOOTER_LEN {
+        warn!("Ignoring the damaged index file");
+        return Ok(None);
+    }
+    let body_len = len - FOOTER_LEN;
+    let mut footer = [0; FOOTER_LEN as usize];
+    Section {
+        file: &file,
+        offset: body_len,
+        end: len,
+    }
+    .read_exact(&mut footer)?;
+    let (entries_end, block_count, body_crc, at) = match decode_footer(&footer) {
+        Some(footer) if footer.0 <= body_len => footer,
+        _ => {
+            warn!("Ignoring the damaged index file");
+            return Ok(None);
+        }
+    };
+    let mut hasher = crc32fast::Hasher::new();
+    let mut body = BufReader::new(Section {
+        file: &file,
+        offset: 0,
+        end: body_len,
+    });
+    loop {
+        let buf = body.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+        hasher.update(buf);
+        let read = buf.len();
+        body.consume(read);
+    }
+    if hasher.finalize() != body_crc {
+        warn!("Ignoring the damaged index file");
+        return Ok(None);
+    }
+    if !gens.contains(&at.gen) || fs::metadata(log_path(dir, at.gen))?.len() < at.offset {
+        return Ok(None);
+    }
+    let mut reader = BufReader::new(Section {
+        file: &file,
+        offset: entries_end,
+        end: body_len,
+    });
+    let mut blocks = Vec::with_capacity(block_count as usize);
+    for _ in 0..block_count {
+        let key = read_key(&mut reader)?;
+        blocks.push((key, read_u64(&mut reader)?));
+    }
+    Ok(Some(IndexFile {
+        file,
+        blocks,
+        entries_end,
+        body_crc,
+        at,
+    }))
+}
+
+/// Removes the index file of a store, if any.
+///
+/// A store opened with an in-memory index does this right away, so the file cannot
+/// outlive the writes it misses.
+pub(crate) fn remove_index_file(dir: &Path) -> Result<()> {
+    match fs::remove_file(index_path(dir)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn encode_key(buf: &mut Vec<u8>, key: &str) {
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(key.as_bytes());
+}
+
+/// Encodes an entry as its key, then the generation, start and end of its record and
+/// its expiry, if any.
+fn encode_entry(buf: &mut Vec<u8>, key: &str, pos: &CommandPos) {
+    encode_key(buf, key);
+    for n in [pos.gen, pos.start, pos.end] {
+        buf.extend_from_slice(&n.to_le_bytes());
+    }
+    buf.push(pos.expires_at.is_some() as u8);
+    buf.extend_from_slice(&pos.expires_at.unwrap_or(0).to_le_bytes());
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_key<R: Read>(reader: &mut R) -> io::Result<String> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let mut key = vec![0; u32::from_le_bytes(len) as usize];
+    reader.read_exact(&mut key)?;
+    String::from_utf8(key).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn read_entry<R: Read>(reader: &mut R) -> io::Result<(String, CommandPos)> {
+    let key = read_key(reader)?;
+    let mut bytes = [0; ENTRY_POS_LEN];
+    reader.read_exact(&mut bytes)?;
+    let u64_at = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+    let pos = CommandPos {
+        gen: u64_at(0),
+        start: u64_at(8),
+        end: u64_at(16),
+        expires_at: (bytes[24] != 0).then(|| u64_at(25)),
+    };
+    Ok((key, pos))
+}
+
+/// Encodes the footer, holding where the entries end, the number of blocks, the
+/// checksum of everything before the footer and the checkpoint, and ending with a
+/// checksum of its own.
+fn encode_footer(entries_end: u64, blocks: usize, body_crc: u32, at: Checkpoint) -> Vec<u8> {
+    let mut footer = Vec::with_capacity(FOOTER_LEN as usize);
+    footer.extend_from_slice(&entries_end.to_le_bytes());
+    footer.extend_from_slice(&(blocks as u64).to_le_bytes());
+    footer.extend_from_slice(&body_crc.to_le_bytes());
+    for n in [
+        at.gen,
+        at.offset,
+        at.uncompacted,
+        at.stale.values,
+        at.stale.tombstones,
+        at.seq,
+        at.kept,
+    ] {
+        footer.extend_from_slice(&n.to_le_bytes());
+    }
+    let crc = crc32fast::hash(&footer);
+    footer.extend_from_slice(&crc.to_le_bytes());
+    footer
+}
+
+/// Decodes a footer, returning `None` if it is damaged.
+fn decode_footer(footer: &[u8]) -> Option<(u64, u64, u32, Checkpoint)> {
+    let (fields, crc) = footer.split_at(footer.len() - 4);
+    if crc32fast::hash(fields).to_le_bytes() != crc {
+        return None;
+    }
+    let u64_at = |i: usize| u64::from_le_bytes(fields[i..i + 8].try_into().unwrap());
+    let body_crc = u32::from_le_bytes(fields[16..20].try_into().unwrap());
+    let at = Checkpoint {
+        gen: u64_at(20),
+        offset: u64_at(28),
+        uncompacted: u64_at(36),
+        stale: StaleRecords {
+            values: u64_at(44),
+            tombstones: u64_at(52),
+        },
+        seq: u64_at(60),
+        kept: u64_at(68),
+    };
+    Some((u64_at(0), u64_at(8), body_crc, at))
+}
@@ -0,0 +1,138 @@
+//! Encryption of the records of a store.
+//!
+//! An encrypted record is an `Encrypted` command holding a random nonce followed by the
+//! sealed encoding of the command it stands for. Checksum records hold no values and are
+//! left as they are, still checksumming the records after them as written.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::{KvsError, Result};
+
+/// Name of the file marking an encrypted store, holding a known value sealed with its
+/// key to check keys against.
+pub(crate) const ENCRYPTION_FILE: &str = "encryption";
+
+/// The value sealed into the encryption file.
+const KEY_CHECK: &[u8] = b"kvs";
+
+/// A key to encrypt the records of a store with, given to
+/// [`KvStoreBuilder::encryption_key`].
+///
+/// It is not printed by `Debug`.
+///
+/// [`KvStoreBuilder::encryption_key`]: crate::KvStoreBuilder::encryption_key
+#[cfg(feature = "encryption")]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct EncryptionKey(pub [u8; 32]);
+
+#[cfg(feature = "encryption")]
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+/// Seals and opens the records of an encrypted store.
+#[cfg(feature = "encryption")]
+#[derive(Clone, Copy)]
+pub(crate) struct Cipher(EncryptionKey);
+
+/// Seals and opens the records of an encrypted store, which cannot be opened without
+/// the `encryption` feature.
+#[cfg(not(feature = "encryption"))]
+#[derive(Clone, Copy)]
+pub(crate) enum Cipher {}
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Cipher(..)")
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl Cipher {
+    /// Length of the nonce before the sealed bytes.
+    const NONCE_LEN: usize = 24;
+
+    pub(crate) fn new(key: EncryptionKey) -> Self {
+        Cipher(key)
+    }
+
+    /// Encrypts the encoding of a record, returning a new nonce followed by the sealed
+    /// bytes.
+    pub(crate) fn seal(self, bytes: &[u8]) -> Result<Vec<u8>> {
+        use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+        use chacha20poly1305::XChaCha20Poly1305;
+
+        let aead = XChaCha20Poly1305::new(&self.0 .0.into());
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let sealed = aead.encrypt(&nonce, bytes).map_err(|_| KvsError::Decrypt)?;
+        let mut record = nonce.to_vec();
+        record.extend_from_slice(&sealed);
+        Ok(record)
+    }
+
+    /// Decrypts the bytes sealed by `seal`, failing with `KvsError::Decrypt` if they
+    /// are damaged or sealed with another key.
+    pub(crate) fn open(self, bytes: &[u8]) -> Result<Vec<u8>> {
+        use chacha20poly1305::aead::{Aead, KeyInit};
+        use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+        if bytes.len() < Self::NONCE_LEN {
+            return Err(KvsError::Decrypt);
+        }
+        let (nonce, sealed) = bytes.split_at(Self::NONCE_LEN);
+        let aead = XChaCha20Poly1305::new(&self.0 .0.into());
+        aead.decrypt(XNonce::from_slice(nonce), sealed)
+            .map_err(|_| KvsError::Decrypt)
+    }
+}
+
+#[cfg(not(feature = "encryption"))]
+impl Cipher {
+    pub(crate) fn seal(self, _bytes: &[u8]) -> Result<Vec<u8>> {
+        match self {}
+    }
+
+    pub(crate) fn open(self, _bytes: &[u8]) -> Result<Vec<u8>> {
+        match self {}
+    }
+}
+
+/// Checks the cipher a store is opened with against the key the store is encrypted
+/// with, and marks a new store as encrypted with it.
+///
+/// Returns `KvsError::EncryptionKeyRequired` if an encrypted store is opened without a
+/// key, `KvsError::WrongEncryptionKey` if it is opened with another key, and
+/// `KvsError::NotEncrypted` if a store with logs that is not encrypted is opened with
+/// a key.
+pub(crate) fn check_cipher(
+    dir: &Path,
+    cipher: Option<Cipher>,
+    has_logs: bool,
+    read_only: bool,
+) -> Result<()> {
+    let path = dir.join(ENCRYPTION_FILE);
+    let check = match fs::read(&path) {
+        Ok(check) => Some(check),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    match (check, cipher) {
+        (None, None) => Ok(()),
+        (Some(_), None) => Err(KvsError::EncryptionKeyRequired),
+        (Some(check), Some(cipher)) => match cipher.open(&check) {
+            Ok(check) if check == KEY_CHECK => Ok(()),
+            _ => Err(KvsError::WrongEncryptionKey),
+        },
+        (None, Some(_)) if has_logs => Err(KvsError::NotEncrypted),
+        (None, Some(_)) if read_only => Ok(()),
+        (None, Some(cipher)) => {
+            fs::write(path, cipher.seal(KEY_CHECK)?)?;
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+use crate::{KvsEngine, KvsError, Result};
+
+/// A `KvsEngine` keeping everything in memory, without touching the filesystem.
+///
+/// It behaves like `KvStore` otherwise, which makes it handy for tests and examples.
+#[derive(Clone, Debug, Default)]
+pub struct MemKvsEngine {
+    map: BTreeMap<String, String>,
+}
+
+impl MemKvsEngine {
+    /// Creates an empty engine.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Returns a copy of all key/value pairs in the engine.
+    pub fn snapshot(&self) -> BTreeMap<String, String> {
+        self.map.clone()
+    }
+}
+
+impl KvsEngine for MemKvsEngine {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.map.insert(key, value);
+        Ok(())
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self.map.get(&key).cloned())
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.map.remove(&key).ok_or(KvsError::KeyNotFound)?;
+        Ok(())
+    }
+
+    fn keys(&mut self) -> Result<Vec<String>> {
+        Ok(self.map.keys().cloned().collect())
+    }
+
+    fn contains_key(&mut self, key: String) -> Result<bool> {
+        Ok(self.map.contains_key(&key))
+    }
+
+    fn key_count(&mut self) -> Result<u64> {
+        Ok(self.map.len() as u64)
+    }
+
+    fn scan_keys(
+        &mut self,
+        prefix: &str,
+        after: Option<&str>,
+        count: usize,
+    ) -> Result<Vec<String>> {
+        let start = match after {
+            Some(after) if after >= prefix => Bound::Excluded(after),
+            _ => Bound::Included(prefix),
+        };
+        Ok(self
+            .map
+            .range::<str, _>((start, Bound::Unbounded))
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(prefix))
+            .take(count)
+            .cloned()
+            .collect())
+    }
+}
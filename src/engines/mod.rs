@@ -0,0 +1,329 @@
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::manifest::{check_version, read_manifest, write_manifest};
+use crate::{EngineStats, KvStore, KvsError, Result, SharedKvStore, WriteBatch};
+
+pub use self::mem::MemKvsEngine;
+#[cfg(feature = "sled")]
+pub use self::sled::SledKvsEngine;
+
+mod mem;
+#[cfg(feature = "sled")]
+mod sled;
+
+/// Name of the file recording which engine owns a store directory.
+pub(crate) const ENGINE_FILE: &str = "engine";
+
+/// A key/value storage engine.
+///
+/// Code generic over `E: KvsEngine` works with any backend.
+pub trait KvsEngine {
+    /// Sets the value of a string key to a string.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    fn set(&mut self, key: String, value: String) -> Result<()>;
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get(&mut self, key: String) -> Result<Option<String>>;
+    /// Sets the value of a string key to arbitrary bytes.
+    ///
+    /// The default sets values that are valid UTF-8 with `set`, and fails with
+    /// `KvsError::Utf8` on others, for engines that only store strings.
+    fn set_bytes(&mut self, key: String, value: Vec<u8>) -> Result<()> {
+        self.set(key, String::from_utf8(value)?)
+    }
+    /// Gets the value of a given string key as bytes, a string value as its UTF-8 bytes.
+    ///
+    /// The default gets the value with `get`.
+    fn get_bytes(&mut self, key: String) -> Result<Option<Vec<u8>>> {
+        Ok(self.get(key)?.map(String::into_bytes))
+    }
+    /// Removes a given key.
+    ///
+    /// Returns `KvsError::KeyNotFound` if the given key is not found.
+    ///
+    /// [`KvsError::KeyNotFound`]: crate::KvsError::KeyNotFound
+    fn remove(&mut self, key: String) -> Result<()>;
+    /// Returns all keys in sorted order.
+    fn keys(&mut self) -> Result<Vec<String>>;
+    /// Returns `true` if the engine contains the given key.
+    fn contains_key(&mut self, key: String) -> Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+    /// Removes every given key that exists, returning how many did.
+    ///
+    /// The default removes them one at a time.
+    fn remove_keys(&mut self, keys: Vec<String>) -> Result<u64> {
+        let mut removed = 0;
+        for key in keys {
+            match self.remove(key) {
+                Ok(()) => removed += 1,
+                Err(KvsError::KeyNotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(removed)
+    }
+    /// Returns the number of keys.
+    fn key_count(&mut self) -> Result<u64> {
+        Ok(self.keys()?.len() as u64)
+    }
+    /// Returns up to `count` keys starting with `prefix` in sorted order, from the first
+    /// one after `after` if it is given.
+    ///
+    /// The default lists every key with `keys`, which engines with ordered keys avoid.
+    fn scan_keys(
+        &mut self,
+        prefix: &str,
+        after: Option<&str>,
+        count: usize,
+    ) -> Result<Vec<String>> {
+        Ok(self
+            .keys()?
+            .into_iter()
+            .filter(|key| key.starts_with(prefix) && after.is_none_or(|after| key.as_str() > after))
+            .take(count)
+            .collect())
+    }
+    /// Syncs every write made so far to disk.
+    ///
+    /// The default does nothing, for engines with nothing to sync.
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+    /// Returns statistics on the engine to monitor it with, or `None` if it keeps none.
+    ///
+    /// The default keeps none.
+    fn engine_stats(&mut self) -> Result<Option<EngineStats>> {
+        Ok(None)
+    }
+}
+
+impl KvsEngine for KvStore {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        KvStore::set(self, key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        KvStore::get(self, key)
+    }
+
+    fn set_bytes(&mut self, key: String, value: Vec<u8>) -> Result<()> {
+        KvStore::set_bytes(self, key, value)
+    }
+
+    fn get_bytes(&mut self, key: String) -> Result<Option<Vec<u8>>> {
+        KvStore::get_bytes(self, key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        KvStore::remove(self, key)
+    }
+
+    fn keys(&mut self) -> Result<Vec<String>> {
+        Ok(KvStore::keys(self).map(Cow::into_owned).collect())
+    }
+
+    fn contains_key(&mut self, key: String) -> Result<bool> {
+        Ok(KvStore::contains_key(self, &key))
+    }
+
+    /// Removes the keys in a single batch, so they are all removed or none are.
+    fn remove_keys(&mut self, keys: Vec<String>) -> Result<u64> {
+        let keys: BTreeSet<String> = keys
+            .into_iter()
+            .filter(|key| KvStore::contains_key(self, key))
+            .collect();
+        let removed = keys.len() as u64;
+        let mut batch = WriteBatch::new();
+        for key in keys {
+            batch.delete(key);
+        }
+        self.write_batch(batch)?;
+        Ok(removed)
+    }
+
+    fn key_count(&mut self) -> Result<u64> {
+        Ok(KvStore::len(self) as u64)
+    }
+
+    fn scan_keys(
+        &mut self,
+        prefix: &str,
+        after: Option<&str>,
+        count: usize,
+    ) -> Result<Vec<String>> {
+        Ok(self
+            .prefixed_keys_after(prefix, after)
+            .take(count)
+            .map(Cow::into_owned)
+            .collect())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        KvStore::sync(self)
+    }
+
+    fn engine_stats(&mut self) -> Result<Option<EngineStats>> {
+        let stats = KvStore::stats(self);
+        Ok(Some(EngineStats {
+            live_keys: stats.live_keys as u64,
+            disk_bytes: stats.disk_bytes,
+            uncompacted_bytes: stats.uncompacted_bytes,
+            compactions: self.compactions(),
+        }))
+    }
+}
+
+impl KvsEngine for SharedKvStore {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        SharedKvStore::set(self, key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        SharedKvStore::get(self, key)
+    }
+
+    fn set_bytes(&mut self, key: String, value: Vec<u8>) -> Result<()> {
+        SharedKvStore::set_bytes(self, key, value)
+    }
+
+    fn get_bytes(&mut self, key: String) -> Result<Option<Vec<u8>>> {
+        SharedKvStore::get_bytes(self, key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        SharedKvStore::remove(self, key)
+    }
+
+    fn keys(&mut self) -> Result<Vec<String>> {
+        Ok(SharedKvStore::keys(self))
+    }
+
+    fn contains_key(&mut self, key: String) -> Result<bool> {
+        Ok(SharedKvStore::contains_key(self, &key))
+    }
+
+    fn key_count(&mut self) -> Result<u64> {
+        Ok(SharedKvStore::len(self) as u64)
+    }
+
+    fn scan_keys(
+        &mut self,
+        prefix: &str,
+        after: Option<&str>,
+        count: usize,
+    ) -> Result<Vec<String>> {
+        Ok(SharedKvStore::scan_keys(self, prefix, after, count))
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        SharedKvStore::sync(self)
+    }
+
+    fn engine_stats(&mut self) -> Result<Option<EngineStats>> {
+        SharedKvStore::measure(self).map(Some)
+    }
+}
+
+/// The engines that can own a store directory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Engine {
+    Kvs,
+    #[cfg_attr(not(feature = "sled"), allow(dead_code))]
+    Sled,
+}
+
+impl fmt::Display for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Engine::Kvs => write!(f, "kvs"),
+            Engine::Sled => write!(f, "sled"),
+        }
+    }
+}
+
+/// Returns the name of the engine owning the store directory at the given path, `kvs`
+/// or `sled`, or `None` if no engine has claimed it yet.
+///
+/// Opening the store with any other engine fails with `KvsError::EngineMismatch`.
+pub fn engine_of<P: AsRef<Path>>(path: P) -> Result<Option<String>> {
+    let dir = path.as_ref();
+    if !dir.is_dir() {
+        return Ok(None);
+    }
+    if let (_, Some(engine)) = read_manifest(dir)? {
+        return Ok(Some(engine));
+    }
+    match fs::read_to_string(dir.join(ENGINE_FILE)) {
+        Ok(name) => Ok(Some(name.trim().to_owned())),
+        // Log files from before the engine was recorded belong to `KvStore`.
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            Ok(has_logs(dir)?.then(|| Engine::Kvs.to_string()))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Checks that a store directory belongs to the given engine, and records it if no
+/// engine owns it yet.
+///
+/// The engine is recorded in the manifest of a new store, and in the engine file, which
+/// stores from before the manifest only have. Returns `KvsError::UnsupportedVersion` if
+/// the store is of a newer format version, and `KvsError::EngineMismatch` if the
+/// directory belongs to another engine.
+pub(crate) fn claim_dir(dir: &Path, engine: Engine, read_only: bool) -> Result<()> {
+    let (version, in_manifest) = read_manifest(dir)?;
+    check_version(version)?;
+    if let Some(recorded) = in_manifest {
+        if recorded != engine.to_string() {
+            return Err(KvsError::EngineMismatch {
+                recorded,
+                requested: engine.to_string(),
+            });
+        }
+    }
+    let path = dir.join(ENGINE_FILE);
+    let recorded = match fs::read_to_string(&path) {
+        Ok(name) => Some(name.trim().to_owned()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    let recorded = match recorded {
+        Some(recorded) => recorded,
+        // Log files from before the engine was recorded belong to `KvStore`.
+        None if engine != Engine::Kvs && has_logs(dir)? => Engine::Kvs.to_string(),
+        None if read_only => return Ok(()),
+        None => {
+            // A directory with logs already is a store from before the manifest, which
+            // only gets one by a migration.
+            if version == 0 && !has_logs(dir)? {
+                write_manifest(dir, &engine.to_string())?;
+            }
+            fs::write(path, engine.to_string())?;
+            return Ok(());
+        }
+    };
+    if recorded != engine.to_string() {
+        return Err(KvsError::EngineMismatch {
+            recorded,
+            requested: engine.to_string(),
+        });
+    }
+    Ok(())
+}
+
+fn has_logs(dir: &Path) -> Result<bool> {
+    for entry in fs::read_dir(dir)? {
+        if entry?.path().extension() == Some("log".as_ref()) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
@@ -0,0 +1,80 @@
+use std::path::Path;
+
+use sled::Db;
+
+use super::{claim_dir, Engine};
+use crate::{KvsEngine, KvsError, Result};
+
+/// A `KvsEngine` backed by the `sled` embedded database.
+///
+/// Available with the `sled` feature. Clones share the same database.
+#[derive(Clone)]
+pub struct SledKvsEngine {
+    db: Db,
+}
+
+impl SledKvsEngine {
+    /// Opens a `SledKvsEngine` with the given path.
+    ///
+    /// Returns `KvsError::EngineMismatch` if the directory belongs to a `KvStore`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        std::fs::create_dir_all(path)?;
+        claim_dir(path, Engine::Sled, false)?;
+        Ok(Self {
+            db: sled::open(path)?,
+        })
+    }
+}
+
+impl KvsEngine for SledKvsEngine {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.db.insert(key, value.into_bytes())?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.db.get(key)? {
+            Some(value) => Ok(Some(String::from_utf8(value.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn set_bytes(&mut self, key: String, value: Vec<u8>) -> Result<()> {
+        self.db.insert(key, value)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn get_bytes(&mut self, key: String) -> Result<Option<Vec<u8>>> {
+        Ok(self.db.get(key)?.map(|value| value.to_vec()))
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.db.remove(key)?.ok_or(KvsError::KeyNotFound)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn keys(&mut self) -> Result<Vec<String>> {
+        self.db
+            .iter()
+            .keys()
+            .map(|key| Ok(String::from_utf8(key?.to_vec())?))
+            .collect()
+    }
+
+    fn contains_key(&mut self, key: String) -> Result<bool> {
+        Ok(self.db.contains_key(key)?)
+    }
+
+    fn key_count(&mut self) -> Result<u64> {
+        Ok(self.db.len() as u64)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
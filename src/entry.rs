@@ -0,0 +1,80 @@
+use std::mem;
+
+use crate::{KvStore, Result};
+
+/// A view into a single key of a store, which is either occupied or vacant.
+///
+/// This is created by [`KvStore::entry`]. It borrows the store mutably for as long as
+/// it lives, and every mutation through it is written to the log and applied to the
+/// index before the call returns, like the writes of the store itself.
+///
+/// [`KvStore::entry`]: crate::KvStore::entry
+pub enum Entry<'a> {
+    /// A key that has a value.
+    Occupied(OccupiedEntry<'a>),
+    /// A key that does not exist or has expired.
+    Vacant(VacantEntry<'a>),
+}
+
+impl Entry<'_> {
+    /// Returns the key of the entry.
+    pub fn key(&self) -> &str {
+        match self {
+            Entry::Occupied(entry) => entry.key(),
+            Entry::Vacant(entry) => entry.key(),
+        }
+    }
+}
+
+/// A view into a key that has a value, which it holds as it was read.
+pub struct OccupiedEntry<'a> {
+    store: &'a mut KvStore,
+    key: String,
+    value: String,
+}
+
+impl OccupiedEntry<'_> {
+    /// Returns the key of the entry.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+    /// Returns the value of the key, without reading it from the log again.
+    pub fn get(&self) -> &str {
+        &self.value
+    }
+    /// Sets the value of the key, returning the previous one.
+    pub fn insert(&mut self, value: String) -> Result<String> {
+        self.store.set(self.key.clone(), value.clone())?;
+        Ok(mem::replace(&mut self.value, value))
+    }
+    /// Removes the key, returning its value.
+    pub fn remove(self) -> Result<String> {
+        self.store.remove(&self.key)?;
+        Ok(self.value)
+    }
+}
+
+/// A view into a key that does not exist or has expired.
+pub struct VacantEntry<'a> {
+    store: &'a mut KvStore,
+    key: String,
+}
+
+impl VacantEntry<'_> {
+    /// Returns the key of the entry.
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+    /// Sets the value of the key.
+    pub fn insert(self, value: String) -> Result<()> {
+        self.store.set(self.key, value)
+    }
+}
+
+/// Returns the entry of a key, given its value if it has one.
+pub(crate) fn entry(store: &mut KvStore, key: String, value: Option<String>) -> Entry<'_> {
+    match value {
+        Some(value) => Entry::Occupied(OccupiedEntry { store, key, value }),
+        None => Entry::Vacant(VacantEntry { store, key }),
+    }
+}
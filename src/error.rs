@@ -1,7 +1,14 @@
+// `failure_derive` expands to impls inside anonymous consts.
+#![allow(non_local_definitions)]
+
 use std::io;
+use std::path::PathBuf;
+use std::string::FromUtf8Error;
 
 use failure::Fail;
 
+use crate::{LogFormat, VerifyProblem};
+
 /// Error type for kvs.
 #[derive(Fail, Debug)]
 pub enum KvsError {
@@ -11,13 +18,244 @@ pub enum KvsError {
     /// Serialization or deserialization error.
     #[fail(display = "{}", _0)]
     Serde(#[cause] serde_json::Error),
+    /// Bincode serialization or deserialization error.
+    #[fail(display = "{}", _0)]
+    Bincode(#[cause] bincode::Error),
+    /// Sled error.
+    #[cfg(feature = "sled")]
+    #[fail(display = "{}", _0)]
+    Sled(#[cause] sled::Error),
+    /// The store exists with a different log format than requested.
+    #[fail(display = "Store uses the {} log format, not {}", recorded, requested)]
+    FormatMismatch {
+        /// Format of the existing store.
+        recorded: LogFormat,
+        /// Format requested when opening the store.
+        requested: LogFormat,
+    },
+    /// The store directory belongs to a different engine than requested.
+    #[fail(
+        display = "Store belongs to the {} engine, not {}",
+        recorded, requested
+    )]
+    EngineMismatch {
+        /// Engine owning the store directory.
+        recorded: String,
+        /// Engine requested when opening the store.
+        requested: String,
+    },
+    /// The recorded log format is not known.
+    #[fail(display = "Unknown log format: {}", _0)]
+    UnknownFormat(String),
+    /// The store is of an on-disk format version newer than the ones supported.
+    #[fail(
+        display = "Store format version {} is not supported, only up to {}",
+        found, supported
+    )]
+    UnsupportedVersion {
+        /// Format version of the store.
+        found: u32,
+        /// Newest format version supported.
+        supported: u32,
+    },
+    /// A typed value does not match the type it is read as.
+    #[fail(display = "Type mismatch: {}", _0)]
+    TypeMismatch(#[cause] serde_json::Error),
+    /// The store directory does not exist and is not to be created.
+    #[fail(display = "No store directory at {:?}", _0)]
+    StoreNotFound(PathBuf),
+    /// The store directory is locked by another open store.
+    #[fail(display = "Store is already open, its lock {:?} is held", _0)]
+    StoreLocked(PathBuf),
+    /// The destination of a backup already has files in it.
+    #[fail(display = "Backup destination {:?} is not empty", _0)]
+    BackupNotEmpty(PathBuf),
+    /// A row of a strict CSV import is malformed.
+    #[fail(display = "Row at line {} is malformed: {}", line, reason)]
+    MalformedCsv {
+        /// Line the row starts on.
+        line: u64,
+        /// Why the row does not read.
+        reason: String,
+    },
+    /// A store to bulk load already has keys.
+    #[fail(display = "Store {:?} is not empty", _0)]
+    StoreNotEmpty(PathBuf),
+    /// A key is given more than once to a bulk load rejecting duplicates.
+    #[fail(display = "Key {:?} is given more than once", _0)]
+    DuplicateKey(String),
+    /// A generation to restore is not retained, or no longer restorable.
+    #[fail(display = "Generation {} is not retained", _0)]
+    GenerationNotFound(u64),
+    /// The log of a generation the store still uses is gone.
+    #[fail(display = "Log {}.log is missing", _0)]
+    MissingGeneration(u64),
+    /// The thread of a background compaction into a generation panicked.
+    #[fail(display = "Compaction into {}.log panicked", _0)]
+    CompactionPanicked(u64),
+    /// The store is opened read-only, so it cannot be written to.
+    #[fail(display = "Store is opened read-only")]
+    ReadOnly,
+    /// A merge is written, or the store holds merge records, but no merge operator is
+    /// registered to resolve them.
+    #[fail(display = "No merge operator is registered")]
+    MissingMergeOperator,
+    /// A command given to `KvStore::apply` only frames the records of a log, and is not
+    /// a change of its own.
+    #[fail(display = "The command is not a change to apply")]
+    NotAChange,
+    /// A value is incremented or decremented but is not an integer.
+    #[fail(display = "The value of key {} is not an integer", key)]
+    NotAnInteger {
+        /// Key of the value.
+        key: String,
+    },
+    /// Incrementing or decrementing a value would overflow it.
+    #[fail(display = "The value of key {} would overflow", key)]
+    IntegerOverflow {
+        /// Key of the value.
+        key: String,
+    },
+    /// The server could not be reached.
+    #[fail(display = "Failed to connect to {}: {}", addr, cause)]
+    Connect {
+        /// Address of the server.
+        addr: String,
+        /// Error connecting to it.
+        #[cause]
+        cause: io::Error,
+    },
+    /// The server refused the connection, most likely because it is not running.
+    #[fail(display = "Connection to {} refused", _0)]
+    ConnectionRefused(String),
+    /// The server did not answer in time.
+    #[fail(display = "Timed out talking to {}", _0)]
+    TimedOut(String),
+    /// Every connection of a client pool is in use, with the largest number of them.
+    #[fail(display = "All {} connections of the pool are in use", _0)]
+    PoolExhausted(usize),
+    /// A thread pool is created with no threads, so it could never run a job.
+    #[fail(display = "A thread pool needs at least one thread")]
+    NoThreads,
+    /// A TLS handshake failed, or a connection broke the TLS protocol.
+    #[cfg(feature = "tls")]
+    #[fail(display = "TLS error: {}", _0)]
+    Tls(#[cause] rustls::Error),
+    /// A TLS certificate, key or server name cannot be used.
+    #[cfg(feature = "tls")]
+    #[fail(display = "Invalid TLS configuration: {}", _0)]
+    TlsConfig(String),
+    /// The server requires an auth token, and the client did not send one.
+    #[fail(display = "The server requires an auth token")]
+    AuthRequired,
+    /// The server rejected the auth token of the client.
+    #[fail(display = "The server rejected the auth token")]
+    AuthFailed,
+    /// The server failed to handle a request.
+    #[fail(display = "Server error: {}", _0)]
+    Server(String),
+    /// The name of a wire protocol is not known.
+    #[fail(display = "Unknown protocol: {}", _0)]
+    UnknownProtocol(String),
+    /// The server does not know a request, or does not know it yet.
+    #[fail(display = "The server does not support the {} request", _0)]
+    UnsupportedRequest(String),
+    /// A request lists more keys than the server answers with at once.
+    #[fail(display = "More than {} keys to list, scan them instead", _0)]
+    TooManyKeys(usize),
+    /// A client broke the wire protocol of the server.
+    #[fail(display = "Protocol error: {}", _0)]
+    Protocol(String),
+    /// The client and server have no protocol version in common.
+    #[fail(
+        display = "No common protocol version, the client speaks up to {} and the server up to {}",
+        client, server
+    )]
+    ProtocolMismatch {
+        /// Newest protocol version of the client.
+        client: u32,
+        /// Newest protocol version of the server.
+        server: u32,
+    },
+    /// The thread running the operations of an `AsyncKvStore` has stopped.
+    #[fail(display = "The store thread has stopped")]
+    WorkerStopped,
     /// Removing non-existent key error.
     #[fail(display = "Key not found")]
     KeyNotFound,
-    /// Unexpected command type error.
-    /// It indicated a corrupted log or a program bug.
-    #[fail(display = "Unexpected command type")]
-    UnexpectedCommandType,
+    /// A namespace name is empty or contains a NUL character.
+    #[fail(display = "Invalid namespace name {:?}", _0)]
+    InvalidNamespace(String),
+    /// A benchmark workload is not valid, or a size distribution does not parse.
+    #[fail(display = "Invalid workload: {}", _0)]
+    InvalidWorkload(String),
+    /// A binary value is read as a string but is not valid UTF-8.
+    #[fail(display = "{}", _0)]
+    Utf8(#[cause] FromUtf8Error),
+    /// A record in the log is damaged.
+    #[fail(display = "Corrupted record at offset {} of {}.log", offset, gen)]
+    Corruption {
+        /// Generation of the damaged log.
+        gen: u64,
+        /// Byte offset of the damaged record.
+        offset: u64,
+    },
+    /// A record in the log could not be read or decoded.
+    #[fail(
+        display = "Failed to read the record at offset {} of {}.log: {}",
+        offset, gen, cause
+    )]
+    LogRead {
+        /// Generation of the log.
+        gen: u64,
+        /// Byte offset of the record.
+        offset: u64,
+        /// Error reading or decoding the record.
+        cause: Box<KvsError>,
+    },
+    /// The changes a change feed is asked for were dropped by a compaction.
+    #[fail(
+        display = "The changes after sequence number {} were compacted away, only the ones after {} are left",
+        since, horizon
+    )]
+    CompactedAway {
+        /// Sequence number the changes were asked for after.
+        since: u64,
+        /// Sequence number the changes left are all after.
+        horizon: u64,
+    },
+    /// A check of the store found a problem.
+    #[fail(display = "{}", _0)]
+    VerifyFailed(VerifyProblem),
+    /// The store is encrypted, and is opened without its key.
+    #[fail(display = "Store is encrypted, and no encryption key is given")]
+    EncryptionKeyRequired,
+    /// The store is encrypted with another key than the one it is opened with.
+    #[fail(display = "Store is encrypted with another key")]
+    WrongEncryptionKey,
+    /// The store is not encrypted, and is opened with an encryption key.
+    #[fail(display = "Store is not encrypted, but an encryption key is given")]
+    NotEncrypted,
+    /// An encrypted record cannot be decrypted with the key of the store.
+    #[fail(display = "Failed to decrypt a record")]
+    Decrypt,
+    /// A compressed record cannot be decompressed.
+    #[fail(display = "Failed to decompress a record: {}", _0)]
+    Decompress(String),
+    /// The index points a key at a record that is not a value of the key.
+    /// It indicates a corrupted log or index, or a program bug.
+    #[fail(
+        display = "Unexpected command type for key {:?} at offset {} of {}.log",
+        key, offset, gen
+    )]
+    UnexpectedCommandType {
+        /// Key whose value was read.
+        key: String,
+        /// Generation of the log.
+        gen: u64,
+        /// Byte offset of the record.
+        offset: u64,
+    },
 }
 
 impl From<io::Error> for KvsError {
@@ -32,5 +270,31 @@ impl From<serde_json::Error> for KvsError {
     }
 }
 
+impl From<bincode::Error> for KvsError {
+    fn from(err: bincode::Error) -> KvsError {
+        KvsError::Bincode(err)
+    }
+}
+
+#[cfg(feature = "sled")]
+impl From<sled::Error> for KvsError {
+    fn from(err: sled::Error) -> KvsError {
+        KvsError::Sled(err)
+    }
+}
+
+#[cfg(feature = "tls")]
+impl From<rustls::Error> for KvsError {
+    fn from(err: rustls::Error) -> KvsError {
+        KvsError::Tls(err)
+    }
+}
+
+impl From<FromUtf8Error> for KvsError {
+    fn from(err: FromUtf8Error) -> KvsError {
+        KvsError::Utf8(err)
+    }
+}
+
 /// Result type for kvs.
 pub type Result<T> = std::result::Result<T, KvsError>;
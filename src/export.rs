@@ -0,0 +1,74 @@
+use serde::{Deserialize, Serialize};
+
+use crate::kv::{bytes_format, Command};
+use crate::CsvRowError;
+
+/// A key/value pair in the newline-delimited JSON written by [`KvStore::export_json`].
+///
+/// The value is under `value` if it is a string, `bytes` (base64) if it was set by
+/// `set_bytes`, and `json` if it was set by `set_as`.
+///
+/// [`KvStore::export_json`]: crate::KvStore::export_json
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct ExportRecord {
+    pub(crate) key: String,
+    #[serde(flatten)]
+    pub(crate) value: ExportValue,
+    /// Expiry time in milliseconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) expires_at: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ExportValue {
+    Value(String),
+    Bytes(#[serde(with = "bytes_format")] Vec<u8>),
+    Json(serde_json::Value),
+}
+
+impl ExportRecord {
+    /// Returns the record exporting the value written by a `Set` command.
+    pub(crate) fn from_command(cmd: Command, expires_at: Option<u64>) -> Option<Self> {
+        let (key, value) = match cmd {
+            Command::Set(key, value) | Command::SetWithExpiry(key, value, _) => {
+                (key, ExportValue::Value(value))
+            }
+            Command::SetBytes(key, value) => (key, ExportValue::Bytes(value)),
+            Command::SetJson(key, value) => (key, ExportValue::Json(value)),
+            _ => return None,
+        };
+        Some(Self {
+            key,
+            value,
+            expires_at,
+        })
+    }
+    /// Returns the command writing the record back.
+    pub(crate) fn into_command(self) -> Command {
+        match (self.value, self.expires_at) {
+            (ExportValue::Value(value), Some(expires_at)) => {
+                Command::SetWithExpiry(self.key, value, expires_at)
+            }
+            (ExportValue::Value(value), None) => Command::Set(self.key, value),
+            (ExportValue::Bytes(value), _) => Command::SetBytes(self.key, value),
+            (ExportValue::Json(value), _) => Command::SetJson(self.key, value),
+        }
+    }
+}
+
+/// What [`KvStore::import_json`] or [`KvStore::import_csv`] did with the records it read.
+///
+/// [`KvStore::import_json`]: crate::KvStore::import_json
+/// [`KvStore::import_csv`]: crate::KvStore::import_csv
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    /// Number of keys written.
+    pub imported: u64,
+    /// Number of keys left as they were because they already existed.
+    pub skipped: u64,
+    /// Number of records dropped because they had expired.
+    pub expired: u64,
+    /// Rows of a CSV import skipped because they are malformed, in order.
+    pub errors: Vec<CsvRowError>,
+}
@@ -0,0 +1,345 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::iter;
+use std::path::Path;
+use std::rc::Rc;
+use std::str::FromStr;
+
+use serde_json::Deserializer;
+
+use crate::compress::compress;
+use crate::encrypt::Cipher;
+use crate::kv::Command;
+use crate::{KvsError, Result};
+
+/// Name of the file recording the log format of a store.
+pub(crate) const FORMAT_FILE: &str = "format";
+
+/// Encoding of the records in the log files of a store.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// One JSON document per record.
+    ///
+    /// This is easy to inspect, and is the format of stores created before the
+    /// format was recorded in the store directory.
+    #[default]
+    Json,
+    /// Length-prefixed bincode records, which are smaller and faster to parse.
+    Bincode,
+}
+
+impl LogFormat {
+    /// Reads the format recorded in the store directory, if any.
+    pub(crate) fn load(dir: &Path) -> Result<Option<LogFormat>> {
+        match fs::read_to_string(dir.join(FORMAT_FILE)) {
+            Ok(name) => name.trim().parse().map(Some),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Records the format in the store directory.
+    pub(crate) fn save(self, dir: &Path) -> Result<()> {
+        fs::write(dir.join(FORMAT_FILE), self.to_string())?;
+        Ok(())
+    }
+
+    /// Appends a command to the buffer as one record.
+    pub(crate) fn encode(self, cmd: &Command, buf: &mut Vec<u8>) -> Result<()> {
+        match self {
+            LogFormat::Json => serde_json::to_writer(buf, cmd)?,
+            LogFormat::Bincode => {
+                let payload = bincode::serialize(cmd)?;
+                buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+                buf.extend_from_slice(&payload);
+            }
+        }
+        Ok(())
+    }
+
+    /// Decodes the first record in the bytes.
+    ///
+    /// Returns the command and the length of its record, or an `UnexpectedEof` error
+    /// if the bytes end before the record does.
+    pub(crate) fn decode_prefix(self, bytes: &[u8]) -> Result<(Command, usize)> {
+        match self {
+            LogFormat::Json => {
+                let mut stream = Deserializer::from_slice(bytes).into_iter::<Command>();
+                match stream.next() {
+                    Some(cmd) => Ok((cmd?, stream.byte_offset())),
+                    None => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                }
+            }
+            LogFormat::Bincode => {
+                let len = match bytes.get(..4) {
+                    Some(len) => u32::from_le_bytes(len.try_into().unwrap()) as usize,
+                    None => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                };
+                match bytes.get(4..4 + len) {
+                    Some(payload) => Ok((bincode::deserialize(payload)?, 4 + len)),
+                    None => Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                }
+            }
+        }
+    }
+
+    /// Returns an iterator over the records of a log.
+    ///
+    /// The iterator ends after the first record that cannot be decoded.
+    pub(crate) fn records<'a, R: Read + 'a>(
+        self,
+        reader: R,
+    ) -> Box<dyn Iterator<Item = Result<Record>> + 'a> {
+        match self {
+            LogFormat::Json => {
+                // Keep the raw bytes the deserializer consumes, to checksum each record.
+                let recorded = Rc::new(RefCell::new(Vec::new()));
+                let recorder = Recorder {
+                    inner: reader,
+                    recorded: Rc::clone(&recorded),
+                };
+                let mut stream = Deserializer::from_reader(recorder).into_iter::<Command>();
+                // Offset of the first byte in `recorded`.
+                let mut base = 0;
+                let mut broken = false;
+                Box::new(iter::from_fn(move || {
+                    if broken {
+                        return None;
+                    }
+                    let start = stream.byte_offset() as u64;
+                    match stream.next()? {
+                        Ok(cmd) => {
+                            let end = stream.byte_offset() as u64;
+                            let mut recorded = recorded.borrow_mut();
+                            let crc = crc32fast::hash(
+                                &recorded[(start - base) as usize..(end - base) as usize],
+                            );
+                            recorded.drain(..(end - base) as usize);
+                            base = end;
+                            Some(Ok(Record::Command {
+                                cmd,
+                                start,
+                                end,
+                                crc,
+                            }))
+                        }
+                        Err(e) => {
+                            broken = true;
+                            if e.is_io() {
+                                Some(Err(e.into()))
+                            } else {
+                                Some(Ok(Record::Broken(start)))
+                            }
+                        }
+                    }
+                }))
+            }
+            LogFormat::Bincode => {
+                let mut reader = reader;
+                let mut pos = 0;
+                let mut broken = false;
+                Box::new(iter::from_fn(move || {
+                    if broken {
+                        return None;
+                    }
+                    let record = read_bincode_record(&mut reader, pos);
+                    match &record {
+                        Ok(Some(Record::Command { end, .. })) => pos = *end,
+                        _ => broken = true,
+                    }
+                    record.transpose()
+                }))
+            }
+        }
+    }
+}
+
+/// How the records of a store are encoded: in its log format, and encrypted if the
+/// store is.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Codec {
+    pub(crate) format: LogFormat,
+    pub(crate) cipher: Option<Cipher>,
+}
+
+impl Codec {
+    /// Appends a command to the buffer as one record, encrypted unless it is a checksum
+    /// record.
+    pub(crate) fn encode(self, cmd: &Command, buf: &mut Vec<u8>) -> Result<()> {
+        match self.cipher {
+            Some(cipher) if !matches!(cmd, Command::Checksum(_) | Command::Sequenced(..)) => {
+                let mut record = Vec::new();
+                self.format.encode(cmd, &mut record)?;
+                let sealed = Command::Encrypted(cipher.seal(&record)?);
+                self.format.encode(&sealed, buf)
+            }
+            _ => self.format.encode(cmd, buf),
+        }
+    }
+
+    /// Appends a command to the buffer as a checksum record followed by the command record.
+    pub(crate) fn encode_checked(self, cmd: &Command, buf: &mut Vec<u8>) -> Result<()> {
+        let mut record = Vec::new();
+        self.encode(cmd, &mut record)?;
+        self.encode(&Command::Checksum(crc32fast::hash(&record)), buf)?;
+        buf.extend_from_slice(&record);
+        Ok(())
+    }
+
+    /// Appends a change to the buffer like `encode_checked`, with its sequence number
+    /// in the checksum record.
+    pub(crate) fn encode_sequenced(self, cmd: &Command, seq: u64, buf: &mut Vec<u8>) -> Result<()> {
+        let mut record = Vec::new();
+        self.encode(cmd, &mut record)?;
+        self.encode(&Command::Sequenced(crc32fast::hash(&record), seq), buf)?;
+        buf.extend_from_slice(&record);
+        Ok(())
+    }
+
+    /// Appends a command to the buffer like `encode_checked`, compressing its record
+    /// unless compression is not enabled or would not make it smaller.
+    pub(crate) fn encode_compressed(self, cmd: &Command, buf: &mut Vec<u8>) -> Result<()> {
+        let mut record = Vec::new();
+        self.format.encode(cmd, &mut record)?;
+        let start = buf.len();
+        if let Some(compressed) = compress(&record) {
+            self.encode_checked(&Command::Compressed(compressed), buf)?;
+            if buf.len() - start < record.len() {
+                return Ok(());
+            }
+            buf.truncate(start);
+        }
+        self.encode_checked(cmd, buf)
+    }
+
+    /// Decodes the first record in the bytes, decrypting it if it is encrypted.
+    ///
+    /// Returns the command and the length of its record as written.
+    pub(crate) fn decode_prefix(self, bytes: &[u8]) -> Result<(Command, usize)> {
+        let (cmd, len) = self.format.decode_prefix(bytes)?;
+        Ok((self.open(cmd)?, len))
+    }
+
+    /// Returns an iterator over the records of a log, decrypted if they are encrypted.
+    ///
+    /// The iterator ends after the first record that cannot be decoded, and fails on
+    /// one that cannot be decrypted.
+    pub(crate) fn records<'a, R: Read + 'a>(
+        self,
+        reader: R,
+    ) -> Box<dyn Iterator<Item = Result<Record>> + 'a> {
+        let records = self.format.records(reader);
+        if self.cipher.is_none() {
+            return records;
+        }
+        Box::new(records.map(move |record| match record? {
+            Record::Command {
+                cmd,
+                start,
+                end,
+                crc,
+            } => Ok(Record::Command {
+                cmd: self.open(cmd)?,
+                start,
+                end,
+                crc,
+            }),
+            broken => Ok(broken),
+        }))
+    }
+
+    /// Returns the command an `Encrypted` record stands for, and any other command as
+    /// it is.
+    fn open(self, cmd: Command) -> Result<Command> {
+        match (cmd, self.cipher) {
+            (Command::Encrypted(sealed), Some(cipher)) => {
+                Ok(self.format.decode_prefix(&cipher.open(&sealed)?)?.0)
+            }
+            (Command::Encrypted(_), None) => Err(KvsError::EncryptionKeyRequired),
+            (cmd, _) => Ok(cmd),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogFormat::Json => write!(f, "json"),
+            LogFormat::Bincode => write!(f, "bincode"),
+        }
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(LogFormat::Json),
+            "bincode" => Ok(LogFormat::Bincode),
+            _ => Err(KvsError::UnknownFormat(s.to_owned())),
+        }
+    }
+}
+
+/// A record read from a log.
+pub(crate) enum Record {
+    /// A command with the start and end offsets and the checksum of its record.
+    Command {
+        cmd: Command,
+        start: u64,
+        end: u64,
+        crc: u32,
+    },
+    /// A record at the given offset that cannot be decoded.
+    Broken(u64),
+}
+
+/// Reader that keeps a copy of every byte read through it.
+struct Recorder<R> {
+    inner: R,
+    recorded: Rc<RefCell<Vec<u8>>>,
+}
+
+impl<R: Read> Read for Recorder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.recorded.borrow_mut().extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Reads the length-prefixed bincode record starting at `pos`.
+///
+/// Returns `None` at a clean end of the log.
+fn read_bincode_record<R: Read>(reader: &mut R, pos: u64) -> Result<Option<Record>> {
+    let mut len = [0; 4];
+    let mut filled = 0;
+    while filled < len.len() {
+        match reader.read(&mut len[filled..])? {
+            0 if filled == 0 => return Ok(None),
+            0 => return Ok(Some(Record::Broken(pos))),
+            n => filled += n,
+        }
+    }
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&len);
+    let len = u32::from_le_bytes(len) as u64;
+    let mut payload = Vec::new();
+    reader.take(len).read_to_end(&mut payload)?;
+    if (payload.len() as u64) < len {
+        return Ok(Some(Record::Broken(pos)));
+    }
+    hasher.update(&payload);
+    Ok(Some(match bincode::deserialize(&payload) {
+        Ok(cmd) => Record::Command {
+            cmd,
+            start: pos,
+            end: pos + 4 + len,
+            crc: hasher.finalize(),
+        },
+        Err(_) => Record::Broken(pos),
+    }))
+}
@@ -0,0 +1,112 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::kv::log_path;
+use crate::Result;
+
+/// Key, start and end offsets, and expiry of a `Set` record.
+pub(crate) type HintEntry = (String, u64, u64, Option<u64>);
+
+/// Entries, sequence number and bytes of older versions kept of a hint file.
+pub(crate) type HintFile = (Vec<HintEntry>, u64, u64);
+
+/// Contents of a hint file, listing the live records of one log generation.
+#[derive(Serialize, Deserialize)]
+struct Hint {
+    // Length of the log when the hint was written.
+    log_len: u64,
+    entries: Vec<HintEntry>,
+    // Sequence number of the last change written before the log was, which is after
+    // every change in it.
+    seq: u64,
+    // Bytes of the older versions of keys the log holds besides the live records. Last,
+    // so a hint written without it fails to read and the log is replayed instead.
+    kept: u64,
+}
+
+/// Returns the path of the hint file for the given generation.
+pub(crate) fn hint_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{gen}.hint"))
+}
+
+/// Writes the hint file of a generation whose log contains exactly the given records,
+/// and `kept` bytes of older versions of keys, written once the change of sequence
+/// number `seq` was.
+///
+/// Returns the size of the hint file.
+pub(crate) fn write_hint(
+    dir: &Path,
+    gen: u64,
+    entries: Vec<HintEntry>,
+    seq: u64,
+    kept: u64,
+) -> Result<u64> {
+    let log_len = fs::metadata(log_path(dir, gen))?.len();
+    let payload = bincode::serialize(&Hint {
+        log_len,
+        entries,
+        seq,
+        kept,
+    })?;
+    let mut bytes = crc32fast::hash(&payload).to_le_bytes().to_vec();
+    bytes.extend_from_slice(&payload);
+    // Write to a temporary file first, so a crash never leaves a partial hint behind.
+    let tmp_path = dir.join(format!("{gen}.hint.tmp"));
+    fs::write(&tmp_path, &bytes)?;
+    fs::rename(tmp_path, hint_path(dir, gen))?;
+    Ok(bytes.len() as u64)
+}
+
+/// Reads the hint file of a generation, returning its entries, sequence number and
+/// bytes of older versions kept.
+///
+/// Returns `None` if there is no hint file, or if it is damaged or does not match the log.
+pub(crate) fn read_hint(dir: &Path, gen: u64) -> Option<HintFile> {
+    match try_read_hint(dir, gen) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Ignoring the hint file of {}.log: {}", gen, e);
+            None
+        }
+    }
+}
+
+fn try_read_hint(dir: &Path, gen: u64) -> Result<Option<HintFile>> {
+    let path = hint_path(dir, gen);
+    let bytes = match fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let log = fs::metadata(log_path(dir, gen))?;
+    if log.modified()? > fs::metadata(&path)?.modified()? {
+        return Ok(None);
+    }
+    if bytes.len() < 4 || crc32fast::hash(&bytes[4..]).to_le_bytes() != bytes[..4] {
+        warn!("Ignoring the damaged hint file of {}.log", gen);
+        return Ok(None);
+    }
+    let hint: Hint = bincode::deserialize(&bytes[4..])?;
+    if hint.log_len != log.len() {
+        return Ok(None);
+    }
+    Ok(Some((hint.entries, hint.seq, hint.kept)))
+}
+
+/// Removes the hint file of a generation, if any.
+///
+/// Returns the size of the removed hint file.
+pub(crate) fn remove_hint(dir: &Path, gen: u64) -> Result<u64> {
+    let path = hint_path(dir, gen);
+    let len = match fs::metadata(&path) {
+        Ok(metadata) => metadata.len(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    fs::remove_file(path)?;
+    Ok(len)
+}
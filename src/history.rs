@@ -0,0 +1,234 @@
+use std::collections::BTreeMap;
+use std::io::{Seek, SeekFrom};
+use std::path::Path;
+
+use crate::format::Record;
+use crate::hint::hint_path;
+use crate::kv::{at_record, Command, CommandPos, LogReaders};
+use crate::{KvsError, Result};
+
+/// A version of a key found in the logs by [`KvStore::history`].
+///
+/// History is best-effort: a compaction keeps only the latest `keep_versions` versions
+/// of every key in the log it writes, and drops the versions of the keys removed unless
+/// `keep_deleted_versions` is set, so only the versions since the last compaction, or
+/// in a compacted log, are found.
+///
+/// [`KvStore::history`]: crate::KvStore::history
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VersionedValue {
+    /// Generation of the log the version is in.
+    pub gen: u64,
+    /// Byte offset of the record of the version in the log.
+    pub offset: u64,
+    /// Sequence number of the change, `None` for records written before changes were
+    /// numbered, and for records copied by a compaction.
+    pub seq: Option<u64>,
+    /// The value, given as UTF-8 with invalid bytes replaced, or `None` if the key was
+    /// removed.
+    pub value: Option<String>,
+    /// Whether the log the version is in was written by a compaction, before which
+    /// the older versions of the key are missing.
+    pub compacted: bool,
+}
+
+/// A version found in a log, whose value is read once every log is scanned.
+struct Found {
+    seq: Option<u64>,
+    // `None` for a removal.
+    pos: Option<CommandPos>,
+    offset: u64,
+}
+
+/// Finds every version of a key in the logs, oldest first.
+pub(crate) fn history(
+    dir: &Path,
+    readers: &mut LogReaders,
+    key: &str,
+) -> Result<Vec<VersionedValue>> {
+    let gens: Vec<u64> = readers.gens().collect();
+    let mut versions = Vec::new();
+    for gen in gens {
+        let mut found = Vec::new();
+        versions_in_log(readers, gen, Some(key), &mut |_, version| {
+            found.push(version)
+        })?;
+        let compacted = hint_path(dir, gen).exists();
+        for Found { seq, pos, offset } in found {
+            let value = match pos {
+                Some(pos) => {
+                    let mut value = Vec::new();
+                    readers.copy_value(key, &pos, &mut value)?;
+                    Some(String::from_utf8_lossy(&value).into_owned())
+                }
+                None => None,
+            };
+            versions.push(VersionedValue {
+                gen,
+                offset,
+                seq,
+                value,
+                compacted,
+            });
+        }
+    }
+    Ok(versions)
+}
+
+/// Older versions of keys a compaction keeps along with their live records, by key.
+pub(crate) type KeptVersions = BTreeMap<String, Kept>;
+
+/// The older versions of a key a compaction keeps.
+#[derive(Default)]
+pub(crate) struct Kept {
+    /// Records of the versions, oldest first.
+    pub(crate) records: Vec<CommandPos>,
+    /// Whether the key was removed after them.
+    pub(crate) removed: bool,
+}
+
+/// Finds the versions of keys to keep in the logs below generation `below`, given
+/// the position of the live record of a key.
+///
+/// Up to `keep` versions of every key are kept, counting its live one. A removed key
+/// keeps its latest `keep` versions if `keep_deleted` is set, and none otherwise.
+pub(crate) fn kept_versions<F>(
+    readers: &mut LogReaders,
+    below: u64,
+    keep: usize,
+    keep_deleted: bool,
+    mut live: F,
+) -> Result<KeptVersions>
+where
+    F: FnMut(&str) -> Result<Option<CommandPos>>,
+{
+    let keep = keep.max(1);
+    let mut scanned = KeptVersions::new();
+    let gens: Vec<u64> = readers.gens().filter(|&gen| gen < below).collect();
+    for gen in gens {
+        versions_in_log(readers, gen, None, &mut |key, version| {
+            if !scanned.contains_key(key) {
+                scanned.insert(key.to_owned(), Kept::default());
+            }
+            let kept = scanned.get_mut(key).unwrap();
+            match version.pos {
+                Some(pos) => {
+                    if kept.records.len() == keep {
+                        kept.records.remove(0);
+                    }
+                    kept.records.push(pos);
+                    kept.removed = false;
+                }
+                None => kept.removed = true,
+            }
+        })?;
+    }
+    let mut versions = KeptVersions::new();
+    for (key, mut kept) in scanned {
+        match live(&key)? {
+            // A live record written outside the logs scanned has no older versions in
+            // them that are still its latest ones.
+            Some(pos) => {
+                let last = kept.records.pop();
+                if last.map(|last| (last.gen, last.start)) != Some((pos.gen, pos.start)) {
+                    continue;
+                }
+            }
+            None if kept.removed && keep_deleted => {}
+            None => continue,
+        }
+        if !kept.records.is_empty() {
+            versions.insert(key, kept);
+        }
+    }
+    Ok(versions)
+}
+
+/// Passes the versions of a key, or of every key if `key` is `None`, in the log of a
+/// generation to `found`, in order.
+fn versions_in_log(
+    readers: &mut LogReaders,
+    gen: u64,
+    key: Option<&str>,
+    found: &mut dyn FnMut(&str, Found),
+) -> Result<()> {
+    let wanted = |name: &str| key.is_none_or(|key| key == name);
+    let format = readers.format;
+    let reader = readers.reader(gen)?;
+    reader.seek(SeekFrom::Start(0)).map_err(at_record(gen, 0))?;
+    // Sequence number of the next record, and where its checksum record starts.
+    let mut next = None;
+    // Key, sequence number, start and bytes still to read of a streamed value.
+    let mut stream: Option<(String, Option<u64>, u64, u64)> = None;
+    for record in format.records(&mut *reader) {
+        let (cmd, start, end) = match record? {
+            Record::Command {
+                cmd, start, end, ..
+            } => (cmd, start, end),
+            Record::Broken(offset) => return Err(KvsError::Corruption { gen, offset }),
+        };
+        let cmd = match cmd {
+            Command::Checksum(_) => {
+                next = Some((None, start));
+                continue;
+            }
+            Command::Sequenced(_, seq) => {
+                next = Some((Some(seq), start));
+                continue;
+            }
+            Command::Chunk(chunk) => {
+                if let Some((_, _, _, remaining)) = &mut stream {
+                    *remaining = remaining.saturating_sub(chunk.len() as u64);
+                    if *remaining == 0 {
+                        let (streamed, seq, start, _) = stream.take().unwrap();
+                        let pos = CommandPos {
+                            gen,
+                            start,
+                            end,
+                            expires_at: None,
+                        };
+                        let version = Found {
+                            seq,
+                            pos: Some(pos),
+                            offset: start,
+                        };
+                        found(&streamed, version);
+                    }
+                }
+                continue;
+            }
+            cmd => cmd.decompress(format).map_err(at_record(gen, start))?,
+        };
+        let (seq, start) = next.take().unwrap_or((None, start));
+        let pos = CommandPos {
+            gen,
+            start,
+            end,
+            expires_at: None,
+        };
+        match cmd {
+            Command::Remove(removed) if wanted(&removed) => found(
+                &removed,
+                Found {
+                    seq,
+                    pos: None,
+                    offset: start,
+                },
+            ),
+            Command::SetStream(streamed, len) if wanted(&streamed) && len > 0 => {
+                stream = Some((streamed, seq, start, len))
+            }
+            cmd => {
+                if let Some(name) = cmd.key().filter(|&name| wanted(name)) {
+                    let version = Found {
+                        seq,
+                        pos: Some(pos),
+                        offset: start,
+                    };
+                    found(name, version);
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,481 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::ops::{AddAssign, Bound, SubAssign};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::kv::{CommandPos, RecordCopier};
+use crate::namespace::namespace_of;
+use crate::snapshot::{write_snapshot, Snapshot};
+use crate::{Result, SizeStats};
+
+/// Entries of an index in key order, borrowing from the index where it can.
+pub(crate) type Entries<'a> =
+    Box<dyn Iterator<Item = Result<(Cow<'a, str>, CommandPos)>> + Send + 'a>;
+
+/// A point in the logs up to which an index reflects every record.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Checkpoint {
+    pub(crate) gen: u64,
+    pub(crate) offset: u64,
+    /// Stale bytes in the logs up to the point.
+    pub(crate) uncompacted: u64,
+    /// Stale records in the logs up to the point.
+    pub(crate) stale: StaleRecords,
+    /// Sequence number of the last change in the logs up to the point.
+    pub(crate) seq: u64,
+    /// Bytes of the older versions of keys compactions kept in the logs up to the point.
+    pub(crate) kept: u64,
+}
+
+/// Numbers of the stale records in the logs, which a compaction drops.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct StaleRecords {
+    /// Values that were overwritten, removed or expired since they were written.
+    pub(crate) values: u64,
+    /// Records of removes and clears.
+    pub(crate) tombstones: u64,
+}
+
+impl AddAssign for StaleRecords {
+    fn add_assign(&mut self, other: Self) {
+        self.values += other.values;
+        self.tombstones += other.tombstones;
+    }
+}
+
+impl SubAssign for StaleRecords {
+    fn sub_assign(&mut self, other: Self) {
+        self.values = self.values.saturating_sub(other.values);
+        self.tombstones = self.tombstones.saturating_sub(other.tombstones);
+    }
+}
+
+/// The map from every key to the position of its latest record.
+///
+/// The in-memory `BTreeMap` is the default, and `DiskIndex` keeps the map in a file for
+/// stores with more keys than fit in memory.
+pub(crate) trait KeyIndex: Send {
+    /// Returns the position of a key.
+    fn get(&self, key: &str) -> Result<Option<CommandPos>>;
+    /// Sets the position of a key, returning its previous one.
+    fn insert(&mut self, key: String, pos: CommandPos) -> Result<Option<CommandPos>>;
+    /// Removes a key, returning its position.
+    fn remove(&mut self, key: &str) -> Result<Option<CommandPos>>;
+    /// Removes every key, returning the number and the total length of their records.
+    fn clear(&mut self) -> Result<(u64, u64)>;
+    /// Returns the entries within the given key range.
+    ///
+    /// Panics if the range start is greater than its end.
+    fn range(&self, start: Bound<&str>, end: Bound<&str>) -> Entries<'_>;
+    /// Returns the entries within the given key range, in reverse key order.
+    ///
+    /// Panics if the range start is greater than its end.
+    fn range_rev(&self, start: Bound<&str>, end: Bound<&str>) -> Entries<'_>;
+    /// Returns every entry.
+    fn iter(&self) -> Entries<'_> {
+        self.range(Bound::Unbounded, Bound::Unbounded)
+    }
+    /// Keeps only the entries whose key and position `keep` returns `true` for.
+    ///
+    /// `keep` is called exactly once for every entry.
+    fn retain(&mut self, keep: &mut dyn FnMut(&str, &CommandPos) -> bool) -> Result<()>;
+    /// Copies the records of the entries after `start` that are in logs below the
+    /// generation of `copier`, in key order, and points the entries at the copies once
+    /// all of them are made and synced.
+    ///
+    /// No more records are copied once `budget` bytes are, but at least one is. Returns
+    /// the key of the last entry copied if entries are left to copy, and `None` once
+    /// none are. The index is left as it is if a copy fails. Afterwards it reflects the
+    /// logs up to `at`.
+    fn relocate(
+        &mut self,
+        at: Checkpoint,
+        start: Bound<&str>,
+        budget: u64,
+        copier: &mut RecordCopier<'_>,
+    ) -> Result<Option<String>>;
+    /// Records that the index reflects the logs up to `at`.
+    fn checkpoint(&mut self, _at: Checkpoint) -> Result<()> {
+        Ok(())
+    }
+    /// Records that the index reflects the logs up to `at`, so that the next open of the
+    /// store starts from there even after a crash.
+    ///
+    /// An index that is rebuilt from the logs after a crash has nothing to record.
+    fn persist(&mut self, _at: Checkpoint) -> Result<()> {
+        Ok(())
+    }
+    /// Saves the index for the next open of the store, given the generation and length
+    /// of every log, and the end of the active log as the point the index reflects.
+    fn save(&mut self, dir: &Path, logs: Vec<(u64, u64)>, at: Checkpoint) -> Result<()>;
+}
+
+impl KeyIndex for BTreeMap<String, CommandPos> {
+    fn get(&self, key: &str) -> Result<Option<CommandPos>> {
+        Ok(BTreeMap::get(self, key).copied())
+    }
+
+    fn insert(&mut self, key: String, pos: CommandPos) -> Result<Option<CommandPos>> {
+        Ok(BTreeMap::insert(self, key, pos))
+    }
+
+    fn remove(&mut self, key: &str) -> Result<Option<CommandPos>> {
+        Ok(BTreeMap::remove(self, key))
+    }
+
+    fn clear(&mut self) -> Result<(u64, u64)> {
+        let index = std::mem::take(self);
+        Ok((
+            index.len() as u64,
+            index.values().map(CommandPos::len).sum(),
+        ))
+    }
+
+    fn range(&self, start: Bound<&str>, end: Bound<&str>) -> Entries<'_> {
+        Box::new(
+            BTreeMap::range::<str, _>(self, (start, end))
+                .map(|(key, &pos)| Ok((Cow::Borrowed(key.as_str()), pos))),
+        )
+    }
+
+    fn range_rev(&self, start: Bound<&str>, end: Bound<&str>) -> Entries<'_> {
+        Box::new(
+            BTreeMap::range::<str, _>(self, (start, end))
+                .rev()
+                .map(|(key, &pos)| Ok((Cow::Borrowed(key.as_str()), pos))),
+        )
+    }
+
+    fn retain(&mut self, keep: &mut dyn FnMut(&str, &CommandPos) -> bool) -> Result<()> {
+        BTreeMap::retain(self, |key, pos| keep(key, pos));
+        Ok(())
+    }
+
+    fn relocate(
+        &mut self,
+        _at: Checkpoint,
+        start: Bound<&str>,
+        budget: u64,
+        copier: &mut RecordCopier<'_>,
+    ) -> Result<Option<String>> {
+        let mut copies = Vec::new();
+        let mut left = false;
+        let gen = copier.gen();
+        for (key, pos) in BTreeMap::range::<str, _>(self, (start, Bound::Unbounded)) {
+            if pos.gen >= gen {
+                continue;
+            }
+            if copier.copied() >= budget && !copies.is_empty() {
+                left = true;
+                break;
+            }
+            copies.push((key.clone(), copier.copy(key, pos)?));
+        }
+        copier.finish()?;
+        let last = copies.last().map(|(key, _)| key.clone());
+        for (key, copy) in copies {
+            *self.get_mut(&key).unwrap() = copy;
+        }
+        Ok(last.filter(|_| left))
+    }
+
+    fn save(&mut self, dir: &Path, logs: Vec<(u64, u64)>, at: Checkpoint) -> Result<()> {
+        let snapshot = Snapshot {
+            logs,
+            cur_gen: at.gen,
+            uncompacted: at.uncompacted,
+            index: std::mem::take(self),
+            stale: at.stale,
+            seq: at.seq,
+            kept: at.kept,
+        };
+        write_snapshot(dir, &snapshot)
+    }
+}
+
+/// Sizes counted by size, whose statistics are kept up to date as sizes are added and
+/// removed.
+#[derive(Default)]
+pub(crate) struct SizeDistribution {
+    // Only the smallest and largest sizes are looked up.
+    counts: BTreeMap<u64, u64>,
+    stats: SizeStats,
+}
+
+impl SizeDistribution {
+    fn bucket(size: u64) -> usize {
+        ((u64::BITS - size.leading_zeros()) as usize).min(31)
+    }
+
+    fn add(&mut self, size: u64) {
+        self.adjust(size, 1);
+    }
+
+    fn remove(&mut self, size: u64) {
+        self.adjust(size, -1);
+    }
+
+    /// Adds `delta` sizes of `size`, or removes them if it is negative.
+    fn adjust(&mut self, size: u64, delta: i64) {
+        let count = self.counts.entry(size).or_default();
+        *count = count.wrapping_add_signed(delta);
+        if *count == 0 {
+            self.counts.remove(&size);
+        }
+        self.stats.count = self.stats.count.wrapping_add_signed(delta);
+        self.stats.total = self.stats.total.wrapping_add_signed(size as i64 * delta);
+        let bucket = &mut self.stats.buckets[Self::bucket(size)];
+        *bucket = bucket.wrapping_add_signed(delta);
+        self.update_bounds();
+    }
+
+    fn update_bounds(&mut self) {
+        self.stats.min = self.counts.keys().next().copied().unwrap_or(0);
+        self.stats.max = self.counts.keys().next_back().copied().unwrap_or(0);
+    }
+
+    fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Returns the statistics of the sizes.
+    pub(crate) fn stats(&self) -> SizeStats {
+        self.stats
+    }
+}
+
+/// An index keeping the total length of the records its entries point at up to date,
+/// along with the distributions of the lengths of its keys and records, the number of
+/// keys of every namespace, and the keys that expire by the time they do.
+///
+/// The keys that expire are kept in memory even if the index is disk-resident, with the
+/// length of their record, so they are found without reading every entry.
+pub(crate) struct LiveIndex {
+    index: Box<dyn KeyIndex>,
+    live: u64,
+    key_sizes: SizeDistribution,
+    value_sizes: SizeDistribution,
+    namespace_keys: BTreeMap<String, usize>,
+    expiring: BTreeMap<(u64, String), u64>,
+}
+
+impl LiveIndex {
+    /// Wraps an index, summing up its entries and collecting the keys that expire once.
+    pub(crate) fn new(index: Box<dyn KeyIndex>) -> Result<Self> {
+        let mut live = Self {
+            index: Box::new(BTreeMap::new()),
+            live: 0,
+            key_sizes: SizeDistribution::default(),
+            value_sizes: SizeDistribution::default(),
+            namespace_keys: BTreeMap::new(),
+            expiring: BTreeMap::new(),
+        };
+        for entry in index.iter() {
+            let (key, pos) = entry?;
+            live.count(key.len(), namespace_of(&key), Some(pos), None);
+            live.track_expiry(&key, Some(pos), None);
+        }
+        live.index = index;
+        Ok(live)
+    }
+
+    /// Returns the total length of the records the entries point at.
+    pub(crate) fn live(&self) -> u64 {
+        self.live
+    }
+
+    /// Returns the number of entries, which the distribution of the key lengths counts.
+    pub(crate) fn len(&self) -> u64 {
+        self.key_sizes.stats().count
+    }
+
+    /// Returns the number of keys that have expired by `now`, but are still in the index.
+    pub(crate) fn expired_len(&self, now: u64) -> u64 {
+        self.expired_range(now).count() as u64
+    }
+
+    /// Returns the number of keys of every namespace that has any, expired keys included.
+    pub(crate) fn namespace_keys(&self) -> &BTreeMap<String, usize> {
+        &self.namespace_keys
+    }
+
+    /// Returns the keys that have expired by `now`, but are still in the index, with the
+    /// length of their record, soonest expired first.
+    pub(crate) fn expired_entries(&self, now: u64) -> impl Iterator<Item = (&str, u64)> {
+        self.expired_range(now)
+            .map(|((_, key), len)| (key.as_str(), *len))
+    }
+
+    /// Returns the distribution of the lengths of the keys.
+    pub(crate) fn key_sizes(&self) -> SizeStats {
+        self.key_sizes.stats()
+    }
+
+    /// Returns the distribution of the lengths of the records the entries point at.
+    pub(crate) fn value_sizes(&self) -> SizeStats {
+        self.value_sizes.stats()
+    }
+
+    /// Counts the entry of a key of length `key_len`, in namespace `name`, pointing at
+    /// `new` in place of `old`.
+    fn count(
+        &mut self,
+        key_len: usize,
+        name: Option<&str>,
+        new: Option<CommandPos>,
+        old: Option<CommandPos>,
+    ) {
+        if let Some(old) = old {
+            self.live -= old.len();
+            self.value_sizes.remove(old.len());
+        }
+        if let Some(new) = new {
+            self.live += new.len();
+            self.value_sizes.add(new.len());
+        }
+        match (new.is_some(), old.is_some()) {
+            (true, false) => {
+                self.key_sizes.add(key_len as u64);
+                if let Some(name) = name {
+                    *self.namespace_keys.entry(name.to_owned()).or_default() += 1;
+                }
+            }
+            (false, true) => {
+                self.key_sizes.remove(key_len as u64);
+                if let Some(name) = name {
+                    let count = self.namespace_keys.get_mut(name).unwrap();
+                    *count -= 1;
+                    if *count == 0 {
+                        self.namespace_keys.remove(name);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Keeps track of when a key expires once its entry points at `new` in place of `old`.
+    fn track_expiry(&mut self, key: &str, new: Option<CommandPos>, old: Option<CommandPos>) {
+        if let Some(expires_at) = old.and_then(|old| old.expires_at) {
+            self.expiring.remove(&(expires_at, key.to_owned()));
+        }
+        if let Some(new) = new {
+            if let Some(expires_at) = new.expires_at {
+                self.expiring
+                    .insert((expires_at, key.to_owned()), new.len());
+            }
+        }
+    }
+
+    /// Returns the keys that have expired by `now`, soonest expired first.
+    pub(crate) fn expired(&self, now: u64) -> Vec<String> {
+        self.expired_range(now)
+            .map(|((_, key), _)| key.clone())
+            .collect()
+    }
+
+    fn expired_range(&self, now: u64) -> impl Iterator<Item = (&(u64, String), &u64)> {
+        self.expiring
+            .range(..(now.saturating_add(1), String::new()))
+    }
+}
+
+impl KeyIndex for LiveIndex {
+    fn get(&self, key: &str) -> Result<Option<CommandPos>> {
+        self.index.get(key)
+    }
+
+    fn insert(&mut self, key: String, pos: CommandPos) -> Result<Option<CommandPos>> {
+        // The key is only copied if it expires, replaces one that might, or is in a
+        // namespace.
+        let namespaced = namespace_of(&key).is_some();
+        let tracked = (pos.expires_at.is_some() || !self.expiring.is_empty() || namespaced)
+            .then(|| key.clone());
+        let key_len = key.len();
+        let old = self.index.insert(key, pos)?;
+        let name = tracked.as_deref().and_then(namespace_of);
+        self.count(key_len, name, Some(pos), old);
+        if let Some(key) = tracked {
+            self.track_expiry(&key, Some(pos), old);
+        }
+        Ok(old)
+    }
+
+    fn remove(&mut self, key: &str) -> Result<Option<CommandPos>> {
+        let old = self.index.remove(key)?;
+        self.count(key.len(), namespace_of(key), None, old);
+        self.track_expiry(key, None, old);
+        Ok(old)
+    }
+
+    fn clear(&mut self) -> Result<(u64, u64)> {
+        let cleared = self.index.clear()?;
+        self.live = 0;
+        self.key_sizes.clear();
+        self.value_sizes.clear();
+        self.namespace_keys.clear();
+        self.expiring.clear();
+        Ok(cleared)
+    }
+
+    fn range(&self, start: Bound<&str>, end: Bound<&str>) -> Entries<'_> {
+        self.index.range(start, end)
+    }
+
+    fn range_rev(&self, start: Bound<&str>, end: Bound<&str>) -> Entries<'_> {
+        self.index.range_rev(start, end)
+    }
+
+    fn retain(&mut self, keep: &mut dyn FnMut(&str, &CommandPos) -> bool) -> Result<()> {
+        let mut dropped = Vec::new();
+        self.index.retain(&mut |key, pos| {
+            let kept = keep(key, pos);
+            if !kept {
+                dropped.push((key.to_owned(), *pos));
+            }
+            kept
+        })?;
+        for (key, pos) in dropped {
+            self.count(key.len(), namespace_of(&key), None, Some(pos));
+            self.track_expiry(&key, None, Some(pos));
+        }
+        Ok(())
+    }
+
+    fn relocate(
+        &mut self,
+        at: Checkpoint,
+        start: Bound<&str>,
+        budget: u64,
+        copier: &mut RecordCopier<'_>,
+    ) -> Result<Option<String>> {
+        // The entries copied point at the copies once this returns, which take the bytes
+        // the copier wrote in place of the bytes it read.
+        copier.take_resized();
+        let last = self.index.relocate(at, start, budget, copier)?;
+        for (size, delta) in copier.take_resized() {
+            self.live = self.live.wrapping_add_signed(size as i64 * delta);
+            self.value_sizes.adjust(size, delta);
+        }
+        for (expiry, len) in copier.take_expiring() {
+            if let Some(tracked) = self.expiring.get_mut(&expiry) {
+                *tracked = len;
+            }
+        }
+        Ok(last)
+    }
+
+    fn checkpoint(&mut self, at: Checkpoint) -> Result<()> {
+        self.index.checkpoint(at)
+    }
+
+    fn persist(&mut self, at: Checkpoint) -> Result<()> {
+        self.index.persist(at)
+    }
+
+    fn save(&mut self, dir: &Path, logs: Vec<(u64, u64)>, at: Checkpoint) -> Result<()> {
+        self.index.save(dir, logs, at)
+    }
+}
@@ -1,180 +1,4261 @@
+use std::borrow::Cow;
 use std::collections::BTreeMap;
 use std::ffi::OsStr;
-use std::fs::{create_dir_all, File, OpenOptions};
+use std::fs::{create_dir_all, File, OpenOptions, TryLockError};
 use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::iter::Rev;
+use std::ops::{Bound, RangeBounds};
 use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{fs, io};
 
+use crate::archive::{archive_dir, archived_gens, prune_archive};
+use crate::batched::Batched;
+use crate::cache::ValueCache;
+use crate::csv::{write_row, CsvReader};
+use crate::disk_index::{remove_index_file, DiskIndex};
+use crate::encrypt::{check_cipher, ENCRYPTION_FILE};
+use crate::engines::{claim_dir, Engine};
+use crate::entry::entry;
+use crate::export::{ExportRecord, ExportValue};
+use crate::format::{Codec, Record};
+use crate::hint::{hint_path, read_hint, remove_hint, write_hint};
+use crate::history::{history, kept_versions, KeptVersions};
+use crate::index::{Checkpoint, Entries, KeyIndex, LiveIndex, StaleRecords};
+use crate::manifest::{migrate, write_manifest};
+use crate::merge::{has_merges, mark_merges, unmark_merges};
+#[cfg(all(feature = "mmap", unix))]
+use crate::mmap::Mmap;
+use crate::namespace::namespace_of;
+use crate::raw::{encode_key, RAW_KEYS_END, RAW_KEY_PREFIX};
+use crate::repair::{intact_record, repair};
+use crate::snapshot::{read_snapshot, remove_snapshot};
+use crate::verify::Verifier;
+use crate::view::{Pins, Snapshot, PINNED_DIR};
+use crate::watch::Watchers;
+use crate::{
+    Changes, CompactionReport, CorruptionPolicy, CsvOptions, CsvRowError, Cursor, Duplicates,
+    Durability, Entry, ImportReport, KvStoreBuilder, KvsError, LogFormat, MergeOperator, Namespace,
+    Options, PurgeReport, RawCommands, RawScan, RepairReport, Result, SkippedRegion, StoreStats,
+    Transaction, VerifyReport, VersionedValue, WatchEvent, WatchHandle, WriteBatch,
+};
+use log::warn;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use serde_json::Deserializer;
 
-use crate::{KvsError, Result};
+/// Name of the file locked by the process that has the store open.
+pub(crate) const LOCK_FILE: &str = "LOCK";
 
-const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+/// Number of value bytes in each record of a streamed value.
+const CHUNK_LEN: usize = 64 * 1024;
 
+/// The `KvStore` stores string key/value pairs.
 ///
+/// Key/value pairs are persisted to log files in the store directory, and an index
+/// maps every key to the position of its latest record. The index is an in-memory
+/// `BTreeMap`, unless the store is opened with a disk-resident index.
 pub struct KvStore {
     folder: PathBuf,
-    writer: BufWriter<File>,
-    readers: BTreeMap<u64, BufReader<File>>,
+    // `None` if the store is read-only.
+    writer: Option<BufWriter<File>>,
+    readers: LogReaders,
     cur_gen: u64,
-    index: BTreeMap<String, (u64, u64, u64)>,
+    // Keeps the total length of the latest records of the keys.
+    index: LiveIndex,
+    // Values recently read, if the store caches them.
+    cache: ValueCache,
+    uncompacted: u64,
+    stale: StaleRecords,
+    // Sequence number of the last change written.
+    seq: u64,
+    // Bytes of the older versions of keys the last compaction kept.
+    kept: u64,
+    // Total size of the logs, kept up to date so `stats` never touches the filesystem.
+    disk_bytes: u64,
+    format: Codec,
+    options: Options,
+    unsynced: Unsynced,
+    // Whether a log was created since the store directory was last synced.
+    dir_unsynced: bool,
+    compaction: Option<Compaction>,
+    // The incremental compaction in progress, if any.
+    stepping: Option<Stepping>,
+    last_compaction: Option<CompactionReport>,
+    // Compactions finished since the store was opened.
+    compactions: u64,
+    // Whether the store directory is marked as possibly holding merge records.
+    merges: bool,
+    watchers: Watchers,
+    // Damaged runs of records skipped when the store was opened.
+    skipped: Vec<SkippedRegion>,
+    // The logs live snapshots read.
+    pins: Pins,
+    // Held for as long as the store is open.
+    _lock: Option<File>,
+}
+
+impl KvStore {
+    /// Opens a `KvStore` with the given path.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    /// The log format is read from the store directory. New stores use `LogFormat::Json`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_dir(path.as_ref(), None, Options::default())
+    }
+    /// Returns a builder to configure a `KvStore` at the given path before opening it.
+    pub fn builder<P: AsRef<Path>>(path: P) -> KvStoreBuilder {
+        KvStoreBuilder::new(path.as_ref().to_owned())
+    }
+    /// Opens a `KvStore` with the given path and tuning options.
+    pub fn open_with<P: AsRef<Path>>(path: P, options: Options) -> Result<Self> {
+        Self::open_dir(path.as_ref(), None, options)
+    }
+    /// Opens an existing `KvStore` with the given path for reading only.
+    ///
+    /// No file in the store directory is created or modified, so this works on a
+    /// read-only filesystem. Writes and compaction return `KvsError::ReadOnly`, and a
+    /// torn write at the tail of the newest log is ignored instead of truncated away.
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let options = Options {
+            read_only: true,
+            ..Options::default()
+        };
+        Self::open_dir(path.as_ref(), None, options)
+    }
+    /// Opens the `KvStore` with the given path and rolls it back to its state as of the end
+    /// of generation `gen`.
+    ///
+    /// The state is rebuilt by replaying the logs up to and including `gen`, from the
+    /// archive and the store directory, and written to a new generation. The logs it
+    /// replaces are archived rather than removed, so the restore itself can be undone.
+    /// Returns `KvsError::GenerationNotFound` if a log it needs is no longer retained.
+    pub fn restore_to_generation<P: AsRef<Path>>(path: P, gen: u64) -> Result<Self> {
+        let mut store = Self::open(path)?;
+        store.restore(gen)?;
+        Ok(store)
+    }
+    /// Opens the `KvStore` with the given path and sets every key/value pair of `pairs`.
+    ///
+    /// The pairs are written with a single flush at the end, and at most one compaction
+    /// is started afterwards, which makes this much faster than `set` in a loop.
+    /// A key given more than once keeps its last value, like repeated `set`s.
+    pub fn from_pairs<P, I>(path: P, pairs: I) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut store = Self::open(path)?;
+        store.set_pairs(pairs)?;
+        Ok(store)
+    }
+    /// Opens the empty `KvStore` with the given path and loads every key/value pair of
+    /// `pairs` straight into a sealed generation.
+    ///
+    /// The pairs are streamed into a log of their own, which is synced once at the end
+    /// and replaces the logs of the store, with the index built as they are written. No
+    /// compaction is started, and nothing is written twice, which makes this the way to
+    /// build a store from a dump. Watchers are sent no events, and the change feed has
+    /// no record of the load. The log is written whole, whatever the maximum segment
+    /// size.
+    ///
+    /// The pairs may come in any order, but sorted pairs lay the values of neighbouring
+    /// keys out next to each other, which makes range scans of the store read the log
+    /// in order. A key given more than once fails the load with
+    /// `KvsError::DuplicateKey`; see [`KvStoreBuilder::bulk_load`] to keep its last value
+    /// instead. A store that already has keys returns `KvsError::StoreNotEmpty`. A load
+    /// that fails leaves the store empty, and a crash midway leaves it with some of the
+    /// pairs.
+    pub fn bulk_load<P, I>(path: P, pairs: I) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        I: IntoIterator<Item = (String, String)>,
+    {
+        Self::builder(path).bulk_load(pairs, Duplicates::Reject)
+    }
+    /// Opens a `KvStore` with the given path, creating it with the given log format.
+    ///
+    /// Returns `KvsError::FormatMismatch` if the store already exists with another format.
+    pub fn open_with_format<P: AsRef<Path>>(path: P, format: LogFormat) -> Result<Self> {
+        Self::open_dir(path.as_ref(), Some(format), Options::default())
+    }
+    /// Salvages what can still be read of the logs of the closed store at the given path,
+    /// which `open` refuses to load once a log is damaged.
+    ///
+    /// Every log is read to its end, skipping each damaged run of bytes up to the next
+    /// intact record, and the keys the intact records leave are written to a new
+    /// generation. A batch or a streamed value is only kept whole, and a suffix or operand
+    /// only with the value it extends. The damaged logs are moved into the `damaged`
+    /// directory of the store, and the others removed. What the damaged runs held is lost,
+    /// so a store is never repaired but by calling this.
+    ///
+    /// Returns `KvsError::StoreLocked` if the store is open.
+    pub fn repair<P: AsRef<Path>>(path: P) -> Result<RepairReport> {
+        repair(path.as_ref())
+    }
+    /// Upgrades the closed store at the given path in place to the on-disk format version
+    /// of this version of kvs, one version at a time.
+    ///
+    /// Before each step, the files of the store directory are copied into a directory of
+    /// the `migration` directory named after the version migrated from. A store already
+    /// at the current version is left as it is. The first version to migrate from is a
+    /// store without a `MANIFEST` file, which gets one.
+    ///
+    /// Returns `KvsError::UnsupportedVersion` if the store is of a newer version.
+    pub fn migrate<P: AsRef<Path>>(path: P) -> Result<()> {
+        migrate(path.as_ref())
+    }
+    pub(crate) fn open_dir(
+        folder: &Path,
+        requested: Option<LogFormat>,
+        options: Options,
+    ) -> Result<Self> {
+        let logs = open_logs(folder, requested, &options)?;
+        Ok(Self {
+            folder: folder.to_owned(),
+            writer: logs.writer,
+            readers: logs.readers,
+            cur_gen: logs.cur_gen,
+            index: LiveIndex::new(match logs.disk_index {
+                Some(index) => Box::new(index),
+                None => Box::new(logs.index),
+            })?,
+            cache: ValueCache::new(options.read_cache_bytes, options.read_cache_entries),
+            uncompacted: logs.uncompacted,
+            stale: logs.stale,
+            seq: logs.seq,
+            kept: logs.kept,
+            disk_bytes: logs.disk_bytes,
+            format: logs.format,
+            options,
+            unsynced: Unsynced::new(),
+            dir_unsynced: true,
+            compaction: None,
+            stepping: None,
+            last_compaction: None,
+            compactions: 0,
+            merges: has_merges(folder),
+            watchers: Watchers::default(),
+            skipped: logs.skipped,
+            pins: Pins::default(),
+            _lock: logs.lock,
+        })
+    }
+    /// Sets the value of a string key to a string.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.append_command(Command::Set(key, value))
+    }
+    /// Sets the value of a string key to a string, which expires after `ttl`.
+    ///
+    /// Once expired, the key is treated as absent and is dropped by the next compaction.
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        let expires_at = now_millis().saturating_add(ttl.as_millis() as u64);
+        self.append_command(Command::SetWithExpiry(key, value, expires_at))
+    }
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist or has expired.
+    /// Returns `KvsError::Utf8` if the value was set by `set_bytes` and is not valid UTF-8.
+    /// Values set by `set_as` are returned as JSON text.
+    pub fn get<K: AsRef<str>>(&mut self, key: K) -> Result<Option<String>> {
+        match self.read_live(key.as_ref())? {
+            Some(cmd) => cmd.into_value(),
+            None => Ok(None),
+        }
+    }
+    /// Sets the value of a string key to arbitrary bytes.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    pub fn set_bytes(&mut self, key: String, value: Vec<u8>) -> Result<()> {
+        self.append_command(Command::SetBytes(key, value))
+    }
+    /// Gets the value of a given string key as bytes.
+    ///
+    /// String values are returned as their UTF-8 bytes. Returns `None` if the given
+    /// key does not exist or has expired.
+    pub fn get_bytes<K: AsRef<str>>(&mut self, key: K) -> Result<Option<Vec<u8>>> {
+        Ok(self.read_live(key.as_ref())?.and_then(Command::into_bytes))
+    }
+    /// Sets the value of a string key to the `len` bytes read from `reader`.
+    ///
+    /// The value is streamed into the log in chunks, so it never has to fit in memory.
+    /// If `reader` fails or ends before `len` bytes, nothing is written and the key
+    /// keeps its previous value, and an `UnexpectedEof` error is returned for a short
+    /// reader. The value reads like one set by `set_bytes`.
+    pub fn set_from_reader<R: Read>(&mut self, key: String, reader: R, len: u64) -> Result<()> {
+        let format = self.format;
+        let mut buf = Vec::new();
+        format.encode_sequenced(
+            &Command::SetStream(key.clone(), len),
+            self.seq + 1,
+            &mut buf,
+        )?;
+        let writer = self.writer()?;
+        let before = writer.stream_position()?;
+        writer.write_all(&buf)?;
+        if let Err(e) = write_chunks(format, writer, reader, len) {
+            // Leave no part of the value behind, which would be taken for a torn write.
+            writer.flush()?;
+            writer.get_ref().set_len(before)?;
+            writer.seek(SeekFrom::End(0))?;
+            return Err(e);
+        }
+        self.finish_stream(key, len, before)
+    }
+    /// Copies the value of a given string key to `writer` as bytes.
+    ///
+    /// Values set by `set_from_reader` are copied a chunk at a time, without reading
+    /// the whole value into memory. Returns the number of bytes copied, or `None` if
+    /// the given key does not exist or has expired.
+    pub fn get_to_writer<K: AsRef<str>, W: Write>(
+        &mut self,
+        key: K,
+        mut writer: W,
+    ) -> Result<Option<u64>> {
+        let key = key.as_ref();
+        match self.index.get(key)? {
+            Some(pos) if pos.is_expired(now_millis()) => {
+                self.index.remove(key)?;
+                self.count_expired(&pos);
+                Ok(None)
+            }
+            Some(pos) => self.readers.copy_value(key, &pos, &mut writer).map(Some),
+            None => Ok(None),
+        }
+    }
+    /// Sets the value of a string key to any serializable value.
+    ///
+    /// The value is serialized once, directly into the log record.
+    pub fn set_as<T: Serialize>(&mut self, key: String, value: &T) -> Result<()> {
+        self.append_command(Command::SetJson(key, serde_json::to_value(value)?))
+    }
+    /// Gets the value of a given string key as the given type.
+    ///
+    /// Returns `None` if the given key does not exist or has expired. Returns
+    /// `KvsError::TypeMismatch` if the value does not deserialize into `T`.
+    pub fn get_as<K: AsRef<str>, T: DeserializeOwned>(&mut self, key: K) -> Result<Option<T>> {
+        match self.read_live(key.as_ref())?.and_then(Command::into_json) {
+            Some(value) => serde_json::from_value(value)
+                .map(Some)
+                .map_err(KvsError::TypeMismatch),
+            None => Ok(None),
+        }
+    }
+    /// Gets the values of many keys at once, in the order the keys are given.
+    ///
+    /// Reads are sorted by their position in the log so the I/O is sequential.
+    /// Keys that do not exist come back as `None`.
+    pub fn get_many<I: IntoIterator<Item = String>>(
+        &mut self,
+        keys: I,
+    ) -> Result<Vec<Option<String>>> {
+        let now = now_millis();
+        let mut positions = Vec::new();
+        let mut values = Vec::new();
+        for (i, key) in keys.into_iter().enumerate() {
+            match self.index.get(&key)? {
+                Some(pos) if !pos.is_expired(now) => positions.push((pos, i, key)),
+                _ => {}
+            }
+            values.push(None);
+        }
+        positions.sort_unstable();
+        for (pos, i, key) in positions {
+            values[i] = self.readers.read_value(&key, &pos)?.into_value()?;
+        }
+        Ok(values)
+    }
+    /// Returns an iterator over the key/value pairs within the given key range, in key order.
+    ///
+    /// Values are read lazily from the log as the iterator advances. The iterator
+    /// borrows the store mutably, so the store cannot be modified mid-iteration.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range start is greater than its end.
+    pub fn range<K: AsRef<str>, R: RangeBounds<K>>(&mut self, range: R) -> Scan<'_> {
+        let start = range.start_bound().map(|key| key.as_ref().to_owned());
+        let end = range.end_bound().map(|key| key.as_ref().to_owned());
+        Scan::new(&self.index, &mut self.readers, (start, end), 0)
+    }
+    /// Returns an iterator over the key/value pairs within the given key range, in reverse
+    /// key order.
+    ///
+    /// This is [`range`] walked from its end, reading values lazily in the same way.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range start is greater than its end.
+    ///
+    /// [`range`]: KvStore::range
+    pub fn range_rev<K: AsRef<str>, R: RangeBounds<K>>(&mut self, range: R) -> Rev<Scan<'_>> {
+        self.range(range).rev()
+    }
+    /// Returns an iterator over the key/value pairs whose key starts with `prefix`, in key order.
+    ///
+    /// An empty prefix matches every key. Values are read lazily as the iterator advances.
+    pub fn scan_prefix(&mut self, prefix: &str) -> Scan<'_> {
+        self.scan_stripped(prefix.to_owned(), 0)
+    }
+    /// Returns an iterator over the key/value pairs whose key starts with `prefix`,
+    /// yielding the keys without their first `strip` bytes.
+    pub(crate) fn scan_stripped(&mut self, prefix: String, strip: usize) -> Scan<'_> {
+        let end = prefix_end(&prefix);
+        let bounds = (Bound::Included(prefix), end);
+        Scan::new(&self.index, &mut self.readers, bounds, strip)
+    }
+    /// Returns an iterator over all live key/value pairs, in key order.
+    ///
+    /// Only the index is walked up front; each value is read from its log as the
+    /// iterator advances, so memory use does not grow with the size of the store.
+    /// A value that fails to read is yielded as an `Err` item and the iteration goes
+    /// on with the next key, so a partially damaged store can still be drained.
+    /// The iterator borrows the store mutably, so the store cannot be written to
+    /// until it is dropped.
+    pub fn iter(&mut self) -> Scan<'_> {
+        self.scan_prefix("")
+    }
+    /// Returns a cursor over the keys of the store, before the first key.
+    ///
+    /// The cursor can seek to any key and move from there in either direction, which
+    /// suits paging through the keys from a bookmark:
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, Result};
+    /// # fn main() -> Result<()> {
+    /// # let dir = tempfile::TempDir::new().unwrap();
+    /// let mut store = KvStore::open(dir.path())?;
+    /// for key in ["a", "b", "c", "d"] {
+    ///     store.set(key.to_owned(), key.to_uppercase())?;
+    /// }
+    /// let mut cursor = store.cursor();
+    /// let mut page = Vec::new();
+    /// // The page after the bookmark "b".
+    /// let mut valid = cursor.seek("b")? && cursor.next()?;
+    /// while valid && page.len() < 2 {
+    ///     page.push(cursor.value()?.unwrap().to_owned());
+    ///     valid = cursor.next()?;
+    /// }
+    /// assert_eq!(page, ["C", "D"]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn cursor(&mut self) -> Cursor<'_> {
+        Cursor::new(&self.index, &mut self.readers)
+    }
+    /// Sets the value of a byte key to arbitrary bytes.
+    ///
+    /// Byte keys are stored in a key space of their own, apart from string keys and
+    /// namespaces, and sort by their bytes. They are stored as strings with every byte
+    /// above `0x7F` taking two bytes, so any byte key round-trips, and the methods taking
+    /// string keys see them encoded under a reserved prefix of two NULs.
+    pub fn set_raw(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.set_bytes(encode_key(&key), value)
+    }
+    /// Gets the value of a byte key as bytes.
+    ///
+    /// Returns `None` if the given key does not exist or has expired.
+    pub fn get_raw(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get_bytes(encode_key(key))
+    }
+    /// Removes a given byte key.
+    ///
+    /// Returns `KvsError::KeyNotFound` if the given key is not found.
+    pub fn remove_raw(&mut self, key: &[u8]) -> Result<()> {
+        self.remove(encode_key(key))
+    }
+    /// Returns `true` if the store contains the given byte key.
+    pub fn contains_key_raw(&self, key: &[u8]) -> bool {
+        self.contains_key(&encode_key(key))
+    }
+    /// Returns an iterator over the byte key/value pairs within the given key range, in
+    /// the order of their keys' bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range start is greater than its end.
+    pub fn range_raw<K: AsRef<[u8]>, R: RangeBounds<K>>(&mut self, range: R) -> RawScan<'_> {
+        let start = match range.start_bound() {
+            Bound::Unbounded => Bound::Included(RAW_KEY_PREFIX.to_owned()),
+            bound => bound.map(|key| encode_key(key.as_ref())),
+        };
+        let end = match range.end_bound() {
+            Bound::Unbounded => Bound::Excluded(RAW_KEYS_END.to_owned()),
+            bound => bound.map(|key| encode_key(key.as_ref())),
+        };
+        RawScan {
+            scan: Scan::new(&self.index, &mut self.readers, (start, end), 0),
+        }
+    }
+    /// Returns an iterator over the byte key/value pairs whose key starts with `prefix`,
+    /// in the order of their keys' bytes.
+    pub fn scan_prefix_raw(&mut self, prefix: &[u8]) -> RawScan<'_> {
+        RawScan {
+            scan: self.scan_stripped(encode_key(prefix), 0),
+        }
+    }
+    /// Removes a given key.
+    ///
+    /// Returns `KvsError::KeyNotFound` if the given key is not found.
+    pub fn remove<K: AsRef<str>>(&mut self, key: K) -> Result<()> {
+        self.writer()?;
+        let key = key.as_ref();
+        match self.index.get(key)? {
+            Some(pos) if !pos.is_expired(now_millis()) => {
+                self.append_command(Command::Remove(key.to_owned()))
+            }
+            _ => Err(KvsError::KeyNotFound),
+        }
+    }
+    /// Moves the value of a key to another key, overwriting the value it has, if any.
+    ///
+    /// The value is copied from the record of `old` to a record of `new` in the log,
+    /// without going through the caller, and keeps its expiry. A tombstone of `old` is
+    /// written after it, so a crash between the two leaves the value under both keys.
+    /// Renaming a key to itself writes nothing.
+    ///
+    /// Returns `KvsError::KeyNotFound` if `old` does not exist or has expired.
+    pub fn rename(&mut self, old: String, new: String) -> Result<()> {
+        self.writer()?;
+        let pos = self.live_pos(&old)?.ok_or(KvsError::KeyNotFound)?;
+        if old == new {
+            return Ok(());
+        }
+        let reader = self.readers.reader(pos.gen)?;
+        if let Some(len) = stream_len(self.format, reader, &old, &pos)? {
+            self.copy_stream(new, &pos, len)?;
+        } else {
+            let cmd = match self.readers.read_value(&old, &pos)? {
+                Command::Set(_, value) => match pos.expires_at {
+                    Some(expires_at) => Command::SetWithExpiry(new, value, expires_at),
+                    None => Command::Set(new, value),
+                },
+                Command::SetWithExpiry(_, value, expires_at) => {
+                    Command::SetWithExpiry(new, value, expires_at)
+                }
+                Command::SetBytes(_, value) => Command::SetBytes(new, value),
+                Command::SetJson(_, value) => Command::SetJson(new, value),
+                _ => {
+                    return Err(KvsError::UnexpectedCommandType {
+                        key: old,
+                        gen: pos.gen,
+                        offset: pos.start,
+                    })
+                }
+            };
+            self.append_command(cmd)?;
+        }
+        self.append_command(Command::Remove(old))
+    }
+    /// Removes a given key, returning the value it had.
+    ///
+    /// Unlike [`remove`], this returns `None` if the given key does not exist or has
+    /// expired, writing nothing. The value is read as by [`get`], and the key is left
+    /// as it is if that fails.
+    ///
+    /// [`remove`]: KvStore::remove
+    /// [`get`]: KvStore::get
+    pub fn pop(&mut self, key: String) -> Result<Option<String>> {
+        self.writer()?;
+        let value = match self.read_live(&key)? {
+            Some(cmd) => cmd.into_value()?,
+            None => return Ok(None),
+        };
+        self.append_command(Command::Remove(key))?;
+        Ok(value)
+    }
+    /// Sets the value of a key to `new` only if its current value is `expected`.
+    ///
+    /// `expected = None` means the key must be absent, and `new = None` removes the key.
+    /// Returns whether the swap happened.
+    pub fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        if self.get(&key)? != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => self.set(key, value)?,
+            None if expected.is_some() => self.remove(key)?,
+            None => {}
+        }
+        Ok(true)
+    }
+    /// Returns the entry of a key, to update it depending on its value.
+    ///
+    /// The value is read once, when the entry is made, and every mutation through the
+    /// entry is written right away. Making an entry and doing nothing with it writes
+    /// nothing.
+    ///
+    /// ```
+    /// # fn main() -> kvs::Result<()> {
+    /// use kvs::{Entry, KvStore};
+    ///
+    /// let dir = tempfile::TempDir::new()?;
+    /// let mut store = KvStore::open(dir.path())?;
+    /// for word in ["a", "b", "a"] {
+    ///     match store.entry(word.to_owned())? {
+    ///         Entry::Occupied(mut entry) => {
+    ///             let count: u64 = entry.get().parse().unwrap();
+    ///             entry.insert((count + 1).to_string())?;
+    ///         }
+    ///         Entry::Vacant(entry) => entry.insert("1".to_owned())?,
+    ///     }
+    /// }
+    /// assert_eq!(store.get("a")?, Some("2".to_owned()));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn entry(&mut self, key: String) -> Result<Entry<'_>> {
+        let value = self.get(&key)?;
+        Ok(entry(self, key, value))
+    }
+    /// Returns the value of a key, first setting it to what `f` returns if the key does
+    /// not exist or has expired.
+    ///
+    /// `f` is only called for a missing key, and nothing is written before it returns,
+    /// so the store is left as it was if it panics.
+    pub fn get_or_insert_with<F: FnOnce() -> String>(
+        &mut self,
+        key: String,
+        f: F,
+    ) -> Result<String> {
+        self.get_or_try_insert_with(key, || Ok(f()))
+    }
+    /// Returns the value of a key, first setting it to what `f` returns if the key does
+    /// not exist or has expired, unless `f` fails.
+    ///
+    /// Like [`get_or_insert_with`], but an error of `f` is returned as it is, leaving the
+    /// key missing.
+    ///
+    /// [`get_or_insert_with`]: KvStore::get_or_insert_with
+    pub fn get_or_try_insert_with<F, E>(
+        &mut self,
+        key: String,
+        f: F,
+    ) -> std::result::Result<String, E>
+    where
+        F: FnOnce() -> std::result::Result<String, E>,
+        E: From<KvsError>,
+    {
+        self.writer()?;
+        if let Some(value) = self.get(&key)? {
+            return Ok(value);
+        }
+        let value = f()?;
+        self.set(key, value.clone())?;
+        Ok(value)
+    }
+    /// Adds `delta` to the integer value of a key, returning the new value.
+    ///
+    /// A key that does not exist or has expired counts as `0`, and a key with an
+    /// expiry keeps it. Returns `KvsError::NotAnInteger` if the value does not parse
+    /// as an `i64`, and `KvsError::IntegerOverflow` if the result would not fit one,
+    /// leaving the value as it was in both cases.
+    pub fn increment(&mut self, key: String, delta: i64) -> Result<i64> {
+        self.update_integer(key, |value| value.checked_add(delta))
+    }
+    /// Subtracts `delta` from the integer value of a key, returning the new value.
+    ///
+    /// This works like [`increment`].
+    ///
+    /// [`increment`]: KvStore::increment
+    pub fn decrement(&mut self, key: String, delta: i64) -> Result<i64> {
+        self.update_integer(key, |value| value.checked_sub(delta))
+    }
+    /// Merges `operand` into the value of a key, with the merge operator of the store.
+    ///
+    /// Only a merge record is written, without reading the value, and reads resolve
+    /// the value by giving the merge operator the value the key had and every operand
+    /// merged into it since. A compaction replaces the merge records of a key with a
+    /// record of the resolved value. While a compaction is in progress, the merged value
+    /// is resolved and written right away instead. A key that does not exist or has
+    /// expired has no value to merge into.
+    ///
+    /// Returns `KvsError::MissingMergeOperator` if no merge operator is registered.
+    pub fn merge(&mut self, key: String, operand: String) -> Result<()> {
+        let merge = self
+            .options
+            .merge_operator
+            .ok_or(KvsError::MissingMergeOperator)?;
+        self.writer()?;
+        let prev = self.live_pos(&key)?;
+        if self.compaction.is_some() || self.stepping.is_some() {
+            return self
+                .write_resolved(key, prev, |existing| merge(existing.as_deref(), &[operand]));
+        }
+        if !self.merges {
+            mark_merges(&self.folder)?;
+            self.merges = true;
+        }
+        self.append_command(Command::Merge(key, operand, prev))
+    }
+    /// Appends `suffix` to the value of a key, returning the length of the value in bytes.
+    ///
+    /// Only the suffix is written, in a record of its own, so growing a value does not
+    /// rewrite it, and reads put the value back together from the records of its
+    /// suffixes. A compaction replaces the records of a key with a record of the whole
+    /// value. While a compaction is in progress, the whole value is written right away
+    /// instead. A key that does not exist or has expired is set to the suffix.
+    pub fn append(&mut self, key: String, suffix: String) -> Result<u64> {
+        self.writer()?;
+        let prev = self.live_pos(&key)?;
+        let len = match &prev {
+            Some(pos) => self.readers.value_len(&key, pos)?,
+            None => 0,
+        } + suffix.len() as u64;
+        if self.compaction.is_some() || self.stepping.is_some() {
+            self.write_resolved(key, prev, |existing| existing.unwrap_or_default() + &suffix)?;
+        } else {
+            self.append_command(Command::Append(key, suffix, prev, len))?;
+        }
+        Ok(len)
+    }
+    /// Applies all writes in the batch atomically.
+    ///
+    /// The batch is written to the log with a single flush, and is either replayed
+    /// entirely or dropped entirely if a crash leaves it torn.
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        trace_span!("write_batch", len = batch.len());
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let mut buf = Vec::new();
+        self.format
+            .encode_checked(&Command::Batch(batch.len()), &mut buf)?;
+        let header_len = buf.len() as u64;
+        let mut spans = Vec::with_capacity(batch.len());
+        for (seq, cmd) in (self.seq + 1..).zip(&batch.commands) {
+            let start = buf.len() as u64;
+            self.format.encode_sequenced(cmd, seq, &mut buf)?;
+            spans.push((start, buf.len() as u64));
+        }
+        let writer = self.writer()?;
+        let base = writer.stream_position()?;
+        writer.write_all(&buf)?;
+        writer.flush()?;
+        self.disk_bytes += buf.len() as u64;
+        if self.unsynced.count_write(self.options.durability) {
+            self.sync_log()?;
+        }
+
+        self.uncompacted += header_len;
+        for (cmd, (start, end)) in batch.commands.into_iter().zip(spans) {
+            self.apply_write(cmd, base + start, base + end)?;
+        }
+        self.watchers.notify();
+        self.checkpoint(base + buf.len() as u64)?;
+        self.roll_if_full()?;
+        self.compact_if_due()
+    }
+    /// Takes a point-in-time view of the store, whose reads ignore the writes made after
+    /// it.
+    ///
+    /// The index is copied, so this takes time and memory in proportion to the number of
+    /// keys, and the keys of a disk-resident index are read from its file. The logs the
+    /// snapshot reads are kept until it is dropped.
+    pub fn snapshot(&mut self) -> Snapshot {
+        let now = now_millis();
+        let index = self
+            .entries()
+            .filter(|(_, pos)| !pos.is_expired(now))
+            .map(|(key, pos)| (key.into_owned(), pos))
+            .collect();
+        let mut readers = LogReaders::new(self.format, false, self.options.max_open_logs);
+        readers.merge_operator = self.options.merge_operator;
+        for gen in self.readers.gens() {
+            readers.add(&self.folder, gen);
+        }
+        Snapshot::new(&self.folder, index, readers, self.pins.clone())
+    }
+    /// Returns a guard whose writes skip the flush every write of the store does, for
+    /// bulk loads.
+    ///
+    /// The writes are flushed once when the guard is finished or dropped, and whenever
+    /// it reads or the active log fills up in between. Returns `KvsError::ReadOnly` if
+    /// the store is read-only.
+    pub fn batched(&mut self) -> Result<Batched<'_>> {
+        Batched::new(self)
+    }
+    /// Starts a transaction, whose writes are applied atomically when it commits.
+    pub fn begin(&mut self) -> Transaction<'_> {
+        Transaction::new(self)
+    }
+    /// Calls `callback` with every change to the keys starting with `prefix`, until the
+    /// returned handle is dropped.
+    ///
+    /// The callback runs on the thread writing the change, once it is written to the log
+    /// and synced if the durability mode asks for it, and applied to the index, so it
+    /// never sees a change a crash could undo at the current durability mode. Every
+    /// watcher whose prefix matches a key is called, in the order they were registered,
+    /// and a callback that panics is logged and leaves the store and the other watchers
+    /// as they are. Compactions and keys expiring send no events, and clearing the store
+    /// sends a removal for every watched key.
+    pub fn watch(
+        &mut self,
+        prefix: String,
+        callback: impl Fn(&WatchEvent) + Send + 'static,
+    ) -> WatchHandle {
+        self.watchers.add(prefix, Box::new(callback))
+    }
+    /// Returns every version of a key still in the logs, oldest first, removals included.
+    ///
+    /// History is best-effort. A compaction keeps only the latest `keep_versions`
+    /// versions of each key, so the versions older than the last compaction that copied
+    /// the key are gone, which the `compacted` flag of the versions it copied tells, and
+    /// a clear drops the history of every key. Every log is read, so this takes time in
+    /// proportion to the size of the store.
+    pub fn history(&mut self, key: &str) -> Result<Vec<VersionedValue>> {
+        history(&self.folder, &mut self.readers, key)
+    }
+    /// Returns the sequence number of the last change written to the store, 0 if there
+    /// is none.
+    ///
+    /// Every set, removal and clear is numbered in order as it is written, and keeps
+    /// its number across restarts.
+    pub fn current_seq(&self) -> u64 {
+        self.seq
+    }
+    /// Returns an iterator over the changes written after the change of sequence number
+    /// `seq`, in order, up to the current sequence number.
+    ///
+    /// A set yields the whole value of the key as of the change. Compactions drop the
+    /// changes they replace, and if one of the changes asked for is gone the iterator
+    /// yields only a `KvsError::CompactedAway` error, which names the sequence number
+    /// the changes left start after, so a reader behind it has to read the store anew
+    /// from `current_seq`. Restoring an archived generation counts as such a change.
+    /// Changes written before sequence numbers were added are not numbered and never
+    /// yielded.
+    pub fn changes_since(&mut self, seq: u64) -> Changes<'_> {
+        Changes::new(&mut self.readers, seq, self.seq)
+    }
+    /// Returns an iterator over the changes in the logs of the generations in `gens`,
+    /// in the order they were written, as commands to `apply` to another store.
+    ///
+    /// A compacted log holds a change for every key live when it was compacted, so a
+    /// store the commands of every generation are applied to ends up with the same keys
+    /// and values as this one. The current generation is in `stats`.
+    pub fn raw_commands<R: RangeBounds<u64>>(&mut self, gens: R) -> RawCommands<'_> {
+        let gens = self
+            .readers
+            .gens()
+            .filter(|gen| gens.contains(gen))
+            .collect();
+        RawCommands::new(&self.readers, gens)
+    }
+    /// Writes a command read from the logs of another store, as by `raw_commands`.
+    ///
+    /// The command is written to the log and applied to the index like the write it
+    /// stands for. Removing a key that does not exist does nothing, as the store the
+    /// command is from may have compacted differently, and merges and appends are
+    /// resolved against the value of this store, ignoring the positions they hold.
+    /// Returns `KvsError::NotAChange` for a command that only frames other records.
+    pub fn apply(&mut self, cmd: Command) -> Result<()> {
+        match cmd {
+            Command::Remove(key) => match self.remove(key) {
+                Err(KvsError::KeyNotFound) => Ok(()),
+                result => result,
+            },
+            Command::Merge(key, operand, _) => self.merge(key, operand),
+            Command::Append(key, suffix, _, _) => self.append(key, suffix).map(drop),
+            Command::Clear => self.clear(),
+            cmd @ (Command::Set(..)
+            | Command::SetWithExpiry(..)
+            | Command::SetBytes(..)
+            | Command::SetJson(..)) => self.append_command(cmd),
+            _ => Err(KvsError::NotAChange),
+        }
+    }
+    /// Writes every command of `cmds` in order, as by `apply`, stopping at the first
+    /// that fails.
+    pub fn apply_all<I: IntoIterator<Item = Command>>(&mut self, cmds: I) -> Result<()> {
+        cmds.into_iter().try_for_each(|cmd| self.apply(cmd))
+    }
+    /// Removes every key from the store at once.
+    ///
+    /// A single record marking the clear is synced to a fresh log, and every older log
+    /// is removed, or archived if old generations are retained. A crash midway leaves
+    /// either the store as it was or an empty store, and no tombstone is written per key.
+    pub fn clear(&mut self) -> Result<()> {
+        self.writer()?;
+        self.finish_compaction(true)?;
+        self.abandon_stepping();
+        trace_span!("clear");
+        let mut watched = Vec::new();
+        for prefix in self.watchers.prefixes() {
+            watched.extend(self.prefixed_keys(&prefix).map(Cow::into_owned));
+        }
+        let mut buf = Vec::new();
+        self.format
+            .encode_sequenced(&Command::Clear, self.seq + 1, &mut buf)?;
+        // Replacing the writer closes the previous active log before it is removed,
+        // which Windows requires.
+        self.cur_gen += 1;
+        let mut writer = new_log_file(&self.folder, self.cur_gen, &mut self.readers)?;
+        writer.write_all(&buf)?;
+        writer.flush()?;
+        writer.get_ref().sync_data()?;
+        self.writer = Some(writer);
+        self.dir_unsynced = true;
+        self.seq += 1;
+        self.unsynced = Unsynced::new();
+        self.index.clear()?;
+        self.cache.clear();
+        for key in watched {
+            self.watchers.queue(WatchEvent::Remove(key));
+        }
+        self.watchers.notify();
+        self.uncompacted = 0;
+        self.kept = 0;
+        // The clear record is left as the only tombstone.
+        self.stale = StaleRecords {
+            values: 0,
+            tombstones: 1,
+        };
+        self.disk_bytes = buf.len() as u64;
+        self.checkpoint(buf.len() as u64)?;
+
+        let cur_gen = self.cur_gen;
+        let stale_gens: Vec<u64> = self.readers.gens().filter(|&gen| gen < cur_gen).collect();
+        let archive = self.options.archive.is_some();
+        for stale_gen in stale_gens {
+            self.readers.remove(stale_gen);
+            self.pins.retire(&self.folder, stale_gen, archive)?;
+        }
+        if let Some(retention) = self.options.archive {
+            prune_archive(&self.folder, retention)?;
+        }
+        if self.merges {
+            unmark_merges(&self.folder)?;
+            self.merges = false;
+        }
+        Ok(())
+    }
+    /// Returns `true` if the store contains the given key.
+    ///
+    /// This is answered from the index without reading the value. A disk-resident
+    /// index that fails to read is logged, and the key counts as absent.
+    pub fn contains_key(&self, key: &str) -> bool {
+        match self.index.get(key) {
+            Ok(pos) => pos.is_some_and(|pos| !pos.is_expired(now_millis())),
+            Err(e) => {
+                self.warn_index_error(e);
+                false
+            }
+        }
+    }
+    /// Returns the number of keys in the store.
+    ///
+    /// This is counted as the index changes, less the keys that expired and are not
+    /// evicted yet, which are found through the keys ordered by expiry time, so it
+    /// takes no time in the number of keys.
+    pub fn len(&self) -> usize {
+        (self.index.len() - self.index.expired_len(now_millis())) as usize
+    }
+    /// Returns `true` if the store contains no keys.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Sets whether record checksums are verified on every read.
+    ///
+    /// Checksums are always verified when the store is opened. Verifying them on
+    /// every read as well detects corruption that happens while the store is open.
+    pub fn set_verify_checksums(&mut self, verify: bool) {
+        self.readers.verify_checksums = verify;
+    }
+    /// Sets the maximum size of a log file, 256 MiB by default.
+    ///
+    /// Once a write makes the active log reach this size, later writes go to a new
+    /// log file, so no single file grows without bound between compactions.
+    pub fn set_max_segment_size(&mut self, size: u64) {
+        self.options.max_segment_size = size;
+    }
+    /// Returns an iterator over all keys in sorted order.
+    ///
+    /// Keys are read from the index, so no log file is touched. Keys of a disk-resident
+    /// index are read from its file as the iterator advances, and a failure to read it
+    /// is logged and ends the iteration.
+    pub fn keys(&self) -> impl Iterator<Item = Cow<'_, str>> {
+        self.live_entries().map(|(key, _)| key)
+    }
+    /// Returns an iterator over all keys in reverse sorted order.
+    ///
+    /// Like [`keys`], this only reads the index.
+    ///
+    /// [`keys`]: KvStore::keys
+    pub fn keys_rev(&self) -> impl Iterator<Item = Cow<'_, str>> {
+        let now = now_millis();
+        self.index
+            .range_rev(Bound::Unbounded, Bound::Unbounded)
+            .map_while(|entry| entry.map_err(|e| self.warn_index_error(e)).ok())
+            .filter(move |(_, pos)| !pos.is_expired(now))
+            .map(|(key, _)| key)
+    }
+    /// Returns an iterator over the keys starting with `prefix` in sorted order.
+    pub(crate) fn prefixed_keys<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = Cow<'a, str>> + 'a {
+        self.prefixed_keys_after(prefix, None)
+    }
+    /// Returns an iterator over the keys starting with `prefix` in sorted order, from the
+    /// first one after `after` if it is given.
+    pub(crate) fn prefixed_keys_after<'a>(
+        &'a self,
+        prefix: &'a str,
+        after: Option<&'a str>,
+    ) -> impl Iterator<Item = Cow<'a, str>> + 'a {
+        let now = now_millis();
+        let start = match after {
+            Some(after) if after >= prefix => Bound::Excluded(after),
+            _ => Bound::Included(prefix),
+        };
+        self.index
+            .range(start, Bound::Unbounded)
+            .map_while(|entry| entry.map_err(|e| self.warn_index_error(e)).ok())
+            .take_while(move |(key, _)| key.starts_with(prefix))
+            .filter(move |(_, pos)| !pos.is_expired(now))
+            .map(|(key, _)| key)
+    }
+    /// Returns a handle on the keys of the namespace of the given name.
+    ///
+    /// Returns `KvsError::InvalidNamespace` if the name is empty or contains a NUL
+    /// character. Keys starting with a NUL are reserved for namespaces.
+    pub fn namespace(&mut self, name: &str) -> Result<Namespace<'_>> {
+        Namespace::new(self, name)
+    }
+    /// Writes every live key/value pair to `writer` as newline-delimited JSON, in key order.
+    ///
+    /// Each line is an object holding the `key`, its value under `value`, `bytes` or
+    /// `json` depending on how it was set, and its `expires_at` time if it has a TTL.
+    /// Pairs are read and written one at a time. Returns the number of pairs written.
+    pub fn export_json<W: Write>(&mut self, writer: W) -> Result<u64> {
+        let mut writer = BufWriter::new(writer);
+        let now = now_millis();
+        let mut exported = 0;
+        for entry in self.index.iter() {
+            let (key, pos) = entry?;
+            if pos.is_expired(now) {
+                continue;
+            }
+            let cmd = self.readers.read_value(&key, &pos)?;
+            if let Some(record) = ExportRecord::from_command(cmd, pos.expires_at) {
+                serde_json::to_writer(&mut writer, &record)?;
+                writer.write_all(b"\n")?;
+                exported += 1;
+            }
+        }
+        writer.flush()?;
+        Ok(exported)
+    }
+    /// Loads key/value pairs written by `export_json` from `reader`.
+    ///
+    /// Every pair is written like a `set` would, so durability and compaction apply as
+    /// usual. Existing keys are overwritten if `overwrite` is set, and skipped otherwise.
+    /// Pairs that have expired since the export are dropped. If reading fails midway,
+    /// the pairs before the failure stay imported.
+    pub fn import_json<R: Read>(&mut self, reader: R, overwrite: bool) -> Result<ImportReport> {
+        self.writer()?;
+        let mut report = ImportReport::default();
+        let records = serde_json::Deserializer::from_reader(BufReader::new(reader));
+        for record in records.into_iter::<ExportRecord>() {
+            let record = record?;
+            if record.expires_at.is_some_and(|at| at <= now_millis()) {
+                report.expired += 1;
+            } else if !overwrite && self.contains_key(&record.key) {
+                report.skipped += 1;
+            } else {
+                self.append_command(record.into_command())?;
+                report.imported += 1;
+            }
+        }
+        Ok(report)
+    }
+    /// Writes every live key with a string value and its value to `writer` as CSV rows of
+    /// two columns, in key order.
+    ///
+    /// Fields holding a comma, a double quote or a line break are quoted, so any key and
+    /// value read back the same with the default [`CsvOptions`]. Values set by
+    /// `set_bytes` or `set_as` are left out, and TTLs are not written. Pairs are read and
+    /// written one at a time. Returns the number of pairs written.
+    pub fn export_csv<W: Write>(&mut self, writer: W) -> Result<u64> {
+        let mut writer = BufWriter::new(writer);
+        let now = now_millis();
+        let mut exported = 0;
+        for entry in self.index.iter() {
+            let (key, pos) = entry?;
+            if pos.is_expired(now) {
+                continue;
+            }
+            let cmd = self.readers.read_value(&key, &pos)?;
+            if let Some(ExportRecord {
+                value: ExportValue::Value(value),
+                ..
+            }) = ExportRecord::from_command(cmd, None)
+            {
+                write_row(&mut writer, &[&key, &value], b',')?;
+                exported += 1;
+            }
+        }
+        writer.flush()?;
+        Ok(exported)
+    }
+    /// Sets a key/value pair for every row of CSV text read from `reader`, taking the
+    /// key and the value from the columns `options` gives.
+    ///
+    /// Every pair is written like a `set` would, overwriting an existing key. A row that
+    /// does not read, or has too few columns, is skipped and reported with the line it
+    /// starts on in [`ImportReport::errors`], unless `options.strict` is set, in which
+    /// case it fails the import with `KvsError::MalformedCsv`. The pairs before a failure
+    /// stay imported.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the delimiter is a double quote or a line break.
+    pub fn import_csv<R: Read>(&mut self, reader: R, options: CsvOptions) -> Result<ImportReport> {
+        assert!(
+            !matches!(options.delimiter, b'"' | b'\n' | b'\r'),
+            "the CSV delimiter cannot be a double quote or a line break"
+        );
+        self.writer()?;
+        let mut report = ImportReport::default();
+        let mut rows = CsvReader::new(BufReader::new(reader), options.delimiter);
+        let columns = options.key_column.max(options.value_column) + 1;
+        let mut header = options.has_header;
+        while let Some((line, row)) = rows.read_row()? {
+            if std::mem::take(&mut header) {
+                continue;
+            }
+            let pair = row.and_then(|mut fields| {
+                if fields.len() < columns {
+                    return Err(format!(
+                        "expected at least {} columns, found {}",
+                        columns,
+                        fields.len()
+                    ));
+                }
+                let key = fields[options.key_column].clone();
+                Ok((key, std::mem::take(&mut fields[options.value_column])))
+            });
+            match pair {
+                Ok((key, value)) => {
+                    self.append_command(Command::Set(key, value))?;
+                    report.imported += 1;
+                }
+                Err(reason) if options.strict => {
+                    return Err(KvsError::MalformedCsv { line, reason })
+                }
+                Err(reason) => report.errors.push(CsvRowError { line, reason }),
+            }
+        }
+        Ok(report)
+    }
+    /// Copies the store into the directory `dest`, which becomes a store of its own.
+    ///
+    /// The copy holds exactly the writes made before the call. Every log is copied up
+    /// to its length at the start of the call, so a background compaction going on does
+    /// not make the copy torn. Returns `KvsError::BackupNotEmpty` if `dest` exists and
+    /// is not an empty directory.
+    pub fn backup_to<P: AsRef<Path>>(&mut self, dest: P) -> Result<()> {
+        let dest = dest.as_ref();
+        if dest.exists() && (!dest.is_dir() || fs::read_dir(dest)?.next().is_some()) {
+            return Err(KvsError::BackupNotEmpty(dest.to_owned()));
+        }
+        trace_span!("backup", dest = %dest.display());
+        let mut logs = Vec::new();
+        for gen in self.readers.gens() {
+            let len = match &mut self.writer {
+                Some(writer) if gen == self.cur_gen => {
+                    writer.flush()?;
+                    writer.stream_position()?
+                }
+                _ => fs::metadata(log_path(&self.folder, gen))?.len(),
+            };
+            logs.push((gen, len));
+        }
+        create_dir_all(dest)?;
+        for (gen, len) in logs {
+            let mut copy = File::create(log_path(dest, gen))?;
+            io::copy(&mut open_log(&self.folder, gen)?.take(len), &mut copy)?;
+            copy.sync_all()?;
+            // A hint only applies to the log it was written for, which it checks itself.
+            let hint = hint_path(&self.folder, gen);
+            if hint.exists() {
+                fs::copy(hint, hint_path(dest, gen))?;
+            }
+        }
+        if self.merges {
+            mark_merges(dest)?;
+        }
+        self.format.format.save(dest)?;
+        if self.format.cipher.is_some() {
+            fs::copy(
+                self.folder.join(ENCRYPTION_FILE),
+                dest.join(ENCRYPTION_FILE),
+            )?;
+        }
+        claim_dir(dest, Engine::Kvs, false)?;
+        write_manifest(dest, &Engine::Kvs.to_string())
+    }
+    /// Checks that the store is consistent, and reports every problem found.
+    ///
+    /// The record of every index entry is read back with its checksums verified, every
+    /// log is replayed from the start into an index of its own that has to match the
+    /// index of the store, and the store directory is searched for files the store
+    /// does not use and for generations missing between the oldest log and the active
+    /// one. The store is not changed. Errors reading the index or listing the directory
+    /// fail the check, while damaged records only go into the report.
+    pub fn verify(&mut self) -> Result<VerifyReport> {
+        self.verify_with(false)
+    }
+    /// Checks that the store is consistent like `verify`, failing with
+    /// `KvsError::VerifyFailed` on the first problem found if `fail_fast` is set.
+    pub fn verify_with(&mut self, fail_fast: bool) -> Result<VerifyReport> {
+        self.finish_compaction(true)?;
+        Verifier {
+            dir: &self.folder,
+            readers: &mut self.readers,
+            index: &self.index,
+            fail_fast,
+            report: VerifyReport::default(),
+        }
+        .run()
+    }
+    /// Returns the total size of the logs of the store, including the bytes of the
+    /// active log still buffered.
+    ///
+    /// This is kept up to date as the logs are written, rolled over and compacted, so it
+    /// never touches the filesystem. The hint, index and snapshot files are not counted.
+    pub fn size_on_disk(&self) -> u64 {
+        self.disk_bytes
+    }
+    /// Returns the total length of the records in the logs a compaction keeps: the
+    /// latest records of the keys, and the older versions kept by `keep_versions`.
+    ///
+    /// This is kept up to date as the index changes, so it never reads the index. Keys
+    /// that expired count until they are evicted, so this can be more than the
+    /// `live_bytes` of [`stats`], which only counts keys that have not. The older
+    /// versions count until the next compaction, even once their key is overwritten.
+    ///
+    /// [`stats`]: KvStore::stats
+    pub fn live_size(&self) -> u64 {
+        self.index.live() + self.kept
+    }
+    /// Returns the damaged runs of records skipped when the store was opened, in the
+    /// order of the logs.
+    ///
+    /// Runs are only skipped if the store is opened with `CorruptionPolicy::Skip`, and
+    /// the list is not updated once compactions drop them.
+    pub fn skipped_regions(&self) -> &[SkippedRegion] {
+        &self.skipped
+    }
+    /// Returns statistics on the keys and disk usage of the store.
+    ///
+    /// They are counted as the index changes, so this reads no entry. Expired keys count
+    /// as stale bytes even before they are evicted; those not evicted yet are found
+    /// through the keys ordered by expiry time.
+    pub fn stats(&self) -> StoreStats {
+        let mut stats = StoreStats {
+            generations: self.readers.gens().count(),
+            current_gen: self.cur_gen,
+            live_keys: self.index.len() as usize,
+            live_bytes: self.index.live(),
+            disk_bytes: self.disk_bytes,
+            uncompacted_bytes: self.uncompacted,
+            cache_hits: self.cache.hits,
+            cache_misses: self.cache.misses,
+            cache_bytes: self.cache.bytes(),
+            key_sizes: self.index.key_sizes(),
+            value_sizes: self.index.value_sizes(),
+            namespace_keys: self.index.namespace_keys().clone(),
+        };
+        for (key, len) in self.index.expired_entries(now_millis()) {
+            stats.live_keys -= 1;
+            stats.live_bytes -= len;
+            stats.uncompacted_bytes += len;
+            if let Some(count) =
+                namespace_of(key).and_then(|name| stats.namespace_keys.get_mut(name))
+            {
+                *count -= 1;
+            }
+        }
+        stats.namespace_keys.retain(|_, count| *count > 0);
+        stats
+    }
+    /// Evicts every expired key from the index, without waiting for it to be read.
+    ///
+    /// An expired key is otherwise only evicted once it is read, or by the next
+    /// compaction. The keys are found through the keys ordered by expiry time, which
+    /// the store keeps in memory, so this does not read every entry. The records of the
+    /// keys evicted count as stale bytes towards the compaction threshold, and the next
+    /// compaction drops them. Nothing is written to the logs, so keys evicted before a
+    /// crash are evicted again.
+    pub fn purge_expired(&mut self) -> Result<PurgeReport> {
+        let mut report = PurgeReport::default();
+        for key in self.index.expired(now_millis()) {
+            if let Some(pos) = self.index.remove(&key)? {
+                self.cache.remove(&key);
+                self.count_expired(&pos);
+                report.purged_keys += 1;
+                report.purged_bytes += pos.len();
+            }
+        }
+        Ok(report)
+    }
+    /// Clears stale entries in the log by copying every live record into a new generation.
+    ///
+    /// Returns a report of what the compaction did, which is also kept for
+    /// [`last_compaction`]. This is a no-op returning an empty report if nothing has
+    /// been overwritten or removed since the last compaction. A background compaction
+    /// in progress is waited for first, and an incremental compaction in progress is
+    /// finished.
+    ///
+    /// [`last_compaction`]: KvStore::last_compaction
+    pub fn compact(&mut self) -> Result<CompactionReport> {
+        trace_span!("compact", reclaimed = tracing::field::Empty);
+        loop {
+            if let Some(report) = self.step_compaction(u64::MAX)? {
+                trace_record!("reclaimed", report.reclaimed_bytes);
+                return Ok(report);
+            }
+        }
+    }
+    /// Takes a step of an incremental compaction, copying live records into the
+    /// compacted log until `budget` bytes are copied.
+    ///
+    /// The first step starts a compaction, and every later one picks up where the
+    /// previous one left off, so the work can be spread out between other operations.
+    /// At least one record is copied per step. Returns whether work is left, and
+    /// `false` once the compaction is done or if there is nothing to compact.
+    ///
+    /// Writes between steps go to a newer log than the compacted one, so they win
+    /// over its copies. Automatic compaction does not start while a compaction is in
+    /// progress, and closing or crashing midway leaves the compacted log as just
+    /// another log, which the next compaction starts over from.
+    pub fn compact_step(&mut self, budget: u64) -> Result<bool> {
+        Ok(self.step_compaction(budget)?.is_none())
+    }
+    /// Returns the report of the last compaction that did anything, whether it was run
+    /// by [`compact`], in steps or automatically.
+    ///
+    /// [`compact`]: KvStore::compact
+    pub fn last_compaction(&self) -> Option<&CompactionReport> {
+        self.last_compaction.as_ref()
+    }
+    /// Returns the number of compactions that finished since the store was opened,
+    /// whether run by [`compact`], in steps or automatically.
+    ///
+    /// [`compact`]: KvStore::compact
+    pub fn compactions(&self) -> u64 {
+        self.compactions
+    }
+    /// Takes a step of an incremental compaction, starting one if none is in progress.
+    ///
+    /// Returns the report of the compaction once it is done, and `None` if work is left.
+    fn step_compaction(&mut self, budget: u64) -> Result<Option<CompactionReport>> {
+        let step_start = Instant::now();
+        self.writer()?;
+        self.finish_compaction(true)?;
+        let started = self.stepping.is_none();
+        if started {
+            let gen = match self.begin_compaction()? {
+                Some(gen) => gen,
+                None => return Ok(Some(CompactionReport::default())),
+            };
+            let kept = self.kept_versions(gen)?;
+            self.stepping = Some(Stepping {
+                log: CompactionLog::create(&self.folder, gen)?,
+                resume: None,
+                // Every stale record so far is in the logs the compacted one replaces.
+                report: CompactionReport {
+                    stale_records: self.stale.values,
+                    tombstones: self.stale.tombstones,
+                    ..CompactionReport::default()
+                },
+                kept,
+                kept_bytes: 0,
+            });
+            // The index points into the compacted log from the first step on.
+            self.readers.add(&self.folder, gen);
+            self.readers.set_writing(Some(gen));
+        }
+        let offset = self.writer()?.stream_position()?;
+        let at = self.checkpoint_at(offset);
+        let state = self.stepping.as_mut().unwrap();
+        let start = state
+            .resume
+            .as_deref()
+            .map_or(Bound::Unbounded, Bound::Excluded);
+        let mut copier = RecordCopier::new(
+            &mut state.log,
+            &mut self.readers,
+            self.format,
+            self.options.compresses(),
+        )
+        .keeping(&state.kept);
+        if started {
+            copier.copy_removed()?;
+        }
+        let resume = self.index.relocate(at, start, budget, &mut copier)?;
+        let (copied, moved, records) = (copier.copied(), copier.moved(), copier.records());
+        let kept_bytes = copier.kept_bytes();
+        state.resume = resume;
+        state.report.bytes_read += moved;
+        state.report.bytes_written += copied + kept_bytes;
+        state.report.live_records += records;
+        state.kept_bytes += kept_bytes;
+        // The records copied out are stale until the logs they were in are removed.
+        self.uncompacted += moved;
+        self.stale.values += records;
+        self.disk_bytes += copied + kept_bytes;
+        if state.resume.is_some() {
+            state.report.duration += step_start.elapsed();
+            self.persist_checkpoint(offset)?;
+            return Ok(None);
+        }
+
+        let state = self.stepping.take().unwrap();
+        let gen = state.log.gen;
+        let mut report = state.report;
+        self.readers.set_writing(None);
+        // A disk-resident index takes the place of the hint, whose entries would all
+        // have to fit in memory. The compacted log only holds everything written before
+        // it if no write came in between steps.
+        let hint_len = if self.options.disk_index || !started {
+            0
+        } else {
+            let entries = self
+                .index
+                .iter()
+                .map(|entry| {
+                    entry.map(|(key, pos)| (key.into_owned(), pos.start, pos.end, pos.expires_at))
+                })
+                .collect::<Result<_>>()?;
+            write_hint(&self.folder, gen, entries, self.seq, state.kept_bytes)?
+        };
+        if started {
+            self.uncompacted = 0;
+        } else {
+            // Everything left in the logs the compacted one replaces is stale.
+            let mut replaced = 0;
+            for stale_gen in self.readers.gens().filter(|&stale_gen| stale_gen < gen) {
+                replaced += fs::metadata(log_path(&self.folder, stale_gen))?.len();
+            }
+            self.uncompacted = self.uncompacted.saturating_sub(replaced);
+        }
+        self.stale -= StaleRecords {
+            values: report.stale_records + report.live_records,
+            tombstones: report.tombstones,
+        };
+        self.kept = state.kept_bytes;
+        let archive = self.options.archive.is_some();
+        let (reclaimed, removed_gens) =
+            self.replace_stale_gens(gen, hint_len, archive, state.log.offset)?;
+        self.persist_checkpoint(offset)?;
+        report.bytes_written += hint_len;
+        report.reclaimed_bytes = reclaimed;
+        report.removed_gens = removed_gens;
+        report.duration += step_start.elapsed();
+        self.last_compaction = Some(report.clone());
+        self.compactions += 1;
+        Ok(Some(report))
+    }
+    /// Counts a value evicted from the index because it has expired as stale.
+    fn count_expired(&mut self, pos: &CommandPos) {
+        self.uncompacted += pos.len();
+        self.stale.values += 1;
+        self.count_replaced(pos);
+    }
+    /// Counts a value that was replaced or has expired towards the incremental
+    /// compaction in progress, if it is in a log the compaction replaces.
+    ///
+    /// Such a value was not copied yet and now never is, so it is dropped along with
+    /// its log.
+    fn count_replaced(&mut self, pos: &CommandPos) {
+        if let Some(state) = &mut self.stepping {
+            if pos.gen < state.log.gen {
+                state.report.stale_records += 1;
+            }
+        }
+    }
+    /// Leaves an incremental compaction in progress unfinished, its log becoming just
+    /// another log.
+    fn abandon_stepping(&mut self) {
+        if self.stepping.take().is_some() {
+            self.readers.set_writing(None);
+        }
+    }
+    /// Replaces the state of the store with its state as of the end of generation `target`.
+    fn restore(&mut self, target: u64) -> Result<()> {
+        self.writer()?;
+        self.finish_compaction(true)?;
+        self.abandon_stepping();
+        trace_span!("restore", target);
+        let archive = archive_dir(&self.folder);
+        let mut dirs: BTreeMap<u64, &Path> = archived_gens(&self.folder)?
+            .into_iter()
+            .map(|gen| (gen, archive.as_path()))
+            .collect();
+        for gen in self.readers.gens() {
+            dirs.insert(gen, &self.folder);
+        }
+        if !dirs.contains_key(&target) {
+            return Err(KvsError::GenerationNotFound(target));
+        }
+        // A compacted log, which has a hint, holds everything written before it.
+        let start = dirs
+            .range(..=target)
+            .rev()
+            .find(|&(&gen, dir)| hint_path(dir, gen).exists())
+            .or_else(|| dirs.iter().next())
+            .map(|(&gen, _)| gen)
+            .unwrap();
+        if let Some(missing) = (start..=target).find(|gen| !dirs.contains_key(gen)) {
+            return Err(KvsError::GenerationNotFound(missing));
+        }
+
+        let mut index = BTreeMap::new();
+        let mut files = LogReaders::new(self.format, false, self.options.max_open_logs);
+        files.merge_operator = self.options.merge_operator;
+        for gen in start..=target {
+            let dir = dirs[&gen];
+            let mut reader = BufReader::new(open_log(dir, gen)?);
+            let mut at = Checkpoint {
+                gen,
+                ..Checkpoint::default()
+            };
+            load(
+                dir,
+                self.format,
+                &mut reader,
+                &mut index,
+                TornTail::Corrupt,
+                &mut at,
+            )?;
+            files.add(dir, gen);
+        }
+        let now = now_millis();
+        index.retain(|_, pos| !pos.is_expired(now));
+
+        let restored_gen = self.cur_gen + 1;
+        self.cur_gen += 2;
+        self.writer = Some(new_log_file(&self.folder, self.cur_gen, &mut self.readers)?);
+        self.dir_unsynced = true;
+        let (copies, _) = copy_records(
+            &self.folder,
+            restored_gen,
+            self.format,
+            self.options.compresses(),
+            &mut files,
+            index.iter(),
+            None,
+        )?;
+        drop(files);
+        for (pos, copy) in index.values_mut().zip(copies) {
+            *pos = copy;
+        }
+        let entries = index
+            .iter()
+            .map(|(key, pos)| (key.clone(), pos.start, pos.end, pos.expires_at))
+            .collect();
+        // The restored keys change without a record in the change feed, which readers
+        // behind it find compacted away.
+        self.seq += 1;
+        let hint_len = write_hint(&self.folder, restored_gen, entries, self.seq, 0)?;
+        self.index.clear()?;
+        self.cache.clear();
+        for (key, pos) in index {
+            self.index.insert(key, pos)?;
+        }
+        self.uncompacted = 0;
+        self.kept = 0;
+        self.stale = StaleRecords::default();
+        self.checkpoint(0)?;
+        self.replace_stale_gens(restored_gen, hint_len, true, 0)?;
+        Ok(())
+    }
+    /// Purges expired keys and switches to a fresh active log for a compaction.
+    ///
+    /// Returns the generation to write the compacted log to, or `None` if there is
+    /// nothing to compact.
+    fn begin_compaction(&mut self) -> Result<Option<u64>> {
+        let now = now_millis();
+        let (uncompacted, stale) = (&mut self.uncompacted, &mut self.stale);
+        self.index.retain(&mut |_, pos| {
+            if pos.is_expired(now) {
+                *uncompacted += pos.len();
+                stale.values += 1;
+            }
+            !pos.is_expired(now)
+        })?;
+        if self.uncompacted == 0 {
+            return Ok(None);
+        }
+        let compaction_gen = self.cur_gen + 1;
+        // Switch to a fresh active log first, so the previous one is no longer
+        // written to by the time its live records are copied out and it is removed.
+        self.cur_gen += 2;
+        self.writer = Some(new_log_file(&self.folder, self.cur_gen, &mut self.readers)?);
+        self.dir_unsynced = true;
+        Ok(Some(compaction_gen))
+    }
+    /// Finds the older versions of keys a compaction into generation `gen` keeps.
+    fn kept_versions(&mut self, gen: u64) -> Result<KeptVersions> {
+        if !self.options.keeps_versions() {
+            return Ok(KeptVersions::new());
+        }
+        let (keep, keep_deleted) = (
+            self.options.keep_versions,
+            self.options.keep_deleted_versions,
+        );
+        let index = &self.index;
+        kept_versions(&mut self.readers, gen, keep, keep_deleted, |key| {
+            index.get(key)
+        })
+    }
+    /// Starts a compaction on a background thread, unless one is already running.
+    ///
+    /// The thread copies the live records into the compacted log through its own
+    /// file handles while writes go on to the fresh active log. The store only
+    /// picks up the result in `finish_compaction`, so the logs it reads from are
+    /// never removed under it.
+    fn spawn_compaction(&mut self) -> Result<()> {
+        if self.compaction.is_some() {
+            return Ok(());
+        }
+        let start = Instant::now();
+        let compaction_gen = match self.begin_compaction()? {
+            Some(gen) => gen,
+            None => return Ok(()),
+        };
+        let (uncompacted, stale) = (self.uncompacted, self.stale);
+        let entries: Vec<(String, CommandPos)> = self
+            .index
+            .iter()
+            .map(|entry| entry.map(|(key, pos)| (key.into_owned(), pos)))
+            .collect::<Result<_>>()?;
+        let gens: Vec<u64> = self
+            .readers
+            .gens()
+            .filter(|&gen| gen < compaction_gen)
+            .collect();
+        let dir = self.folder.clone();
+        let format = self.format;
+        let seq = self.seq;
+        let compress = self.options.compresses();
+        let max_open = self.options.max_open_logs;
+        let merge_operator = self.options.merge_operator;
+        let options = self.options;
+        let handle = thread::spawn(move || {
+            let mut files = LogReaders::new(format, false, max_open);
+            files.merge_operator = merge_operator;
+            for gen in gens {
+                files.add(&dir, gen);
+            }
+            let kept = if options.keeps_versions() {
+                let live = |key: &str| {
+                    let found = entries.binary_search_by(|(entry, _)| entry.as_str().cmp(key));
+                    Ok(found.ok().map(|i| entries[i].1))
+                };
+                let (keep, keep_deleted) = (options.keep_versions, options.keep_deleted_versions);
+                Some(kept_versions(
+                    &mut files,
+                    compaction_gen,
+                    keep,
+                    keep_deleted,
+                    live,
+                )?)
+            } else {
+                None
+            };
+            let (copies, kept_bytes) = copy_records(
+                &dir,
+                compaction_gen,
+                format,
+                compress,
+                &mut files,
+                entries.iter().map(|(key, pos)| (key, pos)),
+                kept.as_ref(),
+            )?;
+            let hint = entries
+                .iter()
+                .zip(&copies)
+                .map(|((key, _), copy)| (key.clone(), copy.start, copy.end, copy.expires_at))
+                .collect();
+            let hint_len = write_hint(&dir, compaction_gen, hint, seq, kept_bytes)?;
+            let moved = entries
+                .into_iter()
+                .zip(copies)
+                .map(|((key, pos), copy)| (key, pos, copy))
+                .collect();
+            Ok((moved, hint_len, kept_bytes, start.elapsed()))
+        });
+        self.compaction = Some(Compaction {
+            gen: compaction_gen,
+            uncompacted,
+            stale,
+            handle,
+        });
+        Ok(())
+    }
+    /// Applies the result of a background compaction once it is done.
+    ///
+    /// Waits for the compaction to be done if `wait` is set, and returns right away otherwise.
+    fn finish_compaction(&mut self, wait: bool) -> Result<()> {
+        match &self.compaction {
+            Some(compaction) if wait || compaction.handle.is_finished() => {}
+            _ => return Ok(()),
+        }
+        let compaction = self.compaction.take().unwrap();
+        trace_span!(
+            "compact",
+            background = true,
+            reclaimed = tracing::field::Empty
+        );
+        let result = match compaction.handle.join() {
+            Ok(result) => result,
+            Err(_) => Err(KvsError::CompactionPanicked(compaction.gen)),
+        };
+        let finish_start = Instant::now();
+        let (moved, hint_len, kept_bytes, duration) = match result {
+            Ok(result) => result,
+            Err(e) => {
+                // Leave no partial log behind, which would be taken for a corrupted one.
+                let path = log_path(&self.folder, compaction.gen);
+                if path.exists() {
+                    fs::remove_file(path)?;
+                }
+                remove_hint(&self.folder, compaction.gen)?;
+                return Err(e);
+            }
+        };
+        let mut report = CompactionReport {
+            bytes_written: hint_len + kept_bytes,
+            live_records: moved.len() as u64,
+            stale_records: compaction.stale.values,
+            tombstones: compaction.stale.tombstones,
+            ..CompactionReport::default()
+        };
+        // Keys written since the compaction started already point past the compacted log,
+        // and their records in the logs it replaces went stale in the meantime.
+        for (key, pos, copy) in moved {
+            report.bytes_read += pos.len();
+            report.bytes_written += copy.len();
+            if self.index.get(&key)? == Some(pos) {
+                self.index.insert(key, copy)?;
+            } else {
+                report.stale_records += 1;
+            }
+        }
+        self.uncompacted = self.uncompacted.saturating_sub(compaction.uncompacted);
+        self.stale -= compaction.stale;
+        self.kept = kept_bytes;
+        let archive = self.options.archive.is_some();
+        let (reclaimed, removed_gens) =
+            self.replace_stale_gens(compaction.gen, hint_len, archive, 0)?;
+        trace_record!("reclaimed", reclaimed);
+        report.reclaimed_bytes = reclaimed;
+        report.removed_gens = removed_gens;
+        report.duration = duration + finish_start.elapsed();
+        self.last_compaction = Some(report);
+        self.compactions += 1;
+        Ok(())
+    }
+    /// Registers the compacted log and removes the generations it replaces, or moves
+    /// them to the archive if `archive` is set.
+    ///
+    /// The first `counted` bytes of the compacted log are already counted in the size of
+    /// the logs. Returns the number of bytes reclaimed in the store directory and the
+    /// generations replaced.
+    fn replace_stale_gens(
+        &mut self,
+        compaction_gen: u64,
+        hint_len: u64,
+        archive: bool,
+        counted: u64,
+    ) -> Result<(u64, Vec<u64>)> {
+        let compacted_len = fs::metadata(log_path(&self.folder, compaction_gen))?.len();
+        self.readers.add(&self.folder, compaction_gen);
+        let stale_gens: Vec<u64> = self
+            .readers
+            .gens()
+            .filter(|&gen| gen < compaction_gen)
+            .collect();
+        self.disk_bytes += compacted_len - counted;
+        let mut stale_bytes = 0;
+        for &stale_gen in &stale_gens {
+            // Close the reader before removing the file, which Windows requires.
+            self.readers.remove(stale_gen);
+            let (log_len, stale_hint_len) = self.pins.retire(&self.folder, stale_gen, archive)?;
+            trace_debug!(
+                gen = stale_gen,
+                bytes = log_len,
+                archive,
+                "retired a stale log"
+            );
+            self.disk_bytes -= log_len;
+            stale_bytes += log_len + stale_hint_len;
+        }
+        if let Some(retention) = self.options.archive {
+            prune_archive(&self.folder, retention)?;
+        }
+        // Merges are resolved instead of written while compacting, so none are left.
+        if self.merges {
+            unmark_merges(&self.folder)?;
+            self.merges = false;
+        }
+        Ok((
+            stale_bytes.saturating_sub(compacted_len + hint_len),
+            stale_gens,
+        ))
+    }
+    /// Returns the position of a key, evicting it if it has expired.
+    fn live_pos(&mut self, key: &str) -> Result<Option<CommandPos>> {
+        match self.index.get(key)? {
+            Some(pos) if pos.is_expired(now_millis()) => {
+                self.index.remove(key)?;
+                self.count_expired(&pos);
+                Ok(None)
+            }
+            pos => Ok(pos),
+        }
+    }
+    /// Sets the value of a key at the position `prev` to what `update` returns for it,
+    /// keeping its expiry, in place of a merge or append record.
+    ///
+    /// A compaction only copies the records of the logs it replaces, so a merge or append
+    /// record written while it is in progress could outlive the records before it.
+    fn write_resolved(
+        &mut self,
+        key: String,
+        prev: Option<CommandPos>,
+        update: impl FnOnce(Option<String>) -> String,
+    ) -> Result<()> {
+        let existing = match &prev {
+            Some(pos) => {
+                let mut value = Vec::new();
+                self.readers.copy_value(&key, pos, &mut value)?;
+                Some(String::from_utf8(value)?)
+            }
+            None => None,
+        };
+        let value = update(existing);
+        self.append_command(match prev.and_then(|prev| prev.expires_at) {
+            Some(expires_at) => Command::SetWithExpiry(key, value, expires_at),
+            None => Command::Set(key, value),
+        })
+    }
+    /// Sets the integer value of a key to what `update` returns for it, counting a key
+    /// that does not exist as `0`, and returns the new value.
+    fn update_integer(
+        &mut self,
+        key: String,
+        update: impl FnOnce(i64) -> Option<i64>,
+    ) -> Result<i64> {
+        self.writer()?;
+        let current = match self.get(&key)? {
+            Some(value) => value
+                .parse::<i64>()
+                .map_err(|_| KvsError::NotAnInteger { key: key.clone() })?,
+            None => 0,
+        };
+        let value = match update(current) {
+            Some(value) => value,
+            None => return Err(KvsError::IntegerOverflow { key }),
+        };
+        let expires_at = self.index.get(&key)?.and_then(|pos| pos.expires_at);
+        self.append_command(match expires_at {
+            Some(expires_at) => Command::SetWithExpiry(key, value.to_string(), expires_at),
+            None => Command::Set(key, value.to_string()),
+        })?;
+        Ok(value)
+    }
+    /// Applies a streamed value written to the active log from `before` on, once all of
+    /// its chunks are.
+    fn finish_stream(&mut self, key: String, len: u64, before: u64) -> Result<()> {
+        let writer = self.writer()?;
+        writer.flush()?;
+        let after = writer.stream_position()?;
+        self.disk_bytes += after - before;
+        if self.unsynced.count_write(self.options.durability) {
+            self.sync_log()?;
+        }
+        self.apply_write(Command::SetStream(key, len), before, after)?;
+        self.watchers.notify();
+        self.checkpoint(after)?;
+        self.roll_if_full()?;
+        self.compact_if_due()
+    }
+    /// Sets the value of `new` to the streamed value at the given position.
+    ///
+    /// The chunks are copied as they are, as only the header holds the key.
+    fn copy_stream(&mut self, new: String, pos: &CommandPos, len: u64) -> Result<()> {
+        let chunks_start = pos.start + self.readers.header_len(pos)?;
+        let mut header = Vec::new();
+        self.format.encode_sequenced(
+            &Command::SetStream(new.clone(), len),
+            self.seq + 1,
+            &mut header,
+        )?;
+        let writer = self.writer.as_mut().ok_or(KvsError::ReadOnly)?;
+        let before = writer.stream_position()?;
+        writer.write_all(&header)?;
+        let copied = self.readers.reader(pos.gen).and_then(|reader| {
+            reader
+                .seek(SeekFrom::Start(chunks_start))
+                .map_err(at_record(pos.gen, pos.start))?;
+            Ok(io::copy(
+                &mut Read::take(reader, pos.end - chunks_start),
+                writer,
+            )?)
+        });
+        if copied
+            .as_ref()
+            .map_or(true, |&copied| copied < pos.end - chunks_start)
+        {
+            // Leave no part of the value behind, which would be taken for a torn write.
+            writer.flush()?;
+            writer.get_ref().set_len(before)?;
+            writer.seek(SeekFrom::End(0))?;
+            let eof = io::Error::from(io::ErrorKind::UnexpectedEof);
+            return Err(copied
+                .err()
+                .unwrap_or_else(|| at_record(pos.gen, pos.start)(eof)));
+        }
+        self.finish_stream(new, len, before)
+    }
+    /// Returns the writer of the active log.
+    ///
+    /// Returns `KvsError::ReadOnly` if the store is read-only.
+    pub(crate) fn writer(&mut self) -> Result<&mut BufWriter<File>> {
+        self.writer.as_mut().ok_or(KvsError::ReadOnly)
+    }
+    /// Appends a command to the active log and applies it to the index.
+    fn append_command(&mut self, cmd: Command) -> Result<()> {
+        let mut buf = Vec::new();
+        self.format.encode_sequenced(&cmd, self.seq + 1, &mut buf)?;
+        let writer = self.writer()?;
+        let before = writer.stream_position()?;
+        writer.write_all(&buf)?;
+        writer.flush()?;
+        self.disk_bytes += buf.len() as u64;
+        if self.unsynced.count_write(self.options.durability) {
+            self.sync_log()?;
+        }
+        let after = before + buf.len() as u64;
+        self.apply_write(cmd, before, after)?;
+        self.watchers.notify();
+        self.checkpoint(after)?;
+        self.roll_if_full()?;
+        self.compact_if_due()
+    }
+    /// Appends a command to the active log from `before` on without flushing it, and
+    /// applies it to the index, returning where the active log ends after it.
+    ///
+    /// The events of watchers stay queued until the log is flushed, which it is before
+    /// it rolls over or is compacted.
+    pub(crate) fn append_deferred(&mut self, cmd: Command, before: u64) -> Result<u64> {
+        let mut buf = Vec::new();
+        self.format.encode_sequenced(&cmd, self.seq + 1, &mut buf)?;
+        self.writer()?.write_all(&buf)?;
+        self.disk_bytes += buf.len() as u64;
+        if self.unsynced.count_write(self.options.durability) {
+            self.writer()?.flush()?;
+            self.sync_log()?;
+        }
+        let after = before + buf.len() as u64;
+        self.apply_write(cmd, before, after)?;
+        self.checkpoint(after)?;
+        if after >= self.options.max_segment_size
+            || self.uncompacted > self.options.compaction_threshold
+        {
+            self.flush_deferred()?;
+            self.roll_if_full()?;
+            self.compact_if_due()?;
+            return Ok(self.writer()?.stream_position()?);
+        }
+        Ok(after)
+    }
+    /// Flushes the writes left buffered by `append_deferred`, and sends their events to
+    /// the watchers.
+    pub(crate) fn flush_deferred(&mut self) -> Result<()> {
+        self.flush()?;
+        self.watchers.notify();
+        Ok(())
+    }
+    /// Applies a command written to the active log between `start` and `end` to the
+    /// index, dropping the cached value of its key.
+    fn apply_write(&mut self, cmd: Command, start: u64, end: u64) -> Result<()> {
+        let key = match &cmd {
+            Command::Remove(key) => Some(key.as_str()),
+            cmd => cmd.key(),
+        };
+        if let Some(key) = key {
+            self.cache.remove(key);
+        }
+        // The key of the event if it is watched, with its value if the command holds it.
+        let event = key
+            .filter(|key| self.watchers.watches(key))
+            .map(|key| (key.to_owned(), cmd.clone().into_bytes()));
+        let removal = matches!(cmd, Command::Remove(_));
+        if cmd.is_change() {
+            self.seq += 1;
+        }
+        let stale = apply(&mut self.index, cmd, (self.cur_gen, start, end))?;
+        self.uncompacted += stale.bytes;
+        self.stale += stale.records;
+        let removed = stale.replaced.is_some();
+        if let Some(replaced) = stale.replaced {
+            self.count_replaced(&replaced);
+        }
+        match event {
+            Some((key, _)) if removal && removed => self.watchers.queue(WatchEvent::Remove(key)),
+            // Removing a key that does not exist changes nothing.
+            Some(_) if removal => {}
+            Some((key, value)) => {
+                let value = match value {
+                    Some(value) => value,
+                    // Merged, appended and streamed values are read back whole. The write
+                    // is applied already, so a failed read only loses the event.
+                    None => {
+                        let pos = CommandPos {
+                            gen: self.cur_gen,
+                            start,
+                            end,
+                            expires_at: None,
+                        };
+                        let mut value = Vec::new();
+                        if let Err(e) = self.readers.copy_value(&key, &pos, &mut value) {
+                            warn!("Failed to read the value of watched key {}: {}", key, e);
+                            return Ok(());
+                        }
+                        value
+                    }
+                };
+                let value = String::from_utf8_lossy(&value).into_owned();
+                self.watchers.queue(WatchEvent::Set(key, value));
+            }
+            None => {}
+        }
+        Ok(())
+    }
+    /// Appends a `Set` record for every pair, flushing only when a log fills up and at the end.
+    ///
+    /// Each filled log and the whole load count as one write towards the durability mode.
+    fn set_pairs<I: IntoIterator<Item = (String, String)>>(&mut self, pairs: I) -> Result<()> {
+        // The position is tracked here, asking the writer for it would flush its buffer.
+        let mut pos = self.writer()?.stream_position()?;
+        let mut buf = Vec::new();
+        for (key, value) in pairs {
+            let cmd = Command::Set(key, value);
+            buf.clear();
+            self.format.encode_sequenced(&cmd, self.seq + 1, &mut buf)?;
+            self.writer()?.write_all(&buf)?;
+            self.disk_bytes += buf.len() as u64;
+            let end = pos + buf.len() as u64;
+            self.apply_write(cmd, pos, end)?;
+            self.checkpoint(end)?;
+            pos = end;
+            if pos >= self.options.max_segment_size {
+                self.flush_pairs()?;
+                self.roll_if_full()?;
+                pos = self.writer()?.stream_position()?;
+            }
+        }
+        self.flush_pairs()?;
+        self.compact_if_due()
+    }
+    /// Loads the pairs of a bulk load into a new generation, which then replaces every
+    /// log of the empty store.
+    pub(crate) fn load_pairs<I>(&mut self, pairs: I, duplicates: Duplicates) -> Result<()>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let active_end = self.writer()?.stream_position()?;
+        self.finish_compaction(true)?;
+        self.abandon_stepping();
+        trace_span!("bulk_load");
+        if !self.is_empty() {
+            return Err(KvsError::StoreNotEmpty(self.folder.clone()));
+        }
+        // Only expired keys are left, which the logs replaced take with them.
+        self.index.clear()?;
+        self.cache.clear();
+        // The log is the newest one while it is written, so a crash midway leaves a torn
+        // tail that the next open drops.
+        let loaded_gen = self.cur_gen + 1;
+        let mut log = create_log(&self.folder, loaded_gen)?;
+        let written = self.write_pairs(&mut log, loaded_gen, active_end, pairs, duplicates);
+        let (stale_bytes, stale) = match written {
+            Ok(stale) => stale,
+            Err(e) => {
+                drop(log);
+                self.index.clear()?;
+                fs::remove_file(log_path(&self.folder, loaded_gen))?;
+                return Err(e);
+            }
+        };
+        drop(log);
+
+        self.cur_gen += 2;
+        self.writer = Some(new_log_file(&self.folder, self.cur_gen, &mut self.readers)?);
+        self.dir_unsynced = true;
+        // The keys loaded change without a record in the change feed, which readers
+        // behind it find compacted away.
+        self.seq += 1;
+        let hint_len = if self.options.disk_index {
+            0
+        } else {
+            let entries = self
+                .index
+                .iter()
+                .map(|entry| entry.map(|(key, pos)| (key.into_owned(), pos.start, pos.end, None)))
+                .collect::<Result<_>>()?;
+            write_hint(&self.folder, loaded_gen, entries, self.seq, 0)?
+        };
+        self.uncompacted = stale_bytes;
+        self.kept = 0;
+        self.stale = stale;
+        self.persist_checkpoint(0)?;
+        let archive = self.options.archive.is_some();
+        self.replace_stale_gens(loaded_gen, hint_len, archive, 0)?;
+        Ok(())
+    }
+    /// Writes the pairs of a bulk load to the log of generation `gen`, inserting them
+    /// into the index, and syncs it.
+    ///
+    /// Returns the bytes and records of the values replaced by later ones of their key.
+    fn write_pairs<I>(
+        &mut self,
+        log: &mut BufWriter<File>,
+        gen: u64,
+        active_end: u64,
+        pairs: I,
+        duplicates: Duplicates,
+    ) -> Result<(u64, StaleRecords)>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let compress = self.options.compresses();
+        let (mut stale_bytes, mut stale) = (0, StaleRecords::default());
+        let mut offset = 0;
+        let mut buf = Vec::new();
+        for (key, value) in pairs {
+            if duplicates == Duplicates::Reject && self.index.get(&key)?.is_some() {
+                return Err(KvsError::DuplicateKey(key));
+            }
+            let cmd = Command::Set(key, value);
+            buf.clear();
+            if compress {
+                self.format.encode_compressed(&cmd, &mut buf)?;
+            } else {
+                self.format.encode_checked(&cmd, &mut buf)?;
+            }
+            log.write_all(&buf)?;
+            let pos = CommandPos {
+                gen,
+                start: offset,
+                end: offset + buf.len() as u64,
+                expires_at: None,
+            };
+            offset = pos.end;
+            let key = match cmd {
+                Command::Set(key, _) => key,
+                cmd => {
+                    return Err(KvsError::UnexpectedCommandType {
+                        key: cmd.key().unwrap_or_default().to_owned(),
+                        gen,
+                        offset: pos.start,
+                    })
+                }
+            };
+            if let Some(replaced) = self.index.insert(key, pos)? {
+                stale_bytes += replaced.len();
+                stale.values += 1;
+            }
+            // The index reflects no more of the active log, which it still is.
+            self.checkpoint(active_end)?;
+        }
+        log.flush()?;
+        log.get_ref().sync_all()?;
+        Ok((stale_bytes, stale))
+    }
+    /// Flushes the records written by `set_pairs`, syncing them if the durability mode asks for it.
+    fn flush_pairs(&mut self) -> Result<()> {
+        self.writer()?.flush()?;
+        if self.unsynced.count_write(self.options.durability) {
+            self.sync_log()?;
+        }
+        self.watchers.notify();
+        Ok(())
+    }
+    /// Compacts the logs once the stale bytes exceed the compaction threshold.
+    fn compact_if_due(&mut self) -> Result<()> {
+        self.finish_compaction(false)?;
+        if self.uncompacted > self.options.compaction_threshold && self.stepping.is_none() {
+            if self.options.background_compaction && !self.options.disk_index {
+                self.spawn_compaction()?;
+            } else {
+                self.compact()?;
+            }
+        }
+        Ok(())
+    }
+    /// Syncs the active log to disk.
+    fn sync_log(&mut self) -> Result<()> {
+        self.writer()?.get_ref().sync_data()?;
+        self.unsynced = Unsynced::new();
+        Ok(())
+    }
+    /// Records in the index that it reflects the active log up to `offset`.
+    fn checkpoint(&mut self, offset: u64) -> Result<()> {
+        self.index.checkpoint(self.checkpoint_at(offset))
+    }
+    /// Records in the index that it reflects the active log up to `offset`, making it
+    /// last through a crash.
+    ///
+    /// This is done after every step of a compaction, which changes what is stale in
+    /// logs the index does not replay.
+    fn persist_checkpoint(&mut self, offset: u64) -> Result<()> {
+        self.index.persist(self.checkpoint_at(offset))
+    }
+    fn checkpoint_at(&self, offset: u64) -> Checkpoint {
+        Checkpoint {
+            gen: self.cur_gen,
+            offset,
+            uncompacted: self.uncompacted,
+            stale: self.stale,
+            seq: self.seq,
+            kept: self.kept,
+        }
+    }
+    /// Switches to a new generation if the active log has reached the maximum segment size.
+    fn roll_if_full(&mut self) -> Result<()> {
+        if self.writer()?.stream_position()? >= self.options.max_segment_size {
+            if self.unsynced.writes > 0 && self.options.durability != Durability::Never {
+                self.sync_log()?;
+            }
+            self.cur_gen += 1;
+            self.writer = Some(new_log_file(&self.folder, self.cur_gen, &mut self.readers)?);
+            self.dir_unsynced = true;
+            trace_debug!(gen = self.cur_gen, "rolled over to a new log");
+        }
+        Ok(())
+    }
+    /// Reads the latest command of a key, evicting the key from the index if it has expired.
+    ///
+    /// The value cache is checked first, and caches the command if it is read from the log.
+    fn read_live(&mut self, key: &str) -> Result<Option<Command>> {
+        let now = now_millis();
+        if let Some(cmd) = self.cache.get(key, now) {
+            return Ok(Some(cmd));
+        }
+        match self.index.get(key)? {
+            Some(pos) if pos.is_expired(now) => {
+                self.index.remove(key)?;
+                self.count_expired(&pos);
+                Ok(None)
+            }
+            Some(pos) => {
+                let cmd = self.readers.read_value(key, &pos)?;
+                self.cache.insert(key, &cmd, &pos);
+                Ok(Some(cmd))
+            }
+            None => Ok(None),
+        }
+    }
+    /// Returns the index entries of keys that have not expired.
+    fn live_entries(&self) -> impl Iterator<Item = (Cow<'_, str>, CommandPos)> {
+        let now = now_millis();
+        self.entries().filter(move |(_, pos)| !pos.is_expired(now))
+    }
+    /// Returns every entry of the index.
+    ///
+    /// A disk-resident index that fails to read is logged, and ends the entries early.
+    fn entries(&self) -> impl Iterator<Item = (Cow<'_, str>, CommandPos)> {
+        self.index
+            .iter()
+            .map_while(|entry| entry.map_err(|e| self.warn_index_error(e)).ok())
+    }
+    fn warn_index_error(&self, e: KvsError) {
+        warn!(
+            "Failed to read the index of {}: {}",
+            self.folder.display(),
+            e
+        );
+    }
+    /// Flushes the writes buffered by the store to the operating system.
+    ///
+    /// Every write is flushed before it returns, so this has nothing to do unless a
+    /// write failed midway. A flushed write survives the process crashing, but not the
+    /// whole system, until it is synced.
+    pub fn flush(&mut self) -> Result<()> {
+        if let Some(writer) = &mut self.writer {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+    /// Flushes the writes buffered by the store and syncs them to disk, whatever the
+    /// durability mode.
+    ///
+    /// The active log is only synced if it was written to since its last sync, and the
+    /// store directory only if a log was created in it since, so the log itself
+    /// survives a crash. A read-only store has nothing to sync.
+    pub fn sync(&mut self) -> Result<()> {
+        if self.writer.is_none() {
+            return Ok(());
+        }
+        self.flush()?;
+        if self.unsynced.writes > 0 {
+            self.sync_log()?;
+        }
+        if self.dir_unsynced {
+            sync_dir(&self.folder)?;
+            self.dir_unsynced = false;
+        }
+        Ok(())
+    }
+    /// Closes the store, returning the errors that dropping it can only log.
+    ///
+    /// The active log is flushed and synced to disk, and the index is saved for the
+    /// next open unless the store is read-only. This is the recommended way to shut a
+    /// store down. Dropping it does the same except for the sync.
+    pub fn close(mut self) -> Result<()> {
+        let result = self.sync_and_snapshot();
+        // Leave nothing for `drop` to do, even if closing failed midway.
+        self.writer = None;
+        result
+    }
+    fn sync_and_snapshot(&mut self) -> Result<()> {
+        if let Some(writer) = &mut self.writer {
+            writer.flush()?;
+            writer.get_ref().sync_data()?;
+        }
+        self.write_snapshot()
+    }
+    /// Saves the index, handing it over to the next open of the store.
+    ///
+    /// An in-memory index is written to a snapshot. A read-only store leaves the store
+    /// directory as it is.
+    fn write_snapshot(&mut self) -> Result<()> {
+        self.finish_compaction(true)?;
+        let writer = match &mut self.writer {
+            Some(writer) => writer,
+            None => return Ok(()),
+        };
+        writer.flush()?;
+        let end = writer.stream_position()?;
+        let mut logs = Vec::new();
+        for gen in self.readers.gens() {
+            let len = if gen == self.cur_gen {
+                end
+            } else {
+                fs::metadata(log_path(&self.folder, gen))?.len()
+            };
+            logs.push((gen, len));
+        }
+        let at = self.checkpoint_at(end);
+        self.index.save(&self.folder, logs, at)
+    }
+}
+
+/// Sets every key/value pair, like [`KvStore::from_pairs`] does for an opened store.
+///
+/// # Panics
+///
+/// Panics if the pairs cannot be written, as `extend` has no way to return the error.
+impl Extend<(String, String)> for KvStore {
+    fn extend<I: IntoIterator<Item = (String, String)>>(&mut self, pairs: I) {
+        if let Err(e) = self.set_pairs(pairs) {
+            panic!(
+                "failed to extend the store at {}: {}",
+                self.folder.display(),
+                e
+            );
+        }
+    }
+}
+
+impl Drop for KvStore {
+    // Closing the store cleanly saves its index, so the next open can skip replaying the logs.
+    fn drop(&mut self) {
+        // Flush first, so buffered writes are not lost even if the snapshot fails.
+        if let Some(writer) = &mut self.writer {
+            if let Err(e) = writer.flush() {
+                warn!(
+                    "Failed to flush the active log of {}: {}",
+                    self.folder.display(),
+                    e
+                );
+            }
+        }
+        if let Err(e) = self.write_snapshot() {
+            warn!(
+                "Failed to write the index snapshot of {}: {}",
+                self.folder.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Iterator over key/value pairs of a `KvStore`.
+///
+/// This struct is created by [`KvStore::iter`], [`KvStore::range`] and [`KvStore::scan_prefix`].
+/// It can be walked from both ends, and the ends stop where they meet.
+pub struct Scan<'a> {
+    index: &'a dyn KeyIndex,
+    readers: &'a mut LogReaders,
+    start: Bound<String>,
+    end: Bound<String>,
+    // Entries from either end of the range, made when the first item is taken from it.
+    front: Option<Entries<'a>>,
+    back: Option<Entries<'a>>,
+    // Keys last yielded from either end, where the other end stops.
+    front_key: Option<String>,
+    back_key: Option<String>,
+    // Number of bytes stripped from the start of the keys yielded.
+    strip: usize,
+    // Keys expired at the time the scan started are skipped.
+    now: u64,
+}
+
+impl<'a> Scan<'a> {
+    pub(crate) fn new(
+        index: &'a dyn KeyIndex,
+        readers: &'a mut LogReaders,
+        (start, end): (Bound<String>, Bound<String>),
+        strip: usize,
+    ) -> Self {
+        Self {
+            index,
+            readers,
+            start,
+            end,
+            front: None,
+            back: None,
+            front_key: None,
+            back_key: None,
+            strip,
+            now: now_millis(),
+        }
+    }
+
+    /// Returns the next live entry from the front, or from the back if `back` is set.
+    fn next_entry(&mut self, back: bool) -> Option<Result<(Cow<'a, str>, CommandPos)>> {
+        let index = self.index;
+        let start = self.start.as_ref().map(String::as_str);
+        let end = self.end.as_ref().map(String::as_str);
+        let (entries, other) = if back {
+            let entries = self.back.get_or_insert_with(|| index.range_rev(start, end));
+            (entries, &self.front_key)
+        } else {
+            let entries = self.front.get_or_insert_with(|| index.range(start, end));
+            (entries, &self.back_key)
+        };
+        let (key, pos) =
+            loop {
+                let (key, pos) = match entries.next()? {
+                    Ok(entry) => entry,
+                    Err(e) => return Some(Err(e)),
+                };
+                let met = other.as_deref().is_some_and(|other| {
+                    if back {
+                        *key <= *other
+                    } else {
+                        *key >= *other
+                    }
+                });
+                if met {
+                    return None;
+                }
+                if !pos.is_expired(self.now) {
+                    break (key, pos);
+                }
+            };
+        let last = if back {
+            &mut self.back_key
+        } else {
+            &mut self.front_key
+        };
+        *last = Some(key.clone().into_owned());
+        Some(Ok((key, pos)))
+    }
+
+    /// Returns the next key from the front, or from the back if `back` is set, without
+    /// its stripped bytes, and the command of its value.
+    pub(crate) fn next_command(&mut self, back: bool) -> Option<Result<(String, Command)>> {
+        let (key, pos) = match self.next_entry(back)? {
+            Ok(entry) => entry,
+            Err(e) => return Some(Err(e)),
+        };
+        Some(
+            self.readers
+                .read_value(&key, &pos)
+                .map(|cmd| (key[self.strip..].to_owned(), cmd)),
+        )
+    }
+
+    fn next_value(&mut self, back: bool) -> Option<Result<(String, String)>> {
+        Some(
+            self.next_command(back)?
+                .and_then(|(key, cmd)| Ok((key, cmd.into_value()?.unwrap_or_default()))),
+        )
+    }
+}
+
+impl Iterator for Scan<'_> {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_value(false)
+    }
+}
+
+impl DoubleEndedIterator for Scan<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.next_value(true)
+    }
+}
+
+/// Returns the bound ending the range of the keys that start with `prefix`.
+///
+/// Characters compare like their UTF-8 encodings, so the keys starting with a prefix
+/// are the ones below the prefix with its last character incremented.
+fn prefix_end(prefix: &str) -> Bound<String> {
+    let mut end = prefix.to_owned();
+    while let Some(last) = end.pop() {
+        // Surrogates are not characters, so they are skipped.
+        if let Some(next) = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32) {
+            end.push(next);
+            return Bound::Excluded(end);
+        }
+    }
+    Bound::Unbounded
+}
+
+/// The state of a store directory after its logs are loaded.
+pub(crate) struct OpenLogs {
+    pub(crate) lock: Option<File>,
+    pub(crate) writer: Option<BufWriter<File>>,
+    pub(crate) readers: LogReaders,
+    pub(crate) cur_gen: u64,
+    /// The in-memory index, empty if the index is disk-resident.
+    pub(crate) index: BTreeMap<String, CommandPos>,
+    pub(crate) disk_index: Option<DiskIndex>,
+    pub(crate) uncompacted: u64,
+    pub(crate) stale: StaleRecords,
+    /// Sequence number of the last change.
+    pub(crate) seq: u64,
+    /// Bytes of the older versions of keys kept by compactions.
+    pub(crate) kept: u64,
+    /// Total size of the logs.
+    pub(crate) disk_bytes: u64,
+    pub(crate) format: Codec,
+    /// Damaged runs of records skipped, if corruption is skipped.
+    pub(crate) skipped: Vec<SkippedRegion>,
+}
+
+/// Opens the logs of a store directory and loads its index.
+pub(crate) fn open_logs(
+    folder: &Path,
+    requested: Option<LogFormat>,
+    options: &Options,
+) -> Result<OpenLogs> {
+    trace_span!("open", path = %folder.display(), read_only = options.read_only);
+    if (!options.create_if_missing || options.read_only) && !folder.is_dir() {
+        return Err(KvsError::StoreNotFound(folder.to_owned()));
+    }
+    let lock = if options.read_only {
+        lock_dir_shared(folder)?
+    } else {
+        create_dir_all(folder)?;
+        Some(lock_dir(folder)?)
+    };
+    claim_dir(folder, Engine::Kvs, options.read_only)?;
+    if options.merge_operator.is_none() && has_merges(folder) {
+        return Err(KvsError::MissingMergeOperator);
+    }
+    let gen_list = list_gens(folder)?;
+    let recorded = LogFormat::load(folder)?;
+    // Logs written before the format was recorded are JSON.
+    let existing = recorded.or_else(|| (!gen_list.is_empty()).then_some(LogFormat::Json));
+    let format = match (existing, requested) {
+        (Some(recorded), Some(requested)) if recorded != requested => {
+            return Err(KvsError::FormatMismatch {
+                recorded,
+                requested,
+            });
+        }
+        (existing, requested) => existing.or(requested).unwrap_or_default(),
+    };
+    if recorded.is_none() && !options.read_only {
+        format.save(folder)?;
+    }
+    let cipher = options.cipher();
+    check_cipher(folder, cipher, !gen_list.is_empty(), options.read_only)?;
+    let format = Codec { format, cipher };
+    let mut readers = LogReaders::new(format, options.maps_logs(), options.max_open_logs);
+    readers.merge_operator = options.merge_operator;
+    let mut disk_index = if options.disk_index {
+        let max_entries = options.index_cache_entries;
+        Some(DiskIndex::open(
+            folder,
+            &gen_list,
+            max_entries,
+            options.read_only,
+        )?)
+    } else {
+        if !options.read_only {
+            remove_index_file(folder)?;
+        }
+        None
+    };
+    // A disk-resident index replaces the snapshot, which would have to fit in memory.
+    let snapshot = match disk_index {
+        Some(_) => None,
+        None => read_snapshot(folder, &gen_list),
+    };
+    if !options.read_only {
+        remove_snapshot(folder)?;
+    }
+    let mut kept = 0;
+    let (mut map, mut uncompacted, mut stale, mut seq, snapshot_len) = match snapshot {
+        Some(snapshot) => {
+            let len = snapshot.logs.last().map(|&(_, len)| len);
+            let seq = snapshot.seq;
+            kept = snapshot.kept;
+            (
+                snapshot.index,
+                snapshot.uncompacted,
+                snapshot.stale,
+                seq,
+                len,
+            )
+        }
+        None => (BTreeMap::new(), 0, StaleRecords::default(), 0, None),
+    };
+    let replay_from = disk_index.as_ref().map(DiskIndex::replay_from);
+    if let Some(at) = replay_from {
+        uncompacted = at.uncompacted;
+        stale = at.stale;
+        seq = at.seq;
+        kept = at.kept;
+    }
+    let index: &mut dyn KeyIndex = match &mut disk_index {
+        Some(disk_index) => disk_index,
+        None => &mut map,
+    };
+    // Only the newest log can end with a torn write, anywhere else it is corruption.
+    let newest_tail = if options.read_only {
+        TornTail::Ignore
+    } else {
+        TornTail::Truncate
+    };
+    let mut skipped = Vec::new();
+    for &gen in &gen_list {
+        let mut reader = BufReader::new(open_log(folder, gen)?);
+        let is_newest = Some(&gen) == gen_list.last();
+        let torn_tail = if is_newest {
+            newest_tail
+        } else {
+            TornTail::Corrupt
+        };
+        // The snapshot covers every log up to its recorded length, and the file of a
+        // disk-resident index every log up to the point it records.
+        let from = match (snapshot_len, replay_from) {
+            (Some(len), _) => is_newest.then_some(len),
+            (None, Some(at)) if gen < at.gen => None,
+            (None, Some(at)) if gen == at.gen => Some(at.offset),
+            (None, Some(_)) => Some(0),
+            (None, None) => match read_hint(folder, gen) {
+                Some((entries, hint_seq, hint_kept)) => {
+                    seq = seq.max(hint_seq);
+                    kept += hint_kept;
+                    for (key, start, end, expires_at) in entries {
+                        let pos = CommandPos {
+                            gen,
+                            start,
+                            end,
+                            expires_at,
+                        };
+                        if let Some(old) = index.insert(key, pos)? {
+                            uncompacted += old.len();
+                            stale.values += 1;
+                        }
+                    }
+                    None
+                }
+                None => Some(0),
+            },
+        };
+        if let Some(offset) = from {
+            let mut at = Checkpoint {
+                gen,
+                offset,
+                uncompacted,
+                stale,
+                seq,
+                kept,
+            };
+            match options.on_corruption {
+                CorruptionPolicy::Fail => {
+                    load(folder, format, &mut reader, index, torn_tail, &mut at)?
+                }
+                CorruptionPolicy::Skip => load_skipping(
+                    folder,
+                    format,
+                    &mut reader,
+                    index,
+                    torn_tail,
+                    &mut at,
+                    &mut skipped,
+                )?,
+            }
+            uncompacted = at.uncompacted;
+            stale = at.stale;
+            seq = at.seq;
+        }
+        readers.add(folder, gen);
+    }
+    // Keep appending to the newest generation unless it is already full.
+    let (cur_gen, writer) = match gen_list.last() {
+        last if options.read_only => (last.cloned().unwrap_or(0), None),
+        Some(&gen) if fs::metadata(log_path(folder, gen))?.len() < options.max_segment_size => {
+            let mut writer = BufWriter::new(
+                OpenOptions::new()
+                    .append(true)
+                    .open(log_path(folder, gen))?,
+            );
+            writer.seek(SeekFrom::End(0))?;
+            (gen, Some(writer))
+        }
+        last => {
+            let gen = last.unwrap_or(&0) + 1;
+            (gen, Some(new_log_file(folder, gen, &mut readers)?))
+        }
+    };
+    let mut disk_bytes = 0;
+    for gen in readers.gens() {
+        disk_bytes += fs::metadata(log_path(folder, gen))?.len();
+    }
+    trace_debug!(
+        logs = gen_list.len(),
+        keys = map.len(),
+        from_snapshot = snapshot_len.is_some(),
+        disk_index = disk_index.is_some(),
+        "loaded the index"
+    );
+    Ok(OpenLogs {
+        lock,
+        writer,
+        readers,
+        cur_gen,
+        index: map,
+        disk_index,
+        uncompacted,
+        stale,
+        seq,
+        kept,
+        disk_bytes,
+        format,
+        skipped,
+    })
+}
+
+/// Takes the exclusive lock of a store directory.
+///
+/// The lock is an OS advisory lock on the lock file, so it goes away with the
+/// process that holds it, even if it crashes.
+pub(crate) fn lock_dir(dir: &Path) -> Result<File> {
+    let path = dir.join(LOCK_FILE);
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(KvsError::StoreLocked(path)),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+/// Takes a shared lock of a store directory, for a read-only store.
+///
+/// Any number of read-only stores can share the directory, but no writing one. If
+/// there is no lock file, no store has ever written to the directory, so there is
+/// nothing to lock.
+fn lock_dir_shared(dir: &Path) -> Result<Option<File>> {
+    let path = dir.join(LOCK_FILE);
+    let file = match File::open(&path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    match file.try_lock_shared() {
+        Ok(()) => Ok(Some(file)),
+        Err(TryLockError::WouldBlock) => Err(KvsError::StoreLocked(path)),
+        Err(TryLockError::Error(e)) => Err(e.into()),
+    }
+}
+
+/// How to handle a torn write at the tail of a log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum TornTail {
+    /// Fail with `KvsError::Corruption`.
+    Corrupt,
+    /// Truncate the log to its last complete record.
+    Truncate,
+    /// Leave the log as it is and only load its complete records.
+    Ignore,
+}
+
+/// Replays the log of a generation into the index, from the point `at` to its end.
+///
+/// A broken record or an unfinished batch at the end of the log is a torn write,
+/// which is handled as `torn_tail` says.
+///
+/// `at` is moved past every record applied to the index, and counts the bytes and the
+/// records in the log that are stale. The index is checkpointed there as it goes.
+pub(crate) fn load(
+    dir: &Path,
+    format: Codec,
+    reader: &mut BufReader<File>,
+    index: &mut dyn KeyIndex,
+    torn_tail: TornTail,
+    at: &mut Checkpoint,
+) -> Result<()> {
+    let (gen, from) = (at.gen, at.offset);
+    // Records of a batch are only applied once the whole batch has been read.
+    let mut batch_len = None;
+    let mut pending = Vec::new();
+    // Checksum and sequence number of the next record, and where the checksum record
+    // starts.
+    let mut checksum = None;
+    // Key, length, bytes still to read, start and sequence number of a streamed value
+    // being read.
+    let mut stream = None;
+    reader
+        .seek(SeekFrom::Start(from))
+        .map_err(at_record(gen, from))?;
+    let mut records = format.records(reader).map(|record| {
+        record.map(|record| match record {
+            Record::Command {
+                cmd,
+                start,
+                end,
+                crc,
+            } => Record::Command {
+                cmd,
+                start: from + start,
+                end: from + end,
+                crc,
+            },
+            Record::Broken(offset) => Record::Broken(from + offset),
+        })
+    });
+    // End of the last record read, where a failing read is.
+    let mut read_to = from;
+    let torn = loop {
+        let record = records
+            .next()
+            .transpose()
+            .map_err(at_record(gen, read_to))?;
+        if let Some(Record::Command { end, .. }) = record {
+            read_to = end;
+        }
+        let (cmd, start, end, seq) = match record {
+            None => break batch_len.is_some() || checksum.is_some() || stream.is_some(),
+            Some(Record::Broken(_)) if torn_tail != TornTail::Corrupt => break true,
+            Some(Record::Broken(offset)) => return Err(KvsError::Corruption { gen, offset }),
+            Some(Record::Command {
+                cmd: Command::Checksum(crc),
+                start,
+                ..
+            }) if checksum.is_none() => {
+                checksum = Some((crc, None, start));
+                continue;
+            }
+            Some(Record::Command {
+                cmd: Command::Sequenced(crc, seq),
+                start,
+                ..
+            }) if checksum.is_none() => {
+                checksum = Some((crc, Some(seq), start));
+                continue;
+            }
+            Some(Record::Command {
+                cmd,
+                start,
+                end,
+                crc,
+            }) => match checksum.take() {
+                // Records written before checksums were introduced are not verified.
+                None => (cmd, start, end, None),
+                Some((expected, seq, checksum_start)) if crc == expected => {
+                    (cmd, checksum_start, end, seq)
+                }
+                Some(_) => return Err(KvsError::Corruption { gen, offset: start }),
+            },
+        };
+        let cmd = cmd.decompress(format).map_err(at_record(gen, start))?;
+        // A streamed value is only applied once all of its chunks have been read.
+        let (cmd, start, seq) = match cmd {
+            Command::Chunk(chunk) => match &mut stream {
+                Some((_, _, remaining, _, _)) if chunk.len() as u64 <= *remaining => {
+                    *remaining -= chunk.len() as u64;
+                    if *remaining > 0 {
+                        continue;
+                    }
+                    let (key, len, _, stream_start, stream_seq) = stream.take().unwrap();
+                    (Command::SetStream(key, len), stream_start, stream_seq)
+                }
+                _ => return Err(KvsError::Corruption { gen, offset: start }),
+            },
+            _ if stream.is_some() => return Err(KvsError::Corruption { gen, offset: start }),
+            Command::SetStream(key, len) if len > 0 => {
+                stream = Some((key, len, len, start, seq));
+                continue;
+            }
+            cmd => (cmd, start, seq),
+        };
+        match cmd {
+            Command::Batch(len) if batch_len.is_none() => {
+                batch_len = Some(len);
+                at.uncompacted += end - start;
+            }
+            // Batches do not nest, so the log is damaged.
+            Command::Batch(_) => return Err(KvsError::Corruption { gen, offset: start }),
+            cmd => pending.push((cmd, start, end, seq)),
+        }
+        if pending.len() >= batch_len.unwrap_or(1) {
+            for (cmd, start, end, seq) in pending.drain(..) {
+                // Compacted logs hold changes older than the ones after them.
+                at.seq = at.seq.max(seq.unwrap_or(0));
+                let stale = apply(index, cmd, (gen, start, end))?;
+                at.uncompacted += stale.bytes;
+                at.stale += stale.records;
+            }
+            batch_len = None;
+            // End of the last record applied to the index.
+            at.offset = end;
+            index.checkpoint(*at)?;
+        }
+    };
+    if torn {
+        drop_torn_tail(dir, gen, at.offset, torn_tail)?;
+    }
+    Ok(())
+}
+
+/// Replays the log of a generation into the index like `load`, but skips every damaged
+/// run of records instead of failing, adding it to `skipped`.
+///
+/// Replay resumes past a damaged run at the first offset holding a checksum record
+/// that matches the record after it. A damaged run reaching the end of the log is a
+/// torn write if `torn_tail` allows for one, and is handled as it says.
+pub(crate) fn load_skipping(
+    dir: &Path,
+    format: Codec,
+    reader: &mut BufReader<File>,
+    index: &mut dyn KeyIndex,
+    torn_tail: TornTail,
+    at: &mut Checkpoint,
+    skipped: &mut Vec<SkippedRegion>,
+) -> Result<()> {
+    let gen = at.gen;
+    // The log is only read into memory once it is found damaged.
+    let mut bytes = None;
+    loop {
+        let damaged = match load(dir, format, reader, index, TornTail::Corrupt, at) {
+            Ok(()) => return Ok(()),
+            Err(KvsError::Corruption { offset, .. }) => offset,
+            Err(KvsError::LogRead { offset, cause, .. }) if !matches!(*cause, KvsError::Io(_)) => {
+                offset
+            }
+            Err(e) => return Err(e),
+        };
+        let bytes = match &mut bytes {
+            Some(bytes) => bytes,
+            None => bytes.insert(fs::read(log_path(dir, gen))?),
+        };
+        // The records after the last one applied are dropped with the damaged run, as
+        // they may be part of the same batch or streamed value.
+        let start = at.offset;
+        let end = (damaged.max(start) + 1..bytes.len() as u64)
+            .find(|&offset| intact_record(format, &bytes[offset as usize..], false).is_some());
+        let end = match end {
+            Some(end) => end,
+            None if torn_tail != TornTail::Corrupt => {
+                return drop_torn_tail(dir, gen, start, torn_tail)
+            }
+            None => bytes.len() as u64,
+        };
+        warn!(
+            "Skipped {} damaged bytes at offset {} of {}",
+            end - start,
+            start,
+            log_path(dir, gen).display()
+        );
+        skipped.push(SkippedRegion {
+            gen,
+            offset: start,
+            len: end - start,
+        });
+        at.uncompacted += end - start;
+        at.offset = end;
+        if end == bytes.len() as u64 {
+            return Ok(());
+        }
+    }
+}
+
+/// Handles a torn write at the tail of the log of a generation, past `committed`, as
+/// `torn_tail` says.
+fn drop_torn_tail(dir: &Path, gen: u64, committed: u64, torn_tail: TornTail) -> Result<()> {
+    let path = log_path(dir, gen);
+    let len = fs::metadata(&path)?.len();
+    match torn_tail {
+        TornTail::Corrupt => {
+            return Err(KvsError::Corruption {
+                gen,
+                offset: committed,
+            })
+        }
+        TornTail::Truncate => {
+            OpenOptions::new()
+                .write(true)
+                .open(&path)?
+                .set_len(committed)?;
+            trace_warn!(
+                gen,
+                bytes = len - committed,
+                "dropped a torn write at the tail of the log"
+            );
+            warn!(
+                "Dropped {} bytes of torn write at the tail of {}",
+                len - committed,
+                path.display()
+            );
+        }
+        TornTail::Ignore => warn!(
+            "Ignoring {} bytes of torn write at the tail of {}",
+            len - committed,
+            path.display()
+        ),
+    }
+    Ok(())
+}
+
+/// What applying a command makes stale in the logs.
+pub(crate) struct Stale {
+    pub(crate) bytes: u64,
+    pub(crate) records: StaleRecords,
+    /// Position of the value the command replaced or removed, if any.
+    pub(crate) replaced: Option<CommandPos>,
+}
+
+impl Stale {
+    /// Returns what replacing `old`, and leaving `tombstone` bytes of tombstone
+    /// behind, makes stale.
+    fn replacing(old: Option<CommandPos>, tombstone: u64) -> Self {
+        Self {
+            bytes: old.map_or(0, |old| old.len()) + tombstone,
+            records: StaleRecords {
+                values: old.is_some() as u64,
+                tombstones: (tombstone > 0) as u64,
+            },
+            replaced: old,
+        }
+    }
+}
+
+/// Applies a `Set`, `Merge`, `Append` or `Remove` command stored at the given position to the index.
+///
+/// Returns what becomes stale in the logs.
+pub(crate) fn apply(
+    index: &mut dyn KeyIndex,
+    cmd: Command,
+    (gen, start, end): (u64, u64, u64),
+) -> Result<Stale> {
+    let mut pos = CommandPos {
+        gen,
+        start,
+        end,
+        expires_at: None,
+    };
+    let stale = match cmd {
+        Command::Set(key, _)
+        | Command::SetBytes(key, _)
+        | Command::SetJson(key, _)
+        | Command::SetStream(key, _) => Stale::replacing(index.insert(key, pos)?, 0),
+        Command::SetWithExpiry(key, _, expires_at) => {
+            pos.expires_at = Some(expires_at);
+            Stale::replacing(index.insert(key, pos)?, 0)
+        }
+        // A compaction replaces the whole chain of merge or append records with a record
+        // of the whole value, so only the newest record of the chain is not stale. The
+        // value expires with the one it was merged or appended into.
+        Command::Merge(key, _, prev) | Command::Append(key, _, prev, _) => {
+            pos.expires_at = prev.and_then(|prev| prev.expires_at);
+            Stale::replacing(index.insert(key, pos)?, 0)
+        }
+        // Both the evicted `Set` record and the tombstone itself are stale.
+        Command::Remove(key) => Stale::replacing(index.remove(&key)?, pos.len()),
+        // Compressed and encrypted records are decoded before they are applied, and the
+        // chunks of a streamed value are applied with its header.
+        Command::Batch(_)
+        | Command::Checksum(_)
+        | Command::Compressed(_)
+        | Command::Encrypted(_)
+        | Command::Chunk(_)
+        | Command::Sequenced(..) => Stale {
+            bytes: pos.len(),
+            records: StaleRecords::default(),
+            replaced: None,
+        },
+        // Only the records it evicts count as stale bytes, so a cleared store has
+        // nothing to compact.
+        Command::Clear => {
+            let (values, bytes) = index.clear()?;
+            Stale {
+                bytes,
+                records: StaleRecords {
+                    values,
+                    tombstones: 1,
+                },
+                replaced: None,
+            }
+        }
+    };
+    Ok(stale)
+}
+
+/// Writes to the active log since it was last synced.
+pub(crate) struct Unsynced {
+    pub(crate) writes: u64,
+    since: Instant,
+}
+
+impl Unsynced {
+    pub(crate) fn new() -> Self {
+        Self {
+            writes: 0,
+            since: Instant::now(),
+        }
+    }
+
+    /// Counts a write, and returns whether the durability mode asks for a sync after it.
+    pub(crate) fn count_write(&mut self, durability: Durability) -> bool {
+        self.writes += 1;
+        match durability {
+            Durability::Always => true,
+            Durability::EveryN(n) => self.writes >= n,
+            Durability::Interval(interval) => self.since.elapsed() >= interval,
+            Durability::Never => false,
+        }
+    }
+}
+
+/// A compaction running on a background thread.
+struct Compaction {
+    // Generation of the compacted log.
+    gen: u64,
+    // Stale bytes and records in the logs when the compaction started.
     uncompacted: u64,
+    stale: StaleRecords,
+    handle: JoinHandle<Result<Compacted>>,
 }
 
-impl KvStore {
+/// An incremental compaction in progress.
+struct Stepping {
+    log: CompactionLog,
+    // The last key copied, which the next step resumes after.
+    resume: Option<String>,
+    // What the steps so far did, and the stale records they are to drop.
+    report: CompactionReport,
+    // The older versions of keys to copy, and the bytes their copies take so far.
+    kept: KeptVersions,
+    kept_bytes: u64,
+}
+
+/// The copied keys, the size of the hint file, the bytes of the older versions of keys
+/// kept and the time taken by a background compaction.
+type Compacted = (Vec<MovedKey>, u64, u64, Duration);
+
+/// Key with the position it was copied from and the position of its copy.
+type MovedKey = (String, CommandPos, CommandPos);
+
+/// Copies the records of the given keys at the given positions into a new log of the
+/// given generation, like `RecordCopier` does, along with the older versions `kept`.
+///
+/// Returns the positions of the copies, in order, and the bytes the copies of the older
+/// versions take.
+pub(crate) fn copy_records<'a, I: Iterator<Item = (&'a String, &'a CommandPos)>>(
+    dir: &Path,
+    gen: u64,
+    format: Codec,
+    compress: bool,
+    readers: &mut LogReaders,
+    entries: I,
+    kept: Option<&KeptVersions>,
+) -> Result<(Vec<CommandPos>, u64)> {
+    let mut log = CompactionLog::create(dir, gen)?;
+    let mut copier = RecordCopier::new(&mut log, readers, format, compress);
+    if let Some(kept) = kept {
+        copier = copier.keeping(kept);
+        copier.copy_removed()?;
+    }
+    let copies = entries
+        .map(|(key, pos)| copier.copy(key, pos))
+        .collect::<Result<_>>()?;
+    copier.finish()?;
+    Ok((copies, copier.kept_bytes()))
+}
+
+/// The log a compaction copies live records into, which it may do over several steps.
+pub(crate) struct CompactionLog {
+    gen: u64,
+    writer: BufWriter<File>,
+    // End of the records copied so far.
+    offset: u64,
+}
+
+impl CompactionLog {
+    /// Creates the log of generation `gen`.
+    pub(crate) fn create(dir: &Path, gen: u64) -> Result<Self> {
+        Ok(Self {
+            gen,
+            writer: create_log(dir, gen)?,
+            offset: 0,
+        })
+    }
+}
+
+/// Copies records of keys into a compaction log, one at a time.
+///
+/// Every record is checked to be a value of its key before it is copied, so a damaged
+/// index fails the copy. Records are compressed on the way if `compress` is set.
+pub(crate) struct RecordCopier<'a> {
+    log: &'a mut CompactionLog,
+    readers: &'a mut LogReaders,
+    format: Codec,
+    compress: bool,
+    // The older versions copied ahead of the record of their key.
+    kept: Option<&'a KeptVersions>,
+    // Bytes written and read, and records copied, by this copier, not counting the
+    // older versions, whose copies take `kept_bytes`.
+    copied: u64,
+    moved: u64,
+    records: u64,
+    kept_bytes: u64,
+    // How many more records of every length there are since last taken, the records
+    // copied counting as removed and their copies as added.
+    resized: BTreeMap<u64, i64>,
+    // The expiry time, key and length of the copies of keys that expire, since last taken.
+    expiring: Vec<((u64, String), u64)>,
+    record: Vec<u8>,
+}
+
+impl<'a> RecordCopier<'a> {
+    /// Creates a copier of records from the logs of `readers` into `log`.
+    pub(crate) fn new(
+        log: &'a mut CompactionLog,
+        readers: &'a mut LogReaders,
+        format: Codec,
+        compress: bool,
+    ) -> Self {
+        Self {
+            log,
+            readers,
+            format,
+            compress,
+            kept: None,
+            copied: 0,
+            moved: 0,
+            records: 0,
+            kept_bytes: 0,
+            resized: BTreeMap::new(),
+            expiring: Vec::new(),
+            record: Vec::new(),
+        }
+    }
+
+    /// Makes the copier copy the given older versions of a key ahead of its record.
+    pub(crate) fn keeping(mut self, kept: &'a KeptVersions) -> Self {
+        self.kept = Some(kept);
+        self
+    }
+
+    /// Returns the generation of the log the records are copied into.
+    pub(crate) fn gen(&self) -> u64 {
+        self.log.gen
+    }
+
+    /// Returns the number of bytes the copies made so far take.
+    pub(crate) fn copied(&self) -> u64 {
+        self.copied
+    }
+
+    /// Returns the number of bytes the records copied so far took where they were.
+    pub(crate) fn moved(&self) -> u64 {
+        self.moved
+    }
+
+    /// Returns the number of records copied so far.
+    pub(crate) fn records(&self) -> u64 {
+        self.records
+    }
+
+    /// Returns by how many the records of every length changed in number, counting the
+    /// records copied since this was last called as removed and their copies as added.
+    pub(crate) fn take_resized(&mut self) -> BTreeMap<u64, i64> {
+        std::mem::take(&mut self.resized)
+    }
+
+    /// Returns the length of the copies of the keys that expire made since this was last
+    /// called, each with the expiry time and key.
+    pub(crate) fn take_expiring(&mut self) -> Vec<((u64, String), u64)> {
+        std::mem::take(&mut self.expiring)
+    }
+
+    /// Returns the number of bytes the copies of older versions made so far take.
+    pub(crate) fn kept_bytes(&self) -> u64 {
+        self.kept_bytes
+    }
+
+    /// Copies the record of a key at the given position, and returns the position of the copy.
+    pub(crate) fn copy(&mut self, key: &str, pos: &CommandPos) -> Result<CommandPos> {
+        if let Some(kept) = self.kept.and_then(|kept| kept.get(key)) {
+            for old in &kept.records {
+                self.kept_bytes += self.copy_record(key, old)?;
+            }
+        }
+        let len = self.copy_record(key, pos)?;
+        let copy = CommandPos {
+            gen: self.log.gen,
+            start: self.log.offset - len,
+            end: self.log.offset,
+            expires_at: pos.expires_at,
+        };
+        self.copied += len;
+        self.moved += pos.len();
+        self.records += 1;
+        *self.resized.entry(pos.len()).or_default() -= 1;
+        *self.resized.entry(len).or_default() += 1;
+        if let Some(expires_at) = pos.expires_at {
+            self.expiring.push(((expires_at, key.to_owned()), len));
+        }
+        Ok(copy)
+    }
+
+    /// Copies the older versions of the removed keys kept, each followed by a tombstone.
+    pub(crate) fn copy_removed(&mut self) -> Result<()> {
+        let Some(kept) = self.kept else {
+            return Ok(());
+        };
+        for (key, kept) in kept.iter().filter(|(_, kept)| kept.removed) {
+            for old in &kept.records {
+                self.kept_bytes += self.copy_record(key, old)?;
+            }
+            let record = &mut self.record;
+            record.clear();
+            self.format
+                .encode_checked(&Command::Remove(key.clone()), record)?;
+            self.log.writer.write_all(record)?;
+            self.log.offset += record.len() as u64;
+            self.kept_bytes += record.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Copies the record of a key at the given position to the end of the log, and
+    /// returns the length of the copy.
+    fn copy_record(&mut self, key: &str, pos: &CommandPos) -> Result<u64> {
+        let format = self.format;
+        let reader = self.readers.reader(pos.gen)?;
+        // Streamed values are copied as they are, without reading them into memory.
+        let len = if pos.len() > CHUNK_LEN as u64 && stream_len(format, reader, key, pos)?.is_some()
+        {
+            reader
+                .seek(SeekFrom::Start(pos.start))
+                .map_err(at_record(pos.gen, pos.start))?;
+            let copied = io::copy(
+                &mut Read::take(&mut *reader, pos.len()),
+                &mut self.log.writer,
+            )?;
+            if copied < pos.len() {
+                let eof = io::Error::from(io::ErrorKind::UnexpectedEof);
+                return Err(at_record(pos.gen, pos.start)(eof));
+            }
+            copied
+        } else {
+            let record = &mut self.record;
+            read_exact_at(reader, pos.start, record, pos.len())
+                .map_err(at_record(pos.gen, pos.start))?;
+            let cmd = decode_command(format, record).map_err(at_record(pos.gen, pos.start))?;
+            let compressed = matches!(cmd, Command::Compressed(_));
+            let cmd = cmd
+                .decompress(format)
+                .map_err(at_record(pos.gen, pos.start))?;
+            check_value(&cmd, key, pos)?;
+            if let Command::Merge(..) | Command::Append(..) = cmd {
+                // A chain of merge and append records is replaced by a single record of
+                // the whole value.
+                let value = self.readers.chained_value(key, pos, cmd)?;
+                let cmd = match pos.expires_at {
+                    Some(expires_at) => Command::SetWithExpiry(key.to_owned(), value, expires_at),
+                    None => Command::Set(key.to_owned(), value),
+                };
+                record.clear();
+                if self.compress {
+                    format.encode_compressed(&cmd, record)?;
+                } else {
+                    format.encode_checked(&cmd, record)?;
+                }
+            } else if self.compress && !compressed {
+                // Records compressed by an earlier compaction are copied as they are.
+                record.clear();
+                format.encode_compressed(&cmd, record)?;
+            }
+            self.log.writer.write_all(record)?;
+            record.len() as u64
+        };
+        self.log.offset += len;
+        Ok(len)
+    }
+
+    /// Flushes the log and syncs it to disk.
+    pub(crate) fn finish(&mut self) -> Result<()> {
+        self.log.writer.flush()?;
+        // The records must be on disk before the only other copy of them is removed.
+        self.log.writer.get_ref().sync_all()?;
+        Ok(())
+    }
+}
+
+/// Returns whether the record of a key at the given position is a streamed value.
+///
+/// Only the header of the record is read.
+fn stream_len(
+    format: Codec,
+    reader: &mut BufReader<File>,
+    key: &str,
+    pos: &CommandPos,
+) -> Result<Option<u64>> {
+    reader
+        .seek(SeekFrom::Start(pos.start))
+        .map_err(at_record(pos.gen, pos.start))?;
+    let mut records = format.records(Read::take(&mut *reader, pos.len()));
+    match next_command(&mut records, false, pos).map_err(at_record(pos.gen, pos.start))? {
+        Some(cmd @ Command::SetStream(_, len)) => check_value(&cmd, key, pos).map(|()| Some(len)),
+        _ => Ok(None),
+    }
+}
+
+/// Reads the `len` bytes at `offset` into the buffer, failing if the log ends before them.
+fn read_exact_at(
+    reader: &mut BufReader<File>,
+    offset: u64,
+    buf: &mut Vec<u8>,
+    len: u64,
+) -> io::Result<()> {
+    reader.seek(SeekFrom::Start(offset))?;
+    buf.clear();
+    reader.take(len).read_to_end(buf)?;
+    if (buf.len() as u64) < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+/// Readers of the log generations of a store.
+///
+/// Readers are opened on the first read of their log, and at most `max_open` of them
+/// are open at once, closing the least recently used one to open another. Every read
+/// seeks its reader first, so reopening a reader loses nothing.
+pub(crate) struct LogReaders {
+    // Directory of the log of every generation.
+    logs: BTreeMap<u64, PathBuf>,
+    // Open readers, with the time of their last use.
+    files: BTreeMap<u64, (BufReader<File>, u64)>,
+    // Counts the uses of readers, to tell which was used least recently.
+    clock: u64,
+    max_open: usize,
+    pub(crate) format: Codec,
+    // Whether to verify record checksums on every read, not only on open.
+    pub(crate) verify_checksums: bool,
+    // Resolves the merge records read, if any merge operator is registered.
+    pub(crate) merge_operator: Option<MergeOperator>,
+    // Maps of the sealed logs, made on their first read, if memory maps are used.
+    #[cfg(all(feature = "mmap", unix))]
+    maps: Option<BTreeMap<u64, Mmap>>,
+    // A log below the newest one that is still written to, by an incremental compaction.
+    #[cfg(all(feature = "mmap", unix))]
+    writing: Option<u64>,
+}
+
+impl LogReaders {
+    /// Creates readers of no log yet, keeping up to `max_open` readers open, which read
+    /// sealed logs through memory maps if `mmap` is set and memory maps are supported.
+    #[cfg_attr(not(all(feature = "mmap", unix)), allow(unused_variables))]
+    pub(crate) fn new(format: Codec, mmap: bool, max_open: usize) -> Self {
+        Self {
+            logs: BTreeMap::new(),
+            files: BTreeMap::new(),
+            clock: 0,
+            max_open: max_open.max(1),
+            format,
+            verify_checksums: false,
+            merge_operator: None,
+            #[cfg(all(feature = "mmap", unix))]
+            maps: mmap.then(BTreeMap::new),
+            #[cfg(all(feature = "mmap", unix))]
+            writing: None,
+        }
+    }
+
+    /// Records that the log of a generation below the newest one is still written to,
+    /// so it is not read through a memory map, which would miss what is appended.
+    #[cfg_attr(not(all(feature = "mmap", unix)), allow(unused_variables))]
+    pub(crate) fn set_writing(&mut self, gen: Option<u64>) {
+        #[cfg(all(feature = "mmap", unix))]
+        {
+            self.writing = gen;
+        }
+    }
+
+    /// Adds the log of a generation in `dir`, to be opened on its first read.
+    pub(crate) fn add(&mut self, dir: &Path, gen: u64) {
+        self.logs.entry(gen).or_insert_with(|| dir.to_owned());
+    }
+
+    /// Returns the generations of the logs, in order.
+    pub(crate) fn gens(&self) -> impl DoubleEndedIterator<Item = u64> + '_ {
+        self.logs.keys().copied()
+    }
+
+    /// Opens a new handle on the log of a generation, apart from its reader.
+    pub(crate) fn open(&self, gen: u64) -> Result<File> {
+        let dir = self
+            .logs
+            .get(&gen)
+            .ok_or(KvsError::MissingGeneration(gen))?;
+        open_pinned_log(dir, gen)
+    }
+
+    /// Removes the log of a generation, closing its reader.
+    pub(crate) fn remove(&mut self, gen: u64) {
+        self.logs.remove(&gen);
+        self.files.remove(&gen);
+        #[cfg(all(feature = "mmap", unix))]
+        if let Some(maps) = &mut self.maps {
+            maps.remove(&gen);
+        }
+    }
+
+    /// Removes the logs of the generations below `gen`, closing their readers.
+    pub(crate) fn remove_below(&mut self, gen: u64) {
+        self.logs.retain(|&log, _| log >= gen);
+        self.files.retain(|&open, _| open >= gen);
+        #[cfg(all(feature = "mmap", unix))]
+        if let Some(maps) = &mut self.maps {
+            maps.retain(|&open, _| open >= gen);
+        }
+    }
+
+    /// Returns the reader of a generation, opening it if it is not open.
     ///
-    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
-        use std::fs::read_dir;
-        let folder = path.as_ref();
-        create_dir_all(folder)?;
-        let mut gen_list: Vec<u64> = read_dir(folder)?
-            .flat_map(|file| -> Result<_> { Ok(file?.path()) })
-            .filter(|f| f.is_file() && f.extension() == Some("log".as_ref()))
-            .flat_map(|path| {
-                path.file_name()
-                    .and_then(OsStr::to_str)
-                    .map(|f| f.trim_end_matches(".log"))
-                    .map(str::parse::<u64>)
-            })
-            .flatten()
-            .collect();
-        gen_list.sort_unstable();
-        let mut readers: BTreeMap<u64, BufReader<File>> = BTreeMap::new();
-        let mut index: BTreeMap<String, (u64, u64, u64)> = BTreeMap::new();
-        let mut uncompacted = 0;
-        for &gen_id in &gen_list {
-            let file = folder.join(format!("{gen_id}.log"));
-            readers.insert(gen_id, BufReader::new(File::open(file)?));
-            let reader = readers.get_mut(&gen_id).unwrap();
-            let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
-            let mut pos = stream.byte_offset();
-            while let Some(cmd) = stream.next() {
-                match cmd? {
-                    Command::Set(key, _) => {
-                        let new_pos = stream.byte_offset();
-                        index.insert(key, (gen_id, pos as u64, new_pos as u64));
-                    }
-                    Command::Remove(key) => {
-                        index.remove(&key);
-                    }
+    /// Returns `KvsError::MissingGeneration` if the store has no log of the generation.
+    pub(crate) fn reader(&mut self, gen: u64) -> Result<&mut BufReader<File>> {
+        self.clock += 1;
+        if !self.files.contains_key(&gen) {
+            let dir = self
+                .logs
+                .get(&gen)
+                .ok_or(KvsError::MissingGeneration(gen))?;
+            let file = open_pinned_log(dir, gen)?;
+            if self.files.len() >= self.max_open {
+                let oldest = self
+                    .files
+                    .iter()
+                    .min_by_key(|(_, &(_, used))| used)
+                    .map(|(&open, _)| open);
+                if let Some(oldest) = oldest {
+                    self.files.remove(&oldest);
                 }
-                pos = stream.byte_offset();
             }
-            uncompacted += pos as u64;
+            self.files.insert(gen, (BufReader::new(file), 0));
         }
-        let cur_gen = gen_list.last().unwrap_or(&0) + 1;
-        let writer = BufWriter::new(
-            OpenOptions::new()
-                .create_new(true)
-                .append(true)
-                .open(folder.join(format!("{cur_gen}.log")))?,
-        );
-        let file = folder.join(format!("{cur_gen}.log"));
-        readers.insert(cur_gen, BufReader::new(File::open(file)?));
-        Ok(Self {
-            folder: folder.to_owned(),
-            writer,
-            readers,
-            cur_gen,
-            index,
-            uncompacted,
+        let (reader, used) = self.files.get_mut(&gen).unwrap();
+        *used = self.clock;
+        Ok(reader)
+    }
+
+    /// Reads the command stored at the given position.
+    ///
+    /// Returns `KvsError::Corruption` if checksums are verified and the record does not match,
+    /// and `KvsError::LogRead` if it cannot be read.
+    pub(crate) fn read(&mut self, pos: &CommandPos) -> Result<Command> {
+        self.read_record(pos).map_err(at_record(pos.gen, pos.start))
+    }
+
+    /// Reads the value of a key stored at the given position.
+    ///
+    /// A merge or append record is resolved into a `Set` of the whole value. Returns
+    /// `KvsError::UnexpectedCommandType` if the record is not a value of the key.
+    pub(crate) fn read_value(&mut self, key: &str, pos: &CommandPos) -> Result<Command> {
+        let cmd = self.read(pos)?;
+        check_value(&cmd, key, pos)?;
+        match cmd {
+            cmd @ (Command::Merge(..) | Command::Append(..)) => {
+                let value = self.chained_value(key, pos, cmd)?;
+                Ok(Command::Set(key.to_owned(), value))
+            }
+            cmd => Ok(cmd),
+        }
+    }
+
+    /// Returns the length in bytes of the value of a key stored at the given position.
+    ///
+    /// Only the record at the position is read, unless it is a merge record.
+    pub(crate) fn value_len(&mut self, key: &str, pos: &CommandPos) -> Result<u64> {
+        let cmd = self.read(pos)?;
+        check_value(&cmd, key, pos)?;
+        Ok(match cmd {
+            Command::Append(.., len) | Command::SetStream(_, len) => len,
+            cmd @ Command::Merge(..) => self.chained_value(key, pos, cmd)?.len() as u64,
+            cmd => cmd.into_bytes().map_or(0, |value| value.len() as u64),
         })
     }
+
+    /// Returns the value of a key resolved from the merge or append record `cmd` at the
+    /// given position.
     ///
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::Set(key.clone(), value);
-        let before = self.writer.stream_position()?;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
-        let after = self.writer.stream_position()?;
-        self.index.insert(key, (self.cur_gen, before, after));
-        self.uncompacted += after - before;
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact()?;
-        }
-        Ok(())
-    }
-    ///
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        if let Some((gen, start, end)) = self.index.get(&key) {
-            let reader = self
-                .readers
-                .get_mut(gen)
-                .expect(&format!("unable to find reader for {gen}.log"));
-            reader.seek(SeekFrom::Start(*start))?;
-            match serde_json::from_reader(reader.take(end - start))? {
-                Command::Set(_, v) => {
-                    return Ok(Some(v));
+    /// The records before it are read back to the first one that is neither, and the
+    /// suffixes and operands since are applied to its value, oldest first. Every run of
+    /// operands is given to the merge operator at once.
+    pub(crate) fn chained_value(
+        &mut self,
+        key: &str,
+        pos: &CommandPos,
+        mut cmd: Command,
+    ) -> Result<String> {
+        enum Link {
+            Merge(String),
+            Append(String),
+        }
+        // Readers of a shared store only know the logs they have read from, and the
+        // records of a chain are all in the same directory.
+        let dir = self.logs.get(&pos.gen).cloned();
+        let mut links = Vec::new();
+        let base = loop {
+            let prev = match cmd {
+                Command::Merge(_, operand, prev) => {
+                    links.push(Link::Merge(operand));
+                    prev
+                }
+                Command::Append(_, suffix, prev, _) => {
+                    links.push(Link::Append(suffix));
+                    prev
+                }
+                cmd => break cmd.into_value()?,
+            };
+            let at = match prev {
+                Some(at) => at,
+                None => break None,
+            };
+            if let Some(dir) = &dir {
+                self.logs.entry(at.gen).or_insert_with(|| dir.clone());
+            }
+            cmd = self.read(&at)?;
+            check_value(&cmd, key, &at)?;
+            if let Command::SetStream(..) = cmd {
+                let mut value = Vec::new();
+                self.copy_value(key, &at, &mut value)?;
+                break Some(String::from_utf8(value)?);
+            }
+        };
+        let merge_operator = self.merge_operator;
+        let merge = |value: &mut Option<String>, operands: &mut Vec<String>| -> Result<()> {
+            if !operands.is_empty() {
+                let merge = merge_operator.ok_or(KvsError::MissingMergeOperator)?;
+                *value = Some(merge(value.as_deref(), operands));
+                operands.clear();
+            }
+            Ok(())
+        };
+        let mut value = base;
+        let mut operands = Vec::new();
+        for link in links.into_iter().rev() {
+            match link {
+                Link::Merge(operand) => operands.push(operand),
+                Link::Append(suffix) => {
+                    merge(&mut value, &mut operands)?;
+                    value.get_or_insert_with(String::new).push_str(&suffix);
                 }
-                _ => {}
             }
         }
-        Ok(None)
+        merge(&mut value, &mut operands)?;
+        Ok(value.unwrap_or_default())
     }
+
+    /// Copies the value of a key stored at the given position to `writer`.
     ///
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
-            let cmd = Command::Remove(key.clone());
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
-            self.index.remove(&key);
-            Ok(())
-        } else {
-            Err(KvsError::KeyNotFound)
+    /// A streamed value is copied one chunk at a time. Returns the number of bytes copied.
+    pub(crate) fn copy_value<W: Write>(
+        &mut self,
+        key: &str,
+        pos: &CommandPos,
+        writer: &mut W,
+    ) -> Result<u64> {
+        let (format, verify) = (self.format, self.verify_checksums);
+        let reader = self.reader(pos.gen)?;
+        reader
+            .seek(SeekFrom::Start(pos.start))
+            .map_err(at_record(pos.gen, pos.start))?;
+        let mut records = format.records(Read::take(&mut *reader, pos.len()));
+        let mut next =
+            || next_command(&mut records, verify, pos).map_err(at_record(pos.gen, pos.start));
+        let corruption = KvsError::Corruption {
+            gen: pos.gen,
+            offset: pos.start,
+        };
+        let cmd = match next()? {
+            Some(cmd) => cmd
+                .decompress(format)
+                .map_err(at_record(pos.gen, pos.start))?,
+            None => return Err(corruption),
+        };
+        check_value(&cmd, key, pos)?;
+        match cmd {
+            cmd @ (Command::Merge(..) | Command::Append(..)) => {
+                // The records of the log are read from again to resolve the chain.
+                drop(records);
+                let value = self.chained_value(key, pos, cmd)?;
+                writer.write_all(value.as_bytes())?;
+                Ok(value.len() as u64)
+            }
+            Command::SetStream(_, len) => {
+                let mut copied = 0;
+                while let Some(cmd) = next()? {
+                    match cmd {
+                        Command::Chunk(chunk) => {
+                            writer.write_all(&chunk)?;
+                            copied += chunk.len() as u64;
+                        }
+                        _ => return Err(corruption),
+                    }
+                }
+                if copied != len {
+                    return Err(corruption);
+                }
+                Ok(copied)
+            }
+            cmd => {
+                let value = cmd.into_bytes().unwrap_or_default();
+                writer.write_all(&value)?;
+                Ok(value.len() as u64)
+            }
+        }
+    }
+
+    /// Returns the length of the first command of a record at the given position, with
+    /// its checksum.
+    pub(crate) fn header_len(&mut self, pos: &CommandPos) -> Result<u64> {
+        let format = self.format;
+        let reader = self.reader(pos.gen)?;
+        reader
+            .seek(SeekFrom::Start(pos.start))
+            .map_err(at_record(pos.gen, pos.start))?;
+        for record in format.records(Read::take(&mut *reader, pos.len())) {
+            match record.map_err(at_record(pos.gen, pos.start))? {
+                Record::Command {
+                    cmd: Command::Checksum(_) | Command::Sequenced(..),
+                    ..
+                } => {}
+                Record::Command { end, .. } => return Ok(end),
+                Record::Broken(_) => break,
+            }
+        }
+        Err(KvsError::Corruption {
+            gen: pos.gen,
+            offset: pos.start,
+        })
+    }
+
+    fn read_record(&mut self, pos: &CommandPos) -> Result<Command> {
+        #[cfg(all(feature = "mmap", unix))]
+        if self.map_sealed(pos.gen)? {
+            let map = &self.maps.as_ref().unwrap()[&pos.gen];
+            let record = map
+                .get(pos.start as usize..pos.end as usize)
+                .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+            return self.decode_record(record, pos);
+        }
+        let reader = self.reader(pos.gen)?;
+        let mut record = Vec::with_capacity(pos.len() as usize);
+        read_exact_at(reader, pos.start, &mut record, pos.len())?;
+        self.decode_record(&record, pos)
+    }
+
+    /// Maps the log of a generation on its first read if memory maps are used and the
+    /// log is sealed, and returns whether it is mapped.
+    #[cfg(all(feature = "mmap", unix))]
+    fn map_sealed(&mut self, gen: u64) -> Result<bool> {
+        let maps = match &mut self.maps {
+            Some(maps) => maps,
+            None => return Ok(false),
+        };
+        if maps.contains_key(&gen) {
+            return Ok(true);
+        }
+        // The newest log may still be written to, so it is read through its reader.
+        if self
+            .logs
+            .keys()
+            .next_back()
+            .is_none_or(|&newest| gen >= newest)
+            || self.writing == Some(gen)
+        {
+            return Ok(false);
+        }
+        let dir = self
+            .logs
+            .get(&gen)
+            .ok_or(KvsError::MissingGeneration(gen))?;
+        // The map outlives the file it is made from, so it takes no reader.
+        maps.insert(gen, Mmap::map(&open_log(dir, gen)?)?);
+        Ok(true)
+    }
+
+    /// Decodes the record of a value read from the given position.
+    fn decode_record(&self, record: &[u8], pos: &CommandPos) -> Result<Command> {
+        let (cmd, mut rest) = self.decode_checked(record, pos)?;
+        match cmd {
+            // Assemble the chunks of a streamed value into a binary value.
+            Command::SetStream(key, len) => {
+                let corruption = KvsError::Corruption {
+                    gen: pos.gen,
+                    offset: pos.start,
+                };
+                let mut value = Vec::with_capacity(len as usize);
+                while !rest.is_empty() {
+                    let (cmd, next) = self.decode_checked(rest, pos)?;
+                    match cmd {
+                        Command::Chunk(chunk) => value.extend_from_slice(&chunk),
+                        _ => return Err(corruption),
+                    }
+                    rest = next;
+                }
+                if value.len() as u64 != len {
+                    return Err(corruption);
+                }
+                Ok(Command::SetBytes(key, value))
+            }
+            cmd => cmd.decompress(self.format),
         }
     }
+
+    /// Decodes the first record in the bytes, verifying its checksum if asked to.
     ///
-    fn compact(&mut self) -> Result<()> {
-        let compaction_gen = self.cur_gen + 1;
-        let compaction_file = self.folder.join(format!("{compaction_gen}.log"));
-        let mut compaction_writer = BufWriter::new(
-            OpenOptions::new()
-                .create_new(true)
-                .append(true)
-                .open(&compaction_file)?,
-        );
-        let mut pos = 0;
-        for (gen, start, end) in self.index.values_mut() {
-            let reader = self
-                .readers
-                .get_mut(gen)
-                .expect(&format!("unable to find reader for {gen}.log"));
-            reader.seek(SeekFrom::Start(*start))?;
-            let len = io::copy(&mut reader.take(*end - *start), &mut compaction_writer)?;
-            *gen = compaction_gen;
-            *start = pos;
-            pos += len;
-            *end = pos;
+    /// Returns the command and the bytes after its record.
+    fn decode_checked<'a>(&self, bytes: &'a [u8], pos: &CommandPos) -> Result<(Command, &'a [u8])> {
+        match self.format.decode_prefix(bytes)? {
+            (Command::Checksum(crc) | Command::Sequenced(crc, _), len) => {
+                let payload = &bytes[len..];
+                let (cmd, len) = self.format.decode_prefix(payload)?;
+                if self.verify_checksums && crc32fast::hash(&payload[..len]) != crc {
+                    return Err(KvsError::Corruption {
+                        gen: pos.gen,
+                        offset: pos.start,
+                    });
+                }
+                Ok((cmd, &payload[len..]))
+            }
+            (cmd, len) => Ok((cmd, &bytes[len..])),
         }
-        self.uncompacted = 0;
-        compaction_writer.flush()?;
-        for gen_id in self.readers.keys() {
-            fs::remove_file(self.folder.join(format!("{gen_id}.log")))?;
+    }
+}
+
+/// Returns the next command of the records of a log, verifying its checksum if asked to.
+///
+/// Returns `None` at the end of the records.
+fn next_command<I: Iterator<Item = Result<Record>>>(
+    records: &mut I,
+    verify: bool,
+    pos: &CommandPos,
+) -> Result<Option<Command>> {
+    let corruption = KvsError::Corruption {
+        gen: pos.gen,
+        offset: pos.start,
+    };
+    let mut expected = None;
+    loop {
+        match records.next().transpose()? {
+            None if expected.is_none() => return Ok(None),
+            None | Some(Record::Broken(_)) => return Err(corruption),
+            Some(Record::Command {
+                cmd: Command::Checksum(crc) | Command::Sequenced(crc, _),
+                ..
+            }) if expected.is_none() => expected = Some(crc),
+            Some(Record::Command { cmd, crc, .. }) => {
+                if verify && expected.is_some_and(|expected| expected != crc) {
+                    return Err(corruption);
+                }
+                return Ok(Some(cmd));
+            }
         }
-        self.cur_gen += 2;
-        self.writer = BufWriter::new(
-            OpenOptions::new()
-                .create_new(true)
-                .append(true)
-                .open(self.folder.join(format!("{}.log", self.cur_gen)))?,
-        );
-        self.readers = BTreeMap::new();
-        self.readers.insert(
-            compaction_gen,
-            BufReader::new(File::open(&compaction_file)?),
-        );
-        let file = self.folder.join(format!("{}.log", self.cur_gen));
-        self.readers
-            .insert(self.cur_gen, BufReader::new(File::open(file)?));
+    }
+}
+
+/// Writes the `len` bytes read from `reader` as the chunk records of a streamed value.
+///
+/// Fails with `UnexpectedEof` if the reader ends before `len` bytes.
+fn write_chunks<R: Read>(
+    format: Codec,
+    writer: &mut BufWriter<File>,
+    mut reader: R,
+    len: u64,
+) -> Result<()> {
+    let mut chunk = Vec::with_capacity(CHUNK_LEN.min(len as usize));
+    let mut buf = Vec::new();
+    let mut remaining = len;
+    while remaining > 0 {
+        let want = remaining.min(CHUNK_LEN as u64);
+        chunk.clear();
+        Read::take(&mut reader, want).read_to_end(&mut chunk)?;
+        if (chunk.len() as u64) < want {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        remaining -= want;
+        // The chunk buffer is moved into the command and back, to be reused.
+        let cmd = Command::Chunk(std::mem::take(&mut chunk));
+        buf.clear();
+        format.encode_checked(&cmd, &mut buf)?;
+        writer.write_all(&buf)?;
+        if let Command::Chunk(bytes) = cmd {
+            chunk = bytes;
+        }
+    }
+    Ok(())
+}
+
+/// Decodes the command of a record, skipping its checksum without verifying it.
+fn decode_command(format: Codec, record: &[u8]) -> Result<Command> {
+    match format.decode_prefix(record)? {
+        (Command::Checksum(_) | Command::Sequenced(..), len) => {
+            Ok(format.decode_prefix(&record[len..])?.0)
+        }
+        (cmd, _) => Ok(cmd),
+    }
+}
+
+/// Checks that a command read from the given position is a value of the key.
+fn check_value(cmd: &Command, key: &str, pos: &CommandPos) -> Result<()> {
+    if cmd.key() == Some(key) {
         Ok(())
+    } else {
+        Err(KvsError::UnexpectedCommandType {
+            key: key.to_owned(),
+            gen: pos.gen,
+            offset: pos.start,
+        })
+    }
+}
+
+/// Returns the current time in milliseconds since the Unix epoch.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Returns a function adding the generation and offset of a record to an error reading it.
+///
+/// Errors that already say which log they are about are kept as they are.
+pub(crate) fn at_record<E: Into<KvsError>>(gen: u64, offset: u64) -> impl FnOnce(E) -> KvsError {
+    move |e| match e.into() {
+        e @ (KvsError::Corruption { .. }
+        | KvsError::LogRead { .. }
+        | KvsError::MissingGeneration(_)) => e,
+        e => KvsError::LogRead {
+            gen,
+            offset,
+            cause: Box::new(e),
+        },
+    }
+}
+
+/// Returns the generations of the logs in a store directory, in order.
+pub(crate) fn list_gens(dir: &Path) -> Result<Vec<u64>> {
+    let mut gens: Vec<u64> = fs::read_dir(dir)?
+        .flat_map(|file| -> Result<_> { Ok(file?.path()) })
+        .filter(|f| f.is_file() && f.extension() == Some("log".as_ref()))
+        .flat_map(|path| {
+            path.file_name()
+                .and_then(OsStr::to_str)
+                .map(|f| f.trim_end_matches(".log"))
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .collect();
+    gens.sort_unstable();
+    Ok(gens)
+}
+
+/// Returns the path of the log file for the given generation.
+pub(crate) fn log_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{gen}.log"))
+}
+
+/// Opens the log of the given generation for reading.
+///
+/// Returns `KvsError::MissingGeneration` if there is no such log.
+pub(crate) fn open_log(dir: &Path, gen: u64) -> Result<File> {
+    File::open(log_path(dir, gen)).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => KvsError::MissingGeneration(gen),
+        _ => e.into(),
+    })
+}
+
+/// Opens the log of a generation in a store directory, or in its `pinned` subdirectory
+/// if it was retired while a snapshot reads it.
+fn open_pinned_log(dir: &Path, gen: u64) -> Result<File> {
+    match open_log(dir, gen) {
+        Err(KvsError::MissingGeneration(_)) => open_log(&dir.join(PINNED_DIR), gen),
+        opened => opened,
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Creates a new log file with the given generation and adds it to the readers.
+///
+/// Returns the writer of the new log.
+fn new_log_file(dir: &Path, gen: u64, readers: &mut LogReaders) -> Result<BufWriter<File>> {
+    let writer = create_log(dir, gen)?;
+    readers.add(dir, gen);
+    Ok(writer)
+}
+
+/// Syncs the entries of a directory to disk, so the files created in it survive a crash.
+///
+/// Directories cannot be opened as files on Windows, where this does nothing.
+fn sync_dir(dir: &Path) -> Result<()> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+/// Creates a new log file with the given generation, and returns its writer.
+pub(crate) fn create_log(dir: &Path, gen: u64) -> Result<BufWriter<File>> {
+    Ok(BufWriter::new(
+        OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(log_path(dir, gen))?,
+    ))
+}
+
+/// A record persisted in the log.
+///
+/// Most commands are changes, which [`KvStore::raw_commands`] reads back out of the logs
+/// and [`KvStore::apply`] writes to a store, to replicate one store into another. The
+/// others only frame the records of a log, and are never read back out.
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[non_exhaustive]
 pub enum Command {
+    /// Sets a key to a string value.
     #[serde(rename = "S")]
     Set(String, String),
+    /// Removes a key.
     #[serde(rename = "R")]
     Remove(String),
+    /// Header of a batch of the given number of commands that follow it.
+    #[serde(rename = "B")]
+    Batch(usize),
+    /// `Set` with an expiry time in milliseconds since the Unix epoch.
+    #[serde(rename = "X")]
+    SetWithExpiry(String, String, u64),
+    /// `Set` with a binary value.
+    #[serde(rename = "Y")]
+    SetBytes(String, #[serde(with = "bytes_format")] Vec<u8>),
+    /// `Set` with a typed value.
+    #[serde(rename = "J")]
+    SetJson(String, #[serde(with = "json_format")] serde_json::Value),
+    /// CRC32 checksum of the record that follows it.
+    #[serde(rename = "C")]
+    Checksum(u32),
+    /// Removes every key written before it.
+    #[serde(rename = "Z")]
+    Clear,
+    /// Header of a binary value of the given length, streamed in the `Chunk` records
+    /// that follow it.
+    #[serde(rename = "L")]
+    SetStream(String, u64),
+    /// Part of a streamed value.
+    #[serde(rename = "K")]
+    Chunk(#[serde(with = "bytes_format")] Vec<u8>),
+    /// The compressed record of another command, written by compactions.
+    #[serde(rename = "D")]
+    Compressed(#[serde(with = "bytes_format")] Vec<u8>),
+    /// Operand to merge into the value of a key, with the position of the previous
+    /// record of the key, if any.
+    #[serde(rename = "M")]
+    Merge(String, String, Option<CommandPos>),
+    /// Suffix to append to the value of a key, with the position of the previous record
+    /// of the key, if any, and the length of the value with the suffix appended.
+    #[serde(rename = "A")]
+    Append(String, String, Option<CommandPos>, u64),
+    /// CRC32 checksum of the record that follows it, which is the change of the given
+    /// sequence number.
+    #[serde(rename = "N")]
+    Sequenced(u32, u64),
+    /// The encrypted record of another command, in an encrypted store.
+    #[serde(rename = "E")]
+    Encrypted(#[serde(with = "bytes_format")] Vec<u8>),
+}
+
+impl Command {
+    /// Returns the command a `Compressed` record stands for, and any other command as it is.
+    pub(crate) fn decompress(self, format: Codec) -> Result<Command> {
+        match self {
+            Command::Compressed(bytes) => {
+                let record = crate::compress::decompress(&bytes)?;
+                Ok(format.format.decode_prefix(&record)?.0)
+            }
+            cmd => Ok(cmd),
+        }
+    }
+
+    /// Returns the key of a command setting a value.
+    pub(crate) fn key(&self) -> Option<&str> {
+        match self {
+            Command::Set(key, _)
+            | Command::SetWithExpiry(key, _, _)
+            | Command::SetBytes(key, _)
+            | Command::SetJson(key, _)
+            | Command::SetStream(key, _)
+            | Command::Merge(key, _, _)
+            | Command::Append(key, ..) => Some(key),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if the command changes keys, and so has a sequence number.
+    pub(crate) fn is_change(&self) -> bool {
+        matches!(self, Command::Remove(_) | Command::Clear) || self.key().is_some()
+    }
+
+    /// Returns the string value written by a `Set` command.
+    pub(crate) fn into_value(self) -> Result<Option<String>> {
+        match self {
+            Command::Set(_, value) | Command::SetWithExpiry(_, value, _) => Ok(Some(value)),
+            Command::SetBytes(_, value) => Ok(Some(String::from_utf8(value)?)),
+            Command::SetJson(_, value) => Ok(Some(value.to_string())),
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the value written by a `Set` command as bytes.
+    pub(crate) fn into_bytes(self) -> Option<Vec<u8>> {
+        match self {
+            Command::Set(_, value) | Command::SetWithExpiry(_, value, _) => {
+                Some(value.into_bytes())
+            }
+            Command::SetBytes(_, value) => Some(value),
+            Command::SetJson(_, value) => Some(value.to_string().into_bytes()),
+            _ => None,
+        }
+    }
+
+    /// Returns the value written by a `Set` command as a JSON value.
+    fn into_json(self) -> Option<serde_json::Value> {
+        match self {
+            Command::Set(_, value) | Command::SetWithExpiry(_, value, _) => {
+                Some(serde_json::Value::String(value))
+            }
+            Command::SetBytes(_, value) => Some(value.into()),
+            Command::SetJson(_, value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+/// Serializes binary values as base64 in human-readable formats, and as raw bytes otherwise.
+pub(crate) mod bytes_format {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&STANDARD.encode(bytes))
+        } else {
+            serializer.serialize_bytes(bytes)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        if deserializer.is_human_readable() {
+            let encoded = String::deserialize(deserializer)?;
+            STANDARD.decode(encoded).map_err(D::Error::custom)
+        } else {
+            Vec::deserialize(deserializer)
+        }
+    }
+}
+
+/// Serializes typed values as JSON values in human-readable formats, and as JSON text
+/// otherwise, since binary formats cannot deserialize a self-describing value.
+mod json_format {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use serde_json::Value;
+
+    pub fn serialize<S: Serializer>(value: &Value, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            value.serialize(serializer)
+        } else {
+            serializer.serialize_str(&value.to_string())
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Value, D::Error> {
+        if deserializer.is_human_readable() {
+            Value::deserialize(deserializer)
+        } else {
+            let text = String::deserialize(deserializer)?;
+            serde_json::from_str(&text).map_err(D::Error::custom)
+        }
+    }
+}
+
+/// Position of a `Set` command in the log, together with the expiry of its key.
+///
+/// Positions are only meaningful to the store whose log they are in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct CommandPos {
+    pub(crate) gen: u64,
+    pub(crate) start: u64,
+    pub(crate) end: u64,
+    pub(crate) expires_at: Option<u64>,
+}
+
+impl CommandPos {
+    pub(crate) fn len(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub(crate) fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
 }
@@ -1,8 +1,99 @@
 #![deny(missing_docs)]
 //! A simple key/value store.
 
+#[cfg(feature = "async")]
+pub use async_client::AsyncKvsClient;
+#[cfg(feature = "async")]
+pub use async_store::AsyncKvStore;
+pub use batch::WriteBatch;
+pub use batched::Batched;
+pub use bench::{
+    BenchReport, BenchTarget, KeyOrder, Latencies, LatencySummary, Op, Ops, SizeDistribution,
+    Workload,
+};
+pub use changes::{Change, ChangeEvent, Changes, RawCommands};
+pub use client::{KeyScan, KvsClient, KvsClientBuilder, Pipeline};
+pub use client_pool::{Exhausted, KvsClientPool, KvsClientPoolBuilder, PooledClient};
+pub use csv::{CsvOptions, CsvRowError};
+pub use cursor::Cursor;
+#[cfg(feature = "encryption")]
+pub use encrypt::EncryptionKey;
+#[cfg(feature = "sled")]
+pub use engines::SledKvsEngine;
+pub use engines::{engine_of, KvsEngine, MemKvsEngine};
+pub use entry::{Entry, OccupiedEntry, VacantEntry};
 pub use error::{KvsError, Result};
-pub use kv::KvStore;
+pub use export::ImportReport;
+pub use format::LogFormat;
+pub use history::VersionedValue;
+pub use kv::{Command, CommandPos, KvStore, Scan};
+pub use merge::MergeOperator;
+pub use namespace::Namespace;
+pub use options::{CorruptionPolicy, Duplicates, Durability, KvStoreBuilder, Options, Retention};
+pub use protocol::Health;
+pub use raw::RawScan;
+pub use repair::RepairReport;
+pub use server::{KvsServer, Protocol, ShutdownHandle};
+pub use shared::SharedKvStore;
+pub use stats::{CompactionReport, EngineStats, PurgeReport, SizeStats, SkippedRegion, StoreStats};
+pub use thread_pool::{SharedQueueThreadPool, ThreadPool};
+#[cfg(feature = "tls")]
+pub use tls::{ClientTlsConfig, ServerTlsConfig};
+pub use transaction::Transaction;
+pub use verify::{VerifyProblem, VerifyReport};
+pub use view::Snapshot;
+pub use watch::{WatchEvent, WatchHandle};
 
+#[macro_use]
+mod trace;
+
+mod archive;
+#[cfg(feature = "async")]
+mod async_client;
+#[cfg(feature = "async")]
+mod async_store;
+mod auth;
+mod batch;
+mod batched;
+mod bench;
+mod cache;
+mod changes;
+mod client;
+mod client_pool;
+mod compress;
+mod csv;
+mod cursor;
+mod disk_index;
+mod encrypt;
+mod engines;
+mod entry;
 mod error;
+mod export;
+mod format;
+mod hint;
+mod history;
+mod index;
 mod kv;
+mod manifest;
+mod merge;
+mod metrics;
+#[cfg(all(feature = "mmap", unix))]
+mod mmap;
+mod namespace;
+mod options;
+mod protocol;
+mod raw;
+mod repair;
+mod resp;
+mod server;
+mod shared;
+mod snapshot;
+mod stats;
+mod stream;
+mod thread_pool;
+#[cfg(feature = "tls")]
+mod tls;
+mod transaction;
+mod verify;
+mod view;
+mod watch;
@@ -0,0 +1,121 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::engines::{Engine, ENGINE_FILE};
+use crate::kv::{lock_dir, LOCK_FILE};
+use crate::{KvsError, Result};
+
+/// Name of the file recording the on-disk format version of a store and its engine.
+pub(crate) const MANIFEST_FILE: &str = "MANIFEST";
+
+/// Name of the directory holding copies of the files of a store from before each of its
+/// migrations.
+pub(crate) const MIGRATION_DIR: &str = "migration";
+
+/// On-disk format version of the stores created by this version of kvs.
+///
+/// Version 0 is a store from before the manifest, and version 1 one with it.
+pub(crate) const FORMAT_VERSION: u32 = 1;
+
+/// Writes the manifest of a store owned by the given engine, at the current version.
+pub(crate) fn write_manifest(dir: &Path, engine: &str) -> Result<()> {
+    // Write to a temporary file first, so a crash never leaves a partial manifest behind.
+    let tmp_path = dir.join(format!("{MANIFEST_FILE}.tmp"));
+    fs::write(
+        &tmp_path,
+        format!("version {FORMAT_VERSION}\nengine {engine}\n"),
+    )?;
+    fs::rename(tmp_path, dir.join(MANIFEST_FILE))?;
+    Ok(())
+}
+
+/// Reads the manifest of a store, returning its format version and engine.
+///
+/// A store without a manifest is of version 0, and its engine is only in the engine file.
+pub(crate) fn read_manifest(dir: &Path) -> Result<(u32, Option<String>)> {
+    let text = match fs::read_to_string(dir.join(MANIFEST_FILE)) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((0, None)),
+        Err(e) => return Err(e.into()),
+    };
+    let mut version = None;
+    let mut engine = None;
+    for line in text.lines() {
+        match line.split_once(' ') {
+            Some(("version", value)) => version = value.parse::<u32>().ok(),
+            Some(("engine", value)) => engine = Some(value.to_owned()),
+            // Lines a later version adds are left to its version number to reject.
+            _ => {}
+        }
+    }
+    match version {
+        Some(version) => Ok((version, engine)),
+        None => Err(io::Error::new(io::ErrorKind::InvalidData, "Manifest has no version").into()),
+    }
+}
+
+/// Returns `KvsError::UnsupportedVersion` if a store is of a newer version than the
+/// current one.
+pub(crate) fn check_version(version: u32) -> Result<()> {
+    if version > FORMAT_VERSION {
+        return Err(KvsError::UnsupportedVersion {
+            found: version,
+            supported: FORMAT_VERSION,
+        });
+    }
+    Ok(())
+}
+
+/// Upgrades a closed store in place, one version at a time, to the current version.
+pub(crate) fn migrate(dir: &Path) -> Result<()> {
+    if !dir.is_dir() {
+        return Err(KvsError::StoreNotFound(dir.to_owned()));
+    }
+    let _lock = lock_dir(dir)?;
+    loop {
+        let (version, _) = read_manifest(dir)?;
+        check_version(version)?;
+        if version == FORMAT_VERSION {
+            return Ok(());
+        }
+        back_up(dir, version)?;
+        // A store from before the manifest gets one, recording the engine owning it.
+        let engine = match fs::read_to_string(dir.join(ENGINE_FILE)) {
+            Ok(name) => name.trim().to_owned(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Engine::Kvs.to_string(),
+            Err(e) => return Err(e.into()),
+        };
+        write_manifest(dir, &engine)?;
+    }
+}
+
+/// Copies the files of a store directory into the migration directory, before the store
+/// is migrated from `version`.
+///
+/// A copy left by a migration that did not finish is kept, as it holds the files from
+/// before that migration.
+fn back_up(dir: &Path, version: u32) -> Result<()> {
+    let backups = dir.join(MIGRATION_DIR);
+    let backup = backups.join(format!("v{version}"));
+    if backup.exists() {
+        return Ok(());
+    }
+    // Copy to a temporary directory first, so a crash never leaves a partial copy behind.
+    let tmp_path = backups.join(format!("v{version}.tmp"));
+    if tmp_path.exists() {
+        fs::remove_dir_all(&tmp_path)?;
+    }
+    fs::create_dir_all(&tmp_path)?;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        match path.file_name() {
+            Some(name) if path.is_file() && name != LOCK_FILE => {
+                fs::copy(&path, tmp_path.join(name))?;
+            }
+            _ => {}
+        }
+    }
+    fs::rename(tmp_path, backup)?;
+    Ok(())
+}
@@ -0,0 +1,44 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::Result;
+
+/// Name of the file marking that the logs of a store may hold merge records.
+pub(crate) const MERGES_FILE: &str = "merges";
+
+/// A function merging operands into the value of a key, registered with
+/// [`Options::merge_operator`].
+///
+/// It is given the value the key had before the operands were merged into it, `None`
+/// if it had none, and the operands, oldest first. It returns the merged value, and has
+/// to return the same value whenever it is given the same arguments, as the merge
+/// records of a key are resolved on every read until a compaction replaces them.
+///
+/// [`Options::merge_operator`]: crate::Options::merge_operator
+pub type MergeOperator = fn(Option<&str>, &[String]) -> String;
+
+fn merges_path(dir: &Path) -> PathBuf {
+    dir.join(MERGES_FILE)
+}
+
+/// Returns whether the logs of a store may hold merge records.
+pub(crate) fn has_merges(dir: &Path) -> bool {
+    merges_path(dir).exists()
+}
+
+/// Marks that the logs of a store may hold merge records, before the first one is
+/// written, so the store is never opened without a merge operator while they do.
+pub(crate) fn mark_merges(dir: &Path) -> Result<()> {
+    fs::write(merges_path(dir), "")?;
+    Ok(())
+}
+
+/// Removes the mark once the logs of a store hold no merge records, which is the case
+/// after a compaction.
+pub(crate) fn unmark_merges(dir: &Path) -> Result<()> {
+    match fs::remove_file(merges_path(dir)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
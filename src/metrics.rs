@@ -0,0 +1,426 @@
+//! Metrics of a server, served in the Prometheus text format over HTTP.
+//!
+//! They are only counted with the `metrics` feature, and compile to nothing otherwise.
+//! Counting a request or the bytes of a read or write is a relaxed atomic addition, so
+//! it costs next to nothing next to serving it.
+
+use std::io::{self, Read, Write};
+#[cfg(feature = "metrics")]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "metrics")]
+use std::sync::Arc;
+
+/// The type of a command a server processed, counted by `kvs_commands_total`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CommandType {
+    Get,
+    Set,
+    Remove,
+    Scan,
+    Exists,
+    Del,
+    Keys,
+    DbSize,
+    Ping,
+    Health,
+    Auth,
+}
+
+/// The type of an error a server answered with, or that ended a connection, counted by
+/// `kvs_errors_total`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ErrorType {
+    /// A key to remove was not found.
+    KeyNotFound,
+    /// A request or command the server does not know.
+    Unsupported,
+    /// A command with the wrong arguments.
+    Invalid,
+    /// A request the engine failed to handle.
+    Failed,
+    /// A connection that ended with an error, like a malformed request.
+    Connection,
+    /// A connection that sent a wrong auth token, or a request before its token.
+    Unauthorized,
+}
+
+#[cfg(feature = "metrics")]
+impl CommandType {
+    const ALL: [CommandType; 11] = [
+        CommandType::Get,
+        CommandType::Set,
+        CommandType::Remove,
+        CommandType::Scan,
+        CommandType::Exists,
+        CommandType::Del,
+        CommandType::Keys,
+        CommandType::DbSize,
+        CommandType::Ping,
+        CommandType::Health,
+        CommandType::Auth,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            CommandType::Get => "get",
+            CommandType::Set => "set",
+            CommandType::Remove => "remove",
+            CommandType::Scan => "scan",
+            CommandType::Exists => "exists",
+            CommandType::Del => "del",
+            CommandType::Keys => "keys",
+            CommandType::DbSize => "dbsize",
+            CommandType::Ping => "ping",
+            CommandType::Health => "health",
+            CommandType::Auth => "auth",
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl ErrorType {
+    const ALL: [ErrorType; 6] = [
+        ErrorType::KeyNotFound,
+        ErrorType::Unsupported,
+        ErrorType::Invalid,
+        ErrorType::Failed,
+        ErrorType::Connection,
+        ErrorType::Unauthorized,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            ErrorType::KeyNotFound => "key_not_found",
+            ErrorType::Unsupported => "unsupported",
+            ErrorType::Invalid => "invalid",
+            ErrorType::Failed => "failed",
+            ErrorType::Connection => "connection",
+            ErrorType::Unauthorized => "unauthorized",
+        }
+    }
+}
+
+/// The counters of a server, shared by its connections.
+#[cfg(feature = "metrics")]
+#[derive(Debug, Default)]
+pub(crate) struct ServerMetrics {
+    commands: [AtomicU64; CommandType::ALL.len()],
+    errors: [AtomicU64; ErrorType::ALL.len()],
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    connections: AtomicU64,
+    active_connections: AtomicU64,
+}
+
+/// The counters of a server, which count nothing without the `metrics` feature.
+#[cfg(not(feature = "metrics"))]
+#[derive(Debug, Default)]
+pub(crate) struct ServerMetrics;
+
+#[cfg(feature = "metrics")]
+impl ServerMetrics {
+    pub(crate) fn command(&self, command: CommandType) {
+        self.commands[command as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn error(&self, error: ErrorType) {
+        self.errors[error as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn read(&self, bytes: usize) {
+        self.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn written(&self, bytes: usize) {
+        self.bytes_written
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a connection as active until the guard returned is dropped.
+    pub(crate) fn connected(self: &Arc<Self>) -> Connected {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        Connected(Arc::clone(self))
+    }
+
+    /// Renders the counters, and the statistics of the engine if it keeps any, in the
+    /// Prometheus text format.
+    pub(crate) fn render(&self, engine: Option<crate::EngineStats>) -> String {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let mut out = String::new();
+        family(
+            &mut out,
+            "kvs_commands_total",
+            "counter",
+            "Commands processed, by type.",
+        );
+        for command in CommandType::ALL {
+            out += &format!(
+                "kvs_commands_total{{command=\"{}\"}} {}\n",
+                command.label(),
+                load(&self.commands[command as usize])
+            );
+        }
+        family(
+            &mut out,
+            "kvs_errors_total",
+            "counter",
+            "Error responses, and connections ended by an error, by type.",
+        );
+        for error in ErrorType::ALL {
+            out += &format!(
+                "kvs_errors_total{{type=\"{}\"}} {}\n",
+                error.label(),
+                load(&self.errors[error as usize])
+            );
+        }
+        let mut sample = |name: &str, kind: &str, help: &str, value: u64| {
+            family(&mut out, name, kind, help);
+            out += &format!("{} {}\n", name, value);
+        };
+        sample(
+            "kvs_read_bytes_total",
+            "counter",
+            "Bytes read from clients.",
+            load(&self.bytes_read),
+        );
+        sample(
+            "kvs_written_bytes_total",
+            "counter",
+            "Bytes written to clients.",
+            load(&self.bytes_written),
+        );
+        sample(
+            "kvs_connections_total",
+            "counter",
+            "Connections accepted.",
+            load(&self.connections),
+        );
+        sample(
+            "kvs_active_connections",
+            "gauge",
+            "Connections open.",
+            load(&self.active_connections),
+        );
+        if let Some(stats) = engine {
+            sample(
+                "kvs_live_keys",
+                "gauge",
+                "Keys that have not expired.",
+                stats.live_keys,
+            );
+            sample(
+                "kvs_disk_bytes",
+                "gauge",
+                "Total size of the log files.",
+                stats.disk_bytes,
+            );
+            sample(
+                "kvs_uncompacted_bytes",
+                "gauge",
+                "Bytes of stale records a compaction would reclaim.",
+                stats.uncompacted_bytes,
+            );
+            sample(
+                "kvs_compactions_total",
+                "counter",
+                "Compactions finished since the store was opened.",
+                stats.compactions,
+            );
+        }
+        out
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+impl ServerMetrics {
+    pub(crate) fn command(&self, _command: CommandType) {}
+
+    pub(crate) fn error(&self, _error: ErrorType) {}
+
+    fn read(&self, _bytes: usize) {}
+
+    fn written(&self, _bytes: usize) {}
+
+    pub(crate) fn connected(&self) -> Connected {
+        Connected
+    }
+}
+
+/// Writes the help and type lines of a metric family.
+#[cfg(feature = "metrics")]
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    *out += &format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind);
+}
+
+/// Counts a connection as active until it is dropped.
+#[cfg(feature = "metrics")]
+pub(crate) struct Connected(Arc<ServerMetrics>);
+
+/// Counts a connection as active until it is dropped.
+#[cfg(not(feature = "metrics"))]
+pub(crate) struct Connected;
+
+#[cfg(feature = "metrics")]
+impl Drop for Connected {
+    fn drop(&mut self) {
+        self.0.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A stream of a connection counting the bytes read from and written to it.
+pub(crate) struct Counted<'a, S> {
+    stream: S,
+    metrics: &'a ServerMetrics,
+}
+
+impl<'a, S> Counted<'a, S> {
+    pub(crate) fn new(stream: S, metrics: &'a ServerMetrics) -> Self {
+        Self { stream, metrics }
+    }
+}
+
+impl<S: Read> Read for Counted<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.stream.read(buf)?;
+        self.metrics.read(read);
+        Ok(read)
+    }
+}
+
+impl<S: Write> Write for Counted<'_, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.stream.write(buf)?;
+        self.metrics.written(written);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(feature = "metrics")]
+pub(crate) use self::exporter::Exporter;
+
+/// The HTTP endpoint serving the metrics.
+#[cfg(feature = "metrics")]
+mod exporter {
+    use std::io::{self, BufRead, BufReader, Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread::{self, JoinHandle};
+    use std::time::Duration;
+
+    use log::{debug, warn};
+
+    use super::ServerMetrics;
+    use crate::server::wake_addr;
+    use crate::KvsEngine;
+
+    /// How long a scrape may take to send its request or read the response.
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Most bytes of a request read, headers included.
+    const MAX_REQUEST: u64 = 8 * 1024;
+
+    /// The thread answering scrapes of `/metrics`, one at a time, until it is stopped.
+    pub(crate) struct Exporter {
+        addr: SocketAddr,
+        stopped: Arc<AtomicBool>,
+        thread: JoinHandle<()>,
+    }
+
+    impl Exporter {
+        /// Serves the metrics, and the statistics of a clone of the engine, on the
+        /// listener.
+        pub(crate) fn start<E: KvsEngine + Send + 'static>(
+            listener: TcpListener,
+            mut engine: E,
+            metrics: Arc<ServerMetrics>,
+        ) -> io::Result<Self> {
+            let addr = listener.local_addr()?;
+            let stopped = Arc::new(AtomicBool::new(false));
+            let stop = Arc::clone(&stopped);
+            let thread = thread::Builder::new()
+                .name("kvs-metrics".to_owned())
+                .spawn(move || {
+                    for stream in listener.incoming() {
+                        if stop.load(Ordering::SeqCst) {
+                            return;
+                        }
+                        let served =
+                            stream.and_then(|stream| answer(&stream, &mut engine, &metrics));
+                        if let Err(e) = served {
+                            debug!("Failed to answer a scrape of the metrics: {}", e);
+                        }
+                    }
+                })?;
+            Ok(Self {
+                addr,
+                stopped,
+                thread,
+            })
+        }
+
+        /// Stops answering scrapes, and waits for the thread to drop its engine.
+        pub(crate) fn stop(self) {
+            self.stopped.store(true, Ordering::SeqCst);
+            if let Err(e) = TcpStream::connect(wake_addr(self.addr)) {
+                warn!(
+                    "Failed to wake the metrics endpoint on {} up: {}",
+                    self.addr, e
+                );
+            }
+            let _ = self.thread.join();
+        }
+    }
+
+    /// Answers an HTTP request for `/metrics`, closing the connection after.
+    fn answer<E: KvsEngine>(
+        stream: &TcpStream,
+        engine: &mut E,
+        metrics: &ServerMetrics,
+    ) -> io::Result<()> {
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        let mut reader = BufReader::new(stream.take(MAX_REQUEST));
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        // The headers are read, so closing the connection does not reset it before the
+        // client reads the response.
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 0 && !header.trim().is_empty() {
+            header.clear();
+        }
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or("");
+        let path = parts.next().unwrap_or("").split('?').next().unwrap();
+        let (status, content_type, body) = match (method, path) {
+            ("GET", "/metrics") => {
+                let stats = engine.engine_stats().unwrap_or_else(|e| {
+                    warn!("Failed to read the statistics of the engine: {}", e);
+                    None
+                });
+                ("200 OK", "text/plain; version=0.0.4", metrics.render(stats))
+            }
+            (_, "/metrics") => (
+                "405 Method Not Allowed",
+                "text/plain",
+                "Only GET is allowed\n".to_owned(),
+            ),
+            _ => ("404 Not Found", "text/plain", "Not found\n".to_owned()),
+        };
+        let mut writer = stream;
+        write!(
+            writer,
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            content_type,
+            body.len(),
+            body
+        )?;
+        writer.flush()
+    }
+}
@@ -0,0 +1,118 @@
+use std::borrow::Cow;
+
+use crate::{KvStore, KvsError, Result, Scan, WriteBatch};
+
+/// Character the keys of a namespace start with, and that ends the namespace name.
+///
+/// Keys of the default namespace are stored as they are, so keys starting with it are
+/// reserved for namespaces.
+const NAMESPACE_MARK: char = '\0';
+
+/// Returns the namespace a stored key is in, `None` for the default namespace.
+pub(crate) fn namespace_of(key: &str) -> Option<&str> {
+    let (name, _) = key
+        .strip_prefix(NAMESPACE_MARK)?
+        .split_once(NAMESPACE_MARK)?;
+    // Byte keys are stored with an empty name.
+    Some(name).filter(|name| !name.is_empty())
+}
+
+/// A handle on the keys of a single namespace of a store.
+///
+/// This is created by [`KvStore::namespace`]. Its keys are stored with the namespace as
+/// a prefix the handle adds and strips, so the same key in two namespaces, or in a
+/// namespace and the default one, names two values. The methods of the store itself see
+/// the keys of every namespace, as stored.
+///
+/// [`KvStore::namespace`]: crate::KvStore::namespace
+pub struct Namespace<'a> {
+    store: &'a mut KvStore,
+    prefix: String,
+}
+
+impl<'a> Namespace<'a> {
+    /// Returns the namespace of the given name in `store`.
+    ///
+    /// Returns `KvsError::InvalidNamespace` if the name is empty or contains a NUL.
+    pub(crate) fn new(store: &'a mut KvStore, name: &str) -> Result<Self> {
+        if name.is_empty() || name.contains(NAMESPACE_MARK) {
+            return Err(KvsError::InvalidNamespace(name.to_owned()));
+        }
+        Ok(Self {
+            store,
+            prefix: format!("{}{}{}", NAMESPACE_MARK, name, NAMESPACE_MARK),
+        })
+    }
+    /// Returns the name of the namespace.
+    pub fn name(&self) -> &str {
+        &self.prefix[1..self.prefix.len() - 1]
+    }
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+    /// Sets the value of a string key to a string.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        let key = self.key(&key);
+        self.store.set(key, value)
+    }
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist or has expired.
+    pub fn get<K: AsRef<str>>(&mut self, key: K) -> Result<Option<String>> {
+        let key = self.key(key.as_ref());
+        self.store.get(key)
+    }
+    /// Removes a given key.
+    ///
+    /// Returns `KvsError::KeyNotFound` if the given key is not found.
+    pub fn remove<K: AsRef<str>>(&mut self, key: K) -> Result<()> {
+        let key = self.key(key.as_ref());
+        self.store.remove(key)
+    }
+    /// Returns `true` if the namespace contains the given key.
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.store.contains_key(&self.key(key))
+    }
+    /// Returns an iterator over the keys of the namespace in sorted order.
+    pub fn keys(&self) -> impl Iterator<Item = Cow<'_, str>> {
+        let strip = self.prefix.len();
+        self.store
+            .prefixed_keys(&self.prefix)
+            .map(move |key| match key {
+                Cow::Borrowed(key) => Cow::Borrowed(&key[strip..]),
+                Cow::Owned(key) => Cow::Owned(key[strip..].to_owned()),
+            })
+    }
+    /// Returns the number of keys in the namespace.
+    pub fn len(&self) -> usize {
+        self.store.prefixed_keys(&self.prefix).count()
+    }
+    /// Returns `true` if the namespace contains no keys.
+    pub fn is_empty(&self) -> bool {
+        self.store.prefixed_keys(&self.prefix).next().is_none()
+    }
+    /// Returns an iterator over the key/value pairs of the namespace whose key starts
+    /// with `prefix`, in key order.
+    ///
+    /// An empty prefix matches every key of the namespace.
+    pub fn scan_prefix(&mut self, prefix: &str) -> Scan<'_> {
+        let strip = self.prefix.len();
+        let prefix = self.key(prefix);
+        self.store.scan_stripped(prefix, strip)
+    }
+    /// Returns an iterator over all live key/value pairs of the namespace, in key order.
+    pub fn iter(&mut self) -> Scan<'_> {
+        self.scan_prefix("")
+    }
+    /// Removes every key of the namespace at once, leaving the other namespaces as
+    /// they are.
+    ///
+    /// The tombstones of the keys are written as a single batch.
+    pub fn clear(&mut self) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for key in self.store.prefixed_keys(&self.prefix) {
+            batch.delete(key.into_owned());
+        }
+        self.store.write_batch(batch)
+    }
+}
@@ -0,0 +1,405 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::encrypt::Cipher;
+#[cfg(feature = "encryption")]
+use crate::EncryptionKey;
+use crate::{KvStore, LogFormat, MergeOperator, Result, SharedKvStore};
+
+/// Tuning options of a store, given to [`KvStore::open_with`].
+///
+/// [`KvStore::open_with`]: crate::KvStore::open_with
+// Not comparable, as the merge operator is a function pointer.
+#[derive(Clone, Copy, Debug)]
+pub struct Options {
+    /// Number of stale bytes in the logs above which a compaction runs automatically.
+    ///
+    /// `u64::MAX` disables automatic compaction, leaving it to [`KvStore::compact`].
+    /// `0` compacts after every write that leaves any stale bytes behind.
+    ///
+    /// [`KvStore::compact`]: crate::KvStore::compact
+    pub compaction_threshold: u64,
+    /// Size at which the active log is closed and writes move on to a new log file.
+    pub max_segment_size: u64,
+    /// Whether the store directory is created if it does not exist.
+    pub create_if_missing: bool,
+    /// When writes are synced to disk.
+    pub durability: Durability,
+    /// What opening the store does with records of the logs that do not read.
+    pub on_corruption: CorruptionPolicy,
+    /// Whether automatic compactions run on a background thread.
+    ///
+    /// Otherwise the write that crosses the compaction threshold compacts the logs
+    /// before it returns. Either way, [`KvStore::compact`] compacts synchronously.
+    ///
+    /// [`KvStore::compact`]: crate::KvStore::compact
+    pub background_compaction: bool,
+    /// Whether the store is opened for reading only, like [`KvStore::open_read_only`] does.
+    ///
+    /// [`KvStore::open_read_only`]: crate::KvStore::open_read_only
+    pub read_only: bool,
+    /// Whether logs replaced by a compaction are moved to the `archive` subdirectory
+    /// instead of being removed, and how long they are kept there.
+    ///
+    /// Archived logs are what [`KvStore::restore_to_generation`] restores from.
+    ///
+    /// [`KvStore::restore_to_generation`]: crate::KvStore::restore_to_generation
+    pub archive: Option<Retention>,
+    /// Number of the latest versions of every key a compaction keeps, counting the live
+    /// one, for [`KvStore::history`].
+    ///
+    /// `1` keeps only the live version. Keeping more makes every compaction read all
+    /// the records of the logs it replaces to find the older versions, which it copies
+    /// ahead of the live one, as values merged or appended into resolved in full. The
+    /// versions kept count towards [`KvStore::live_size`] rather than as stale bytes.
+    /// Background compactions keep them too, but not [`SharedKvStore`], and not
+    /// [`KvStore::restore_to_generation`].
+    ///
+    /// [`KvStore::history`]: crate::KvStore::history
+    /// [`KvStore::live_size`]: crate::KvStore::live_size
+    /// [`KvStore::restore_to_generation`]: crate::KvStore::restore_to_generation
+    pub keep_versions: usize,
+    /// Whether a compaction keeps the latest `keep_versions` versions of the keys that
+    /// were removed, along with a tombstone, instead of dropping them.
+    pub keep_deleted_versions: bool,
+    /// Whether the logs written by compactions are compressed.
+    ///
+    /// Records are compressed one by one, and only where it makes them smaller. The
+    /// active log is never compressed, and compressed logs are read transparently.
+    #[cfg(feature = "compression")]
+    pub compress_compacted: bool,
+    /// Whether logs that are no longer written to are read through memory maps.
+    ///
+    /// This makes random reads cheaper, as they need no system calls. The active log
+    /// is always read through a buffered reader, since it keeps growing. Only
+    /// available on Unix.
+    #[cfg(all(feature = "mmap", unix))]
+    pub mmap_reads: bool,
+    /// Whether the index is kept in a sorted file in the store directory instead of in
+    /// memory, for stores with more keys than fit in memory.
+    ///
+    /// Only the entries changed since the file was last written and a cache of entries
+    /// read from it stay in memory, up to `index_cache_entries` of each, so the memory
+    /// used does not grow with the number of keys. Lookups read the file instead, and
+    /// it is rewritten whenever the changed entries fill up. Background compaction does
+    /// not apply to a store with a disk-resident index.
+    pub disk_index: bool,
+    /// Number of changed entries, and of cached entries, a disk-resident index keeps in
+    /// memory.
+    pub index_cache_entries: usize,
+    /// Bytes of recently read values kept in memory, so reading them again needs no
+    /// read from the logs. `0` disables the value cache.
+    ///
+    /// The least recently used values are evicted to stay within the budget, counting
+    /// every value for its key and value plus a small overhead. Writing a key drops
+    /// its cached value. Only [`KvStore`] caches values.
+    pub read_cache_bytes: u64,
+    /// Number of values the value cache holds at most, on top of `read_cache_bytes`.
+    pub read_cache_entries: usize,
+    /// Number of log files kept open for reading at once.
+    ///
+    /// Logs are opened on their first read, and the least recently read one is closed
+    /// to open another once this many are open.
+    pub max_open_logs: usize,
+    /// The function resolving the operands written by [`KvStore::merge`].
+    ///
+    /// A store holding merge records fails to open with
+    /// `KvsError::MissingMergeOperator` without one, and should always be opened with
+    /// the same one.
+    ///
+    /// [`KvStore::merge`]: crate::KvStore::merge
+    pub merge_operator: Option<MergeOperator>,
+    /// The key the records of the store are encrypted with.
+    ///
+    /// A new store opened with a key is encrypted with it, and has to be opened with the
+    /// same key from then on. Opening an encrypted store without its key fails with
+    /// `KvsError::EncryptionKeyRequired` or `KvsError::WrongEncryptionKey`, and opening
+    /// a store that is not encrypted with a key fails with `KvsError::NotEncrypted`.
+    #[cfg(feature = "encryption")]
+    pub encryption_key: Option<EncryptionKey>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            compaction_threshold: 1024 * 1024,
+            max_segment_size: 256 * 1024 * 1024,
+            create_if_missing: true,
+            durability: Durability::default(),
+            on_corruption: CorruptionPolicy::default(),
+            background_compaction: false,
+            read_only: false,
+            archive: None,
+            keep_versions: 1,
+            keep_deleted_versions: false,
+            #[cfg(feature = "compression")]
+            compress_compacted: false,
+            #[cfg(all(feature = "mmap", unix))]
+            mmap_reads: false,
+            disk_index: false,
+            index_cache_entries: 100_000,
+            read_cache_bytes: 0,
+            read_cache_entries: usize::MAX,
+            max_open_logs: 64,
+            merge_operator: None,
+            #[cfg(feature = "encryption")]
+            encryption_key: None,
+        }
+    }
+}
+
+impl Options {
+    /// Returns whether compactions write compressed logs.
+    pub(crate) fn compresses(&self) -> bool {
+        #[cfg(feature = "compression")]
+        return self.compress_compacted;
+        #[cfg(not(feature = "compression"))]
+        false
+    }
+    /// Returns whether compactions keep any version of a key but its live one.
+    pub(crate) fn keeps_versions(&self) -> bool {
+        self.keep_versions > 1 || self.keep_deleted_versions
+    }
+    /// Returns the cipher of the encryption key, if any.
+    pub(crate) fn cipher(&self) -> Option<Cipher> {
+        #[cfg(feature = "encryption")]
+        return self.encryption_key.map(Cipher::new);
+        #[cfg(not(feature = "encryption"))]
+        None
+    }
+    /// Returns whether sealed logs are read through memory maps.
+    pub(crate) fn maps_logs(&self) -> bool {
+        #[cfg(all(feature = "mmap", unix))]
+        return self.mmap_reads;
+        #[cfg(not(all(feature = "mmap", unix)))]
+        false
+    }
+}
+
+/// When the writes to a store are synced to disk.
+///
+/// Every write is flushed to the OS before it returns regardless of the mode, so it
+/// survives a crash of the process. The mode chooses how much can be lost when the
+/// whole system crashes or loses power. Compaction always syncs the log it writes
+/// before removing the logs it replaces.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Durability {
+    /// Sync after every write, so no write that has returned can be lost.
+    Always,
+    /// Sync after every given number of writes, so fewer writes than that can be lost.
+    EveryN(u64),
+    /// Sync on the first write after the given time has passed since the last sync.
+    ///
+    /// Writes made since the last sync can be lost, which may be more than the
+    /// interval's worth if the store goes idle right after them.
+    Interval(Duration),
+    /// Never sync explicitly, leaving it to the OS.
+    #[default]
+    Never,
+}
+
+/// What opening a store does with records of its logs that do not read.
+///
+/// A torn write at the tail of the newest log is always dropped, as it is what a crash
+/// in the middle of a write leaves behind.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CorruptionPolicy {
+    /// Fail with `KvsError::Corruption`, leaving [`KvStore::repair`] to salvage the rest.
+    ///
+    /// [`KvStore::repair`]: crate::KvStore::repair
+    #[default]
+    Fail,
+    /// Skip every damaged run of records, loading the records after it, and keep the
+    /// runs skipped for [`KvStore::skipped_regions`].
+    ///
+    /// Replay resumes at the first record after the run whose checksum matches. The
+    /// logs are left as they are, and the runs skipped count as stale bytes, which the
+    /// next compaction drops. Records written before checksums were cannot be told
+    /// apart from damage past a damaged run, and are skipped with it.
+    ///
+    /// [`KvStore::skipped_regions`]: crate::KvStore::skipped_regions
+    Skip,
+}
+
+/// What a bulk load does with a key given more than once.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Duplicates {
+    /// Fail with `KvsError::DuplicateKey`, loading nothing.
+    #[default]
+    Reject,
+    /// Keep the last value given, like repeated `set`s.
+    ///
+    /// The earlier values are left in the log as stale bytes, which the next compaction
+    /// drops.
+    LastWins,
+}
+
+/// How long the logs replaced by compactions are kept in the archive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Retention {
+    /// Keep the given number of the newest archived logs.
+    Count(usize),
+    /// Keep archived logs for the given time after they are archived.
+    Age(Duration),
+}
+
+/// Builder collecting the configuration of a store before opening it.
+///
+/// This is created by [`KvStore::builder`]. The defaults match [`KvStore::open`].
+///
+/// [`KvStore::builder`]: crate::KvStore::builder
+/// [`KvStore::open`]: crate::KvStore::open
+#[derive(Clone, Debug)]
+pub struct KvStoreBuilder {
+    path: PathBuf,
+    format: Option<LogFormat>,
+    options: Options,
+}
+
+impl KvStoreBuilder {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            format: None,
+            options: Options::default(),
+        }
+    }
+    /// Sets the number of stale bytes above which a compaction runs automatically.
+    pub fn compaction_threshold(mut self, threshold: u64) -> Self {
+        self.options.compaction_threshold = threshold;
+        self
+    }
+    /// Sets the size at which the active log is rolled over to a new log file.
+    pub fn max_segment_size(mut self, size: u64) -> Self {
+        self.options.max_segment_size = size;
+        self
+    }
+    /// Sets the log format of a new store.
+    ///
+    /// Opening fails with `KvsError::FormatMismatch` if the store already exists with another format.
+    pub fn format(mut self, format: LogFormat) -> Self {
+        self.format = Some(format);
+        self
+    }
+    /// Sets whether the store directory is created if it does not exist, which is the default.
+    ///
+    /// Otherwise opening a missing directory fails with `KvsError::StoreNotFound`.
+    pub fn create_if_missing(mut self, create: bool) -> Self {
+        self.options.create_if_missing = create;
+        self
+    }
+    /// Sets when writes are synced to disk, which is never by default.
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.options.durability = durability;
+        self
+    }
+    /// Sets what opening the store does with records of the logs that do not read,
+    /// which is to fail by default.
+    pub fn on_corruption(mut self, policy: CorruptionPolicy) -> Self {
+        self.options.on_corruption = policy;
+        self
+    }
+    /// Sets whether automatic compactions run on a background thread, which is off by default.
+    pub fn background_compaction(mut self, background: bool) -> Self {
+        self.options.background_compaction = background;
+        self
+    }
+    /// Sets whether the store is opened for reading only, which is off by default.
+    ///
+    /// Opening a missing directory for reading only fails with `KvsError::StoreNotFound`,
+    /// whether or not it is to be created if missing.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.options.read_only = read_only;
+        self
+    }
+    /// Sets that logs replaced by a compaction are archived with the given retention.
+    ///
+    /// By default they are removed.
+    pub fn archive(mut self, retention: Retention) -> Self {
+        self.options.archive = Some(retention);
+        self
+    }
+    /// Sets the number of the latest versions of every key a compaction keeps, which is
+    /// only the live one by default.
+    pub fn keep_versions(mut self, versions: usize) -> Self {
+        self.options.keep_versions = versions;
+        self
+    }
+    /// Sets whether a compaction keeps the versions of removed keys, which is off by
+    /// default.
+    pub fn keep_deleted_versions(mut self, keep: bool) -> Self {
+        self.options.keep_deleted_versions = keep;
+        self
+    }
+    /// Sets whether the logs written by compactions are compressed, which is off by default.
+    #[cfg(feature = "compression")]
+    pub fn compress_compacted(mut self, compress: bool) -> Self {
+        self.options.compress_compacted = compress;
+        self
+    }
+    /// Sets whether sealed logs are read through memory maps, which is off by default.
+    #[cfg(all(feature = "mmap", unix))]
+    pub fn mmap_reads(mut self, mmap: bool) -> Self {
+        self.options.mmap_reads = mmap;
+        self
+    }
+    /// Sets whether the index is kept on disk instead of in memory, which is off by default.
+    pub fn disk_index(mut self, disk_index: bool) -> Self {
+        self.options.disk_index = disk_index;
+        self
+    }
+    /// Sets how many entries a disk-resident index keeps in memory, 100,000 by default.
+    pub fn index_cache_entries(mut self, entries: usize) -> Self {
+        self.options.index_cache_entries = entries;
+        self
+    }
+    /// Sets the bytes of values to cache in memory, which is `0`, disabling the cache,
+    /// by default.
+    pub fn read_cache_bytes(mut self, bytes: u64) -> Self {
+        self.options.read_cache_bytes = bytes;
+        self
+    }
+    /// Sets the number of values to cache in memory at most, which is unlimited by default.
+    pub fn read_cache_entries(mut self, entries: usize) -> Self {
+        self.options.read_cache_entries = entries;
+        self
+    }
+    /// Sets the number of log files kept open for reading at once, 64 by default.
+    pub fn max_open_logs(mut self, max: usize) -> Self {
+        self.options.max_open_logs = max;
+        self
+    }
+    /// Sets the function resolving the operands written by `KvStore::merge`, which has
+    /// no default.
+    pub fn merge_operator(mut self, merge: MergeOperator) -> Self {
+        self.options.merge_operator = Some(merge);
+        self
+    }
+    /// Sets the key the records of the store are encrypted with, which has no default.
+    #[cfg(feature = "encryption")]
+    pub fn encryption_key(mut self, key: [u8; 32]) -> Self {
+        self.options.encryption_key = Some(EncryptionKey(key));
+        self
+    }
+    /// Opens the store with the collected configuration.
+    pub fn open(self) -> Result<KvStore> {
+        KvStore::open_dir(&self.path, self.format, self.options)
+    }
+    /// Opens the store with the collected configuration, and bulk loads `pairs` into it
+    /// like [`KvStore::bulk_load`], treating a key given more than once as `duplicates`
+    /// says.
+    pub fn bulk_load<I>(self, pairs: I, duplicates: Duplicates) -> Result<KvStore>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut store = self.open()?;
+        store.load_pairs(pairs, duplicates)?;
+        Ok(store)
+    }
+    /// Opens the store with the collected configuration as a [`SharedKvStore`].
+    ///
+    /// Background compaction, a disk-resident index and the value cache do not apply to it.
+    pub fn open_shared(self) -> Result<SharedKvStore> {
+        SharedKvStore::open_dir(&self.path, self.format, self.options)
+    }
+}
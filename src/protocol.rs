@@ -0,0 +1,245 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::auth::AuthToken;
+use crate::metrics::CommandType;
+use crate::{KvsError, Result};
+
+/// Magic string opening the handshake of both sides, so strangers are told apart.
+pub(crate) const MAGIC: &str = "kvs";
+/// Oldest protocol version this build speaks.
+pub(crate) const MIN_VERSION: u32 = 1;
+/// Newest protocol version this build speaks.
+///
+/// Version 2 tags every message with the ID of its request, so a client can pipeline
+/// requests and check which one each response answers.
+pub(crate) const MAX_VERSION: u32 = 2;
+
+/// The first message each side sends on a new connection.
+///
+/// Both sides use the lower of the two versions, if they both still speak it.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct Hello {
+    pub(crate) magic: String,
+    /// Newest protocol version the sender speaks.
+    pub(crate) version: u32,
+}
+
+impl Hello {
+    pub(crate) fn new() -> Self {
+        Self {
+            magic: MAGIC.to_owned(),
+            version: MAX_VERSION,
+        }
+    }
+    /// Checks the magic string of a received handshake.
+    pub(crate) fn check_magic(&self) -> Result<()> {
+        if self.magic == MAGIC {
+            Ok(())
+        } else {
+            Err(KvsError::Protocol(format!("bad magic {:?}", self.magic)))
+        }
+    }
+}
+
+/// Returns the protocol version both sides agree on, given the newest each speaks.
+pub(crate) fn negotiate(client: u32, server: u32) -> Result<u32> {
+    let version = client.min(server);
+    if version < MIN_VERSION {
+        return Err(KvsError::ProtocolMismatch { client, server });
+    }
+    Ok(version)
+}
+
+/// A message after the handshake, tagged with the protocol version it is encoded in.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) enum Frame<T> {
+    V1(T),
+    /// A message with the ID of the request it is or answers.
+    V2 {
+        id: u64,
+        message: T,
+    },
+}
+
+impl<T> Frame<T> {
+    /// Wraps a message in the frame of the negotiated version, with the ID of its
+    /// request if the version has room for one.
+    pub(crate) fn new(version: u32, id: u64, message: T) -> Self {
+        match version {
+            1 => Frame::V1(message),
+            2 => Frame::V2 { id, message },
+            _ => unreachable!("version {} is never negotiated", version),
+        }
+    }
+    /// Unwraps a message and the ID of its request, checking it is in the negotiated
+    /// version. A version 1 message has no ID, and is given `0`.
+    pub(crate) fn into_message(self, version: u32) -> Result<(u64, T)> {
+        let (found, id, message) = match self {
+            Frame::V1(message) => (1, 0, message),
+            Frame::V2 { id, message } => (2, id, message),
+        };
+        if found != version {
+            return Err(KvsError::Protocol(format!(
+                "a version {} message after negotiating version {}",
+                found, version
+            )));
+        }
+        Ok((id, message))
+    }
+}
+
+/// A request from a client to `kvs-server`.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) enum Request {
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    Remove {
+        key: String,
+    },
+    /// Lists the next keys after `cursor`, which is empty to start from the first key.
+    Scan {
+        cursor: String,
+        prefix: Option<String>,
+        /// Number of keys to list, which the server may lower.
+        count: usize,
+    },
+    Exists {
+        key: String,
+    },
+    /// Removes every key given that exists.
+    Del {
+        keys: Vec<String>,
+    },
+    /// Lists every key starting with `prefix`, failing if there are too many.
+    Keys {
+        prefix: Option<String>,
+    },
+    DbSize,
+    /// Answered with `Pong` without touching the engine, to check the server is up.
+    Ping,
+    /// Checks that the engine can serve requests.
+    Health,
+    /// Authenticates the connection, as its first request if the server requires a token.
+    Auth {
+        token: AuthToken,
+    },
+}
+
+impl Request {
+    /// Names of the variants, which tell a request this build does not know apart from
+    /// a malformed one.
+    pub(crate) const NAMES: &'static [&'static str] = &[
+        "Get", "Set", "Remove", "Scan", "Exists", "Del", "Keys", "DbSize", "Ping", "Health", "Auth",
+    ];
+
+    /// Returns the type the request is counted as by the metrics of a server.
+    pub(crate) fn command_type(&self) -> CommandType {
+        match self {
+            Request::Get { .. } => CommandType::Get,
+            Request::Set { .. } => CommandType::Set,
+            Request::Remove { .. } => CommandType::Remove,
+            Request::Scan { .. } => CommandType::Scan,
+            Request::Exists { .. } => CommandType::Exists,
+            Request::Del { .. } => CommandType::Del,
+            Request::Keys { .. } => CommandType::Keys,
+            Request::DbSize => CommandType::DbSize,
+            Request::Ping => CommandType::Ping,
+            Request::Health => CommandType::Health,
+            Request::Auth { .. } => CommandType::Auth,
+        }
+    }
+    /// Returns `true` if the request changes nothing, so it can be sent again.
+    pub(crate) fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            Request::Get { .. }
+                | Request::Scan { .. }
+                | Request::Exists { .. }
+                | Request::Keys { .. }
+                | Request::DbSize
+                | Request::Ping
+                | Request::Health
+                | Request::Auth { .. }
+        )
+    }
+}
+
+/// Returns the name of the request a message holds, which is its only field, or itself
+/// for a request without fields.
+pub(crate) fn request_name(message: &Value) -> Option<&str> {
+    match message {
+        Value::String(name) => Some(name),
+        Value::Object(fields) if fields.len() == 1 => fields.keys().next().map(String::as_str),
+        _ => None,
+    }
+}
+
+/// The response of `kvs-server` to a request.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) enum Response {
+    /// The request succeeded, with the value of the key for a `Get`.
+    Ok(Option<String>),
+    /// The keys listed by a `Scan` or `Keys`, with the cursor to list the next ones from,
+    /// which is empty once every key is listed.
+    Keys { keys: Vec<String>, cursor: String },
+    /// Whether the key of an `Exists` exists.
+    Bool(bool),
+    /// The number of keys a `Del` removed, or that a `DbSize` counted.
+    Count(u64),
+    /// The request is not one the server knows, most likely from a newer client, with
+    /// its name.
+    Unsupported(String),
+    /// The answer to a `Ping`.
+    Pong,
+    /// The health of the engine checked by a `Health`.
+    Health(Health),
+    /// The server requires an `Auth` before any other request.
+    AuthRequired,
+    /// The token of an `Auth` is not the one of the server.
+    AuthFailed,
+    /// The key of a `Remove` does not exist.
+    KeyNotFound,
+    /// The request failed on the server, with the error message.
+    Err(String),
+}
+
+/// Whether the engine of a server can serve requests, returned by
+/// [`KvsClient::health`].
+///
+/// [`KvsClient::health`]: crate::KvsClient::health
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Health {
+    /// The engine answered a read in time.
+    Ok,
+    /// The engine failed a read, or was slow to answer it, for the given reason.
+    Degraded(String),
+}
+
+/// Returns the cursor a scan resumes from after the given key.
+///
+/// The cursor is the key encoded in base64, which clients treat as opaque.
+pub(crate) fn encode_cursor(key: &str) -> String {
+    URL_SAFE_NO_PAD.encode(key)
+}
+
+/// Returns the key a scan resumes after for the given cursor, or `None` for an empty
+/// cursor, which starts from the first key.
+pub(crate) fn decode_cursor(cursor: &str) -> Result<Option<String>> {
+    if cursor.is_empty() {
+        return Ok(None);
+    }
+    URL_SAFE_NO_PAD
+        .decode(cursor)
+        .ok()
+        .and_then(|key| String::from_utf8(key).ok())
+        .map(Some)
+        .ok_or_else(|| KvsError::Protocol(format!("invalid scan cursor {:?}", cursor)))
+}
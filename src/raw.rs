@@ -0,0 +1,70 @@
+use crate::{Result, Scan};
+
+/// Prefix of the stored keys that byte keys are encoded to.
+///
+/// No namespace has an empty name, so the encoded keys are apart from the keys of every
+/// namespace, and come before them.
+pub(crate) const RAW_KEY_PREFIX: &str = "\0\0";
+
+/// First stored key after every encoded byte key.
+pub(crate) const RAW_KEYS_END: &str = "\0\u{1}";
+
+/// Encodes a byte key as a stored key.
+///
+/// Every byte is stored as the character of the same code point, which UTF-8 encodes
+/// as the byte itself below `0x80` and as two bytes above. Code points compare like
+/// their UTF-8 encodings, so the stored keys sort like the byte keys.
+pub(crate) fn encode_key(key: &[u8]) -> String {
+    let mut encoded = String::with_capacity(RAW_KEY_PREFIX.len() + key.len());
+    encoded.push_str(RAW_KEY_PREFIX);
+    encoded.extend(key.iter().map(|&byte| char::from(byte)));
+    encoded
+}
+
+/// Decodes the byte key of a stored key, `None` if it is not an encoded byte key.
+pub(crate) fn decode_key(key: &str) -> Option<Vec<u8>> {
+    key.strip_prefix(RAW_KEY_PREFIX)?
+        .chars()
+        .map(|c| u8::try_from(c).ok())
+        .collect()
+}
+
+/// An iterator over the byte key/value pairs of a store, in key order.
+///
+/// This is created by [`KvStore::scan_prefix_raw`] and [`KvStore::range_raw`]. Values are
+/// read lazily as the iterator advances, and yielded as bytes.
+///
+/// [`KvStore::scan_prefix_raw`]: crate::KvStore::scan_prefix_raw
+/// [`KvStore::range_raw`]: crate::KvStore::range_raw
+pub struct RawScan<'a> {
+    pub(crate) scan: Scan<'a>,
+}
+
+impl RawScan<'_> {
+    fn next_pair(&mut self, back: bool) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        loop {
+            let (key, cmd) = match self.scan.next_command(back)? {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(e)),
+            };
+            // Keys stored with the prefix by the string methods are not byte keys.
+            if let Some(key) = decode_key(&key) {
+                return Some(Ok((key, cmd.into_bytes().unwrap_or_default())));
+            }
+        }
+    }
+}
+
+impl Iterator for RawScan<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_pair(false)
+    }
+}
+
+impl DoubleEndedIterator for RawScan<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.next_pair(true)
+    }
+}
@@ -0,0 +1,361 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use crate::disk_index::remove_index_file;
+use crate::encrypt::check_cipher;
+use crate::engines::{claim_dir, Engine};
+use crate::format::Codec;
+use crate::hint::{remove_hint, write_hint};
+use crate::kv::{create_log, list_gens, lock_dir, log_path, Command, CommandPos};
+use crate::snapshot::remove_snapshot;
+use crate::{LogFormat, Result};
+
+/// Name of the directory of a store the damaged logs are moved into by a repair.
+pub(crate) const DAMAGED_DIR: &str = "damaged";
+
+/// What a repair of a store salvaged, returned by [`KvStore::repair`].
+///
+/// Every byte of the logs is counted either as recovered or as lost.
+///
+/// [`KvStore::repair`]: crate::KvStore::repair
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Number of records read back intact and kept.
+    pub records_recovered: usize,
+    /// Number of records read back intact but dropped, as part of a batch or a streamed
+    /// value that was damaged, or as a suffix or operand of a value that was lost.
+    pub records_lost: usize,
+    /// Bytes of the logs in the records kept.
+    pub bytes_recovered: u64,
+    /// Bytes of the logs that do not read, or in the records dropped.
+    pub bytes_lost: u64,
+    /// Generations of the logs found damaged, moved into the `damaged` directory.
+    pub damaged_gens: Vec<u64>,
+    /// Generation of the log the recovered keys were written to, `None` if the store had
+    /// no logs.
+    pub gen: Option<u64>,
+}
+
+/// A record read intact from a log, or a run of bytes that does not read.
+enum Scanned {
+    Record {
+        cmd: Command,
+        seq: Option<u64>,
+        len: u64,
+    },
+    Damaged(u64),
+}
+
+/// The records of a key to write, oldest first: a value, and then the suffixes and
+/// operands merged into it, with their sequence numbers.
+type Chain = Vec<(Command, Option<u64>)>;
+
+/// A change read from a log, with its sequence number and the length of its records.
+type Change = (Command, Option<u64>, u64);
+
+/// Salvages what can still be read of the logs of a closed store.
+pub(crate) fn repair(dir: &Path) -> Result<RepairReport> {
+    let _lock = lock_dir(dir)?;
+    claim_dir(dir, Engine::Kvs, true)?;
+    let gens = list_gens(dir)?;
+    // The records of an encrypted store cannot be told apart without its key.
+    check_cipher(dir, None, !gens.is_empty(), true)?;
+    let mut salvage = Salvage::default();
+    let Some(&last) = gens.last() else {
+        return Ok(salvage.report);
+    };
+    // Logs written before the format was recorded are JSON.
+    let format = Codec {
+        format: LogFormat::load(dir)?.unwrap_or(LogFormat::Json),
+        cipher: None,
+    };
+    for &gen in &gens {
+        let bytes = fs::read(log_path(dir, gen))?;
+        let scanned = scan(format, &bytes);
+        if scanned.iter().any(|s| matches!(s, Scanned::Damaged(_))) {
+            salvage.report.damaged_gens.push(gen);
+        }
+        salvage.log(format, scanned);
+    }
+
+    // The salvaged keys are written to a generation of their own, with a hint keeping
+    // its sequence number, and later writes go to the one after it.
+    let gen = last + 1;
+    let mut buf = Vec::new();
+    let mut entries = Vec::new();
+    for (key, chain) in salvage.keys {
+        let mut prev: Option<CommandPos> = None;
+        for (cmd, seq) in chain {
+            let (cmd, expires_at) = match cmd {
+                Command::Merge(key, operand, _) => (
+                    Command::Merge(key, operand, prev),
+                    prev.and_then(|p| p.expires_at),
+                ),
+                Command::Append(key, suffix, _, len) => (
+                    Command::Append(key, suffix, prev, len),
+                    prev.and_then(|p| p.expires_at),
+                ),
+                cmd @ Command::SetWithExpiry(_, _, expires_at) => (cmd, Some(expires_at)),
+                cmd => (cmd, None),
+            };
+            let start = buf.len() as u64;
+            match seq {
+                Some(seq) => format.encode_sequenced(&cmd, seq, &mut buf)?,
+                None => format.encode_checked(&cmd, &mut buf)?,
+            }
+            prev = Some(CommandPos {
+                gen,
+                start,
+                end: buf.len() as u64,
+                expires_at,
+            });
+        }
+        if let Some(pos) = prev {
+            entries.push((key, pos.start, pos.end, pos.expires_at));
+        }
+    }
+    let mut log = create_log(dir, gen)?;
+    log.write_all(&buf)?;
+    log.flush()?;
+    log.get_ref().sync_all()?;
+    drop(log);
+    // The keys lost change without a record in the change feed, which readers behind it
+    // find compacted away.
+    write_hint(dir, gen, entries, salvage.seq + 1, 0)?;
+    create_log(dir, gen + 1)?;
+
+    for &gen in &gens {
+        if salvage.report.damaged_gens.contains(&gen) {
+            let damaged = dir.join(DAMAGED_DIR);
+            fs::create_dir_all(&damaged)?;
+            fs::rename(log_path(dir, gen), log_path(&damaged, gen))?;
+        } else {
+            fs::remove_file(log_path(dir, gen))?;
+        }
+        remove_hint(dir, gen)?;
+    }
+    remove_snapshot(dir)?;
+    remove_index_file(dir)?;
+    salvage.report.gen = Some(gen);
+    Ok(salvage.report)
+}
+
+/// Splits a log into the records that read intact and the damaged runs of bytes
+/// between them.
+///
+/// A record is intact if the checksum record before it matches it, or, up to the first
+/// damage, if it decodes without one, as records written before checksums were do. Past
+/// a damaged run, every offset is tried until one holds a checksum record matching the
+/// record after it.
+fn scan(format: Codec, bytes: &[u8]) -> Vec<Scanned> {
+    let mut scanned = Vec::new();
+    let mut at = 0;
+    // Start of the damaged run being skipped, if any.
+    let mut damaged = None;
+    while at < bytes.len() {
+        match intact_record(format, &bytes[at..], damaged.is_none()) {
+            Some((cmd, seq, len)) => {
+                if let Some(start) = damaged.take() {
+                    scanned.push(Scanned::Damaged((at - start) as u64));
+                }
+                scanned.push(Scanned::Record {
+                    cmd,
+                    seq,
+                    len: len as u64,
+                });
+                at += len;
+            }
+            None => {
+                damaged.get_or_insert(at);
+                at += 1;
+            }
+        }
+    }
+    if let Some(start) = damaged {
+        scanned.push(Scanned::Damaged((at - start) as u64));
+    }
+    scanned
+}
+
+/// Decodes the record at the start of the bytes, after its checksum record or, if
+/// `unchecked` is set, without one.
+///
+/// Returns the command, its sequence number and the length of its records.
+pub(crate) fn intact_record(
+    format: Codec,
+    bytes: &[u8],
+    unchecked: bool,
+) -> Option<(Command, Option<u64>, usize)> {
+    // Every JSON record is an object, which rules out most offsets of a damaged run.
+    if format.format == LogFormat::Json && bytes.first() != Some(&b'{') {
+        return None;
+    }
+    let (cmd, checksum_len) = format.decode_prefix(bytes).ok()?;
+    let (crc, seq) = match cmd {
+        Command::Checksum(crc) => (crc, None),
+        Command::Sequenced(crc, seq) => (crc, Some(seq)),
+        cmd => return unchecked.then_some((cmd, None, checksum_len)),
+    };
+    let record = &bytes[checksum_len..];
+    let (cmd, len) = format.decode_prefix(record).ok()?;
+    if let Command::Checksum(_) | Command::Sequenced(..) = cmd {
+        return None;
+    }
+    (crc32fast::hash(&record[..len]) == crc).then_some((cmd, seq, checksum_len + len))
+}
+
+/// The keys left by the intact records of the logs read so far.
+#[derive(Default)]
+struct Salvage {
+    keys: BTreeMap<String, Chain>,
+    // Sequence number of the last change read.
+    seq: u64,
+    report: RepairReport,
+    // Number and bytes of the records read but not applied yet.
+    held: (usize, u64),
+    // Length and changes, with their sequence numbers and lengths, of a batch being read.
+    batch: Option<(usize, Vec<Change>)>,
+    stream: Option<Stream>,
+}
+
+/// A streamed value being read.
+struct Stream {
+    key: String,
+    seq: Option<u64>,
+    len: u64,
+    value: Vec<u8>,
+    // Length of the records read so far.
+    records_len: u64,
+}
+
+impl Salvage {
+    /// Applies the intact records of a log, in order.
+    ///
+    /// A batch is only applied once all of its records are read, and a streamed value
+    /// once all of its chunks are, so both are dropped whole if a damaged run or the end
+    /// of the log cuts them short.
+    fn log(&mut self, format: Codec, scanned: Vec<Scanned>) {
+        for scanned in scanned {
+            let (cmd, seq, len) = match scanned {
+                Scanned::Record { cmd, seq, len } => (cmd, seq, len),
+                Scanned::Damaged(len) => {
+                    self.report.bytes_lost += len;
+                    self.drop_held();
+                    continue;
+                }
+            };
+            self.held = (self.held.0 + 1, self.held.1 + len);
+            let Ok(cmd) = cmd.decompress(format) else {
+                self.drop_held();
+                continue;
+            };
+            let change = match (cmd, &mut self.stream) {
+                (Command::Chunk(chunk), Some(stream))
+                    if stream.value.len() as u64 + chunk.len() as u64 <= stream.len =>
+                {
+                    stream.value.extend_from_slice(&chunk);
+                    stream.records_len += len;
+                    if (stream.value.len() as u64) < stream.len {
+                        continue;
+                    }
+                    let stream = self.stream.take().unwrap();
+                    let cmd = Command::SetBytes(stream.key, stream.value);
+                    (cmd, stream.seq, stream.records_len)
+                }
+                (_, Some(_)) | (Command::Chunk(_), None) => {
+                    self.drop_held();
+                    continue;
+                }
+                (Command::SetStream(key, value_len), None) if value_len > 0 => {
+                    self.stream = Some(Stream {
+                        key,
+                        seq,
+                        len: value_len,
+                        value: Vec::new(),
+                        records_len: len,
+                    });
+                    continue;
+                }
+                (Command::SetStream(key, _), None) => {
+                    (Command::SetBytes(key, Vec::new()), seq, len)
+                }
+                // Batches do not nest, so the log is damaged.
+                (Command::Batch(_), None) if self.batch.is_some() => {
+                    self.drop_held();
+                    continue;
+                }
+                (Command::Batch(batch_len), None) => {
+                    self.batch = Some((batch_len, Vec::new()));
+                    if batch_len > 0 {
+                        continue;
+                    }
+                    self.commit(Vec::new());
+                    continue;
+                }
+                (cmd, None) => (cmd, seq, len),
+            };
+            match &mut self.batch {
+                Some((batch_len, changes)) => {
+                    changes.push(change);
+                    if changes.len() >= *batch_len {
+                        let (_, changes) = self.batch.take().unwrap();
+                        self.commit(changes);
+                    }
+                }
+                None => self.commit(vec![change]),
+            }
+        }
+        self.drop_held();
+    }
+
+    /// Applies changes, along with the records read before them.
+    fn commit(&mut self, changes: Vec<Change>) {
+        self.batch = None;
+        self.report.records_recovered += self.held.0;
+        self.report.bytes_recovered += self.held.1;
+        self.held = (0, 0);
+        for (cmd, seq, len) in changes {
+            if !self.apply(cmd, seq) {
+                self.report.records_recovered -= 1;
+                self.report.bytes_recovered -= len;
+                self.report.records_lost += 1;
+                self.report.bytes_lost += len;
+            }
+        }
+    }
+
+    /// Counts the records read but not applied yet as lost, and forgets them.
+    fn drop_held(&mut self) {
+        self.report.records_lost += self.held.0;
+        self.report.bytes_lost += self.held.1;
+        self.held = (0, 0);
+        self.batch = None;
+        self.stream = None;
+    }
+
+    /// Applies a change to the keys, returning `false` if it extends a value that was
+    /// lost.
+    fn apply(&mut self, cmd: Command, seq: Option<u64>) -> bool {
+        self.seq = self.seq.max(seq.unwrap_or(0));
+        match cmd {
+            Command::Remove(key) => {
+                self.keys.remove(&key);
+            }
+            Command::Clear => self.keys.clear(),
+            Command::Merge(_, _, Some(_)) | Command::Append(_, _, Some(_), _) => {
+                match self.keys.get_mut(cmd.key().unwrap()) {
+                    Some(chain) => chain.push((cmd, seq)),
+                    None => return false,
+                }
+            }
+            cmd => {
+                if let Some(key) = cmd.key() {
+                    self.keys.insert(key.to_owned(), vec![(cmd, seq)]);
+                }
+            }
+        }
+        true
+    }
+}
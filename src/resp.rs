@@ -0,0 +1,107 @@
+//! The Redis serialization protocol (RESP), as far as `kvs-server` speaks it.
+
+use std::io::{BufRead, Read, Write};
+
+use crate::{KvsError, Result};
+
+/// Longest bulk string accepted from a client.
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+/// Longest line accepted from a client, which bounds inline commands.
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+/// Reads the next command sent by a client, as its name followed by its arguments.
+///
+/// Both arrays of bulk strings and inline commands, which are lines of words, are
+/// accepted. Returns `None` once the client has closed the connection, and an empty
+/// command for an empty inline line.
+pub(crate) fn read_command<R: BufRead>(reader: &mut R) -> Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        let words = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|word| !word.is_empty())
+            .map(|word| word.to_vec())
+            .collect();
+        return Ok(Some(words));
+    }
+    let count = parse_len(&line[1..])?;
+    let mut args = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        let line = read_line(reader)?.ok_or_else(|| protocol_error("unexpected end of stream"))?;
+        if line.first() != Some(&b'$') {
+            return Err(protocol_error("expected a bulk string"));
+        }
+        let len = parse_len(&line[1..])?;
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg)?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("bulk string not terminated by CRLF"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// Reads a line without its line ending, or `None` at the end of the stream.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let limit = MAX_LINE_LEN as u64 + 2;
+    if Read::take(&mut *reader, limit).read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        if line.len() as u64 == limit {
+            return Err(protocol_error("line too long"));
+        }
+        return Err(protocol_error("unexpected end of stream"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8]) -> Result<usize> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse().ok())
+        .filter(|&len| len <= MAX_BULK_LEN)
+        .ok_or_else(|| protocol_error("invalid length"))
+}
+
+fn protocol_error(message: &str) -> KvsError {
+    KvsError::Protocol(message.to_owned())
+}
+
+/// A reply to a command.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    /// A bulk string, or the null bulk string for `None`.
+    Bulk(Option<Vec<u8>>),
+}
+
+impl Reply {
+    /// Writes the reply to a client.
+    pub(crate) fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        match self {
+            Reply::Simple(s) => write!(writer, "+{}\r\n", s)?,
+            // Line breaks would end the reply early.
+            Reply::Error(e) => write!(writer, "-{}\r\n", e.replace(['\r', '\n'], " "))?,
+            Reply::Integer(n) => write!(writer, ":{}\r\n", n)?,
+            Reply::Bulk(None) => writer.write_all(b"$-1\r\n")?,
+            Reply::Bulk(Some(bytes)) => {
+                write!(writer, "${}\r\n", bytes.len())?;
+                writer.write_all(bytes)?;
+                writer.write_all(b"\r\n")?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,772 @@
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info, warn};
+use serde::Deserialize;
+use serde_json::{Deserializer, Value};
+
+use crate::auth::{AuthToken, AUTH_FAILURE_DELAY};
+#[cfg(feature = "metrics")]
+use crate::metrics::Exporter;
+use crate::metrics::{CommandType, Counted, ErrorType, ServerMetrics};
+use crate::protocol::{
+    decode_cursor, encode_cursor, negotiate, request_name, Frame, Health, Hello, Request, Response,
+    MAX_VERSION,
+};
+use crate::resp::{read_command, Reply};
+use crate::stream::Stream;
+#[cfg(feature = "tls")]
+use crate::ServerTlsConfig;
+use crate::{KvsEngine, KvsError, Result, ThreadPool};
+
+/// Most keys listed in answer to a single request.
+///
+/// A `Scan` lists no more whatever count it asks for, and a `Keys` request matching more
+/// fails, so the server never buffers every key of a large store into one response.
+const MAX_KEYS: usize = 10_000;
+
+/// Reserved key the health check reads. Keys starting with a NUL are reserved for
+/// namespaces, and a namespaced key has a second NUL, so no key written can be it.
+const HEALTH_KEY: &str = "\0kvs-health";
+
+/// How long the read of a health check may take before the engine counts as degraded.
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a server shutting down waits for its connections by default.
+const GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// The wire protocol a server speaks with its clients.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Protocol {
+    /// JSON encoded requests and responses, as used by [`KvsClient`].
+    ///
+    /// [`KvsClient`]: crate::KvsClient
+    #[default]
+    Json,
+    /// The Redis serialization protocol, answering `GET`, `SET`, `DEL`, `EXISTS` and `PING`.
+    ///
+    /// Values are set and read as bytes with `KvsEngine::set_bytes` and `get_bytes`, so
+    /// they can be any bytes if the engine stores bytes. Keys must be UTF-8.
+    Resp,
+}
+
+impl FromStr for Protocol {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(Protocol::Json),
+            "resp" => Ok(Protocol::Resp),
+            _ => Err(KvsError::UnknownProtocol(s.to_owned())),
+        }
+    }
+}
+
+/// A server serving a `KvsEngine` over TCP.
+///
+/// Every connection is served on a thread of the pool, with its own clone of the
+/// engine, so the clones must share the state of the store like `SharedKvStore` does.
+/// Clients send requests in the protocol of the server and get one response for each, in order.
+pub struct KvsServer<E, P> {
+    engine: E,
+    pool: P,
+    protocol: Protocol,
+    grace_period: Duration,
+    auth_token: Option<AuthToken>,
+    #[cfg(feature = "tls")]
+    tls: Option<ServerTlsConfig>,
+    shutdown: ShutdownHandle,
+    metrics: Arc<ServerMetrics>,
+    #[cfg(feature = "metrics")]
+    metrics_listener: Option<TcpListener>,
+}
+
+impl<E: KvsEngine + Clone + Send + 'static, P: ThreadPool> KvsServer<E, P> {
+    /// Creates a server for the given engine, speaking JSON and serving clients on the pool.
+    pub fn new(engine: E, pool: P) -> Self {
+        Self {
+            engine,
+            pool,
+            protocol: Protocol::default(),
+            grace_period: GRACE_PERIOD,
+            auth_token: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            shutdown: ShutdownHandle::default(),
+            metrics: Arc::default(),
+            #[cfg(feature = "metrics")]
+            metrics_listener: None,
+        }
+    }
+    /// Sets the protocol the server speaks.
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+    /// Sets how long a shutdown waits for the requests in flight, 10 seconds by default.
+    pub fn grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+    /// Requires every client to authenticate with the given token before any request.
+    ///
+    /// The first request on a connection must be an `Auth` carrying the token, or
+    /// `AUTH <token>` in the Redis protocol. Anything else, or a wrong token, is
+    /// answered with an error after a delay, and the connection is closed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the token is empty.
+    pub fn auth_token(mut self, token: String) -> Self {
+        self.auth_token = Some(AuthToken::new(token));
+        self
+    }
+    /// Serves clients over TLS, with the certificate and key of the config.
+    ///
+    /// The handshake is done on the thread serving the connection, and a client failing
+    /// it is disconnected. Available with the `tls` feature.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, config: ServerTlsConfig) -> Self {
+        self.tls = Some(config);
+        self
+    }
+    /// Serves the metrics of the server at `/metrics` over HTTP on the given listener,
+    /// in the Prometheus text format, for as long as the server serves clients.
+    ///
+    /// The metrics count the commands processed and the errors by type, the bytes read
+    /// and written, and the connections, along with the statistics of the engine if it
+    /// keeps any. Available with the `metrics` feature.
+    #[cfg(feature = "metrics")]
+    pub fn metrics_listener(mut self, listener: TcpListener) -> Self {
+        self.metrics_listener = Some(listener);
+        self
+    }
+    /// Returns a handle shutting the server down, which works before it starts serving too.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+    /// Listens on the given address and serves clients until the listener fails or the
+    /// server is shut down.
+    pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        self.serve(TcpListener::bind(addr)?)
+    }
+    /// Serves clients connecting to the given listener, until it fails or the server is
+    /// shut down.
+    ///
+    /// A client that fails, disconnects mid-request or panics its handler does not stop the
+    /// server, nor does a connection failing before it is served, like one reset by its
+    /// client as soon as it is accepted. Only a failure of the listener does.
+    ///
+    /// Once shut down, the server stops accepting connections and lets every client
+    /// finish the request it is sending or being answered, then closes its connection.
+    /// The connections still open at the end of the grace period get an error response,
+    /// if they can take one, and are closed. The metrics endpoint stops, then the engine
+    /// is synced, and dropped with the server.
+    pub fn serve(mut self, listener: TcpListener) -> Result<()> {
+        self.shutdown.listening(listener.local_addr()?);
+        #[cfg(feature = "metrics")]
+        let exporter = match self.metrics_listener.take() {
+            Some(listener) => Some(Exporter::start(
+                listener,
+                self.engine.clone(),
+                Arc::clone(&self.metrics),
+            )?),
+            None => None,
+        };
+        let open = Arc::new(OpenConnections::default());
+        // The error the listener failed with, if it did.
+        let mut failed = None;
+        for (id, stream) in listener.incoming().enumerate() {
+            if self.shutdown.is_requested() {
+                break;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) if is_connection_error(&e) => {
+                    warn!("Failed to accept a connection: {}", e);
+                    continue;
+                }
+                Err(e) => {
+                    failed = Some(e);
+                    break;
+                }
+            };
+            let (stream, peer, registered) = match self.accept(id, stream, &open) {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    self.metrics.error(ErrorType::Connection);
+                    warn!("Dropping a connection before serving it: {}", e);
+                    continue;
+                }
+            };
+            debug!("Connection from {}", peer);
+            let connection = Connection {
+                engine: self.engine.clone(),
+                metrics: Arc::clone(&self.metrics),
+                auth_token: self.auth_token.clone(),
+            };
+            let protocol = self.protocol;
+            let shutdown = self.shutdown.clone();
+            self.pool.spawn(move || {
+                // Held until the job ends, even if it panics, and dropped after the
+                // connection, so the engine is only left to the server once it closes.
+                let registered = registered;
+                let mut connection = connection;
+                // A connection still queued when the server shut down has sent no
+                // request it would expect an answer to.
+                if shutdown.is_requested() {
+                    debug!("Connection from {} dropped by the shutdown", peer);
+                    return;
+                }
+                let _connected = connection.metrics.connected();
+                let served = stream.handshake().and_then(|()| match protocol {
+                    Protocol::Json => connection.serve_json(stream, &registered),
+                    Protocol::Resp => connection.serve_resp(stream),
+                });
+                if let Err(e) = served {
+                    connection.metrics.error(ErrorType::Connection);
+                    error!("Error serving {}: {}", peer, e);
+                }
+                debug!("Connection from {} closed", peer);
+            });
+        }
+        open.drain(self.grace_period, self.protocol);
+        #[cfg(feature = "metrics")]
+        if let Some(exporter) = exporter {
+            exporter.stop();
+        }
+        let synced = self.engine.sync();
+        match failed {
+            Some(e) => Err(e.into()),
+            None => synced,
+        }
+    }
+
+    /// Sets an accepted connection up to be served, returning it with the address of
+    /// its client and its registration as open.
+    fn accept(
+        &self,
+        id: usize,
+        stream: TcpStream,
+        open: &Arc<OpenConnections>,
+    ) -> Result<(Stream, SocketAddr, Registered)> {
+        // Responses are flushed whole, so holding them back only delays them.
+        stream.set_nodelay(true)?;
+        let peer = stream.peer_addr()?;
+        let stream = self.secure(stream)?;
+        let registered = open.register(id, &stream)?;
+        Ok((stream, peer, registered))
+    }
+
+    /// Wraps an accepted connection in TLS if the server is set to, leaving the
+    /// handshake to the thread serving it.
+    #[cfg(feature = "tls")]
+    fn secure(&self, stream: TcpStream) -> Result<Stream> {
+        match &self.tls {
+            Some(tls) => Ok(Stream::Tls(tls.accept(stream)?)),
+            None => Ok(Stream::Tcp(stream)),
+        }
+    }
+
+    #[cfg(not(feature = "tls"))]
+    fn secure(&self, stream: TcpStream) -> Result<Stream> {
+        Ok(Stream::Tcp(stream))
+    }
+}
+
+/// A handle shutting a [`KvsServer`] down gracefully, from any thread.
+///
+/// Clones of the handle shut down the same server, and shutting it down more than once
+/// does nothing.
+#[derive(Clone, Debug, Default)]
+pub struct ShutdownHandle {
+    state: Arc<ShutdownState>,
+}
+
+#[derive(Debug, Default)]
+struct ShutdownState {
+    requested: AtomicBool,
+    // Address the server listens on, once it does.
+    addr: Mutex<Option<SocketAddr>>,
+}
+
+impl ShutdownHandle {
+    /// Shuts the server down, returning without waiting for it to stop.
+    pub fn shutdown(&self) {
+        if self.state.requested.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        // Wake the server up from waiting for a connection.
+        if let Some(addr) = *self.state.addr.lock().unwrap() {
+            if let Err(e) = TcpStream::connect(wake_addr(addr)) {
+                warn!("Failed to wake the server on {} up: {}", addr, e);
+            }
+        }
+    }
+
+    fn is_requested(&self) -> bool {
+        self.state.requested.load(Ordering::SeqCst)
+    }
+
+    /// Records the address the server listens on. It is set before the server checks
+    /// whether it is shut down, and read after a shutdown is requested, so either the
+    /// server sees the request or the handle wakes it up.
+    fn listening(&self, addr: SocketAddr) {
+        *self.state.addr.lock().unwrap() = Some(addr);
+    }
+}
+
+/// Returns `true` for the errors accepting a connection that come from the connection,
+/// like its client resetting it, rather than from the listener.
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::WouldBlock
+    )
+}
+
+/// Returns the address to connect to a listener on, which is a loopback address for a
+/// listener on every interface.
+pub(crate) fn wake_addr(mut addr: SocketAddr) -> SocketAddr {
+    if addr.ip().is_unspecified() {
+        match addr {
+            SocketAddr::V4(_) => addr.set_ip(Ipv4Addr::LOCALHOST.into()),
+            SocketAddr::V6(_) => addr.set_ip(Ipv6Addr::LOCALHOST.into()),
+        }
+    }
+    addr
+}
+
+/// The connections a server is serving, so that shutting it down can wait for them.
+#[derive(Default)]
+struct OpenConnections {
+    // With the protocol version negotiated on them, for the JSON protocol.
+    streams: Mutex<BTreeMap<usize, (Stream, u32)>>,
+    closed: Condvar,
+}
+
+/// A connection registered as open until it is dropped.
+struct Registered {
+    open: Arc<OpenConnections>,
+    id: usize,
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        self.open.streams.lock().unwrap().remove(&self.id);
+        self.open.closed.notify_all();
+    }
+}
+
+impl Registered {
+    /// Records the protocol version negotiated on the connection.
+    fn negotiated(&self, version: u32) {
+        if let Some((_, negotiated)) = self.open.streams.lock().unwrap().get_mut(&self.id) {
+            *negotiated = version;
+        }
+    }
+}
+
+impl OpenConnections {
+    fn register(self: &Arc<Self>, id: usize, stream: &Stream) -> Result<Registered> {
+        let stream = stream.try_clone()?;
+        self.streams
+            .lock()
+            .unwrap()
+            .insert(id, (stream, MAX_VERSION));
+        Ok(Registered {
+            open: Arc::clone(self),
+            id,
+        })
+    }
+
+    /// Waits up to the grace period for every connection to close, once no more
+    /// requests are read from them, then closes the ones left.
+    fn drain(&self, grace_period: Duration, protocol: Protocol) {
+        let deadline = Instant::now() + grace_period;
+        let mut streams = self.streams.lock().unwrap();
+        info!("Shutting down, waiting for {} connections", streams.len());
+        // A connection reading its next request sees the end of it, and one handling a
+        // request can still answer it.
+        for (stream, _) in streams.values() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+        while !streams.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            streams = self.closed.wait_timeout(streams, deadline - now).unwrap().0;
+        }
+        if !streams.is_empty() {
+            warn!(
+                "Closing {} connections still open after the grace period",
+                streams.len()
+            );
+        }
+        let message = "The server is shutting down";
+        for (stream, version) in streams.values() {
+            let mut writer = stream;
+            let _ = match protocol {
+                Protocol::Json => serde_json::to_writer(
+                    &mut writer,
+                    &Frame::new(*version, 0, Response::Err(message.to_owned())),
+                )
+                .map_err(KvsError::from),
+                Protocol::Resp => Reply::Error(format!("ERR {}", message)).write_to(&mut writer),
+            };
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Skips the whitespace before the next message, returning `false` at the end of the
+/// stream instead.
+fn skip_whitespace<R: BufRead>(reader: &mut R) -> io::Result<bool> {
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(false);
+        }
+        let blank = buf.iter().take_while(|b| b.is_ascii_whitespace()).count();
+        let found = blank < buf.len();
+        reader.consume(blank);
+        if found {
+            return Ok(true);
+        }
+    }
+}
+
+/// A connection of a client, with the engine it is served from.
+struct Connection<E> {
+    engine: E,
+    metrics: Arc<ServerMetrics>,
+    // The token the client must authenticate with, if the server requires one.
+    auth_token: Option<AuthToken>,
+}
+
+impl<E: KvsEngine> Connection<E> {
+    /// Serves requests in the JSON protocol, which a client may pipeline.
+    ///
+    /// Requests are read one at a time from whatever the client sent, however it comes
+    /// in pieces, and the responses are only flushed once no more requests are
+    /// buffered, so a batch of requests is answered in as few writes as it came in.
+    fn serve_json(&mut self, stream: Stream, registered: &Registered) -> Result<()> {
+        let metrics = Arc::clone(&self.metrics);
+        let mut reader = BufReader::new(Counted::new(&stream, &metrics));
+        let mut writer = BufWriter::new(Counted::new(&stream, &metrics));
+        let hello = match Hello::deserialize(&mut Deserializer::from_reader(&mut reader)) {
+            Ok(hello) => hello,
+            // Most likely a client from before the handshake, which can show this response.
+            Err(e) => {
+                let message = "Expected a protocol handshake, the client may be too old";
+                serde_json::to_writer(&mut writer, &Response::Err(message.to_owned()))?;
+                writer.flush()?;
+                return Err(e.into());
+            }
+        };
+        hello.check_magic()?;
+        serde_json::to_writer(&mut writer, &Hello::new())?;
+        writer.flush()?;
+        let version = negotiate(hello.version, MAX_VERSION)?;
+        registered.negotiated(version);
+        debug!("Speaking protocol version {}", version);
+        // The token the next request must carry, until one does.
+        let mut required = self.auth_token.clone();
+        loop {
+            if reader.buffer().is_empty() {
+                writer.flush()?;
+            }
+            if !skip_whitespace(&mut reader)? {
+                writer.flush()?;
+                return Ok(());
+            }
+            // Every message is an object, so reading one never reads past its end.
+            let frame = Frame::<Value>::deserialize(&mut Deserializer::from_reader(&mut reader))?;
+            let (id, message) = frame.into_message(version)?;
+            if let Some(token) = &required {
+                let response = match Request::deserialize(&message) {
+                    Ok(Request::Auth { token: given }) => {
+                        metrics.command(CommandType::Auth);
+                        if token.matches(given.as_bytes()) {
+                            Response::Ok(None)
+                        } else {
+                            Response::AuthFailed
+                        }
+                    }
+                    _ => Response::AuthRequired,
+                };
+                if let Response::Ok(_) = response {
+                    required = None;
+                    serde_json::to_writer(&mut writer, &Frame::new(version, id, response))?;
+                    continue;
+                }
+                self.reject(&stream, &format!("{:?}", response));
+                serde_json::to_writer(&mut writer, &Frame::new(version, id, response))?;
+                writer.flush()?;
+                return Ok(());
+            }
+            let request = match Request::deserialize(&message) {
+                Ok(request) => request,
+                // A request of a newer client is answered, so the client can go on.
+                Err(e) => match request_name(&message) {
+                    Some(name) if !Request::NAMES.contains(&name) => {
+                        debug!("Unsupported request: {}", name);
+                        metrics.error(ErrorType::Unsupported);
+                        let response = Response::Unsupported(name.to_owned());
+                        serde_json::to_writer(&mut writer, &Frame::new(version, id, response))?;
+                        continue;
+                    }
+                    _ => return Err(e.into()),
+                },
+            };
+            debug!("Request {}: {:?}", id, request);
+            metrics.command(request.command_type());
+            let response = match self.handle(request) {
+                Ok(response) => response,
+                Err(KvsError::KeyNotFound) => {
+                    metrics.error(ErrorType::KeyNotFound);
+                    Response::KeyNotFound
+                }
+                Err(e) => {
+                    metrics.error(ErrorType::Failed);
+                    Response::Err(e.to_string())
+                }
+            };
+            serde_json::to_writer(&mut writer, &Frame::new(version, id, response))?;
+        }
+    }
+
+    fn handle(&mut self, request: Request) -> Result<Response> {
+        let value = match request {
+            // Answered whatever the engine is doing, even compacting.
+            Request::Ping => return Ok(Response::Pong),
+            // Only a token again after the first one authenticated the connection.
+            Request::Auth { token } => match &self.auth_token {
+                Some(expected) if !expected.matches(token.as_bytes()) => {
+                    return Ok(Response::AuthFailed)
+                }
+                _ => None,
+            },
+            Request::Health => return Ok(Response::Health(self.health())),
+            Request::Get { key } => self.engine.get(key)?,
+            Request::Set { key, value } => self.engine.set(key, value).map(|()| None)?,
+            Request::Remove { key } => self.engine.remove(key).map(|()| None)?,
+            Request::Scan {
+                cursor,
+                prefix,
+                count,
+            } => return self.scan(&cursor, prefix.as_deref().unwrap_or(""), count),
+            Request::Exists { key } => return Ok(Response::Bool(self.engine.contains_key(key)?)),
+            Request::Del { keys } => return Ok(Response::Count(self.engine.remove_keys(keys)?)),
+            Request::Keys { prefix } => {
+                let prefix = prefix.as_deref().unwrap_or("");
+                let keys = self.engine.scan_keys(prefix, None, MAX_KEYS + 1)?;
+                if keys.len() > MAX_KEYS {
+                    return Err(KvsError::TooManyKeys(MAX_KEYS));
+                }
+                return Ok(Response::Keys {
+                    keys,
+                    cursor: String::new(),
+                });
+            }
+            Request::DbSize => return Ok(Response::Count(self.engine.key_count()?)),
+        };
+        Ok(Response::Ok(value))
+    }
+
+    /// Counts and logs a connection failing to authenticate, then waits before it is
+    /// answered and closed, so guessing the token takes a while.
+    fn reject(&self, stream: &Stream, reason: &str) {
+        self.metrics.error(ErrorType::Unauthorized);
+        match stream.tcp().peer_addr() {
+            Ok(peer) => warn!("Rejecting {}: {}", peer, reason),
+            Err(_) => warn!("Rejecting a connection: {}", reason),
+        }
+        thread::sleep(AUTH_FAILURE_DELAY);
+    }
+
+    /// Checks that the engine can serve requests by reading a reserved key, which must
+    /// take no longer than `HEALTH_TIMEOUT`.
+    fn health(&mut self) -> Health {
+        let start = Instant::now();
+        match self.engine.contains_key(HEALTH_KEY.to_owned()) {
+            Ok(_) if start.elapsed() > HEALTH_TIMEOUT => Health::Degraded(format!(
+                "the engine took {:?} to answer a read",
+                start.elapsed()
+            )),
+            Ok(_) => Health::Ok,
+            Err(e) => Health::Degraded(format!("the engine failed a read: {}", e)),
+        }
+    }
+
+    /// Lists the keys after a cursor like Redis `SCAN`.
+    ///
+    /// The cursor is the last key listed, so the scan resumes after it whatever is
+    /// written in between. A key present for the whole scan is listed exactly once, and
+    /// a key added or removed during it may or may not be.
+    fn scan(&mut self, cursor: &str, prefix: &str, count: usize) -> Result<Response> {
+        let after = decode_cursor(cursor)?;
+        let count = count.clamp(1, MAX_KEYS);
+        let keys = self.engine.scan_keys(prefix, after.as_deref(), count)?;
+        let cursor = match keys.last() {
+            Some(last) if keys.len() == count => encode_cursor(last),
+            _ => String::new(),
+        };
+        Ok(Response::Keys { keys, cursor })
+    }
+
+    fn serve_resp(&mut self, stream: Stream) -> Result<()> {
+        let metrics = Arc::clone(&self.metrics);
+        let mut reader = BufReader::new(Counted::new(&stream, &metrics));
+        let mut writer = BufWriter::new(Counted::new(&stream, &metrics));
+        // The token the next command must carry, until one does.
+        let mut required = self.auth_token.clone();
+        loop {
+            // Pipelined commands are answered together, like JSON requests.
+            if reader.buffer().is_empty() {
+                writer.flush()?;
+            }
+            let command = match read_command(&mut reader) {
+                Ok(Some(command)) => command,
+                Ok(None) => {
+                    writer.flush()?;
+                    return Ok(());
+                }
+                // Like Redis, tell the client what went wrong before hanging up.
+                Err(e @ KvsError::Protocol(_)) => {
+                    Reply::Error(format!("ERR {}", e)).write_to(&mut writer)?;
+                    writer.flush()?;
+                    return Err(e);
+                }
+                Err(e) => return Err(e),
+            };
+            if command.is_empty() {
+                continue;
+            }
+            if let Some(token) = &required {
+                let reply = match authenticate_resp(token, &command) {
+                    Some(true) => {
+                        metrics.command(CommandType::Auth);
+                        required = None;
+                        Reply::Simple("OK").write_to(&mut writer)?;
+                        continue;
+                    }
+                    Some(false) => {
+                        metrics.command(CommandType::Auth);
+                        Reply::Error("WRONGPASS invalid auth token".to_owned())
+                    }
+                    None => Reply::Error("NOAUTH Authentication required".to_owned()),
+                };
+                if let Reply::Error(reason) = &reply {
+                    self.reject(&stream, reason);
+                }
+                reply.write_to(&mut writer)?;
+                writer.flush()?;
+                return Ok(());
+            }
+            debug!("Command: {:?}", String::from_utf8_lossy(&command[0]));
+            let reply = self.handle_resp(command).unwrap_or_else(|e| match e {
+                KvsError::Utf8(_) => {
+                    metrics.error(ErrorType::Invalid);
+                    Reply::Error(
+                        "ERR keys must be UTF-8, as must values the engine stores as strings"
+                            .to_owned(),
+                    )
+                }
+                e => {
+                    metrics.error(ErrorType::Failed);
+                    Reply::Error(format!("ERR {}", e))
+                }
+            });
+            reply.write_to(&mut writer)?;
+        }
+    }
+
+    fn handle_resp(&mut self, command: Vec<Vec<u8>>) -> Result<Reply> {
+        let mut args = command.into_iter();
+        let name = String::from_utf8_lossy(&args.next().unwrap()).to_ascii_lowercase();
+        let args: Vec<Vec<u8>> = args.collect();
+        let (command, arity_ok) = match name.as_str() {
+            "get" => (CommandType::Get, args.len() == 1),
+            "set" => (CommandType::Set, args.len() == 2),
+            "del" => (CommandType::Del, !args.is_empty()),
+            "exists" => (CommandType::Exists, !args.is_empty()),
+            "ping" => (CommandType::Ping, args.len() <= 1),
+            "auth" => (CommandType::Auth, args.len() == 1),
+            _ => {
+                self.metrics.error(ErrorType::Unsupported);
+                return Ok(Reply::Error(format!("ERR unknown command '{}'", name)));
+            }
+        };
+        self.metrics.command(command);
+        if !arity_ok {
+            self.metrics.error(ErrorType::Invalid);
+            let message = format!("ERR wrong number of arguments for '{}' command", name);
+            return Ok(Reply::Error(message));
+        }
+        let mut args = args.into_iter();
+        let reply = match name.as_str() {
+            "get" => Reply::Bulk(
+                self.engine
+                    .get_bytes(String::from_utf8(args.next().unwrap())?)?,
+            ),
+            "set" => {
+                let key = String::from_utf8(args.next().unwrap())?;
+                self.engine.set_bytes(key, args.next().unwrap())?;
+                Reply::Simple("OK")
+            }
+            "del" => {
+                let keys = args
+                    .map(String::from_utf8)
+                    .collect::<std::result::Result<_, _>>()?;
+                Reply::Integer(self.engine.remove_keys(keys)? as i64)
+            }
+            "exists" => {
+                let mut found = 0;
+                for key in args {
+                    if self.engine.contains_key(String::from_utf8(key)?)? {
+                        found += 1;
+                    }
+                }
+                Reply::Integer(found)
+            }
+            "ping" => match args.next() {
+                Some(message) => Reply::Bulk(Some(message)),
+                None => Reply::Simple("PONG"),
+            },
+            // Only a token again after the first one authenticated the connection.
+            "auth" => {
+                let given = args.next().unwrap();
+                match &self.auth_token {
+                    Some(expected) if !expected.matches(&given) => {
+                        Reply::Error("WRONGPASS invalid auth token".to_owned())
+                    }
+                    _ => Reply::Simple("OK"),
+                }
+            }
+            _ => unreachable!(),
+        };
+        Ok(reply)
+    }
+}
+
+/// Returns whether a command is `AUTH` with the given token, or `None` if it is another
+/// command.
+fn authenticate_resp(token: &AuthToken, command: &[Vec<u8>]) -> Option<bool> {
+    match command {
+        [name, given] if name.eq_ignore_ascii_case(b"auth") => Some(token.matches(given)),
+        _ => None,
+    }
+}
@@ -0,0 +1,484 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{BufWriter, Seek, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+
+use log::warn;
+
+use crate::archive::{prune_archive, retire_log};
+use crate::format::Codec;
+use crate::hint::write_hint;
+use crate::index::StaleRecords;
+use crate::kv::{
+    apply, copy_records, create_log, log_path, now_millis, open_logs, Command, CommandPos,
+    LogReaders, Unsynced,
+};
+use crate::merge::unmark_merges;
+use crate::snapshot::{write_snapshot, Snapshot};
+use crate::{Durability, EngineStats, KvsError, LogFormat, Options, Result};
+
+/// A handle to a store that can be cloned and shared between threads.
+///
+/// Every clone has its own file handles, so reads on different clones never wait for
+/// each other. Writes are serialized, and only lock the index to update it. Automatic
+/// compaction runs on the writing thread while reads go on.
+///
+/// A read holds the index lock while it reads its record, so a compaction can only
+/// remove a log once no read is left that may still point into it.
+pub struct SharedKvStore {
+    shared: Arc<Shared>,
+    readers: Mutex<LogReaders>,
+}
+
+/// The state of a store shared by all clones of its handle.
+struct Shared {
+    folder: PathBuf,
+    format: Codec,
+    options: Options,
+    index: RwLock<BTreeMap<String, CommandPos>>,
+    // The keys of the index that expire, by the time they do, so the expired ones are
+    // counted without reading every entry. Locked while the index is, after it.
+    expiring: Mutex<BTreeSet<(u64, String)>>,
+    // Total size of the logs, kept up to date by the writes.
+    disk_bytes: AtomicU64,
+    // `None` if the store is read-only.
+    writer: Mutex<Option<Writer>>,
+    // Logs below this generation have been removed, so readers close their handles to them.
+    safe_point: AtomicU64,
+    // Held for as long as the store is open.
+    _lock: Option<File>,
+}
+
+/// The active log of a shared store, and everything only writes touch.
+struct Writer {
+    writer: BufWriter<File>,
+    cur_gen: u64,
+    gens: BTreeSet<u64>,
+    uncompacted: u64,
+    stale: StaleRecords,
+    // Sequence number of the last change written.
+    seq: u64,
+    unsynced: Unsynced,
+    // Compactions finished since the store was opened.
+    compactions: u64,
+}
+
+impl SharedKvStore {
+    /// Opens a `SharedKvStore` with the given path.
+    ///
+    /// This behaves like [`KvStore::open`], and the store can be opened with either afterwards.
+    ///
+    /// [`KvStore::open`]: crate::KvStore::open
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_dir(path.as_ref(), None, Options::default())
+    }
+    pub(crate) fn open_dir(
+        folder: &Path,
+        requested: Option<LogFormat>,
+        options: Options,
+    ) -> Result<Self> {
+        // The index has to be shared between threads, so it is kept in memory.
+        let options = Options {
+            disk_index: false,
+            ..options
+        };
+        let logs = open_logs(folder, requested, &options)?;
+        let gens = logs.readers.gens().collect();
+        let writer = logs.writer.map(|writer| Writer {
+            writer,
+            cur_gen: logs.cur_gen,
+            gens,
+            uncompacted: logs.uncompacted,
+            stale: logs.stale,
+            seq: logs.seq,
+            unsynced: Unsynced::new(),
+            compactions: 0,
+        });
+        let expiring = logs
+            .index
+            .iter()
+            .filter_map(|(key, pos)| Some((pos.expires_at?, key.clone())))
+            .collect();
+        let shared = Shared {
+            folder: folder.to_owned(),
+            format: logs.format,
+            options,
+            index: RwLock::new(logs.index),
+            expiring: Mutex::new(expiring),
+            disk_bytes: AtomicU64::new(logs.disk_bytes),
+            writer: Mutex::new(writer),
+            safe_point: AtomicU64::new(0),
+            _lock: logs.lock,
+        };
+        Ok(Self {
+            shared: Arc::new(shared),
+            readers: Mutex::new(logs.readers),
+        })
+    }
+    /// Sets the value of a string key to a string.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        let mut writer = self.shared.writer.lock().unwrap();
+        let writer = writer.as_mut().ok_or(KvsError::ReadOnly)?;
+        self.shared.append(writer, Command::Set(key, value))
+    }
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist or has expired.
+    pub fn get<K: AsRef<str>>(&self, key: K) -> Result<Option<String>> {
+        match self.read_live(key.as_ref())? {
+            Some(cmd) => cmd.into_value(),
+            None => Ok(None),
+        }
+    }
+    /// Sets the value of a string key to arbitrary bytes.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    pub fn set_bytes(&self, key: String, value: Vec<u8>) -> Result<()> {
+        let mut writer = self.shared.writer.lock().unwrap();
+        let writer = writer.as_mut().ok_or(KvsError::ReadOnly)?;
+        self.shared.append(writer, Command::SetBytes(key, value))
+    }
+    /// Gets the value of a given string key as bytes.
+    ///
+    /// String values are returned as their UTF-8 bytes. Returns `None` if the given
+    /// key does not exist or has expired.
+    pub fn get_bytes<K: AsRef<str>>(&self, key: K) -> Result<Option<Vec<u8>>> {
+        Ok(self.read_live(key.as_ref())?.and_then(Command::into_bytes))
+    }
+    /// Reads the record of a key that has not expired.
+    fn read_live(&self, key: &str) -> Result<Option<Command>> {
+        let index = self.shared.index.read().unwrap();
+        let pos = match index.get(key) {
+            Some(pos) if !pos.is_expired(now_millis()) => pos,
+            _ => return Ok(None),
+        };
+        let mut readers = self.readers.lock().unwrap();
+        let safe_point = self.shared.safe_point.load(Ordering::Acquire);
+        readers.remove_below(safe_point);
+        // The index only points into logs that are still there.
+        readers.add(&self.shared.folder, pos.gen);
+        readers.read_value(key, pos).map(Some)
+    }
+    /// Removes a given key.
+    ///
+    /// Returns `KvsError::KeyNotFound` if the given key is not found.
+    pub fn remove<K: AsRef<str>>(&self, key: K) -> Result<()> {
+        let key = key.as_ref();
+        // Hold the writer while checking, so no other write can remove the key in between.
+        let mut writer = self.shared.writer.lock().unwrap();
+        let writer = writer.as_mut().ok_or(KvsError::ReadOnly)?;
+        if self.contains_key(key) {
+            self.shared.append(writer, Command::Remove(key.to_owned()))
+        } else {
+            Err(KvsError::KeyNotFound)
+        }
+    }
+    /// Returns `true` if the store contains the given key.
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.shared
+            .index
+            .read()
+            .unwrap()
+            .get(key)
+            .is_some_and(|pos| !pos.is_expired(now_millis()))
+    }
+    /// Returns the number of keys in the store.
+    ///
+    /// Keys that expired are found through the keys ordered by expiry time, so this
+    /// takes no time in the number of keys.
+    pub fn len(&self) -> usize {
+        let index = self.shared.index.read().unwrap();
+        let expiring = self.shared.expiring.lock().unwrap();
+        index.len() - expired(&expiring, now_millis()).count()
+    }
+    /// Returns `true` if the store contains no keys.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Returns all keys in sorted order.
+    ///
+    /// The keys are copied out of the index, so writes can go on while they are used.
+    pub fn keys(&self) -> Vec<String> {
+        let now = now_millis();
+        let index = self.shared.index.read().unwrap();
+        index
+            .iter()
+            .filter(|(_, pos)| !pos.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+    /// Returns up to `count` keys starting with `prefix` in sorted order, from the first
+    /// one after `after` if it is given.
+    pub(crate) fn scan_keys(&self, prefix: &str, after: Option<&str>, count: usize) -> Vec<String> {
+        let now = now_millis();
+        let start = match after {
+            Some(after) if after >= prefix => Bound::Excluded(after),
+            _ => Bound::Included(prefix),
+        };
+        let index = self.shared.index.read().unwrap();
+        index
+            .range::<str, _>((start, Bound::Unbounded))
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .filter(|(_, pos)| !pos.is_expired(now))
+            .take(count)
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+    /// Flushes the writes buffered by the store and syncs them to disk, whatever the
+    /// durability mode. A read-only store has nothing to sync.
+    pub fn sync(&self) -> Result<()> {
+        match self.shared.writer.lock().unwrap().as_mut() {
+            Some(writer) => {
+                writer.writer.flush()?;
+                writer.sync()
+            }
+            None => Ok(()),
+        }
+    }
+    /// Clears stale entries in the log by copying every live record into a new generation.
+    ///
+    /// Returns the number of bytes reclaimed on disk, which [`KvStore::compact`] reports
+    /// as `reclaimed_bytes`.
+    ///
+    /// [`KvStore::compact`]: crate::KvStore::compact
+    pub fn compact(&self) -> Result<u64> {
+        let mut writer = self.shared.writer.lock().unwrap();
+        let writer = writer.as_mut().ok_or(KvsError::ReadOnly)?;
+        self.shared.compact(writer)
+    }
+    /// Returns the number of compactions that finished since the store was opened, by
+    /// any of its clones, whether run by hand or automatically. A read-only store never
+    /// compacts.
+    pub fn compactions(&self) -> u64 {
+        self.shared
+            .writer
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(0, |writer| writer.compactions)
+    }
+    /// Returns the statistics `KvsEngine::engine_stats` reports, which are counted as
+    /// the store changes.
+    pub(crate) fn measure(&self) -> Result<EngineStats> {
+        let mut stats = EngineStats {
+            live_keys: self.len() as u64,
+            disk_bytes: self.shared.disk_bytes.load(Ordering::Relaxed),
+            ..EngineStats::default()
+        };
+        if let Some(writer) = self.shared.writer.lock().unwrap().as_ref() {
+            stats.uncompacted_bytes = writer.uncompacted;
+            stats.compactions = writer.compactions;
+        }
+        Ok(stats)
+    }
+}
+
+impl Clone for SharedKvStore {
+    // The clone opens its own file handles as it reads.
+    fn clone(&self) -> Self {
+        let mut readers = LogReaders::new(
+            self.shared.format,
+            self.shared.options.maps_logs(),
+            self.shared.options.max_open_logs,
+        );
+        readers.merge_operator = self.shared.options.merge_operator;
+        Self {
+            shared: Arc::clone(&self.shared),
+            readers: Mutex::new(readers),
+        }
+    }
+}
+
+impl Shared {
+    /// Appends a command to the active log and applies it to the index.
+    fn append(&self, writer: &mut Writer, cmd: Command) -> Result<()> {
+        let mut buf = Vec::new();
+        self.format
+            .encode_sequenced(&cmd, writer.seq + 1, &mut buf)?;
+        let before = writer.writer.stream_position()?;
+        writer.writer.write_all(&buf)?;
+        writer.writer.flush()?;
+        if writer.unsynced.count_write(self.options.durability) {
+            writer.sync()?;
+        }
+        self.disk_bytes
+            .fetch_add(buf.len() as u64, Ordering::Relaxed);
+        let pos = (writer.cur_gen, before, before + buf.len() as u64);
+        writer.seq += 1;
+        let mut index = self.index.write().unwrap();
+        let mut expiring = self.expiring.lock().unwrap();
+        // Only sets and removals are written, which never expire, so the keys that expire
+        // only change by the ones they replace.
+        let key = match &cmd {
+            Command::Remove(key) => Some(key.as_str()),
+            cmd => cmd.key(),
+        };
+        let key = key.filter(|_| !expiring.is_empty()).map(str::to_owned);
+        let stale = apply(&mut *index, cmd, pos)?;
+        let replaced = stale.replaced.and_then(|old| old.expires_at);
+        if let (Some(key), Some(expires_at)) = (key, replaced) {
+            expiring.remove(&(expires_at, key));
+        }
+        drop(expiring);
+        drop(index);
+        writer.uncompacted += stale.bytes;
+        writer.stale += stale.records;
+        if pos.2 >= self.options.max_segment_size {
+            if writer.unsynced.writes > 0 && self.options.durability != Durability::Never {
+                writer.sync()?;
+            }
+            writer.cur_gen += 1;
+            writer.writer = create_log(&self.folder, writer.cur_gen)?;
+            writer.gens.insert(writer.cur_gen);
+            trace_debug!(gen = writer.cur_gen, "rolled over to a new log");
+        }
+        if writer.uncompacted > self.options.compaction_threshold {
+            self.compact(writer)?;
+        }
+        Ok(())
+    }
+    /// Copies every live record into a new generation, and removes the older ones.
+    fn compact(&self, writer: &mut Writer) -> Result<u64> {
+        trace_span!("compact", reclaimed = tracing::field::Empty);
+        let now = now_millis();
+        let mut index = self.index.write().unwrap();
+        index.retain(|_, pos| {
+            if pos.is_expired(now) {
+                writer.uncompacted += pos.len();
+                writer.stale.values += 1;
+            }
+            !pos.is_expired(now)
+        });
+        let mut expiring = self.expiring.lock().unwrap();
+        *expiring = expiring.split_off(&(now.saturating_add(1), String::new()));
+        drop(expiring);
+        drop(index);
+        if writer.uncompacted == 0 {
+            trace_record!("reclaimed", 0);
+            return Ok(0);
+        }
+        let compaction_gen = writer.cur_gen + 1;
+        writer.cur_gen += 2;
+        writer.writer = create_log(&self.folder, writer.cur_gen)?;
+        writer.gens.insert(writer.cur_gen);
+
+        let mut files = LogReaders::new(self.format, false, self.options.max_open_logs);
+        files.merge_operator = self.options.merge_operator;
+        for &gen in writer.gens.range(..compaction_gen) {
+            files.add(&self.folder, gen);
+        }
+        // Holding the writer keeps the index as it is, so reads can go on while copying.
+        let (copies, _) = copy_records(
+            &self.folder,
+            compaction_gen,
+            self.format,
+            self.options.compresses(),
+            &mut files,
+            self.index.read().unwrap().iter(),
+            None,
+        )?;
+        writer.gens.insert(compaction_gen);
+        let entries = {
+            let mut index = self.index.write().unwrap();
+            for (pos, copy) in index.values_mut().zip(copies) {
+                *pos = copy;
+            }
+            index
+                .iter()
+                .map(|(key, pos)| (key.clone(), pos.start, pos.end, pos.expires_at))
+                .collect()
+        };
+        let hint_len = write_hint(&self.folder, compaction_gen, entries, writer.seq, 0)?;
+        // No read can reach the stale logs any more.
+        self.safe_point.store(compaction_gen, Ordering::Release);
+        drop(files);
+        let mut stale_bytes = 0;
+        let stale_gens: Vec<u64> = writer.gens.range(..compaction_gen).cloned().collect();
+        for stale_gen in stale_gens {
+            writer.gens.remove(&stale_gen);
+            let archive = self.options.archive.is_some();
+            let (log_len, hint_len) = retire_log(&self.folder, stale_gen, archive)?;
+            trace_debug!(
+                gen = stale_gen,
+                bytes = log_len,
+                archive,
+                "retired a stale log"
+            );
+            stale_bytes += log_len + hint_len;
+            self.disk_bytes.fetch_sub(log_len, Ordering::Relaxed);
+        }
+        if let Some(retention) = self.options.archive {
+            prune_archive(&self.folder, retention)?;
+        }
+        // The compacted log has the merges resolved, and no more are written.
+        unmark_merges(&self.folder)?;
+        writer.uncompacted = 0;
+        writer.stale = StaleRecords::default();
+        writer.compactions += 1;
+        let compacted_len = fs::metadata(log_path(&self.folder, compaction_gen))?.len();
+        self.disk_bytes.fetch_add(compacted_len, Ordering::Relaxed);
+        let reclaimed = stale_bytes.saturating_sub(compacted_len + hint_len);
+        trace_record!("reclaimed", reclaimed);
+        Ok(reclaimed)
+    }
+    /// Writes the index to a snapshot, handing it over to the next open of the store.
+    fn write_snapshot(&mut self) -> Result<()> {
+        let writer = match self.writer.get_mut().unwrap() {
+            Some(writer) => writer,
+            None => return Ok(()),
+        };
+        writer.writer.flush()?;
+        let mut logs = Vec::with_capacity(writer.gens.len());
+        for &gen in &writer.gens {
+            let len = if gen == writer.cur_gen {
+                writer.writer.stream_position()?
+            } else {
+                fs::metadata(log_path(&self.folder, gen))?.len()
+            };
+            logs.push((gen, len));
+        }
+        let snapshot = Snapshot {
+            logs,
+            cur_gen: writer.cur_gen,
+            uncompacted: writer.uncompacted,
+            index: std::mem::take(self.index.get_mut().unwrap()),
+            stale: writer.stale,
+            seq: writer.seq,
+            // Older versions of keys are not kept track of, so they go uncounted.
+            kept: 0,
+        };
+        write_snapshot(&self.folder, &snapshot)
+    }
+}
+
+impl Drop for Shared {
+    // Dropping the last handle closes the store, which saves its index like `KvStore` does.
+    fn drop(&mut self) {
+        if let Err(e) = self.write_snapshot() {
+            warn!(
+                "Failed to write the index snapshot of {}: {}",
+                self.folder.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Returns the keys of a set ordered by expiry time that have expired by `now`.
+fn expired(expiring: &BTreeSet<(u64, String)>, now: u64) -> impl Iterator<Item = &(u64, String)> {
+    expiring.range(..(now.saturating_add(1), String::new()))
+}
+
+impl Writer {
+    /// Syncs the active log to disk.
+    fn sync(&mut self) -> Result<()> {
+        self.writer.get_ref().sync_data()?;
+        self.unsynced = Unsynced::new();
+        Ok(())
+    }
+}
@@ -0,0 +1,102 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::index::StaleRecords;
+use crate::kv::{log_path, CommandPos};
+use crate::Result;
+
+/// Name of the file holding the index snapshot of a store.
+pub(crate) const SNAPSHOT_FILE: &str = "index.snapshot";
+
+/// The in-memory state of a store, written when it is closed cleanly.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Snapshot {
+    /// Generation and length of every log when the snapshot was written.
+    pub(crate) logs: Vec<(u64, u64)>,
+    pub(crate) cur_gen: u64,
+    pub(crate) uncompacted: u64,
+    pub(crate) index: BTreeMap<String, CommandPos>,
+    pub(crate) stale: StaleRecords,
+    pub(crate) seq: u64,
+    // Last, so a snapshot written without it fails to read instead of being misread.
+    pub(crate) kept: u64,
+}
+
+fn snapshot_path(dir: &Path) -> PathBuf {
+    dir.join(SNAPSHOT_FILE)
+}
+
+/// Writes the index snapshot of a store.
+pub(crate) fn write_snapshot(dir: &Path, snapshot: &Snapshot) -> Result<()> {
+    let payload = bincode::serialize(snapshot)?;
+    let mut bytes = crc32fast::hash(&payload).to_le_bytes().to_vec();
+    bytes.extend_from_slice(&payload);
+    // Write to a temporary file first, so a crash never leaves a partial snapshot behind.
+    let tmp_path = dir.join(format!("{SNAPSHOT_FILE}.tmp"));
+    fs::write(&tmp_path, &bytes)?;
+    fs::rename(tmp_path, snapshot_path(dir))?;
+    Ok(())
+}
+
+/// Reads the index snapshot of a store whose logs are the given generations.
+///
+/// Returns `None` if there is no snapshot, or if it is damaged or does not match the
+/// logs. The newest log may have grown since the snapshot was written, in which case
+/// the records after its recorded length still have to be replayed.
+pub(crate) fn read_snapshot(dir: &Path, gens: &[u64]) -> Option<Snapshot> {
+    match try_read_snapshot(dir, gens) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            warn!("Ignoring the index snapshot: {}", e);
+            None
+        }
+    }
+}
+
+fn try_read_snapshot(dir: &Path, gens: &[u64]) -> Result<Option<Snapshot>> {
+    let path = snapshot_path(dir);
+    let bytes = match fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if bytes.len() < 4 || crc32fast::hash(&bytes[4..]).to_le_bytes() != bytes[..4] {
+        warn!("Ignoring the damaged index snapshot");
+        return Ok(None);
+    }
+    let snapshot: Snapshot = bincode::deserialize(&bytes[4..])?;
+    if !snapshot
+        .logs
+        .iter()
+        .map(|&(gen, _)| gen)
+        .eq(gens.iter().cloned())
+        || Some(&snapshot.cur_gen) != gens.last()
+    {
+        return Ok(None);
+    }
+    let written = fs::metadata(&path)?.modified()?;
+    for (i, &(gen, len)) in snapshot.logs.iter().enumerate() {
+        let log = fs::metadata(log_path(dir, gen))?;
+        let grown = i + 1 == snapshot.logs.len() && log.len() > len;
+        if !grown && (log.len() != len || log.modified()? > written) {
+            return Ok(None);
+        }
+    }
+    Ok(Some(snapshot))
+}
+
+/// Removes the index snapshot of a store, if any.
+///
+/// This is done as soon as the store is opened, so a crash after later writes
+/// cannot bring back the outdated snapshot.
+pub(crate) fn remove_snapshot(dir: &Path) -> Result<()> {
+    match fs::remove_file(snapshot_path(dir)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use serde::Serialize;
+
+/// Statistics on the keys and disk usage of a store, returned by [`KvStore::stats`].
+///
+/// [`KvStore::stats`]: crate::KvStore::stats
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct StoreStats {
+    /// Number of keys that have not expired.
+    pub live_keys: usize,
+    /// Number of log files, including the active one.
+    pub generations: usize,
+    /// Generation of the active log.
+    pub current_gen: u64,
+    /// Total size of the log files.
+    pub disk_bytes: u64,
+    /// Bytes of stale records in the logs, which a compaction would reclaim.
+    pub uncompacted_bytes: u64,
+    /// Bytes of the latest records of the live keys.
+    pub live_bytes: u64,
+    /// Number of reads answered from the value cache.
+    pub cache_hits: u64,
+    /// Number of reads the value cache could not answer, if it is enabled.
+    pub cache_misses: u64,
+    /// Bytes the values in the value cache count for.
+    pub cache_bytes: u64,
+    /// Number of keys that have not expired in every namespace but the default one,
+    /// which holds the rest of `live_keys`.
+    pub namespace_keys: BTreeMap<String, usize>,
+    /// Lengths of the keys, counting the expired ones not evicted yet.
+    pub key_sizes: SizeStats,
+    /// Lengths of the latest records of the keys, which hold their values, counting the
+    /// expired ones not evicted yet.
+    pub value_sizes: SizeStats,
+}
+
+/// Distribution of sizes in bytes, part of [`StoreStats`].
+///
+/// It is kept up to date as the index changes, so it is never computed from the entries.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct SizeStats {
+    /// Number of sizes.
+    pub count: u64,
+    /// Smallest size, or `0` if there are none.
+    pub min: u64,
+    /// Largest size, or `0` if there are none.
+    pub max: u64,
+    /// Sum of the sizes.
+    pub total: u64,
+    /// Numbers of sizes by power of two. The first bucket counts the sizes of `0`, the
+    /// bucket `i` the sizes from `2^(i-1)` to `2^i - 1`, and the last one every size
+    /// from `2^30` on.
+    pub buckets: [u64; 32],
+}
+
+impl SizeStats {
+    /// Returns the mean size, or `0.0` if there are none.
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.total as f64 / self.count as f64
+        }
+    }
+}
+
+/// Statistics on an engine to monitor it with, returned by
+/// [`KvsEngine::engine_stats`].
+///
+/// [`KvsEngine::engine_stats`]: crate::KvsEngine::engine_stats
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct EngineStats {
+    /// Number of keys that have not expired.
+    pub live_keys: u64,
+    /// Total size of the log files.
+    pub disk_bytes: u64,
+    /// Bytes of stale records in the logs, which a compaction would reclaim.
+    pub uncompacted_bytes: u64,
+    /// Number of compactions that finished since the engine was opened.
+    pub compactions: u64,
+}
+
+/// What a compaction did, returned by [`KvStore::compact`].
+///
+/// The numbers are counted as the live records are copied, and cover every step of an
+/// incremental compaction.
+///
+/// [`KvStore::compact`]: crate::KvStore::compact
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CompactionReport {
+    /// Bytes of live records read from the logs the compaction replaces.
+    pub bytes_read: u64,
+    /// Bytes written to the compacted log and its hint file.
+    pub bytes_written: u64,
+    /// Bytes freed in the store directory, net of the bytes written.
+    pub reclaimed_bytes: u64,
+    /// Number of live values copied into the compacted log.
+    pub live_records: u64,
+    /// Number of values dropped because they were overwritten, removed or expired.
+    pub stale_records: u64,
+    /// Number of records of removes and clears dropped.
+    pub tombstones: u64,
+    /// Time spent compacting, not counting the time between the steps of an
+    /// incremental compaction.
+    pub duration: Duration,
+    /// Generations of the logs removed, or archived if logs are archived, oldest first.
+    pub removed_gens: Vec<u64>,
+}
+
+/// A damaged run of records skipped when a store was opened with
+/// `CorruptionPolicy::Skip`, returned by [`KvStore::skipped_regions`].
+///
+/// [`KvStore::skipped_regions`]: crate::KvStore::skipped_regions
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SkippedRegion {
+    /// Generation of the log.
+    pub gen: u64,
+    /// Byte offset of the start of the run.
+    pub offset: u64,
+    /// Length of the run in bytes.
+    pub len: u64,
+}
+
+/// What a sweep of the expired keys did, returned by [`KvStore::purge_expired`].
+///
+/// [`KvStore::purge_expired`]: crate::KvStore::purge_expired
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PurgeReport {
+    /// Number of expired keys evicted from the index.
+    pub purged_keys: u64,
+    /// Bytes of the records of the keys evicted, which now count as stale.
+    pub purged_bytes: u64,
+}
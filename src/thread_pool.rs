@@ -0,0 +1,89 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use log::{debug, error};
+
+use crate::{KvsError, Result};
+
+/// A pool of threads running jobs.
+pub trait ThreadPool {
+    /// Creates a pool with the given number of threads.
+    ///
+    /// Fails with `KvsError::NoThreads` if `threads` is 0.
+    fn new(threads: u32) -> Result<Self>
+    where
+        Self: Sized;
+    /// Runs a job on one of the threads of the pool.
+    ///
+    /// A job that panics does not take its thread out of the pool.
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A `ThreadPool` whose threads take jobs from one shared queue.
+///
+/// Dropping the pool lets its threads finish the queued jobs, then stop.
+pub struct SharedQueueThreadPool {
+    sender: Sender<Job>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        if threads == 0 {
+            return Err(KvsError::NoThreads);
+        }
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads {
+            spawn_worker(Worker(Arc::clone(&receiver)))?;
+        }
+        Ok(Self { sender })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        // The threads only stop once the sender is dropped, so they are still receiving.
+        self.sender
+            .send(Box::new(job))
+            .expect("the pool has no threads left");
+    }
+}
+
+/// A thread of the pool, which is replaced if a job panics on it.
+struct Worker(Arc<Mutex<Receiver<Job>>>);
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let worker = Worker(Arc::clone(&self.0));
+            if let Err(e) = spawn_worker(worker) {
+                error!("Failed to replace a panicked pool thread: {}", e);
+            }
+        }
+    }
+}
+
+fn spawn_worker(worker: Worker) -> Result<()> {
+    thread::Builder::new().spawn(move || run_jobs(worker))?;
+    Ok(())
+}
+
+fn run_jobs(worker: Worker) {
+    loop {
+        // The lock is released before the job runs, so a panic can never poison it.
+        let job = worker.0.lock().unwrap().recv();
+        match job {
+            Ok(job) => job(),
+            Err(_) => {
+                debug!("Pool thread stopped");
+                return;
+            }
+        }
+    }
+}
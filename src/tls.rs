@@ -0,0 +1,295 @@
+//! TLS between clients and servers, with rustls.
+
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{
+    ClientConfig, ClientConnection, Connection, RootCertStore, ServerConfig, ServerConnection,
+};
+
+use crate::{KvsError, Result};
+
+/// Size of the buffer the records of a connection are read off its socket into.
+const RECORDS_LEN: usize = 16 * 1024;
+
+/// How a client connects to a server over TLS, given to [`KvsClientBuilder::tls`].
+///
+/// The certificate of the server is checked against the authorities the system trusts,
+/// or the given ones, and must be valid for the name of the server. Options added
+/// later, like a certificate of the client for mutual TLS, default to off.
+///
+/// Available with the `tls` feature.
+///
+/// [`KvsClientBuilder::tls`]: crate::KvsClientBuilder::tls
+#[derive(Clone, Debug, Default)]
+pub struct ClientTlsConfig {
+    /// PEM encoded certificates of the authorities to trust instead of the ones of the
+    /// system, like the one signing the self-signed certificates of internal servers.
+    pub ca_cert: Option<Vec<u8>>,
+    /// Name the certificate of the server must be valid for, the host of its address by
+    /// default.
+    pub server_name: Option<String>,
+}
+
+impl ClientTlsConfig {
+    fn rustls_config(&self) -> Result<Arc<ClientConfig>> {
+        let mut roots = RootCertStore::empty();
+        match &self.ca_cert {
+            Some(pem) => {
+                for cert in certs(pem)? {
+                    roots.add(cert)?;
+                }
+            }
+            None => {
+                let native = rustls_native_certs::load_native_certs();
+                // Certificates of the system rustls cannot use are skipped.
+                roots.add_parsable_certificates(native.certs);
+                if roots.is_empty() {
+                    return Err(KvsError::TlsConfig(match native.errors.first() {
+                        Some(e) => format!("no trusted certificates in the system: {}", e),
+                        None => "no trusted certificates in the system".to_owned(),
+                    }));
+                }
+            }
+        }
+        let config = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(Arc::new(config))
+    }
+
+    /// Returns the name the certificate of the server at the given address must be
+    /// valid for.
+    fn server_name(&self, addr: &str) -> Result<ServerName<'static>> {
+        let name = match &self.server_name {
+            Some(name) => name.as_str(),
+            None => {
+                let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+                host.trim_start_matches('[').trim_end_matches(']')
+            }
+        };
+        ServerName::try_from(name.to_owned())
+            .map_err(|_| KvsError::TlsConfig(format!("invalid server name {:?}", name)))
+    }
+}
+
+/// The TLS settings of a client, whose rustls configuration is built once for all the
+/// connections of the client and its clones.
+#[derive(Clone, Debug)]
+pub(crate) struct ClientTls {
+    settings: ClientTlsConfig,
+    config: Arc<OnceLock<Arc<ClientConfig>>>,
+}
+
+impl ClientTls {
+    pub(crate) fn new(settings: ClientTlsConfig) -> Self {
+        Self {
+            settings,
+            config: Arc::default(),
+        }
+    }
+
+    /// Starts TLS on a connection to the server at the given address, completing the
+    /// handshake.
+    pub(crate) fn connect(&self, tcp: TcpStream, addr: &str) -> Result<TlsStream> {
+        let config = match self.config.get() {
+            Some(config) => Arc::clone(config),
+            None => {
+                let config = self.settings.rustls_config()?;
+                // A clone connecting meanwhile may have built it too, and either does.
+                Arc::clone(self.config.get_or_init(|| config))
+            }
+        };
+        let session = ClientConnection::new(config, self.settings.server_name(addr)?)?;
+        let stream = TlsStream::new(tcp, session.into());
+        stream.handshake()?;
+        Ok(stream)
+    }
+}
+
+/// The certificate and key a server serves TLS with, given to [`KvsServer::tls`].
+///
+/// Available with the `tls` feature.
+///
+/// [`KvsServer::tls`]: crate::KvsServer::tls
+#[derive(Clone)]
+pub struct ServerTlsConfig {
+    config: Arc<ServerConfig>,
+}
+
+impl ServerTlsConfig {
+    /// Reads a PEM encoded certificate chain, starting with the certificate of the
+    /// server, and its PEM encoded private key.
+    ///
+    /// Returns `KvsError::TlsConfig` if either cannot be read, and `KvsError::Tls` if
+    /// rustls refuses them, like a key that is not the one of the certificate.
+    pub fn from_pem(cert_chain: &[u8], private_key: &[u8]) -> Result<Self> {
+        let certs = certs(cert_chain)?;
+        let key = PrivateKeyDer::from_pem_slice(private_key)
+            .map_err(|e| KvsError::TlsConfig(format!("invalid private key: {}", e)))?;
+        let mut config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+        // A client tells a connection the server closed by it sending something unasked,
+        // which session tickets would be.
+        config.send_tls13_tickets = 0;
+        Ok(Self {
+            config: Arc::new(config),
+        })
+    }
+
+    /// Returns a TLS connection over an accepted TCP stream, whose handshake is still
+    /// to be done.
+    pub(crate) fn accept(&self, tcp: TcpStream) -> Result<TlsStream> {
+        let session = ServerConnection::new(Arc::clone(&self.config))?;
+        Ok(TlsStream::new(tcp, session.into()))
+    }
+}
+
+impl fmt::Debug for ServerTlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ServerTlsConfig(..)")
+    }
+}
+
+/// Returns the crypto provider of every connection, which is the one of the `ring`
+/// feature of rustls whatever other provider the build enables.
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// Reads the PEM encoded certificates of a chain, or of trusted authorities.
+fn certs(pem: &[u8]) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_slice_iter(pem)
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| KvsError::TlsConfig(format!("invalid certificate: {}", e)))?;
+    if certs.is_empty() {
+        return Err(KvsError::TlsConfig("no certificate found".to_owned()));
+    }
+    Ok(certs)
+}
+
+/// A TLS connection over a TCP stream, which one thread can read while another writes
+/// it, so a client can pipeline requests.
+///
+/// The halves share the session, which is locked only to hand it the records read and
+/// to take the records to write, never while waiting on the socket. The connection is
+/// closed with its TCP stream, without a `close_notify`: every message is delimited, so
+/// one cut short fails to parse anyway.
+#[derive(Clone)]
+pub(crate) struct TlsStream {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    tcp: TcpStream,
+    session: Mutex<Connection>,
+    // Held while records are written, so they go out in the order the session made them.
+    sending: Mutex<()>,
+}
+
+impl TlsStream {
+    fn new(tcp: TcpStream, session: Connection) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                tcp,
+                session: Mutex::new(session),
+                sending: Mutex::new(()),
+            }),
+        }
+    }
+
+    pub(crate) fn tcp(&self) -> &TcpStream {
+        &self.shared.tcp
+    }
+
+    /// Completes the handshake, failing with `KvsError::Tls` if the peer fails it.
+    pub(crate) fn handshake(&self) -> Result<()> {
+        let mut session = self.shared.session.lock().unwrap();
+        // Nothing reads or writes the connection before its handshake, so the session
+        // can be locked while waiting on the socket.
+        while session.is_handshaking() {
+            session
+                .complete_io(&mut &self.shared.tcp)
+                .map_err(tls_error)?;
+        }
+        Ok(())
+    }
+}
+
+impl Shared {
+    /// Writes the records the session has to send.
+    fn send(&self, mut session: MutexGuard<'_, Connection>) -> io::Result<()> {
+        let mut records = Vec::new();
+        while session.wants_write() {
+            session.write_tls(&mut records)?;
+        }
+        if records.is_empty() {
+            return Ok(());
+        }
+        let _sending = self.sending.lock().unwrap();
+        drop(session);
+        (&self.tcp).write_all(&records)
+    }
+}
+
+impl Read for &TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let shared = &*self.shared;
+        let mut records = [0; RECORDS_LEN];
+        loop {
+            let mut session = shared.session.lock().unwrap();
+            match session.reader().read(buf) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                read => return read,
+            }
+            drop(session);
+            let len = (&shared.tcp).read(&mut records)?;
+            if len == 0 {
+                return Ok(0);
+            }
+            let mut session = shared.session.lock().unwrap();
+            let mut received = &records[..len];
+            while !received.is_empty() {
+                session.read_tls(&mut received)?;
+                session
+                    .process_new_packets()
+                    .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            }
+            // Alerts, or answers to the peer updating its keys.
+            shared.send(session)?;
+        }
+    }
+}
+
+impl Write for &TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut session = self.shared.session.lock().unwrap();
+        let written = session.writer().write(buf)?;
+        self.shared.send(session)?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut session = self.shared.session.lock().unwrap();
+        session.writer().flush()?;
+        self.shared.send(session)
+    }
+}
+
+/// Returns the error to report for a failed handshake, which is `KvsError::Tls` unless
+/// the socket failed.
+fn tls_error(e: io::Error) -> KvsError {
+    if e.get_ref().is_some_and(|inner| inner.is::<rustls::Error>()) {
+        let inner = e.into_inner().unwrap();
+        return KvsError::Tls(*inner.downcast::<rustls::Error>().unwrap());
+    }
+    KvsError::Io(e)
+}
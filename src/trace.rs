@@ -0,0 +1,33 @@
+//! Instrumentation with `tracing`, which compiles to nothing unless the `tracing` feature is on.
+
+/// Enters a debug span for the rest of the enclosing block.
+macro_rules! trace_span {
+    ($($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!($($arg)+).entered();
+    };
+}
+
+/// Records a field of the current span, which it has declared as `tracing::field::Empty`.
+macro_rules! trace_record {
+    ($field:literal, $value:expr) => {
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record($field, $value);
+    };
+}
+
+/// Emits a debug event.
+macro_rules! trace_debug {
+    ($($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)+);
+    };
+}
+
+/// Emits a warn event.
+macro_rules! trace_warn {
+    ($($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        tracing::warn!($($arg)+);
+    };
+}
@@ -0,0 +1,54 @@
+use std::collections::BTreeMap;
+
+use crate::{KvStore, Result, WriteBatch};
+
+/// A transaction buffering writes to a store until they are committed all at once.
+///
+/// This is created by [`KvStore::begin`]. Its writes are only visible to reads through
+/// the transaction until [`commit`] writes them to the log as one batch, which is
+/// either replayed entirely or dropped entirely if a crash leaves it torn. Dropping the
+/// transaction without committing it aborts it, writing nothing.
+///
+/// [`KvStore::begin`]: crate::KvStore::begin
+/// [`commit`]: Transaction::commit
+pub struct Transaction<'a> {
+    store: &'a mut KvStore,
+    batch: WriteBatch,
+    // The latest write of every key written, `None` for a delete.
+    writes: BTreeMap<String, Option<String>>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(store: &'a mut KvStore) -> Self {
+        Self {
+            store,
+            batch: WriteBatch::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+    /// Gets the value of a key, as written by the transaction if it wrote the key.
+    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
+        match self.writes.get(key) {
+            Some(value) => Ok(value.clone()),
+            None => self.store.get(key),
+        }
+    }
+    /// Sets the value of a string key to a string when the transaction commits.
+    pub fn put(&mut self, key: String, value: String) {
+        self.writes.insert(key.clone(), Some(value.clone()));
+        self.batch.put(key, value);
+    }
+    /// Removes a given key when the transaction commits.
+    ///
+    /// Removing a key that does not exist is a no-op.
+    pub fn delete(&mut self, key: String) {
+        self.writes.insert(key.clone(), None);
+        self.batch.delete(key);
+    }
+    /// Writes every write of the transaction to the store atomically.
+    pub fn commit(self) -> Result<()> {
+        self.store.write_batch(self.batch)
+    }
+    /// Drops every write of the transaction, like dropping it does.
+    pub fn abort(self) {}
+}
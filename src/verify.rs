@@ -0,0 +1,257 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+
+use crate::archive::ARCHIVE_DIR;
+use crate::disk_index::INDEX_FILE;
+use crate::encrypt::ENCRYPTION_FILE;
+use crate::engines::ENGINE_FILE;
+use crate::format::FORMAT_FILE;
+use crate::index::{Checkpoint, KeyIndex};
+use crate::kv::{load, log_path, now_millis, CommandPos, LogReaders, TornTail, LOCK_FILE};
+use crate::manifest::{MANIFEST_FILE, MIGRATION_DIR};
+use crate::merge::MERGES_FILE;
+use crate::repair::DAMAGED_DIR;
+use crate::snapshot::SNAPSHOT_FILE;
+use crate::view::PINNED_DIR;
+use crate::{KvsError, Result};
+
+/// What a check of a store found, returned by [`KvStore::verify`].
+///
+/// [`KvStore::verify`]: crate::KvStore::verify
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Number of index entries whose record was read back.
+    pub entries_checked: usize,
+    /// Number of logs replayed.
+    pub logs_checked: usize,
+    /// Bytes of the logs outside the records the index points at.
+    pub garbage_bytes: u64,
+    /// Problems found, in the order they were found.
+    pub problems: Vec<VerifyProblem>,
+}
+
+impl VerifyReport {
+    /// Returns `true` if no problem was found.
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// A problem found by [`KvStore::verify`].
+///
+/// [`KvStore::verify`]: crate::KvStore::verify
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VerifyProblem {
+    /// The record the index points a key at does not read as a value of the key.
+    BadEntry {
+        /// Key of the entry.
+        key: String,
+        /// Generation of the log the entry points into.
+        gen: u64,
+        /// Byte offset of the record the entry points at.
+        offset: u64,
+        /// Why the record does not read.
+        error: String,
+    },
+    /// A log does not read to its end.
+    BadLog {
+        /// Generation of the log.
+        gen: u64,
+        /// Byte offset of the end of the last record read.
+        offset: u64,
+        /// Why the rest of the log does not read.
+        error: String,
+    },
+    /// Replaying the logs gives a key another record than the index, or none.
+    IndexMismatch(String),
+    /// A file in the store directory the store does not use.
+    OrphanFile(PathBuf),
+    /// No log is left of a generation the store reads, or of one between the oldest log
+    /// and the active one.
+    MissingGeneration(u64),
+}
+
+impl fmt::Display for VerifyProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyProblem::BadEntry {
+                key,
+                gen,
+                offset,
+                error,
+            } => write!(
+                f,
+                "The entry of key {:?} at offset {} of {}.log does not read: {}",
+                key, offset, gen, error
+            ),
+            VerifyProblem::BadLog { gen, offset, error } => write!(
+                f,
+                "{}.log does not read past offset {}: {}",
+                gen, offset, error
+            ),
+            VerifyProblem::IndexMismatch(key) => {
+                write!(f, "The index does not match the logs for key {:?}", key)
+            }
+            VerifyProblem::OrphanFile(path) => write!(f, "{} is not used", path.display()),
+            VerifyProblem::MissingGeneration(gen) => write!(f, "Log {}.log is missing", gen),
+        }
+    }
+}
+
+/// Checks a store, collecting what it finds into a report.
+pub(crate) struct Verifier<'a> {
+    pub(crate) dir: &'a Path,
+    pub(crate) readers: &'a mut LogReaders,
+    pub(crate) index: &'a dyn KeyIndex,
+    // Whether to fail with the first problem found.
+    pub(crate) fail_fast: bool,
+    pub(crate) report: VerifyReport,
+}
+
+impl Verifier<'_> {
+    /// Runs every check, and returns the report.
+    pub(crate) fn run(mut self) -> Result<VerifyReport> {
+        let now = now_millis();
+        let entries = self
+            .index
+            .iter()
+            .map(|entry| entry.map(|(key, pos)| (key.into_owned(), pos)))
+            .filter(|entry| !matches!(entry, Ok((_, pos)) if pos.is_expired(now)))
+            .collect::<Result<BTreeMap<_, _>>>()?;
+        self.check_entries(&entries)?;
+        self.check_logs(&entries, now)?;
+        self.check_files()?;
+        let mut logs_len = 0;
+        for gen in self.readers.gens() {
+            if let Ok(metadata) = fs::metadata(log_path(self.dir, gen)) {
+                logs_len += metadata.len();
+            }
+        }
+        let live: u64 = entries.values().map(CommandPos::len).sum();
+        self.report.garbage_bytes = logs_len.saturating_sub(live);
+        Ok(self.report)
+    }
+
+    fn found(&mut self, problem: VerifyProblem) -> Result<()> {
+        if self.fail_fast {
+            return Err(KvsError::VerifyFailed(problem));
+        }
+        self.report.problems.push(problem);
+        Ok(())
+    }
+
+    /// Reads back the record of every entry, verifying its checksums.
+    fn check_entries(&mut self, entries: &BTreeMap<String, CommandPos>) -> Result<()> {
+        let verify = self.readers.verify_checksums;
+        self.readers.verify_checksums = true;
+        let mut result = Ok(());
+        for (key, pos) in entries {
+            self.report.entries_checked += 1;
+            if let Err(e) = self.readers.copy_value(key, pos, &mut io::sink()) {
+                result = self.found(VerifyProblem::BadEntry {
+                    key: key.clone(),
+                    gen: pos.gen,
+                    offset: pos.start,
+                    error: e.to_string(),
+                });
+                if result.is_err() {
+                    break;
+                }
+            }
+        }
+        self.readers.verify_checksums = verify;
+        result
+    }
+
+    /// Replays every log from the start, and compares the keys it gives with the index.
+    fn check_logs(&mut self, entries: &BTreeMap<String, CommandPos>, now: u64) -> Result<()> {
+        let format = self.readers.format;
+        let mut replayed = BTreeMap::new();
+        let gens: Vec<u64> = self.readers.gens().collect();
+        for gen in gens {
+            self.report.logs_checked += 1;
+            let mut reader = match self.readers.open(gen) {
+                Ok(file) => BufReader::new(file),
+                // A missing log is reported with the files.
+                Err(KvsError::MissingGeneration(_)) => continue,
+                Err(e) => return Err(e),
+            };
+            let mut at = Checkpoint {
+                gen,
+                ..Checkpoint::default()
+            };
+            let loaded = load(
+                self.dir,
+                format,
+                &mut reader,
+                &mut replayed,
+                TornTail::Corrupt,
+                &mut at,
+            );
+            if let Err(e) = loaded {
+                self.found(VerifyProblem::BadLog {
+                    gen,
+                    offset: at.offset,
+                    error: e.to_string(),
+                })?;
+            }
+        }
+        replayed.retain(|_, pos| !pos.is_expired(now));
+        let keys: BTreeSet<&String> = entries.keys().chain(replayed.keys()).collect();
+        for key in keys {
+            if entries.get(key) != replayed.get(key) {
+                self.found(VerifyProblem::IndexMismatch(key.clone()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Looks for files the store does not use, and for logs missing between the oldest
+    /// one and the active one.
+    fn check_files(&mut self) -> Result<()> {
+        let gens: BTreeSet<u64> = self.readers.gens().collect();
+        let mut paths: Vec<PathBuf> = fs::read_dir(self.dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<_>>()?;
+        paths.sort();
+        let mut on_disk = BTreeSet::new();
+        for path in paths {
+            let name = path.file_name().and_then(|name| name.to_str());
+            let known = match name {
+                Some(
+                    LOCK_FILE | ENGINE_FILE | FORMAT_FILE | MERGES_FILE | INDEX_FILE
+                    | SNAPSHOT_FILE | ARCHIVE_DIR | DAMAGED_DIR | MANIFEST_FILE | MIGRATION_DIR
+                    | ENCRYPTION_FILE | PINNED_DIR,
+                ) => true,
+                Some(name) => match name.split_once('.') {
+                    Some((gen, ext @ ("log" | "hint"))) => match gen.parse::<u64>() {
+                        Ok(gen) if gens.contains(&gen) => {
+                            if ext == "log" {
+                                on_disk.insert(gen);
+                            }
+                            true
+                        }
+                        _ => false,
+                    },
+                    _ => false,
+                },
+                None => false,
+            };
+            if !known {
+                self.found(VerifyProblem::OrphanFile(path))?;
+            }
+        }
+        let (Some(&first), Some(&last)) = (gens.first(), gens.last()) else {
+            return Ok(());
+        };
+        for gen in first..=last {
+            if !on_disk.contains(&gen) {
+                self.found(VerifyProblem::MissingGeneration(gen))?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use log::warn;
+
+use crate::archive::{retire_log, retire_log_from};
+use crate::hint::hint_path;
+use crate::kv::{log_path, now_millis, CommandPos, LogReaders};
+use crate::{Result, Scan};
+
+/// Name of the subdirectory holding the logs retired while a snapshot still reads them.
+pub(crate) const PINNED_DIR: &str = "pinned";
+
+/// The generations of the logs live snapshots read, by the number of snapshots reading
+/// each.
+///
+/// A log retired by a compaction or a clear while a snapshot reads it is moved to the
+/// `pinned` subdirectory, where a reopen of the store does not replay it, and only
+/// removed or archived once the last snapshot reading it is dropped.
+#[derive(Clone, Default)]
+pub(crate) struct Pins(Arc<Mutex<Pinned>>);
+
+#[derive(Default)]
+struct Pinned {
+    counts: BTreeMap<u64, usize>,
+    // Generations moved to the `pinned` subdirectory, with whether they are archived
+    // once they are released.
+    retired: BTreeMap<u64, bool>,
+}
+
+impl Pins {
+    fn pin(&self, gens: &[u64]) {
+        let mut pinned = self.0.lock().unwrap();
+        for &gen in gens {
+            *pinned.counts.entry(gen).or_default() += 1;
+        }
+    }
+
+    /// Releases the generations a snapshot read, retiring the ones left unread.
+    fn unpin(&self, dir: &Path, gens: &[u64]) {
+        let mut pinned = self.0.lock().unwrap();
+        for &gen in gens {
+            let count = pinned.counts.get_mut(&gen).unwrap();
+            *count -= 1;
+            if *count > 0 {
+                continue;
+            }
+            pinned.counts.remove(&gen);
+            if let Some(archive) = pinned.retired.remove(&gen) {
+                if let Err(e) = retire_log_from(&dir.join(PINNED_DIR), dir, gen, archive) {
+                    warn!("Failed to retire log {} of {}: {}", gen, dir.display(), e);
+                }
+            }
+        }
+    }
+
+    /// Retires a log replaced by a compaction like `retire_log`, unless a snapshot still
+    /// reads it, in which case it is moved to the `pinned` subdirectory until it is
+    /// released.
+    ///
+    /// Returns the sizes of the log and of its hint.
+    pub(crate) fn retire(&self, dir: &Path, gen: u64, archive: bool) -> Result<(u64, u64)> {
+        let mut pinned = self.0.lock().unwrap();
+        if !pinned.counts.contains_key(&gen) {
+            return retire_log(dir, gen, archive);
+        }
+        let to = dir.join(PINNED_DIR);
+        fs::create_dir_all(&to)?;
+        let log_len = fs::metadata(log_path(dir, gen))?.len();
+        fs::rename(log_path(dir, gen), log_path(&to, gen))?;
+        let hint_len = match fs::metadata(hint_path(dir, gen)) {
+            Ok(metadata) => {
+                fs::rename(hint_path(dir, gen), hint_path(&to, gen))?;
+                metadata.len()
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        pinned.retired.insert(gen, archive);
+        Ok((log_len, hint_len))
+    }
+}
+
+/// A point-in-time view of a store, whose reads ignore the writes made after it.
+///
+/// This is created by [`KvStore::snapshot`]. It holds a copy of the index of the store
+/// and readers of its own, so the store keeps accepting writes, and the logs it reads
+/// are kept until it is dropped, even if a compaction replaces them. Keys expire in
+/// the snapshot as they do in the store. The logs are only kept from compactions and
+/// clears of the store the snapshot was taken from.
+///
+/// [`KvStore::snapshot`]: crate::KvStore::snapshot
+pub struct Snapshot {
+    dir: PathBuf,
+    index: BTreeMap<String, CommandPos>,
+    readers: LogReaders,
+    pins: Pins,
+    gens: Vec<u64>,
+}
+
+impl Snapshot {
+    pub(crate) fn new(
+        dir: &Path,
+        index: BTreeMap<String, CommandPos>,
+        readers: LogReaders,
+        pins: Pins,
+    ) -> Self {
+        let gens: Vec<u64> = readers.gens().collect();
+        pins.pin(&gens);
+        Self {
+            dir: dir.to_owned(),
+            index,
+            readers,
+            pins,
+            gens,
+        }
+    }
+    /// Gets the string value a key had when the snapshot was taken.
+    ///
+    /// Returns `None` if the key did not exist or has expired since.
+    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
+        match self.index.get(key) {
+            Some(pos) if !pos.is_expired(now_millis()) => {
+                self.readers.read_value(key, pos)?.into_value()
+            }
+            _ => Ok(None),
+        }
+    }
+    /// Returns an iterator over the key/value pairs within the given key range, in key
+    /// order, as they were when the snapshot was taken.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range start is greater than its end.
+    pub fn range<K: AsRef<str>, R: RangeBounds<K>>(&mut self, range: R) -> Scan<'_> {
+        let start = range.start_bound().map(|key| key.as_ref().to_owned());
+        let end = range.end_bound().map(|key| key.as_ref().to_owned());
+        Scan::new(&self.index, &mut self.readers, (start, end), 0)
+    }
+    /// Returns an iterator over all key/value pairs in key order, as they were when the
+    /// snapshot was taken.
+    pub fn iter(&mut self) -> Scan<'_> {
+        self.range::<&str, _>(..)
+    }
+    /// Returns an iterator over all keys of the snapshot in sorted order.
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        let now = now_millis();
+        self.index
+            .iter()
+            .filter(move |(_, pos)| !pos.is_expired(now))
+            .map(|(key, _)| key.as_str())
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.pins.unpin(&self.dir, &self.gens);
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::BTreeMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, Weak};
+
+use log::warn;
+
+/// A change to a watched key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WatchEvent {
+    /// The key was set to the value, given as UTF-8 with invalid bytes replaced.
+    Set(String, String),
+    /// The key was removed.
+    Remove(String),
+}
+
+impl WatchEvent {
+    /// Returns the key that changed.
+    pub fn key(&self) -> &str {
+        match self {
+            WatchEvent::Set(key, _) | WatchEvent::Remove(key) => key,
+        }
+    }
+}
+
+/// A callback of a watcher, locked while it runs so it never runs twice at once.
+type Callback = Arc<Mutex<Box<dyn Fn(&WatchEvent) + Send>>>;
+
+/// The watchers of a store, by the id of their handle.
+#[derive(Default)]
+struct Registry {
+    watchers: BTreeMap<u64, (String, Callback)>,
+    next_id: u64,
+}
+
+/// The watchers of a store and the events waiting to be sent to them.
+///
+/// Events are queued as writes are applied to the index, and sent once the write is
+/// flushed, and synced if the durability mode asks for it.
+#[derive(Default)]
+pub(crate) struct Watchers {
+    registry: Arc<Mutex<Registry>>,
+    pending: Vec<WatchEvent>,
+}
+
+impl Watchers {
+    /// Registers a callback for the keys starting with `prefix`.
+    pub(crate) fn add(
+        &mut self,
+        prefix: String,
+        callback: Box<dyn Fn(&WatchEvent) + Send>,
+    ) -> WatchHandle {
+        let mut registry = self.registry.lock().unwrap();
+        let id = registry.next_id;
+        registry.next_id += 1;
+        registry
+            .watchers
+            .insert(id, (prefix, Arc::new(Mutex::new(callback))));
+        WatchHandle {
+            registry: Arc::downgrade(&self.registry),
+            id,
+        }
+    }
+    /// Returns `true` if a watcher is registered for the key.
+    pub(crate) fn watches(&self, key: &str) -> bool {
+        let registry = self.registry.lock().unwrap();
+        registry
+            .watchers
+            .values()
+            .any(|(prefix, _)| key.starts_with(prefix.as_str()))
+    }
+    /// Returns the prefixes of the watchers, without the ones within others.
+    pub(crate) fn prefixes(&self) -> Vec<String> {
+        let registry = self.registry.lock().unwrap();
+        let mut prefixes: Vec<String> = registry
+            .watchers
+            .values()
+            .map(|(prefix, _)| prefix.clone())
+            .collect();
+        prefixes.sort();
+        prefixes.dedup_by(|prefix, outer| prefix.starts_with(outer.as_str()));
+        prefixes
+    }
+    /// Queues an event until `notify` is called.
+    pub(crate) fn queue(&mut self, event: WatchEvent) {
+        self.pending.push(event);
+    }
+    /// Sends every queued event to the watchers of its key, in the order they were queued.
+    ///
+    /// The callbacks run without the watchers locked, so they can drop watch handles, and
+    /// a callback that panics is logged and does not stop the others.
+    pub(crate) fn notify(&mut self) {
+        for event in self.pending.drain(..) {
+            let callbacks: Vec<Callback> = {
+                let registry = self.registry.lock().unwrap();
+                registry
+                    .watchers
+                    .values()
+                    .filter(|(prefix, _)| event.key().starts_with(prefix.as_str()))
+                    .map(|(_, callback)| Arc::clone(callback))
+                    .collect()
+            };
+            for callback in callbacks {
+                // The panic is caught while the guard is held, so the lock is not poisoned.
+                let callback = callback.lock().unwrap();
+                if panic::catch_unwind(AssertUnwindSafe(|| callback(&event))).is_err() {
+                    warn!("A watcher of {} panicked", event.key());
+                }
+            }
+        }
+    }
+}
+
+/// A handle keeping a watcher registered.
+///
+/// This is returned by [`KvStore::watch`]. Dropping it unregisters the watcher, after
+/// which its callback is not called again.
+///
+/// [`KvStore::watch`]: crate::KvStore::watch
+pub struct WatchHandle {
+    registry: Weak<Mutex<Registry>>,
+    id: u64,
+}
+
+impl Drop for WatchHandle {
+    fn drop(&mut self) {
+        if let Some(registry) = self.registry.upgrade() {
+            registry.lock().unwrap().watchers.remove(&self.id);
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use std::borrow::Cow;
 use std::process::Command;
 
 use assert_cmd::prelude::*;
@@ -6,7 +7,10 @@ use predicates::str::{contains, is_empty, PredicateStrExt};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
-use kvs::{KvStore, Result};
+use kvs::{
+    Change, ChangeEvent, Durability, Entry, KvStore, KvsEngine, KvsError, LogFormat, Options,
+    Result, SharedKvStore, SharedQueueThreadPool, ThreadPool, WatchEvent, WriteBatch,
+};
 
 // `kvs` with no args should exit with a non-zero code.
 #[test]
@@ -19,7 +23,7 @@ fn cli_no_args() {
 fn cli_version() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["-V"])
+        .args(["-V"])
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
@@ -30,7 +34,7 @@ fn cli_get_non_existent_key() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -43,7 +47,7 @@ fn cli_rm_non_existent_key() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -56,7 +60,7 @@ fn cli_set() {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "key1", "value1"])
+        .args(["set", "key1", "value1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -74,7 +78,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -82,7 +86,7 @@ fn cli_get_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key2"])
+        .args(["get", "key2"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -102,7 +106,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "key1"])
+        .args(["rm", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -110,7 +114,7 @@ fn cli_rm_stored() -> Result<()> {
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "key1"])
+        .args(["get", "key1"])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -123,13 +127,13 @@ fn cli_rm_stored() -> Result<()> {
 fn cli_invalid_get() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .assert()
         .failure();
 }
@@ -138,19 +142,19 @@ fn cli_invalid_get() {
 fn cli_invalid_set() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["set", "extra", "extra", "field"])
+        .args(["set", "extra", "extra", "field"])
         .assert()
         .failure();
 }
@@ -159,13 +163,13 @@ fn cli_invalid_set() {
 fn cli_invalid_rm() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .assert()
         .failure();
 
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .assert()
         .failure();
 }
@@ -174,11 +178,56 @@ fn cli_invalid_rm() {
 fn cli_invalid_subcommand() {
     Command::cargo_bin("kvs")
         .unwrap()
-        .args(&["unknown", "subcommand"])
+        .args(["unknown", "subcommand"])
         .assert()
         .failure();
 }
 
+// `kvs --path <DIR>` should operate on the given directory instead of the current one.
+#[test]
+fn cli_path() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store_dir = temp_dir.path().join("store");
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["set", "key1", "value1", "--path"])
+        .arg(&store_dir)
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["--path"])
+        .arg(&store_dir)
+        .args(["get", "key1"])
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    assert!(std::fs::read_dir(temp_dir.path())?.count() == 1);
+
+    let mut store = KvStore::open(&store_dir)?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Failures other than a missing key should exit with non-zero code and the error on stderr.
+#[test]
+fn cli_error() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let file = temp_dir.path().join("file");
+    std::fs::write(&file, "not a store")?;
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1", "--path"])
+        .arg(&file)
+        .assert()
+        .failure()
+        .stdout(is_empty())
+        .stderr(contains("exists"));
+    Ok(())
+}
+
 // Should get previously stored value.
 #[test]
 fn get_stored_value() -> Result<()> {
@@ -188,14 +237,14 @@ fn get_stored_value() -> Result<()> {
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
 
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
 
     // Open from disk again and check persistent data.
     drop(store);
     let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
 
     Ok(())
 }
@@ -207,16 +256,16 @@ fn overwrite_value() -> Result<()> {
     let mut store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
     store.set("key1".to_owned(), "value2".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
 
     // Open from disk again and check persistent data.
     drop(store);
     let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key1")?, Some("value3".to_owned()));
 
     Ok(())
 }
@@ -228,12 +277,12 @@ fn get_non_existent_value() -> Result<()> {
     let mut store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key2")?, None);
 
     // Open from disk again and check persistent data.
     drop(store);
     let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key2")?, None);
 
     Ok(())
 }
@@ -242,7 +291,7 @@ fn get_non_existent_value() -> Result<()> {
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let mut store = KvStore::open(temp_dir.path())?;
-    assert!(store.remove("key1".to_owned()).is_err());
+    assert!(store.remove("key1").is_err());
     Ok(())
 }
 
@@ -251,8 +300,8 @@ fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let mut store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
-    assert!(store.remove("key1".to_owned()).is_ok());
-    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.remove("key1").is_ok());
+    assert_eq!(store.get("key1")?, None);
     Ok(())
 }
 
@@ -301,3 +350,7233 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+fn dir_size(path: &std::path::Path) -> u64 {
+    let entries = WalkDir::new(path).into_iter();
+    let len: walkdir::Result<u64> = entries
+        .map(|res| {
+            res.and_then(|entry| entry.metadata())
+                .map(|metadata| metadata.len())
+        })
+        .sum();
+    len.expect("fail to get directory size")
+}
+
+// Removing keys should count toward compaction.
+// Delete most keys and check the directory shrinks.
+#[test]
+fn compaction_after_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let value = "v".repeat(1024);
+    for key_id in 0..2000 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+    let full_size = dir_size(temp_dir.path());
+
+    for key_id in 100..2000 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    assert!(dir_size(temp_dir.path()) < full_size);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(value.clone()));
+    }
+    for key_id in 100..2000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+    }
+    Ok(())
+}
+
+fn log_files(path: &std::path::Path) -> usize {
+    std::fs::read_dir(path)
+        .expect("fail to read directory")
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("log".as_ref()))
+        .count()
+}
+
+// Records written right before and right after a compaction must survive it,
+// and the previous active log must be cleaned up.
+#[test]
+fn compaction_keeps_active_log_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let value = "v".repeat(1024);
+    let mut current_size = dir_size(temp_dir.path());
+    let mut compacted_at = None;
+    for iter in 0..10000 {
+        store.set(format!("key{}", iter % 100), format!("{}{}", value, iter))?;
+        let new_size = dir_size(temp_dir.path());
+        if new_size < current_size {
+            compacted_at = Some(iter);
+            break;
+        }
+        current_size = new_size;
+    }
+    let last_iter = compacted_at.expect("No compaction detected");
+    assert_eq!(log_files(temp_dir.path()), 2);
+    store.set("after".to_owned(), "compaction".to_owned())?;
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    for iter in last_iter - 99..=last_iter {
+        assert_eq!(
+            store.get(format!("key{}", iter % 100))?,
+            Some(format!("{}{}", value, iter))
+        );
+    }
+    assert_eq!(store.get("after")?, Some("compaction".to_owned()));
+    Ok(())
+}
+
+fn newest_log(path: &std::path::Path) -> std::path::PathBuf {
+    std::fs::read_dir(path)
+        .expect("fail to read directory")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .max_by_key(|path| {
+            path.file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok())
+        })
+        .expect("no log file found")
+}
+
+fn append_to(path: &std::path::Path, bytes: &[u8]) {
+    use std::io::Write;
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(path)
+        .expect("fail to open log file");
+    file.write_all(bytes).expect("fail to append to log file");
+}
+
+// A torn record at the tail of the newest log should be dropped on open.
+#[test]
+fn open_with_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log = newest_log(temp_dir.path());
+    let committed_len = std::fs::metadata(&log)?.len();
+    append_to(&log, br#"{"S":["key3","val"#);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(std::fs::metadata(&log)?.len(), committed_len);
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3")?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let log = newest_log(temp_dir.path());
+    append_to(&log, b"\x00garbage\xff");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A broken record in an older generation is real damage and should fail open.
+#[test]
+fn open_with_corrupted_old_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let old_log = newest_log(temp_dir.path());
+
+    // Start a newer generation so the first one is no longer the tail.
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    std::fs::File::create(temp_dir.path().join("100.log"))?;
+    assert_ne!(newest_log(temp_dir.path()), old_log);
+
+    append_to(&old_log, b"garbage");
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    Ok(())
+}
+
+// Opening a store without writing to it should not create new log files.
+#[test]
+fn reopen_without_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for _ in 0..50 {
+        let store = KvStore::open(temp_dir.path())?;
+        drop(store);
+    }
+    assert_eq!(log_files(temp_dir.path()), 1);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    for _ in 0..50 {
+        let mut store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    }
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    drop(store);
+    assert_eq!(log_files(temp_dir.path()), 1);
+    Ok(())
+}
+
+// Manual compaction should reclaim the space of overwritten and removed keys.
+#[test]
+fn manual_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.compact()?.reclaimed_bytes, 0);
+
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    for key_id in 50..100 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    let size_before = dir_size(temp_dir.path());
+    let reclaimed = store.compact()?.reclaimed_bytes;
+    assert!(reclaimed > 0);
+    assert_eq!(dir_size(temp_dir.path()), size_before - reclaimed);
+    assert_eq!(store.compact()?.reclaimed_bytes, 0);
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..50 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("9".to_owned()));
+    }
+    for key_id in 50..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+    }
+    Ok(())
+}
+
+// Should list live keys in sorted order.
+#[test]
+fn list_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys().count(), 0);
+
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key2")?;
+    assert_eq!(store.keys().collect::<Vec<_>>(), vec!["key1", "key3"]);
+
+    store.compact()?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    assert_eq!(
+        store.keys().collect::<Vec<_>>(),
+        vec!["key0", "key1", "key3"]
+    );
+
+    // Collect keys first, then remove them in a second pass.
+    let to_remove: Vec<String> = store
+        .keys()
+        .filter(|key| *key != "key1")
+        .map(Cow::into_owned)
+        .collect();
+    for key in to_remove {
+        store.remove(key)?;
+    }
+    assert_eq!(store.keys().collect::<Vec<_>>(), vec!["key1"]);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys().collect::<Vec<_>>(), vec!["key1"]);
+    Ok(())
+}
+
+fn pairs(items: &[(&str, &str)]) -> Vec<(String, String)> {
+    items
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+// Should scan key/value pairs within a range in key order.
+#[test]
+fn range_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.range::<&str, _>(..).next().is_none());
+
+    for key in ["a", "b", "c", "d", "e"] {
+        store.set(key.to_owned(), format!("{}1", key))?;
+    }
+    store.remove("d")?;
+    store.compact()?;
+    // Values now straddle the compacted generation and the active one.
+    for key in ["f", "g", "h"] {
+        store.set(key.to_owned(), format!("{}2", key))?;
+    }
+    store.set("c".to_owned(), "c2".to_owned())?;
+
+    let scanned: Vec<_> = store.range("b".."g").collect::<Result<_>>()?;
+    assert_eq!(
+        scanned,
+        pairs(&[("b", "b1"), ("c", "c2"), ("e", "e1"), ("f", "f2")])
+    );
+
+    let scanned: Vec<_> = store.range("x".."x").collect::<Result<_>>()?;
+    assert!(scanned.is_empty());
+    let scanned: Vec<_> = store.range("bb".."bc").collect::<Result<_>>()?;
+    assert!(scanned.is_empty());
+
+    let scanned: Vec<_> = store.range("f"..).collect::<Result<_>>()?;
+    assert_eq!(scanned, pairs(&[("f", "f2"), ("g", "g2"), ("h", "h2")]));
+    let scanned: Vec<_> = store.range(..="b").collect::<Result<_>>()?;
+    assert_eq!(scanned, pairs(&[("a", "a1"), ("b", "b1")]));
+    let scanned: Vec<_> = store
+        .range("c".to_owned().."e".to_owned())
+        .collect::<Result<_>>()?;
+    assert_eq!(scanned, pairs(&[("c", "c2")]));
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    let scanned: Vec<_> = store.range::<&str, _>(..).collect::<Result<_>>()?;
+    assert_eq!(
+        scanned,
+        pairs(&[
+            ("a", "a1"),
+            ("b", "b1"),
+            ("c", "c2"),
+            ("e", "e1"),
+            ("f", "f2"),
+            ("g", "g2"),
+            ("h", "h2"),
+        ])
+    );
+    Ok(())
+}
+
+// Should scan the key/value pairs under a prefix.
+#[test]
+fn prefix_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.scan_prefix("").next().is_none());
+
+    store.set("session:1:token".to_owned(), "t1".to_owned())?;
+    store.set("session:2:token".to_owned(), "t2".to_owned())?;
+    store.set("session:".to_owned(), "root".to_owned())?;
+    store.set("sessions".to_owned(), "other".to_owned())?;
+    store.set("user:1".to_owned(), "u1".to_owned())?;
+    store.set("\u{10FFFF}".to_owned(), "top1".to_owned())?;
+    store.set("\u{10FFFF}\u{10FFFF}".to_owned(), "top2".to_owned())?;
+
+    let scanned: Vec<_> = store.scan_prefix("session:").collect::<Result<_>>()?;
+    assert_eq!(
+        scanned,
+        pairs(&[
+            ("session:", "root"),
+            ("session:1:token", "t1"),
+            ("session:2:token", "t2"),
+        ])
+    );
+    assert!(store.scan_prefix("session:3").next().is_none());
+    assert!(store.scan_prefix("zzz").next().is_none());
+
+    let scanned: Vec<_> = store.scan_prefix("\u{10FFFF}").collect::<Result<_>>()?;
+    assert_eq!(
+        scanned,
+        pairs(&[("\u{10FFFF}", "top1"), ("\u{10FFFF}\u{10FFFF}", "top2")])
+    );
+
+    assert_eq!(store.scan_prefix("").count(), 7);
+    Ok(())
+}
+
+// Should iterate every live pair across generations, before and after compaction,
+// and carry on past a damaged record.
+#[test]
+fn iter_all_pairs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.iter().next().is_none());
+
+    store.set_max_segment_size(64);
+    let mut expected = std::collections::BTreeMap::new();
+    for i in 0..40 {
+        let key = format!("key{:02}", i);
+        store.set(key.clone(), format!("value{:02}", i))?;
+        expected.insert(key, format!("value{:02}", i));
+    }
+    for i in (0..40).step_by(3) {
+        let key = format!("key{:02}", i);
+        store.remove(&key)?;
+        expected.remove(&key);
+    }
+    for i in (1..40).step_by(4) {
+        let key = format!("key{:02}", i);
+        store.set(key.clone(), format!("new{:02}", i))?;
+        expected.insert(key, format!("new{:02}", i));
+    }
+    assert!(log_files(temp_dir.path()) > 1);
+    let expected: Vec<_> = expected.into_iter().collect();
+    assert_eq!(store.iter().collect::<Result<Vec<_>>>()?, expected);
+
+    store.compact()?;
+    assert_eq!(store.iter().collect::<Result<Vec<_>>>()?, expected);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.iter().collect::<Result<Vec<_>>>()?, expected);
+
+    // A damaged value is yielded as an error without ending the iteration.
+    let (log, value_at) = std::fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .find_map(|path| {
+            let bytes = std::fs::read(&path).expect("fail to read log file");
+            let at = bytes.windows(7).position(|window| window == b"value02")?;
+            Some((path, at))
+        })
+        .expect("value not found in logs");
+    flip_byte(&log, value_at);
+    store.set_verify_checksums(true);
+    let items: Vec<_> = store.iter().collect();
+    assert_eq!(items.len(), expected.len());
+    for (item, (key, value)) in items.into_iter().zip(&expected) {
+        match item {
+            Err(KvsError::Corruption { .. }) => assert_eq!(key, "key02"),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(pair) => assert_eq!(pair, (key.clone(), value.clone())),
+        }
+    }
+    Ok(())
+}
+
+// Should bulk load many pairs, last write winning, and keep them after reopen.
+#[test]
+fn bulk_from_pairs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut oracle = std::collections::HashMap::new();
+    let pairs: Vec<_> = (0..100_000)
+        .map(|i| (format!("key{}", i % 60_000), format!("value{}", i)))
+        .collect();
+    for (key, value) in &pairs {
+        oracle.insert(key.clone(), value.clone());
+    }
+    let mut store = KvStore::from_pairs(temp_dir.path(), pairs)?;
+    assert_eq!(store.len(), oracle.len());
+    assert_eq!(store.get("key0")?, Some("value60000".to_owned()));
+
+    let more: Vec<_> = (0..1_000)
+        .map(|i| (format!("key{}", i * 7), format!("more{}", i)))
+        .collect();
+    for (key, value) in &more {
+        oracle.insert(key.clone(), value.clone());
+    }
+    store.extend(more);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), oracle.len());
+    for (key, value) in &oracle {
+        assert_eq!(store.get(key)?.as_ref(), Some(value));
+    }
+    Ok(())
+}
+
+// `bulk_load` should write the pairs to a single sealed generation without compacting,
+// reject duplicate keys unless told to keep the last value, and refuse a store with keys.
+#[test]
+fn bulk_load() -> Result<()> {
+    use kvs::Duplicates;
+
+    for disk_index in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        // Stale logs of a store emptied before the load are replaced.
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set("removed".to_owned(), "value".to_owned())?;
+        store.remove("removed")?;
+        drop(store);
+
+        let pairs = (0..1_000)
+            .rev()
+            .map(|i| (format!("key{}", i % 800), format!("value{}", i)));
+        let store = KvStore::builder(temp_dir.path())
+            .disk_index(disk_index)
+            .compaction_threshold(1)
+            .bulk_load(pairs, Duplicates::LastWins)?;
+        let stats = store.stats();
+        assert_eq!(stats.live_keys, 800);
+        assert_eq!(stats.generations, 2);
+        assert!(stats.uncompacted_bytes > 0);
+        drop(store);
+
+        let mut store = KvStore::builder(temp_dir.path())
+            .disk_index(disk_index)
+            .open()?;
+        assert_eq!(store.len(), 800);
+        assert_eq!(store.get("key0")?, Some("value0".to_owned()));
+        assert_eq!(store.get("key799")?, Some("value799".to_owned()));
+        assert!(store.verify()?.is_ok());
+        assert!(matches!(
+            KvStore::bulk_load(temp_dir.path(), vec![]),
+            Err(KvsError::StoreLocked(_))
+        ));
+        drop(store);
+        assert!(matches!(
+            KvStore::bulk_load(
+                temp_dir.path(),
+                vec![("more".to_owned(), "value".to_owned())]
+            ),
+            Err(KvsError::StoreNotEmpty(_))
+        ));
+    }
+
+    // A duplicate fails the load, leaving the store empty.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pairs = vec![
+        ("b".to_owned(), "1".to_owned()),
+        ("a".to_owned(), "2".to_owned()),
+        ("b".to_owned(), "3".to_owned()),
+    ];
+    match KvStore::bulk_load(temp_dir.path(), pairs) {
+        Err(KvsError::DuplicateKey(key)) => assert_eq!(key, "b"),
+        other => panic!("expected a duplicate key, got {:?}", other.map(|_| ())),
+    }
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.is_empty());
+    assert!(store.verify()?.is_ok());
+    store.set("a".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("a")?, Some("value".to_owned()));
+    Ok(())
+}
+
+// The key count should follow a random sequence of set/remove/reopen.
+#[test]
+fn key_count() -> Result<()> {
+    use std::collections::HashMap;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.is_empty());
+
+    let mut oracle = HashMap::new();
+    // A small xorshift generator keeps the sequence deterministic.
+    let mut seed: u32 = 0x9e37_79b9;
+    let mut next = || {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        seed
+    };
+    for step in 0..2000 {
+        let key = format!("key{}", next() % 200);
+        match next() % 10 {
+            0..=5 => {
+                store.set(key.clone(), format!("{}", step))?;
+                oracle.insert(key, format!("{}", step));
+            }
+            6..=8 => {
+                assert_eq!(
+                    store.remove(key.clone()).is_ok(),
+                    oracle.remove(&key).is_some()
+                );
+            }
+            _ => {
+                drop(store);
+                store = KvStore::open(temp_dir.path())?;
+            }
+        }
+        assert_eq!(store.len(), oracle.len());
+        assert_eq!(store.is_empty(), oracle.is_empty());
+    }
+    for (key, value) in oracle {
+        assert_eq!(store.get(key)?, Some(value));
+    }
+    Ok(())
+}
+
+// Should check key existence without reading values.
+#[test]
+fn contains_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(!store.contains_key("key1"));
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(store.contains_key("key1"));
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    store.remove("key1")?;
+    assert!(!store.contains_key("key1"));
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(!store.contains_key("key1"));
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert!(store.contains_key("key1"));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.contains_key("key1"));
+    Ok(())
+}
+
+// A batch should be applied entirely, or dropped entirely if its write is torn.
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.write_batch(WriteBatch::new())?;
+
+    let mut batch = WriteBatch::new();
+    batch.put("key1".to_owned(), "batch1".to_owned());
+    batch.delete("key2".to_owned());
+    batch.put("key3".to_owned(), "batch3".to_owned());
+    batch.delete("missing".to_owned());
+    assert_eq!(batch.len(), 4);
+
+    let log = newest_log(temp_dir.path());
+    let before_batch = std::fs::metadata(&log)?.len();
+    store.write_batch(batch)?;
+    assert_eq!(store.get("key1")?, Some("batch1".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    assert_eq!(store.get("key3")?, Some("batch3".to_owned()));
+    drop(store);
+    let after_batch = std::fs::metadata(&log)?.len();
+    let full_log = std::fs::read(&log)?;
+
+    // Simulate the writer being killed after every possible number of bytes.
+    for len in before_batch..after_batch {
+        std::fs::write(&log, &full_log[..len as usize])?;
+        let mut store = KvStore::open(temp_dir.path())?;
+        assert_eq!(std::fs::metadata(&log)?.len(), before_batch);
+        assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+        assert_eq!(store.get("key3")?, None);
+    }
+
+    std::fs::write(&log, &full_log)?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("batch1".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    assert_eq!(store.get("key3")?, Some("batch3".to_owned()));
+    Ok(())
+}
+
+// Should get many values at once in request order.
+#[test]
+fn get_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.get_many(Vec::new())?.is_empty());
+
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.compact()?;
+    store.set("key3".to_owned(), "new3".to_owned())?;
+    store.remove("key5")?;
+
+    let keys = ["key7", "missing", "key3", "key5", "key0", "key7"];
+    let values = store.get_many(keys.iter().map(|key| key.to_string()))?;
+    assert_eq!(
+        values,
+        vec![
+            Some("value7".to_owned()),
+            None,
+            Some("new3".to_owned()),
+            None,
+            Some("value0".to_owned()),
+            Some("value7".to_owned()),
+        ]
+    );
+    Ok(())
+}
+
+// Should only swap values that match the expectation.
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    // Only if absent.
+    assert!(store.compare_and_swap("job1".to_owned(), None, Some("worker1".to_owned()))?);
+    assert!(!store.compare_and_swap("job1".to_owned(), None, Some("worker2".to_owned()))?);
+    assert_eq!(store.get("job1")?, Some("worker1".to_owned()));
+
+    assert!(!store.compare_and_swap(
+        "job1".to_owned(),
+        Some("worker2".to_owned()),
+        Some("worker3".to_owned())
+    )?);
+    assert!(store.compare_and_swap(
+        "job1".to_owned(),
+        Some("worker1".to_owned()),
+        Some("done".to_owned())
+    )?);
+    assert_eq!(store.get("job1")?, Some("done".to_owned()));
+
+    // Swap to delete.
+    assert!(!store.compare_and_swap("job1".to_owned(), Some("worker1".to_owned()), None)?);
+    assert!(store.compare_and_swap("job1".to_owned(), Some("done".to_owned()), None)?);
+    assert!(!store.contains_key("job1"));
+    assert!(store.compare_and_swap("job1".to_owned(), None, None)?);
+    assert!(!store.contains_key("job1"));
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("job1")?, None);
+    Ok(())
+}
+
+// Keys set with a TTL should disappear once expired.
+#[test]
+fn set_with_ttl() -> Result<()> {
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    store.set_with_ttl("zero".to_owned(), "value".to_owned(), Duration::ZERO)?;
+    assert_eq!(store.get("zero")?, None);
+    assert!(!store.contains_key("zero"));
+
+    store.set_with_ttl(
+        "short".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    store.set_with_ttl(
+        "long".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    store.set("forever".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("short")?, Some("value".to_owned()));
+    assert_eq!(store.len(), 3);
+
+    // Entries written on one run and read after restart.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("long")?, Some("value".to_owned()));
+    sleep(Duration::from_millis(300));
+    assert_eq!(store.keys().collect::<Vec<_>>(), vec!["forever", "long"]);
+    assert_eq!(store.len(), 2);
+    let scanned: Vec<_> = store.range::<&str, _>(..).collect::<Result<_>>()?;
+    assert_eq!(scanned, pairs(&[("forever", "value"), ("long", "value")]));
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("short")?, None);
+    assert!(store.remove("short").is_err());
+
+    // Overwriting an expired key makes it live again.
+    store.set("zero".to_owned(), "again".to_owned())?;
+    assert_eq!(store.get("zero")?, Some("again".to_owned()));
+
+    // Compaction drops expired entries for good.
+    store.compact()?;
+    drop(store);
+    for entry in std::fs::read_dir(temp_dir.path())? {
+        let log = std::fs::read(entry?.path())?;
+        assert!(!log.windows(5).any(|window| window == b"short"));
+    }
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 3);
+    assert_eq!(store.get("short")?, None);
+    assert_eq!(store.get("long")?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Should round-trip arbitrary bytes.
+#[test]
+fn binary_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let nul = vec![0u8, 1, 0, 2, 0];
+    let invalid_utf8 = vec![0xff, 0xfe, 0xc3, 0x28];
+    store.set_bytes("nul".to_owned(), nul.clone())?;
+    store.set_bytes("invalid".to_owned(), invalid_utf8.clone())?;
+    store.set_bytes("empty".to_owned(), Vec::new())?;
+    store.set("string".to_owned(), "value".to_owned())?;
+
+    assert_eq!(store.get_bytes("nul")?, Some(nul.clone()));
+    assert_eq!(store.get_bytes("invalid")?, Some(invalid_utf8.clone()));
+    assert_eq!(store.get_bytes("empty")?, Some(Vec::new()));
+    assert_eq!(store.get_bytes("string")?, Some(b"value".to_vec()));
+    assert_eq!(store.get_bytes("missing")?, None);
+    assert_eq!(store.get("nul")?, Some("\0\u{1}\0\u{2}\0".to_owned()));
+    assert!(store.get("invalid").is_err());
+
+    // Multi-megabyte blobs cross the compaction threshold.
+    let blob: Vec<u8> = (0..3 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    for iter in 0..3u8 {
+        let mut value = blob.clone();
+        value[0] = iter;
+        store.set_bytes("blob".to_owned(), value)?;
+    }
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    let mut expected = blob;
+    expected[0] = 2;
+    assert_eq!(store.get_bytes("blob")?, Some(expected));
+    assert_eq!(store.get_bytes("nul")?, Some(nul));
+    assert_eq!(store.get_bytes("invalid")?, Some(invalid_utf8));
+    assert_eq!(store.get("string")?, Some("value".to_owned()));
+    assert!(dir_size(temp_dir.path()) < 3 * 3 * 1024 * 1024);
+    Ok(())
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+struct User {
+    name: String,
+    age: u32,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+enum Shape {
+    Circle(f64),
+    Rect { width: u32, height: u32 },
+}
+
+// Should round-trip typed values.
+#[test]
+fn typed_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+
+    let user = User {
+        name: "alice".to_owned(),
+        age: 30,
+    };
+    let shapes = vec![
+        Shape::Circle(1.5),
+        Shape::Rect {
+            width: 2,
+            height: 3,
+        },
+    ];
+    store.set_as("user".to_owned(), &user)?;
+    store.set_as("shapes".to_owned(), &shapes)?;
+    store.set_as("count".to_owned(), &42u32)?;
+    store.set("name".to_owned(), "bob".to_owned())?;
+
+    assert_eq!(store.get_as::<_, User>("user")?, Some(user));
+    assert_eq!(store.get_as::<_, Vec<Shape>>("shapes")?, Some(shapes));
+    assert_eq!(store.get_as::<_, u32>("count")?, Some(42));
+    assert_eq!(store.get_as::<_, String>("name")?, Some("bob".to_owned()));
+    assert_eq!(store.get_as::<_, u32>("missing")?, None);
+    assert_eq!(store.get("count")?, Some("42".to_owned()));
+    assert!(matches!(
+        store.get_as::<_, u32>("user"),
+        Err(KvsError::TypeMismatch(_))
+    ));
+
+    for iter in 0..100u32 {
+        store.set_as("count".to_owned(), &iter)?;
+    }
+    assert!(store.compact()?.reclaimed_bytes > 0);
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_as::<_, u32>("count")?, Some(99));
+    assert_eq!(
+        store.get_as::<_, User>("user")?,
+        Some(User {
+            name: "alice".to_owned(),
+            age: 30,
+        })
+    );
+    assert_eq!(
+        store.get_as::<_, Vec<Shape>>("shapes")?.map(|s| s.len()),
+        Some(2)
+    );
+    Ok(())
+}
+
+// A bincode store should work like a JSON one and be picked up by `open`.
+#[test]
+fn bincode_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open_with_format(temp_dir.path(), LogFormat::Bincode)?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set_bytes("bytes".to_owned(), vec![0, 0xff, 1])?;
+    store.set_as("typed".to_owned(), &vec![1u32, 2, 3])?;
+    let mut batch = WriteBatch::new();
+    batch.put("key2".to_owned(), "value2".to_owned());
+    batch.put("key3".to_owned(), "value3".to_owned());
+    store.write_batch(batch)?;
+    store.remove("key3")?;
+    for iter in 0..100 {
+        store.set("key4".to_owned(), format!("{}", iter))?;
+    }
+    assert!(store.compact()?.reclaimed_bytes > 0);
+    store.set("key5".to_owned(), "value5".to_owned())?;
+    drop(store);
+
+    let log = newest_log(temp_dir.path());
+    append_to(&log, &[42, 0, 0, 0, 1, 2]);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3")?, None);
+    assert_eq!(store.get("key4")?, Some("99".to_owned()));
+    assert_eq!(store.get("key5")?, Some("value5".to_owned()));
+    assert_eq!(store.get_bytes("bytes")?, Some(vec![0, 0xff, 1]));
+    assert_eq!(store.get_as::<_, Vec<u32>>("typed")?, Some(vec![1, 2, 3]));
+    drop(store);
+
+    assert!(matches!(
+        KvStore::open_with_format(temp_dir.path(), LogFormat::Json),
+        Err(KvsError::FormatMismatch { .. })
+    ));
+    KvStore::open_with_format(temp_dir.path(), LogFormat::Bincode)?;
+    Ok(())
+}
+
+// Stores created before the format was recorded are JSON.
+#[test]
+fn legacy_json_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    std::fs::remove_file(temp_dir.path().join("format"))?;
+
+    assert!(matches!(
+        KvStore::open_with_format(temp_dir.path(), LogFormat::Bincode),
+        Err(KvsError::FormatMismatch { .. })
+    ));
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    drop(store);
+    let mut store = KvStore::open_with_format(temp_dir.path(), LogFormat::Json)?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    Ok(())
+}
+
+fn flip_byte(path: &std::path::Path, offset: usize) {
+    let mut bytes = std::fs::read(path).expect("fail to read log file");
+    bytes[offset] ^= 0x01;
+    std::fs::write(path, bytes).expect("fail to write log file");
+}
+
+// Flipped bytes inside a record should be detected by its checksum.
+#[test]
+fn checksum_corruption() -> Result<()> {
+    for format in [LogFormat::Json, LogFormat::Bincode] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_format(temp_dir.path(), format)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "abcdefgh".to_owned())?;
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        drop(store);
+
+        let log = newest_log(temp_dir.path());
+        let original = std::fs::read(&log)?;
+        let value_at = original
+            .windows(8)
+            .position(|window| window == b"abcdefgh")
+            .expect("value not found in log");
+        for offset in value_at..value_at + 8 {
+            std::fs::write(&log, &original)?;
+            flip_byte(&log, offset);
+            match KvStore::open(temp_dir.path()) {
+                Err(KvsError::Corruption { offset: at, .. }) => assert!(at <= offset as u64),
+                Err(e) => panic!("unexpected error: {}", e),
+                Ok(_) => panic!("corruption at offset {} not detected", offset),
+            }
+        }
+
+        // Corruption while the store is open is caught on read if asked for.
+        std::fs::write(&log, &original)?;
+        let mut store = KvStore::open(temp_dir.path())?;
+        flip_byte(&log, value_at);
+        assert_eq!(store.get("key2")?, Some("`bcdefgh".to_owned()));
+        store.set_verify_checksums(true);
+        assert!(matches!(
+            store.get("key2"),
+            Err(KvsError::Corruption { .. })
+        ));
+        assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    }
+    Ok(())
+}
+
+// A store opened with `CorruptionPolicy::Skip` should load every record around a
+// damaged run at the start, middle or end of a log, and count the run as stale.
+#[test]
+fn skip_corruption() -> Result<()> {
+    use kvs::CorruptionPolicy;
+
+    for format in [LogFormat::Json, LogFormat::Bincode] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::builder(temp_dir.path())
+            .format(format)
+            .max_segment_size(1024)
+            .open()?;
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        // Without a snapshot, every log is replayed.
+        let base = crashed_copy(temp_dir.path());
+        drop(store);
+        let original = std::fs::read(base.path().join("1.log"))?;
+        let len = original.len();
+
+        for (at, damaged) in [(0, 10), (len / 2, 10), (len - 10, 10)] {
+            let crashed = crashed_copy(base.path());
+            let log = crashed.path().join("1.log");
+            let mut bytes = original.clone();
+            bytes[at..at + damaged].fill(b'#');
+            std::fs::write(&log, &bytes)?;
+            assert!(KvStore::open(crashed.path()).is_err());
+
+            let open = || {
+                KvStore::builder(crashed.path())
+                    .on_corruption(CorruptionPolicy::Skip)
+                    .compaction_threshold(u64::MAX)
+                    .open()
+            };
+            let mut store = open()?;
+            let skipped = store.skipped_regions().to_vec();
+            assert_eq!(skipped.len(), 1);
+            let region = skipped[0];
+            assert_eq!(region.gen, 1);
+            assert!(region.offset <= at as u64);
+            assert!(region.offset + region.len >= (at + damaged) as u64);
+            assert!(region.offset + region.len <= len as u64);
+            assert!(store.stats().uncompacted_bytes >= region.len);
+            assert_eq!(store.live_size(), store.stats().live_bytes);
+            let found = (0..100)
+                .filter(|i| store.get(format!("key{}", i)).unwrap().is_some())
+                .count();
+            assert!((95..100).contains(&found));
+            assert_eq!(store.get("key99")?, Some("value99".to_owned()));
+
+            // Writes are allowed, and a compaction drops the damaged run.
+            store.set("new".to_owned(), "value".to_owned())?;
+            drop(store);
+            let mut store = open()?;
+            assert_eq!(store.get("new")?, Some("value".to_owned()));
+            store.compact()?;
+            drop(store);
+            let store = KvStore::open(crashed.path())?;
+            assert_eq!(store.len(), found + 1);
+        }
+    }
+    Ok(())
+}
+
+// Errors reading a record should say which log and offset it is at.
+#[test]
+fn read_error_location() -> Result<()> {
+    for format in [LogFormat::Json, LogFormat::Bincode] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_format(temp_dir.path(), format)?;
+        // A stale record, so there is something to compact.
+        store.set("key1".to_owned(), "stale".to_owned())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        let log = newest_log(temp_dir.path());
+        let gen: u64 = log.file_stem().unwrap().to_str().unwrap().parse().unwrap();
+        let key3_at = std::fs::metadata(&log)?.len();
+        store.set("key3".to_owned(), "value3".to_owned())?;
+
+        // Cut the log short in the middle of the record of `key3`.
+        let file = std::fs::OpenOptions::new().write(true).open(&log)?;
+        file.set_len(key3_at + 3)?;
+        match store.get("key3") {
+            Err(e @ KvsError::LogRead { .. }) => {
+                let location = format!("offset {} of {}.log", key3_at, gen);
+                assert!(e.to_string().contains(&location), "{}", e);
+            }
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(value) => panic!("read {:?} from a truncated record", value),
+        }
+        assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+        match store.compact() {
+            Err(KvsError::LogRead { gen: g, offset, .. }) => {
+                assert_eq!((g, offset), (gen, key3_at))
+            }
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("compacted a truncated record"),
+        }
+        assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    }
+    Ok(())
+}
+
+// An index pointing a key at its tombstone should fail reads and compaction
+// rather than look like a missing key.
+#[test]
+fn index_points_at_tombstone() -> Result<()> {
+    for format in [LogFormat::Json, LogFormat::Bincode] {
+        // A log holding the record of `key1` followed by its tombstone.
+        let scratch = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_format(scratch.path(), format)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        let set_len = std::fs::metadata(newest_log(scratch.path()))?.len() as usize;
+        store.remove("key1")?;
+        drop(store);
+        let tombstone = std::fs::read(newest_log(scratch.path()))?[set_len..].to_vec();
+        assert!(tombstone.len() <= set_len);
+
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_format(temp_dir.path(), format)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "stale".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        drop(store);
+
+        // Overwrite the record of `key1` behind the back of the snapshot, which still
+        // points the key at it.
+        let log = newest_log(temp_dir.path());
+        let modified = std::fs::metadata(&log)?.modified()?;
+        let mut bytes = std::fs::read(&log)?;
+        let padding = if format == LogFormat::Json { b' ' } else { 0 };
+        bytes[..set_len].fill(padding);
+        bytes[..tombstone.len()].copy_from_slice(&tombstone);
+        std::fs::write(&log, bytes)?;
+        std::fs::File::options()
+            .write(true)
+            .open(&log)?
+            .set_modified(modified)?;
+
+        let mut store = KvStore::open(temp_dir.path())?;
+        assert!(store.contains_key("key1"));
+        match store.get("key1") {
+            Err(KvsError::UnexpectedCommandType { key, offset, .. }) => {
+                assert_eq!((key.as_str(), offset), ("key1", 0))
+            }
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(value) => panic!("read {:?} from a tombstone", value),
+        }
+        assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+        assert!(matches!(
+            store.compact(),
+            Err(KvsError::UnexpectedCommandType { .. })
+        ));
+        assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    }
+    Ok(())
+}
+
+// A log removed from under an open store should fail the operations that need it
+// with an error instead of a panic.
+#[test]
+fn missing_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SharedKvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let log = newest_log(temp_dir.path());
+    let gen: u64 = log.file_stem().unwrap().to_str().unwrap().parse().unwrap();
+    std::fs::remove_file(&log)?;
+    // A clone opens its file handles as it reads.
+    assert!(matches!(
+        store.clone().get("key1"),
+        Err(KvsError::MissingGeneration(g)) if g == gen
+    ));
+    drop(store);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder(temp_dir.path())
+        .max_segment_size(1)
+        .compaction_threshold(200)
+        .background_compaction(true)
+        .open()?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    // The first log holds `key0`, and was rolled over from right away.
+    std::fs::remove_file(temp_dir.path().join("1.log"))?;
+    // The background compaction opens every log again.
+    let mut result = Ok(());
+    for i in 0..20 {
+        result = store.set("key1".to_owned(), format!("value{}", i));
+        if result.is_err() {
+            break;
+        }
+    }
+    match result.and_then(|()| store.compact()) {
+        Err(KvsError::MissingGeneration(gen)) => assert_eq!(gen, 1),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("compacted without 1.log"),
+    }
+    Ok(())
+}
+
+// Records without checksums from older logs should still open.
+#[test]
+fn unchecked_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(
+        temp_dir.path().join("1.log"),
+        br#"{"S":["key1","value1"]}{"S":["key2","value2"]}{"R":"key1"}"#,
+    )?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_verify_checksums(true);
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    Ok(())
+}
+
+fn hint_files(path: &std::path::Path) -> Vec<std::path::PathBuf> {
+    std::fs::read_dir(path)
+        .expect("fail to read directory")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("hint".as_ref()))
+        .collect()
+}
+
+// Compaction should write a hint file, and damaged or stale hints must be ignored.
+#[test]
+fn hint_files_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.remove("key0")?;
+    store.compact()?;
+    store.compact()?;
+    store.set("key1".to_owned(), "new".to_owned())?;
+    drop(store);
+    let hints = hint_files(temp_dir.path());
+    assert_eq!(hints.len(), 1);
+
+    // The snapshot written on close would be used in place of the hints.
+    let snapshot = temp_dir.path().join("index.snapshot");
+    let check = || -> Result<()> {
+        if snapshot.exists() {
+            std::fs::remove_file(&snapshot)?;
+        }
+        let mut store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.len(), 99);
+        assert_eq!(store.get("key0")?, None);
+        assert_eq!(store.get("key1")?, Some("new".to_owned()));
+        for key_id in 2..100 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some("9".to_owned()));
+        }
+        Ok(())
+    };
+    check()?;
+
+    // A damaged hint falls back to replaying the log.
+    let hint = std::fs::read(&hints[0])?;
+    flip_byte(&hints[0], hint.len() / 2);
+    check()?;
+    std::fs::write(&hints[0], &hint[..hint.len() / 2])?;
+    check()?;
+
+    // A hint that does not match its log is stale.
+    std::fs::write(&hints[0], &hint)?;
+    append_to(&hints[0].with_extension("log"), br#"{"S":["key0","back"]}"#);
+    std::fs::remove_file(&snapshot)?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 100);
+    assert_eq!(store.get("key0")?, Some("back".to_owned()));
+    Ok(())
+}
+
+// Opening a store should build the index of a compacted log from its hint file, without
+// reading the log, and replay the log once the hint is gone.
+#[test]
+fn open_with_hint_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let snapshot = temp_dir.path().join("index.snapshot");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let value = "v".repeat(100);
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+    store.remove("key0")?;
+    store.compact()?;
+    drop(store);
+    let hints = hint_files(temp_dir.path());
+    assert_eq!(hints.len(), 1);
+    let hint = std::fs::read(&hints[0])?;
+    let log = hints[0].with_extension("log");
+
+    // The snapshot written on close would be used in place of the hints.
+    let replayed = crashed_copy(temp_dir.path());
+    std::fs::remove_file(replayed.path().join("index.snapshot"))?;
+    std::fs::remove_file(replayed.path().join(hints[0].file_name().unwrap()))?;
+    let mut store = KvStore::open(replayed.path())?;
+    assert_eq!(store.len(), 999);
+    assert_eq!(store.get("key999")?, Some(value.clone()));
+    drop(store);
+
+    // Damage every value of the log, then write the hint again so it is not older than
+    // the log. The hinted open never reads the values, so it still builds the index.
+    std::fs::remove_file(&snapshot)?;
+    let damaged: Vec<u8> = std::fs::read(&log)?
+        .into_iter()
+        .map(|byte| if byte == b'v' { b'w' } else { byte })
+        .collect();
+    std::fs::write(&log, damaged)?;
+    std::fs::write(&hints[0], &hint)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 999);
+    assert!(store.contains_key("key999"));
+    drop(store);
+
+    // Without the hint the damaged log is replayed, which fails.
+    std::fs::remove_file(&snapshot)?;
+    std::fs::remove_file(&hints[0])?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::Corruption { .. })
+    ));
+    Ok(())
+}
+
+// A store closed cleanly should come back from its index snapshot.
+#[test]
+fn index_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let snapshot = temp_dir.path().join("index.snapshot");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key0")?;
+    drop(store);
+    assert!(snapshot.exists());
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    // The snapshot is outdated as soon as the store may be written to.
+    assert!(!snapshot.exists());
+    assert_eq!(store.len(), 99);
+    assert_eq!(store.get("key0")?, None);
+    assert_eq!(store.get("key99")?, Some("value99".to_owned()));
+    store.set("key100".to_owned(), "value100".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 100);
+    assert_eq!(store.get("key100")?, Some("value100".to_owned()));
+    Ok(())
+}
+
+// Copies the files of an open store, which is what a crash would leave on disk.
+fn crashed_copy(path: &std::path::Path) -> TempDir {
+    let copy = TempDir::new().expect("unable to create temporary working directory");
+    for entry in std::fs::read_dir(path).expect("fail to read directory") {
+        let path = entry.unwrap().path();
+        if path.file_name() != Some("LOCK".as_ref()) {
+            std::fs::copy(&path, copy.path().join(path.file_name().unwrap()))
+                .expect("fail to copy store file");
+        }
+    }
+    copy
+}
+
+// A store that is not closed cleanly leaves no snapshot, so its logs are replayed.
+#[test]
+fn crash_without_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.remove("key1")?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    let crashed = crashed_copy(temp_dir.path());
+    drop(store);
+    assert!(!crashed.path().join("index.snapshot").exists());
+
+    let mut store = KvStore::open(crashed.path())?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A snapshot that is stale or damaged should be ignored for the hint files of the logs.
+#[test]
+fn snapshot_falls_back_to_hints() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let snapshot = temp_dir.path().join("index.snapshot");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "v".repeat(100))?;
+    }
+    drop(store);
+    let stale = std::fs::read(&snapshot)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.remove("key0")?;
+    store.compact()?;
+    drop(store);
+    let mut damaged = std::fs::read(&snapshot)?;
+    let middle = damaged.len() / 2;
+    damaged[middle] ^= 0xff;
+
+    // Damage every value of the compacted log, which replaying it would fail on, then
+    // write its hint again so it is not older than the log.
+    let hints = hint_files(temp_dir.path());
+    assert_eq!(hints.len(), 1);
+    let hint = std::fs::read(&hints[0])?;
+    let log = hints[0].with_extension("log");
+    let values: Vec<u8> = std::fs::read(&log)?
+        .into_iter()
+        .map(|byte| if byte == b'v' { b'w' } else { byte })
+        .collect();
+    std::fs::write(&log, values)?;
+    std::fs::write(&hints[0], &hint)?;
+
+    for bytes in [stale, damaged] {
+        std::fs::write(&snapshot, bytes)?;
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.len(), 99);
+        assert!(!store.contains_key("key0"));
+        assert!(store.contains_key("key99"));
+    }
+    Ok(())
+}
+
+// A snapshot older than the logs must not bring back removed keys.
+#[test]
+fn snapshot_older_than_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let snapshot = temp_dir.path().join("index.snapshot");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+    let old_snapshot = std::fs::read(&snapshot)?;
+
+    // Records appended after the snapshot are replayed on top of it.
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.remove("key1")?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    std::fs::write(&snapshot, &old_snapshot)?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 2);
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+
+    // A compaction replaces the logs the snapshot refers to.
+    store.remove("key2")?;
+    store.compact()?;
+    drop(store);
+    std::fs::write(&snapshot, &old_snapshot)?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 1);
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, None);
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// The active log should roll over to a new generation at the maximum segment size.
+#[test]
+fn segment_rollover() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_max_segment_size(4096);
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    assert!(log_files(temp_dir.path()) > 5);
+    for entry in std::fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.extension() == Some("log".as_ref()) {
+            // A log only exceeds the limit by the record that made it full.
+            assert!(std::fs::metadata(path)?.len() < 4096 + 100);
+        }
+    }
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("value{}", key_id))
+        );
+    }
+    // Compaction copies the records of every segment.
+    store.remove("key0")?;
+    store.compact()?;
+    let crashed = crashed_copy(temp_dir.path());
+    let mut store = KvStore::open(crashed.path())?;
+    assert_eq!(store.len(), 999);
+    assert_eq!(store.get("key999")?, Some("value999".to_owned()));
+    Ok(())
+}
+
+// Writes until the newest log changes, which only a compaction does here.
+fn writes_until_compaction(store: &mut KvStore, path: &std::path::Path) -> Result<usize> {
+    let log = newest_log(path);
+    for n in 1..10000 {
+        store.set("key".to_owned(), "v".repeat(1000))?;
+        if newest_log(path) != log {
+            return Ok(n);
+        }
+    }
+    Ok(usize::MAX)
+}
+
+// Automatic compaction should run once the stale bytes exceed the configured threshold.
+#[test]
+fn compaction_threshold() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        compaction_threshold: 100 * 1024,
+        ..Options::default()
+    };
+    let mut store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key".to_owned(), "v".repeat(1000))?;
+    let record_len = std::fs::metadata(newest_log(temp_dir.path()))?.len() as usize;
+    // Every overwrite makes the previous record stale.
+    let expected = 100 * 1024 / record_len + 1;
+    assert_eq!(
+        writes_until_compaction(&mut store, temp_dir.path())?,
+        expected
+    );
+    assert_eq!(store.get("key")?, Some("v".repeat(1000)));
+
+    // `0` compacts on every overwrite.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        compaction_threshold: 0,
+        ..Options::default()
+    };
+    let mut store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key".to_owned(), "v".repeat(1000))?;
+    assert_eq!(writes_until_compaction(&mut store, temp_dir.path())?, 1);
+    assert_eq!(writes_until_compaction(&mut store, temp_dir.path())?, 1);
+
+    // `u64::MAX` leaves compaction to `compact`.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options {
+        compaction_threshold: u64::MAX,
+        ..Options::default()
+    };
+    let mut store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(
+        writes_until_compaction(&mut store, temp_dir.path())?,
+        usize::MAX
+    );
+    assert!(store.compact()?.reclaimed_bytes > 0);
+    assert_eq!(store.get("key")?, Some("v".repeat(1000)));
+    Ok(())
+}
+
+// The builder should configure the store, with defaults matching `open`.
+#[test]
+fn store_builder() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder(temp_dir.path())
+        .format(LogFormat::Bincode)
+        .max_segment_size(4096)
+        .compaction_threshold(u64::MAX)
+        .open()?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    assert!(log_files(temp_dir.path()) > 1);
+    drop(store);
+    assert!(matches!(
+        KvStore::builder(temp_dir.path())
+            .format(LogFormat::Json)
+            .open(),
+        Err(KvsError::FormatMismatch { .. })
+    ));
+    let mut store = KvStore::builder(temp_dir.path()).open()?;
+    assert_eq!(store.get("key999")?, Some("value999".to_owned()));
+
+    // A missing directory is only created if asked to.
+    let missing = temp_dir.path().join("missing");
+    assert!(matches!(
+        KvStore::builder(&missing).create_if_missing(false).open(),
+        Err(KvsError::StoreNotFound(path)) if path == missing
+    ));
+    assert!(!missing.exists());
+    KvStore::builder(&missing).open()?;
+    assert!(missing.is_dir());
+    KvStore::builder(&missing).create_if_missing(false).open()?;
+    Ok(())
+}
+
+// Every durability mode should keep the writes across reopens and compactions.
+#[test]
+fn durability_modes() -> Result<()> {
+    use std::time::Duration;
+
+    for durability in [
+        Durability::Always,
+        Durability::EveryN(7),
+        Durability::Interval(Duration::from_millis(1)),
+        Durability::Never,
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::builder(temp_dir.path())
+            .durability(durability)
+            .max_segment_size(1024)
+            .compaction_threshold(4096)
+            .open()?;
+        for iter in 0..10 {
+            for key_id in 0..20 {
+                store.set(format!("key{}", key_id), format!("{}", iter))?;
+            }
+            let mut batch = WriteBatch::new();
+            batch.put("batch".to_owned(), format!("{}", iter));
+            store.write_batch(batch)?;
+        }
+        let crashed = crashed_copy(temp_dir.path());
+        drop(store);
+
+        let mut store = KvStore::open(crashed.path())?;
+        for key_id in 0..20 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some("9".to_owned()));
+        }
+        assert_eq!(store.get("batch")?, Some("9".to_owned()));
+    }
+    Ok(())
+}
+
+// `flush` and `sync` should push the writes to the logs whatever the durability mode,
+// and do nothing when there is nothing to push or the store is read-only.
+#[test]
+fn flush_and_sync() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder(temp_dir.path())
+        .durability(Durability::Never)
+        .max_segment_size(1024)
+        .open()?;
+    store.sync()?;
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    store.flush()?;
+    store.sync()?;
+    store.sync()?;
+    let crashed = crashed_copy(temp_dir.path());
+    store.flush()?;
+    drop(store);
+
+    let mut store = KvStore::open(crashed.path())?;
+    assert_eq!(store.len(), 50);
+    assert_eq!(store.get("key49")?, Some("value".to_owned()));
+    drop(store);
+
+    let mut store = KvStore::open_read_only(temp_dir.path())?;
+    store.flush()?;
+    store.sync()?;
+    assert_eq!(store.len(), 50);
+    Ok(())
+}
+
+// `history` should list every version of a key left in the logs, removals included,
+// until a compaction keeps only the latest one.
+#[test]
+fn key_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder(temp_dir.path())
+        .max_segment_size(256)
+        .compaction_threshold(u64::MAX)
+        .open()?;
+    assert!(store.history("config")?.is_empty());
+    for i in 0..5 {
+        store.set("config".to_owned(), format!("v{}", i))?;
+        store.set(format!("other{}", i), "value".to_owned())?;
+    }
+    store.remove("config")?;
+    store.set("config".to_owned(), "back".to_owned())?;
+    store.append("config".to_owned(), "+1".to_owned())?;
+
+    let history = store.history("config")?;
+    let values: Vec<Option<&str>> = history.iter().map(|v| v.value.as_deref()).collect();
+    assert_eq!(
+        values,
+        [
+            Some("v0"),
+            Some("v1"),
+            Some("v2"),
+            Some("v3"),
+            Some("v4"),
+            None,
+            Some("back"),
+            Some("back+1"),
+        ]
+    );
+    assert!(history.windows(2).all(|w| w[0].seq < w[1].seq));
+    assert!(history.iter().all(|v| !v.compacted));
+    assert!(history.last().unwrap().gen > history[0].gen);
+    assert_eq!(store.history("other0")?.len(), 1);
+
+    store.compact()?;
+    let history = store.history("config")?;
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].value.as_deref(), Some("back+1"));
+    assert!(history[0].compacted);
+    store.remove("other0")?;
+    assert_eq!(store.history("other0")?.len(), 2);
+    store.compact()?;
+    assert!(store.history("other0")?.is_empty());
+    Ok(())
+}
+
+// A compaction with `keep_versions` should keep the latest versions of every key, count
+// them as live, and keep the versions of removed keys only with `keep_deleted_versions`.
+#[test]
+fn keep_versions() -> Result<()> {
+    for (disk_index, keep_deleted) in [(false, false), (false, true), (true, true)] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let open = || {
+            KvStore::builder(temp_dir.path())
+                .compaction_threshold(u64::MAX)
+                .disk_index(disk_index)
+                .keep_versions(3)
+                .keep_deleted_versions(keep_deleted)
+                .open()
+        };
+        let mut store = open()?;
+        for i in 0..10 {
+            store.set("key".to_owned(), format!("value{}", i))?;
+            store.set("gone".to_owned(), format!("value{}", i))?;
+        }
+        store.remove("gone")?;
+        let live = store.live_size();
+        store.compact()?;
+        let values = |store: &mut KvStore, key: &str| -> Result<Vec<Option<String>>> {
+            Ok(store.history(key)?.into_iter().map(|v| v.value).collect())
+        };
+        let kept = [
+            Some("value7".to_owned()),
+            Some("value8".to_owned()),
+            Some("value9".to_owned()),
+        ];
+        assert_eq!(values(&mut store, "key")?, kept);
+        assert_eq!(store.get("key")?, Some("value9".to_owned()));
+        let mut gone = Vec::new();
+        if keep_deleted {
+            gone.extend(kept.clone());
+            gone.push(None);
+        }
+        assert_eq!(values(&mut store, "gone")?, gone);
+        assert_eq!(store.get("gone")?, None);
+        let kept_size = store.live_size();
+        assert!(kept_size > live);
+        assert_eq!(store.stats().uncompacted_bytes, 0);
+
+        // The versions survive a reopen, and the next compaction keeps the latest ones.
+        drop(store);
+        let mut store = open()?;
+        assert_eq!(store.live_size(), kept_size);
+        assert_eq!(store.get("gone")?, None);
+        store.set("key".to_owned(), "value10".to_owned())?;
+        store.compact()?;
+        assert_eq!(
+            values(&mut store, "key")?,
+            ["value8", "value9", "value10"].map(|v| Some(v.to_owned()))
+        );
+        assert_eq!(values(&mut store, "gone")?, gone);
+        let report = store.compact()?;
+        assert_eq!(report, kvs::CompactionReport::default());
+    }
+    Ok(())
+}
+
+// `purge_expired` should evict the expired keys without reading them, counting their
+// records as stale, and leave the other keys alone, also once the store is reopened.
+#[test]
+fn purge_expired() -> Result<()> {
+    use std::time::Duration;
+    for disk_index in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let open = |path: &std::path::Path| {
+            KvStore::builder(path)
+                .compaction_threshold(u64::MAX)
+                .disk_index(disk_index)
+                .open()
+        };
+        let mut store = open(temp_dir.path())?;
+        for i in 0..20 {
+            if i % 2 == 0 {
+                store.set_with_ttl(format!("ttl{}", i), "value".to_owned(), Duration::ZERO)?;
+            } else {
+                store.set(format!("key{}", i), "value".to_owned())?;
+            }
+        }
+        store.set_with_ttl(
+            "later".to_owned(),
+            "value".to_owned(),
+            Duration::from_secs(3600),
+        )?;
+        // A key set again without a TTL no longer expires.
+        store.set_with_ttl("kept".to_owned(), "value".to_owned(), Duration::ZERO)?;
+        store.set("kept".to_owned(), "value".to_owned())?;
+        // Evicting a key by reading it leaves nothing for the sweep.
+        assert_eq!(store.get("ttl0")?, None);
+        let uncompacted = store.stats().uncompacted_bytes;
+        let live = store.live_size();
+
+        let report = store.purge_expired()?;
+        assert_eq!(report.purged_keys, 9);
+        assert_eq!(store.live_size(), live - report.purged_bytes);
+        assert_eq!(store.stats().uncompacted_bytes, uncompacted);
+        assert_eq!(store.purge_expired()?, kvs::PurgeReport::default());
+        assert_eq!(store.len(), 12);
+        assert_eq!(store.get("later")?, Some("value".to_owned()));
+        assert_eq!(store.get("kept")?, Some("value".to_owned()));
+
+        // The keys come back from the logs on a reopen after a crash, and are purged again.
+        let crashed = crashed_copy(temp_dir.path());
+        drop(store);
+        let mut store = open(crashed.path())?;
+        assert_eq!(store.purge_expired()?.purged_keys, 10);
+        assert_eq!(store.len(), 12);
+        store.compact()?;
+        assert_eq!(store.purge_expired()?, kvs::PurgeReport::default());
+        drop(store);
+        let mut store = open(crashed.path())?;
+        assert_eq!(store.purge_expired()?, kvs::PurgeReport::default());
+        assert_eq!(store.len(), 12);
+    }
+    Ok(())
+}
+
+// A snapshot should keep reading the values it was taken with through overwrites,
+// removals and compactions, which only retire the logs it reads once it is dropped.
+#[test]
+fn snapshot_reads() -> Result<()> {
+    for archive in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut builder = KvStore::builder(temp_dir.path())
+            .max_segment_size(1024)
+            .compaction_threshold(u64::MAX);
+        if archive {
+            builder = builder.archive(kvs::Retention::Count(100));
+        }
+        let mut store = builder.clone().open()?;
+        for i in 0..50 {
+            store.set(format!("key{}", i), format!("old{}", i))?;
+        }
+        store.append("key0".to_owned(), "+".to_owned())?;
+        let mut snapshot = store.snapshot();
+
+        for i in 0..25 {
+            store.set(format!("key{}", i), format!("new{}", i))?;
+        }
+        for i in 25..50 {
+            store.remove(format!("key{}", i))?;
+        }
+        store.set("added".to_owned(), "value".to_owned())?;
+        store.compact()?;
+        assert_eq!(store.get("key0")?, Some("new0".to_owned()));
+        assert_eq!(store.get("key30")?, None);
+
+        assert_eq!(snapshot.get("key0")?, Some("old0+".to_owned()));
+        assert_eq!(snapshot.get("key30")?, Some("old30".to_owned()));
+        assert_eq!(snapshot.get("added")?, None);
+        assert_eq!(snapshot.keys().count(), 50);
+        let pairs: Vec<(String, String)> = snapshot.range("key1".."key2").collect::<Result<_>>()?;
+        assert_eq!(pairs.len(), 11);
+        assert_eq!(pairs[0], ("key1".to_owned(), "old1".to_owned()));
+        store.clear()?;
+        assert_eq!(snapshot.get("key49")?, Some("old49".to_owned()));
+
+        // The logs the snapshot reads are out of the way of a reopen.
+        store.set("key30".to_owned(), "after".to_owned())?;
+        drop(store);
+        let store = builder.clone().open()?;
+        assert_eq!(store.keys().count(), 1);
+        assert_eq!(snapshot.iter().count(), 50);
+        drop(snapshot);
+        drop(store);
+        let pinned = temp_dir.path().join("pinned");
+        assert_eq!(std::fs::read_dir(&pinned)?.count(), 0);
+        assert!(KvStore::open(temp_dir.path())?.verify()?.is_ok());
+    }
+    Ok(())
+}
+
+// Writes through a `batched` guard should read back before they are flushed, roll
+// over and compact like other writes, and reach the logs by the time it is dropped.
+#[test]
+fn batched_writes() -> Result<()> {
+    use std::sync::{Arc, Mutex};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder(temp_dir.path())
+        .max_segment_size(1024)
+        .compaction_threshold(4096)
+        .open()?;
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&events);
+    let _handle = store.watch("key1".to_owned(), move |event| {
+        recorded.lock().unwrap().push(event.clone())
+    });
+    let mut batched = store.batched()?;
+    batched.set("key1".to_owned(), "first".to_owned())?;
+    assert!(events.lock().unwrap().is_empty());
+    assert_eq!(batched.get("key1")?, Some("first".to_owned()));
+    assert_eq!(events.lock().unwrap().len(), 1);
+    for iter in 0..20 {
+        for key_id in 0..20 {
+            batched.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    batched.remove("key0")?;
+    assert!(matches!(batched.remove("key0"), Err(KvsError::KeyNotFound)));
+    assert_eq!(batched.get("key19")?, Some("19".to_owned()));
+    batched.set("key1".to_owned(), "last".to_owned())?;
+    drop(batched);
+    // The watcher sees key1 and key10 to key19.
+    assert_eq!(events.lock().unwrap().len(), 2 + 11 * 20);
+    assert!(store.stats().generations > 1);
+    let crashed = crashed_copy(temp_dir.path());
+    drop(store);
+
+    let mut store = KvStore::open(crashed.path())?;
+    assert_eq!(store.get("key0")?, None);
+    assert_eq!(store.get("key1")?, Some("last".to_owned()));
+    for key_id in 2..20 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("19".to_owned()));
+    }
+    drop(store);
+
+    let mut store = KvStore::open_read_only(temp_dir.path())?;
+    assert!(matches!(store.batched(), Err(KvsError::ReadOnly)));
+    Ok(())
+}
+
+// Automatic compactions on a background thread should not lose concurrent writes.
+#[test]
+fn background_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder(temp_dir.path())
+        .compaction_threshold(64 * 1024)
+        .background_compaction(true)
+        .open()?;
+    for iter in 0..200 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        store.remove(format!("key{}", iter % 100))?;
+        store.set(format!("key{}", iter % 100), format!("{}", iter))?;
+    }
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("199".to_owned()));
+    }
+    drop(store);
+    // Only a few generations are left behind once the last compaction is done.
+    assert!(log_files(temp_dir.path()) <= 3);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 100);
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("199".to_owned()));
+    }
+    Ok(())
+}
+
+// The engine stats of a shared store should count the keys not expired and the size of
+// the logs through writes, rollovers and compactions.
+#[test]
+fn shared_store_engine_stats() -> Result<()> {
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set_with_ttl(
+            format!("short{}", key_id),
+            "value".to_owned(),
+            Duration::from_millis(200),
+        )?;
+    }
+    drop(store);
+
+    let mut store = KvStore::builder(temp_dir.path())
+        .max_segment_size(4096)
+        .compaction_threshold(u64::MAX)
+        .open_shared()?;
+    let check = |store: &mut SharedKvStore, live_keys: u64| -> Result<()> {
+        let stats = store.engine_stats()?.unwrap();
+        assert_eq!(stats.live_keys, live_keys);
+        assert_eq!(store.len() as u64, live_keys);
+        assert_eq!(stats.disk_bytes, logs_size(temp_dir.path()));
+        Ok(())
+    };
+    check(&mut store, 10)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    // Keys set again no longer expire.
+    store.set("short0".to_owned(), "value".to_owned())?;
+    store.remove("short1")?;
+    check(&mut store, 109)?;
+    sleep(Duration::from_millis(300));
+    check(&mut store, 101)?;
+
+    SharedKvStore::compact(&store)?;
+    check(&mut store, 101)?;
+    store.remove("key0")?;
+    check(&mut store, 100)?;
+    Ok(())
+}
+
+// Clones of a shared store should serve reads from many threads while another one writes.
+#[test]
+fn shared_store_threads() -> Result<()> {
+    fn assert_shareable<T: Clone + Send + Sync>() {}
+    assert_shareable::<SharedKvStore>();
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder(temp_dir.path())
+        .compaction_threshold(32 * 1024)
+        .open_shared()?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), format!("{}-0", key_id))?;
+    }
+    let writer = {
+        let store = store.clone();
+        std::thread::spawn(move || -> Result<()> {
+            for iter in 1..50 {
+                for key_id in 0..100 {
+                    store.set(format!("key{}", key_id), format!("{}-{}", key_id, iter))?;
+                }
+                store.remove(format!("key{}", iter % 100))?;
+                store.set(
+                    format!("key{}", iter % 100),
+                    format!("{}-{}", iter % 100, iter),
+                )?;
+            }
+            Ok(())
+        })
+    };
+    let readers: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for iter in 0..2000 {
+                    let key_id = (thread_id * 37 + iter) % 100;
+                    // A key being removed and set again may be missing for a moment.
+                    if let Some(value) = store.get(format!("key{}", key_id))? {
+                        assert!(value.starts_with(&format!("{}-", key_id)));
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    writer.join().unwrap()?;
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    assert_eq!(store.len(), 100);
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("{}-49", key_id))
+        );
+    }
+    assert!(log_files(temp_dir.path()) <= 3);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 100);
+    assert_eq!(store.get("key99")?, Some("99-49".to_owned()));
+    Ok(())
+}
+
+// A store directory can only be opened by one store at a time.
+#[test]
+fn store_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let lock = temp_dir.path().join("LOCK");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::StoreLocked(path)) if path == lock
+    ));
+    assert!(matches!(
+        SharedKvStore::open(temp_dir.path()),
+        Err(KvsError::StoreLocked(_))
+    ));
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("already open"));
+
+    // The lock is released on drop, even though the lock file stays.
+    drop(store);
+    assert!(lock.exists());
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["get", "key1"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    let store = SharedKvStore::open(temp_dir.path())?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::StoreLocked(_))
+    ));
+    drop(store);
+    KvStore::open(temp_dir.path())?;
+    Ok(())
+}
+
+// A read-only store should serve reads without touching the store directory.
+#[cfg(unix)]
+#[test]
+fn read_only_store() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2")?;
+    drop(store);
+    // A torn write is left as it is.
+    append_to(&newest_log(temp_dir.path()), br#"{"C":1}{"S":["key3","val"#);
+
+    let listing = |path: &std::path::Path| -> Vec<(std::path::PathBuf, u64)> {
+        let mut files: Vec<_> = std::fs::read_dir(path)
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                let len = std::fs::metadata(&path).unwrap().len();
+                (path, len)
+            })
+            .collect();
+        files.sort();
+        files
+    };
+    let before = listing(temp_dir.path());
+    for entry in std::fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o444))?;
+    }
+    std::fs::set_permissions(temp_dir.path(), std::fs::Permissions::from_mode(0o555))?;
+
+    let mut store = KvStore::open_read_only(temp_dir.path())?;
+    let mut other = KvStore::builder(temp_dir.path()).read_only(true).open()?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(other.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    assert_eq!(store.get("key3")?, None);
+    assert_eq!(store.keys().collect::<Vec<_>>(), vec!["key1"]);
+    assert!(matches!(
+        store.set("key1".to_owned(), "value".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(store.remove("key1"), Err(KvsError::ReadOnly)));
+    assert!(matches!(store.compact(), Err(KvsError::ReadOnly)));
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::StoreLocked(_))
+    ));
+    drop(store);
+    drop(other);
+    assert_eq!(listing(temp_dir.path()), before);
+
+    let shared = KvStore::builder(temp_dir.path())
+        .read_only(true)
+        .open_shared()?;
+    assert_eq!(shared.get("key1")?, Some("value1".to_owned()));
+    assert!(matches!(shared.remove("key1"), Err(KvsError::ReadOnly)));
+    drop(shared);
+    assert_eq!(listing(temp_dir.path()), before);
+
+    std::fs::set_permissions(temp_dir.path(), std::fs::Permissions::from_mode(0o755))?;
+    assert!(matches!(
+        KvStore::open_read_only(temp_dir.path().join("missing")),
+        Err(KvsError::StoreNotFound(_))
+    ));
+    Ok(())
+}
+
+// Behavior every `KvsEngine` must share, run against an empty engine.
+fn engine_conformance<E: KvsEngine>(mut engine: E) -> Result<()> {
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(!engine.contains_key("key1".to_owned())?);
+    assert!(matches!(
+        engine.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert!(engine.contains_key("key2".to_owned())?);
+    assert_eq!(engine.keys()?, vec!["key1", "key2"]);
+
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(matches!(
+        engine.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(engine.keys()?, vec!["key2"]);
+
+    engine.set(String::new(), String::new())?;
+    assert_eq!(engine.get(String::new())?, Some(String::new()));
+    engine.set("ключ 🔑".to_owned(), "значение\n\"quoted\"".to_owned())?;
+    assert_eq!(
+        engine.get("ключ 🔑".to_owned())?,
+        Some("значение\n\"quoted\"".to_owned())
+    );
+    Ok(())
+}
+
+// `KvStore` should behave like every other engine.
+#[test]
+fn kv_store_conformance() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    engine_conformance(KvStore::open(temp_dir.path())?)
+}
+
+// `SharedKvStore` should behave like every other engine.
+#[test]
+fn shared_kv_store_conformance() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    engine_conformance(SharedKvStore::open(temp_dir.path())?)
+}
+
+// A store directory should only be opened by the engine that owns it.
+#[test]
+fn engine_marker() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let marker = temp_dir.path().join("engine");
+    KvStore::open(temp_dir.path())?;
+    assert_eq!(std::fs::read_to_string(&marker)?, "kvs");
+    std::fs::write(&marker, "sled")?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::EngineMismatch { recorded, requested })
+            if recorded == "sled" && requested == "kvs"
+    ));
+
+    // Stores from before the engine was recorded belong to `KvStore`.
+    std::fs::remove_file(&marker)?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(std::fs::read_to_string(&marker)?, "kvs");
+    Ok(())
+}
+
+// `SledKvsEngine` should behave like every other engine.
+#[cfg(feature = "sled")]
+#[test]
+fn sled_conformance() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    engine_conformance(kvs::SledKvsEngine::open(temp_dir.path())?)
+}
+
+// Directories of `SledKvsEngine` and `KvStore` must not be opened by the other engine.
+#[cfg(feature = "sled")]
+#[test]
+fn sled_engine_marker() -> Result<()> {
+    use kvs::SledKvsEngine;
+
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut engine = SledKvsEngine::open(sled_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    drop(engine);
+    assert!(matches!(
+        KvStore::open(sled_dir.path()),
+        Err(KvsError::EngineMismatch { .. })
+    ));
+    let mut engine = SledKvsEngine::open(sled_dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(kvs_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    assert!(matches!(
+        SledKvsEngine::open(kvs_dir.path()),
+        Err(KvsError::EngineMismatch { .. })
+    ));
+    // Logs without a marker also belong to `KvStore`.
+    std::fs::remove_file(kvs_dir.path().join("engine"))?;
+    assert!(matches!(
+        SledKvsEngine::open(kvs_dir.path()),
+        Err(KvsError::EngineMismatch { .. })
+    ));
+    Ok(())
+}
+
+// `MemKvsEngine` should behave like every other engine.
+#[test]
+fn mem_conformance() -> Result<()> {
+    engine_conformance(kvs::MemKvsEngine::new())?;
+
+    let mut engine = kvs::MemKvsEngine::new();
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    let expected: std::collections::BTreeMap<_, _> =
+        pairs(&[("key1", "value1"), ("key2", "value2")])
+            .into_iter()
+            .collect();
+    assert_eq!(engine.snapshot(), expected);
+    Ok(())
+}
+
+// Creates a thread pool for a server.
+fn pool(threads: u32) -> SharedQueueThreadPool {
+    SharedQueueThreadPool::new(threads).expect("unable to create thread pool")
+}
+
+// Starts a server on an ephemeral port, returning its address.
+fn spawn_server<E: KvsEngine + Clone + Send + 'static>(
+    server: kvs::KvsServer<E, SharedQueueThreadPool>,
+) -> Result<std::net::SocketAddr> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    std::thread::spawn(move || server.serve(listener));
+    Ok(addr)
+}
+
+// Sends a raw request to a server and reads back its response.
+fn roundtrip(stream: &mut std::net::TcpStream, request: &str) -> Result<serde_json::Value> {
+    use std::io::Write;
+    stream.write_all(request.as_bytes())?;
+    let mut de = serde_json::Deserializer::from_reader(&*stream);
+    Ok(serde::Deserialize::deserialize(&mut de)?)
+}
+
+// Connects to a server and completes the handshake of protocol version 1.
+fn connect_v1(addr: std::net::SocketAddr) -> Result<std::net::TcpStream> {
+    let mut stream = std::net::TcpStream::connect(addr)?;
+    let hello = roundtrip(&mut stream, r#"{"magic":"kvs","version":1}"#)?;
+    assert_eq!(hello, serde_json::json!({ "magic": "kvs", "version": 2 }));
+    Ok(stream)
+}
+
+// `KvsServer` should answer requests in order, telling a missing key apart from other errors.
+#[test]
+fn server_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(kvs::KvsServer::new(
+        SharedKvStore::open(temp_dir.path())?,
+        pool(4),
+    ))?;
+    let mut stream = connect_v1(addr)?;
+
+    let ok_none = serde_json::json!({ "V1": { "Ok": null } });
+    let set = r#"{"V1":{"Set":{"key":"key1","value":"value1"}}}"#;
+    assert_eq!(roundtrip(&mut stream, set)?, ok_none);
+    let get = r#"{"V1":{"Get":{"key":"key1"}}}"#;
+    assert_eq!(
+        roundtrip(&mut stream, get)?,
+        serde_json::json!({ "V1": { "Ok": "value1" } })
+    );
+    let get = r#"{"V1":{"Get":{"key":"key2"}}}"#;
+    assert_eq!(roundtrip(&mut stream, get)?, ok_none);
+    let remove = r#"{"V1":{"Remove":{"key":"key1"}}}"#;
+    assert_eq!(roundtrip(&mut stream, remove)?, ok_none);
+    assert_eq!(
+        roundtrip(&mut stream, remove)?,
+        serde_json::json!({ "V1": "KeyNotFound" })
+    );
+    Ok(())
+}
+
+// A client disconnecting mid-request should not stop `KvsServer`.
+#[test]
+fn server_client_disconnect() -> Result<()> {
+    use std::io::Write;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(kvs::KvsServer::new(
+        SharedKvStore::open(temp_dir.path())?,
+        pool(4),
+    ))?;
+
+    let mut stream = connect_v1(addr)?;
+    stream.write_all(br#"{"V1":{"Set":{"key":"key1","#)?;
+    drop(stream);
+    let mut stream = std::net::TcpStream::connect(addr)?;
+    stream.write_all(br#"{"magic":"kv"#)?;
+    drop(stream);
+    let mut stream = connect_v1(addr)?;
+    stream.write_all(b"not json")?;
+    drop(stream);
+
+    let mut stream = connect_v1(addr)?;
+    let set = r#"{"V1":{"Set":{"key":"key1","value":"value1"}}}"#;
+    assert_eq!(
+        roundtrip(&mut stream, set)?,
+        serde_json::json!({ "V1": { "Ok": null } })
+    );
+    let get = r#"{"V1":{"Get":{"key":"key1"}}}"#;
+    assert_eq!(
+        roundtrip(&mut stream, get)?,
+        serde_json::json!({ "V1": { "Ok": "value1" } })
+    );
+    Ok(())
+}
+
+// A client resetting its connection before the server accepts it should not stop
+// `KvsServer`.
+#[cfg(unix)]
+#[test]
+fn server_client_reset() -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    // Queued on the listener, then reset by closing it with a zero linger.
+    let stream = std::net::TcpStream::connect(addr)?;
+    let linger = libc::linger {
+        l_onoff: 1,
+        l_linger: 0,
+    };
+    // SAFETY: the option is a `linger` of the size given, and the socket is open.
+    let set = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_LINGER,
+            &linger as *const libc::linger as *const libc::c_void,
+            std::mem::size_of::<libc::linger>() as libc::socklen_t,
+        )
+    };
+    assert_eq!(set, 0);
+    drop(stream);
+
+    let store = SharedKvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let server = kvs::KvsServer::new(store, pool(2));
+    std::thread::spawn(move || server.serve(listener));
+    let mut client = kvs::KvsClient::connect(addr)?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// `KvsServer` should answer `Exists`, `Del`, `Keys` and `DbSize`, and answer a request
+// it does not know with an error that leaves the connection usable.
+#[test]
+fn server_key_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SharedKvStore::open(temp_dir.path())?;
+    for key in ["a1", "a2", "b1"] {
+        store.set(key.to_owned(), "value".to_owned())?;
+    }
+    let addr = spawn_server(kvs::KvsServer::new(store.clone(), pool(4)))?;
+    let mut stream = connect_v1(addr)?;
+
+    let exists = r#"{"V1":{"Exists":{"key":"a1"}}}"#;
+    assert_eq!(
+        roundtrip(&mut stream, exists)?,
+        serde_json::json!({ "V1": { "Bool": true } })
+    );
+    let exists = r#"{"V1":{"Exists":{"key":"c1"}}}"#;
+    assert_eq!(
+        roundtrip(&mut stream, exists)?,
+        serde_json::json!({ "V1": { "Bool": false } })
+    );
+    let keys = r#"{"V1":{"Keys":{"prefix":"a"}}}"#;
+    assert_eq!(
+        roundtrip(&mut stream, keys)?,
+        serde_json::json!({ "V1": { "Keys": { "keys": ["a1", "a2"], "cursor": "" } } })
+    );
+    assert_eq!(
+        roundtrip(&mut stream, r#"{"V1":"DbSize"}"#)?,
+        serde_json::json!({ "V1": { "Count": 3 } })
+    );
+    // Only the keys that exist count, once each.
+    let del = r#"{"V1":{"Del":{"keys":["a1","c1","b1","a1"]}}}"#;
+    assert_eq!(
+        roundtrip(&mut stream, del)?,
+        serde_json::json!({ "V1": { "Count": 2 } })
+    );
+    assert_eq!(store.keys(), ["a2"]);
+
+    // A request from a newer client, with fields or without.
+    let unknown = r#"{"V1":{"Frobnicate":{"key":"a2"}}}"#;
+    assert_eq!(
+        roundtrip(&mut stream, unknown)?,
+        serde_json::json!({ "V1": { "Unsupported": "Frobnicate" } })
+    );
+    assert_eq!(
+        roundtrip(&mut stream, r#"{"V1":"Flush"}"#)?,
+        serde_json::json!({ "V1": { "Unsupported": "Flush" } })
+    );
+    assert_eq!(
+        roundtrip(&mut stream, r#"{"V1":"DbSize"}"#)?,
+        serde_json::json!({ "V1": { "Count": 1 } })
+    );
+
+    // Listing more keys than the server answers with at once fails.
+    for i in 0..10_000 {
+        store.set(format!("many{}", i), "value".to_owned())?;
+    }
+    let response = roundtrip(&mut stream, r#"{"V1":{"Keys":{"prefix":null}}}"#)?;
+    assert!(response["V1"]["Err"].as_str().unwrap().contains("10000"));
+
+    let mut client = kvs::KvsClient::connect(addr)?;
+    assert!(client.exists("a2".to_owned())?);
+    assert!(!client.exists("a1".to_owned())?);
+    assert_eq!(client.keys("a")?, ["a2"]);
+    assert_eq!(client.dbsize()?, 10_001);
+    assert!(matches!(client.keys(""), Err(KvsError::Server(_))));
+    assert_eq!(client.keys("many")?.len(), 10_000);
+    assert_eq!(client.del(vec!["a2".to_owned(), "a1".to_owned()])?, 1);
+    Ok(())
+}
+
+// Client and server without a common protocol version should both hang up with a clear error.
+#[test]
+fn protocol_version_mismatch() -> Result<()> {
+    use std::io::Read;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(kvs::KvsServer::new(
+        SharedKvStore::open(temp_dir.path())?,
+        pool(4),
+    ))?;
+
+    // The server tells a client it cannot serve which version it speaks before hanging up.
+    let mut stream = std::net::TcpStream::connect(addr)?;
+    let hello = roundtrip(&mut stream, r#"{"magic":"kvs","version":0}"#)?;
+    assert_eq!(hello, serde_json::json!({ "magic": "kvs", "version": 2 }));
+    assert_eq!(stream.read(&mut [0; 1])?, 0);
+
+    // A client from before the handshake gets an error response it can show.
+    let mut stream = std::net::TcpStream::connect(addr)?;
+    let response = roundtrip(&mut stream, r#"{"Get":{"key":"key1"}}"#)?;
+    assert!(response["Err"].as_str().unwrap().contains("handshake"));
+
+    // The client refuses a server it cannot talk to.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let fake_addr = listener.local_addr()?;
+    let fake_server = std::thread::spawn(move || -> Result<()> {
+        let (mut stream, _) = listener.accept()?;
+        let hello = roundtrip(&mut stream, r#"{"magic":"kvs","version":0}"#)?;
+        assert_eq!(hello, serde_json::json!({ "magic": "kvs", "version": 2 }));
+        Ok(())
+    });
+    match kvs::KvsClient::connect(fake_addr) {
+        Err(KvsError::ProtocolMismatch { client, server }) => {
+            assert_eq!((client, server), (2, 0));
+        }
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("connected to an incompatible server"),
+    }
+    fake_server.join().unwrap()?;
+    Ok(())
+}
+
+// `KvsClient` should round-trip every command through a server.
+#[test]
+fn client_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(kvs::KvsServer::new(
+        SharedKvStore::open(temp_dir.path())?,
+        pool(4),
+    ))?;
+    let mut client = kvs::KvsClient::connect(addr)?;
+
+    client.set("key1".to_owned(), "line1\nline2".to_owned())?;
+    client.set("ключ".to_owned(), "値 🦀".to_owned())?;
+    assert_eq!(
+        client.get("key1".to_owned())?,
+        Some("line1\nline2".to_owned())
+    );
+    assert_eq!(client.get("ключ".to_owned())?, Some("値 🦀".to_owned()));
+    assert_eq!(client.get("key2".to_owned())?, None);
+    client.remove("key1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    assert!(matches!(
+        client.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    Ok(())
+}
+
+// `KvsClient::scan` should follow the cursors of the server through a store mutated
+// meanwhile, listing every key present the whole time exactly once.
+#[test]
+fn client_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SharedKvStore::open(temp_dir.path())?;
+    for i in 0..10_000 {
+        store.set(format!("key{:05}", i), "value".to_owned())?;
+    }
+    store.set("other".to_owned(), "value".to_owned())?;
+    let addr = spawn_server(kvs::KvsServer::new(store.clone(), pool(4)))?;
+
+    // Every third key is removed and a key is added next to it while the scan runs.
+    let writer = std::thread::spawn(move || -> Result<()> {
+        for i in (0..10_000).step_by(3) {
+            store.remove(format!("key{:05}", i))?;
+            store.set(format!("key{:05}+", i), "value".to_owned())?;
+        }
+        Ok(())
+    });
+    let mut client = kvs::KvsClient::connect(addr)?;
+    let keys = client.scan("key").collect::<Result<Vec<String>>>()?;
+    writer.join().unwrap()?;
+    assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+    assert!(keys.iter().all(|key| key.starts_with("key")));
+    let keys: std::collections::HashSet<String> = keys.into_iter().collect();
+    for i in (0..10_000).filter(|i| i % 3 != 0) {
+        assert!(keys.contains(&format!("key{:05}", i)));
+    }
+    assert_eq!(client.scan("").count(), 10_001);
+    assert_eq!(client.scan("nothing").count(), 0);
+
+    // A batch holds as many keys as asked for, with the cursor to go on from.
+    let mut stream = connect_v1(addr)?;
+    let scan = r#"{"V1":{"Scan":{"cursor":"","prefix":"key","count":100}}}"#;
+    let response = roundtrip(&mut stream, scan)?;
+    assert_eq!(
+        response["V1"]["Keys"]["keys"].as_array().unwrap().len(),
+        100
+    );
+    assert!(!response["V1"]["Keys"]["cursor"]
+        .as_str()
+        .unwrap()
+        .is_empty());
+    let scan = r#"{"V1":{"Scan":{"cursor":"!","prefix":null,"count":100}}}"#;
+    let response = roundtrip(&mut stream, scan)?;
+    assert!(response["V1"]["Err"].as_str().unwrap().contains("cursor"));
+    Ok(())
+}
+
+// Connecting to an address nobody listens on should fail naming the address.
+#[test]
+fn client_connection_refused() -> Result<()> {
+    // Bind and release a port, so nothing is likely to listen on it.
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let err = kvs::KvsClient::connect(addr).err().unwrap();
+    assert!(matches!(err, KvsError::ConnectionRefused(_)));
+    assert!(err.to_string().contains(&addr.to_string()));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", &addr.to_string()])
+        .assert()
+        .failure()
+        .stderr(contains(addr.to_string()));
+    Ok(())
+}
+
+// Starts a proxy on an ephemeral port forwarding connections to a server, delaying
+// every byte by the given time each way like a network, and returns its address.
+fn spawn_delaying_proxy(
+    server: std::net::SocketAddr,
+    delay: std::time::Duration,
+) -> Result<std::net::SocketAddr> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    std::thread::spawn(move || -> Result<()> {
+        for client in listener.incoming() {
+            let client = client?;
+            let upstream = std::net::TcpStream::connect(server)?;
+            for stream in [&client, &upstream] {
+                stream.set_nodelay(true)?;
+            }
+            delay_copy(client.try_clone()?, upstream.try_clone()?, delay);
+            delay_copy(upstream, client, delay);
+        }
+        Ok(())
+    });
+    Ok(addr)
+}
+
+// Copies what one stream reads to another, each chunk once it is `delay` old.
+fn delay_copy(
+    mut from: std::net::TcpStream,
+    mut to: std::net::TcpStream,
+    delay: std::time::Duration,
+) {
+    use std::io::{Read, Write};
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let mut buf = [0; 64 * 1024];
+        while let Ok(n @ 1..) = from.read(&mut buf) {
+            let due = std::time::Instant::now() + delay;
+            if sender.send((due, buf[..n].to_vec())).is_err() {
+                return;
+            }
+        }
+    });
+    std::thread::spawn(move || {
+        for (due, bytes) in receiver {
+            std::thread::sleep(due.saturating_duration_since(std::time::Instant::now()));
+            if to.write_all(&bytes).is_err() {
+                return;
+            }
+        }
+        let _ = to.shutdown(std::net::Shutdown::Write);
+    });
+}
+
+// A `KvsClient` pipeline should get the results of its requests in order, each failing
+// on its own, and beat sending the requests one at a time. The server should answer
+// requests split across reads or sent together, with the ID of each.
+#[test]
+fn client_pipeline() -> Result<()> {
+    use std::io::Write;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(kvs::KvsServer::new(
+        SharedKvStore::open(temp_dir.path())?,
+        pool(4),
+    ))?;
+    let mut client = kvs::KvsClient::connect(addr)?;
+    let mut pipeline = client.pipeline();
+    pipeline
+        .set("key1".to_owned(), "value1".to_owned())
+        .get("key1".to_owned())
+        .remove("key2".to_owned())
+        .get("key2".to_owned())
+        .remove("key1".to_owned());
+    assert_eq!(pipeline.len(), 5);
+    let results = pipeline.execute()?;
+    assert_eq!(results.len(), 5);
+    assert!(matches!(results[0], Ok(None)));
+    assert_eq!(results[1].as_ref().ok(), Some(&Some("value1".to_owned())));
+    assert!(matches!(results[2], Err(KvsError::KeyNotFound)));
+    assert!(matches!(results[3], Ok(None)));
+    assert!(matches!(results[4], Ok(None)));
+    assert!(client.pipeline().execute()?.is_empty());
+
+    for i in 0..1000 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    // Over a network, where round trips take longer than handling a request.
+    let proxy = spawn_delaying_proxy(addr, std::time::Duration::from_micros(200))?;
+    let mut client = kvs::KvsClient::connect(proxy)?;
+    let started = std::time::Instant::now();
+    for i in 0..1000 {
+        assert_eq!(
+            client.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    let sequential = started.elapsed();
+    let started = std::time::Instant::now();
+    let mut pipeline = client.pipeline();
+    for i in 0..1000 {
+        pipeline.get(format!("key{}", i));
+    }
+    let results = pipeline.execute()?;
+    let pipelined = started.elapsed();
+    for (i, result) in results.into_iter().enumerate() {
+        assert_eq!(result?, Some(format!("value{}", i)));
+    }
+    assert!(
+        pipelined * 4 < sequential,
+        "{:?} pipelined against {:?} sequential",
+        pipelined,
+        sequential
+    );
+
+    // Raw version 2 requests, one split across two writes and two sent together.
+    let mut stream = std::net::TcpStream::connect(addr)?;
+    let hello = roundtrip(&mut stream, r#"{"magic":"kvs","version":2}"#)?;
+    assert_eq!(hello, serde_json::json!({ "magic": "kvs", "version": 2 }));
+    stream.write_all(br#"{"V2":{"id":7,"message":{"Get":"#)?;
+    stream.flush()?;
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    let response = roundtrip(&mut stream, r#"{"key":"key1"}}}}"#)?;
+    assert_eq!(
+        response,
+        serde_json::json!({ "V2": { "id": 7, "message": { "Ok": "value1" } } })
+    );
+    stream.write_all(br#"{"V2":{"id":8,"message":{"Get":{"key":"key2"}}}}"#)?;
+    let response = roundtrip(&mut stream, "\n{\"V2\":{\"id\":9,\"message\":\"DbSize\"}}")?;
+    assert_eq!(
+        response,
+        serde_json::json!({ "V2": { "id": 8, "message": { "Ok": "value2" } } })
+    );
+    let mut de = serde_json::Deserializer::from_reader(&stream);
+    let response: serde_json::Value = serde::Deserialize::deserialize(&mut de)?;
+    assert_eq!(
+        response,
+        serde_json::json!({ "V2": { "id": 9, "message": { "Count": 1000 } } })
+    );
+    Ok(())
+}
+
+// Starts a fake server on an ephemeral port closing every connection after answering
+// one request, which fails for the key `fail`, and returns its address and the number
+// of connections it accepted.
+fn spawn_one_request_server() -> Result<(
+    std::net::SocketAddr,
+    std::sync::Arc<std::sync::atomic::AtomicUsize>,
+)> {
+    use std::io::Write;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&accepted);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let answered = stream.and_then(|mut stream| {
+                let mut messages = serde_json::Deserializer::from_reader(stream.try_clone()?)
+                    .into_iter::<serde_json::Value>();
+                messages.next();
+                stream.write_all(br#"{"magic":"kvs","version":1}"#)?;
+                let request = messages.next().and_then(|request| request.ok());
+                let response = match request {
+                    Some(request) if request["V1"]["Get"]["key"] == "fail" => {
+                        r#"{"V1":{"Err":"failed"}}"#
+                    }
+                    _ => r#"{"V1":{"Ok":"value1"}}"#,
+                };
+                stream.write_all(response.as_bytes())
+            });
+            if answered.is_err() {
+                return;
+            }
+        }
+    });
+    Ok((addr, accepted))
+}
+
+// `KvsClient` should time out on a server that does not answer, tell a refused
+// connection and an error of the server apart, and retry reads on a new connection
+// once the server closes one, but writes only if asked to.
+#[test]
+fn client_timeouts_and_retries() -> Result<()> {
+    use std::sync::atomic::Ordering;
+    use std::time::{Duration, Instant};
+    let timeout = Duration::from_millis(200);
+
+    // A server accepting connections without ever answering.
+    let stalled = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = stalled.local_addr()?;
+    let started = Instant::now();
+    match kvs::KvsClient::builder(addr).timeout(timeout).connect() {
+        Err(KvsError::TimedOut(timed_out)) => assert_eq!(timed_out, addr.to_string()),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("connected to a server that does not answer"),
+    }
+    assert!(started.elapsed() < Duration::from_secs(2));
+    let started = Instant::now();
+    let connected = kvs::KvsClient::builder(addr)
+        .timeout(timeout)
+        .retries(2)
+        .connect();
+    assert!(matches!(connected, Err(KvsError::TimedOut(_))));
+    assert!(started.elapsed() >= timeout * 3);
+
+    let refused = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+    let connected = kvs::KvsClient::builder(refused).retries(1).connect();
+    assert!(matches!(connected, Err(KvsError::ConnectionRefused(_))));
+
+    // Without retries, a request on a closed connection fails, and the next one
+    // connects again.
+    let (addr, accepted) = spawn_one_request_server()?;
+    let mut client = kvs::KvsClient::builder(addr).timeout(timeout).connect()?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        client.get("key1".to_owned()),
+        Err(KvsError::Io(_))
+    ));
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(accepted.load(Ordering::SeqCst), 2);
+
+    let mut client = kvs::KvsClient::builder(addr)
+        .timeout(timeout)
+        .retries(1)
+        .connect()?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    // Retried too, and answered like a `Get` by the fake server.
+    assert!(matches!(
+        client.exists("key1".to_owned()),
+        Err(KvsError::Protocol(_))
+    ));
+    let set = client.set("key1".to_owned(), "value1".to_owned());
+    assert!(matches!(set, Err(KvsError::Io(_))));
+    // An error answered by the server is not retried.
+    let before = accepted.load(Ordering::SeqCst);
+    match client.get("fail".to_owned()) {
+        Err(KvsError::Server(message)) => assert_eq!(message, "failed"),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("the request did not fail"),
+    }
+    assert_eq!(accepted.load(Ordering::SeqCst), before + 1);
+
+    let mut client = kvs::KvsClient::builder(addr)
+        .timeout(timeout)
+        .retries(1)
+        .retry_writes(true)
+        .connect()?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    Ok(())
+}
+
+// A `SharedKvStore` counting its clones, which a server makes one of per connection.
+struct CountingEngine {
+    store: SharedKvStore,
+    clones: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+}
+
+impl Clone for CountingEngine {
+    fn clone(&self) -> Self {
+        self.clones
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        Self {
+            store: self.store.clone(),
+            clones: self.clones.clone(),
+        }
+    }
+}
+
+impl KvsEngine for CountingEngine {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.store.set(key, value)
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.store.get(key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.store.remove(key)
+    }
+
+    fn keys(&mut self) -> Result<Vec<String>> {
+        Ok(self.store.keys())
+    }
+}
+
+// `KvsClientPool` should share no more connections than it is allowed between more
+// threads than that, replacing the broken ones and closing the idle ones.
+#[test]
+fn client_pool() -> Result<()> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let accepted = std::sync::Arc::new(AtomicUsize::new(0));
+    let engine = CountingEngine {
+        store: SharedKvStore::open(temp_dir.path())?,
+        clones: accepted.clone(),
+    };
+    let addr = spawn_server(kvs::KvsServer::new(engine, pool(8)))?;
+    let clients = kvs::KvsClient::builder(addr).pool(4).build()?;
+    let threads: Vec<_> = (0..16)
+        .map(|i| {
+            let clients = clients.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for j in 0..50 {
+                    let key = format!("key{}-{}", i, j);
+                    let mut client = clients.get()?;
+                    client.set(key.clone(), j.to_string())?;
+                    assert_eq!(client.get(key)?, Some(j.to_string()));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap()?;
+    }
+    assert_eq!(clients.connections(), 4);
+    assert_eq!(clients.idle_connections(), 4);
+    assert_eq!(accepted.load(Ordering::SeqCst), 4);
+    let mut client = clients.get()?;
+    assert_eq!(client.dbsize()?, 16 * 50);
+    drop(client);
+
+    // An exhausted pool waits only as long as it is told to.
+    let clients = kvs::KvsClient::builder(addr)
+        .pool(1)
+        .when_exhausted(kvs::Exhausted::Wait(Duration::from_millis(50)))
+        .build()?;
+    let client = clients.get()?;
+    assert!(matches!(clients.get(), Err(KvsError::PoolExhausted(1))));
+    drop(client);
+    // The connection given back is the one checked out again.
+    let _client = clients.get()?;
+    assert_eq!(accepted.load(Ordering::SeqCst), 5);
+
+    // A client whose connection the server closed is replaced.
+    let (closing, closing_accepted) = spawn_one_request_server()?;
+    let clients = kvs::KvsClient::builder(closing).pool(1).build()?;
+    for _ in 0..3 {
+        let mut client = clients.get()?;
+        assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+        // Give the server time to close the connection.
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(clients.connections(), 0);
+    assert_eq!(closing_accepted.load(Ordering::SeqCst), 3);
+
+    // Idle connections are closed after the timeout, down to the minimum.
+    let clients = kvs::KvsClient::builder(addr)
+        .pool(4)
+        .min_idle(1)
+        .idle_timeout(Some(Duration::from_millis(100)))
+        .build()?;
+    let held: Vec<_> = (0..3).map(|_| clients.get()).collect::<Result<_>>()?;
+    assert_eq!(clients.connections(), 3);
+    drop(held);
+    std::thread::sleep(Duration::from_millis(1500));
+    assert_eq!(clients.connections(), 1);
+    Ok(())
+}
+
+// `kvs-client` should work like `kvs` against a server.
+#[test]
+fn cli_client() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(kvs::KvsServer::new(
+        SharedKvStore::open(temp_dir.path())?,
+        pool(4),
+    ))?
+    .to_string();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", &addr])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["--addr", &addr, "get", "key1"])
+        .assert()
+        .success()
+        .stdout(eq("value1").trim());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", &addr])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", &addr])
+        .assert()
+        .success()
+        .stdout(eq("Key not found").trim());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", &addr])
+        .assert()
+        .failure()
+        .stdout(eq("Key not found").trim());
+    Ok(())
+}
+
+// Starts `kvs-server` with the given arguments, returning it once it logs that it is
+// serving, along with that line.
+fn start_kvs_server(args: &[&str]) -> (std::process::Child, String) {
+    use std::io::BufRead;
+    let mut child = std::process::Command::new(assert_cmd::cargo::cargo_bin("kvs-server"))
+        .args(args)
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .expect("unable to start kvs-server");
+    let mut stderr = std::io::BufReader::new(child.stderr.take().unwrap());
+    let mut line = String::new();
+    stderr
+        .read_line(&mut line)
+        .expect("unable to read kvs-server output");
+    assert!(
+        line.contains("serving"),
+        "kvs-server did not start: {}",
+        line
+    );
+    (child, line)
+}
+
+// `kvs-server` should serve the engine a store directory was created with, refusing
+// another one, and fail before serving on an address it cannot use.
+#[test]
+fn server_startup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path().to_str().unwrap();
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")?
+        .local_addr()?
+        .to_string();
+    let (mut server, line) = start_kvs_server(&["--addr", &addr, "--engine", "kvs", "--dir", dir]);
+    assert!(line.contains(env!("CARGO_PKG_VERSION")));
+    assert!(line.contains("kvs engine"));
+    assert!(line.contains(&addr));
+    kvs::KvsClient::connect(&*addr)?.set("key1".to_owned(), "value1".to_owned())?;
+
+    // A second server on the same port exits with an error.
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", &addr, "--dir", other_dir.path().to_str().unwrap()])
+        .assert()
+        .failure()
+        .stderr(contains(format!("Cannot listen on {}", addr)));
+    server.kill()?;
+    server.wait()?;
+
+    // The store directory keeps the engine it was created with.
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", &addr, "--engine", "sled", "--dir", dir])
+        .assert()
+        .failure()
+        .stderr(contains(
+            "belongs to the kvs engine, not sled, start it with --engine kvs",
+        ));
+    let (mut server, line) = start_kvs_server(&["--addr", &addr, "--dir", dir]);
+    assert!(line.contains("kvs engine"));
+    assert_eq!(
+        kvs::KvsClient::connect(&*addr)?.get("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    server.kill()?;
+    server.wait()?;
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "localhost", "--dir", dir])
+        .assert()
+        .failure()
+        .stderr(contains("IP address and a port"));
+    for grace_period in ["-1", "inf", "1e300"] {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", &addr, "--dir", dir])
+            .arg(format!("--grace-period={}", grace_period))
+            .assert()
+            .failure()
+            .stderr(contains("must be a number of seconds"));
+    }
+    let file = temp_dir.path().join("file");
+    std::fs::write(&file, "not a directory")?;
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--addr",
+            &addr,
+            "--dir",
+            file.join("store").to_str().unwrap(),
+        ])
+        .assert()
+        .failure()
+        .stderr(contains("Cannot open the store"));
+    Ok(())
+}
+
+// An engine taking its time to answer a `get` of the key `slow`.
+#[derive(Clone)]
+struct SlowEngine;
+
+impl KvsEngine for SlowEngine {
+    fn set(&mut self, _: String, _: String) -> Result<()> {
+        Ok(())
+    }
+
+    fn get(&mut self, key: String) -> Result<Option<String>> {
+        if key == "slow" {
+            std::thread::sleep(std::time::Duration::from_secs(2));
+        }
+        Ok(None)
+    }
+
+    fn remove(&mut self, _: String) -> Result<()> {
+        Err(KvsError::KeyNotFound)
+    }
+
+    fn keys(&mut self) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+}
+
+// Shutting `KvsServer` down should let the requests in flight finish and leave the store
+// clean, and answer the ones still running after the grace period with an error.
+#[test]
+fn server_shutdown() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = kvs::KvsServer::new(SharedKvStore::open(temp_dir.path())?, pool(4));
+    let shutdown = server.shutdown_handle();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let serving = std::thread::spawn(move || server.serve(listener));
+    let clients: Vec<_> = (0..4)
+        .map(|i| {
+            std::thread::spawn(move || {
+                let mut acked = Vec::new();
+                let mut client = kvs::KvsClient::connect(addr).unwrap();
+                for j in 0.. {
+                    let key = format!("key{}-{}", i, j);
+                    match client.set(key.clone(), "value".to_owned()) {
+                        Ok(()) => acked.push(key),
+                        Err(_) => break,
+                    }
+                }
+                acked
+            })
+        })
+        .collect();
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    shutdown.shutdown();
+    serving.join().unwrap()?;
+    let acked: Vec<String> = clients
+        .into_iter()
+        .flat_map(|client| client.join().unwrap())
+        .collect();
+    assert!(!acked.is_empty());
+    assert!(std::net::TcpStream::connect(addr).is_err());
+    // Every clone of the store is dropped, so it opens again.
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.verify()?.is_ok());
+    for key in acked {
+        assert_eq!(store.get(key)?, Some("value".to_owned()));
+    }
+
+    // A request outlasting the grace period.
+    let server = kvs::KvsServer::new(SlowEngine, pool(2))
+        .grace_period(std::time::Duration::from_millis(100));
+    let shutdown = server.shutdown_handle();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let serving = std::thread::spawn(move || server.serve(listener));
+    let mut stream = connect_v1(addr)?;
+    let get = r#"{"V1":{"Get":{"key":"slow"}}}"#;
+    let answered = std::thread::spawn(move || roundtrip(&mut stream, get).unwrap());
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    let started = std::time::Instant::now();
+    shutdown.shutdown();
+    serving.join().unwrap()?;
+    assert!(started.elapsed() < std::time::Duration::from_secs(1));
+    assert_eq!(
+        answered.join().unwrap(),
+        serde_json::json!({ "V1": { "Err": "The server is shutting down" } })
+    );
+    Ok(())
+}
+
+// `kvs-server` should shut down gracefully on SIGTERM, exiting successfully.
+#[cfg(unix)]
+#[test]
+fn server_stop_signal() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dir = temp_dir.path().to_str().unwrap();
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")?
+        .local_addr()?
+        .to_string();
+    let (mut server, _) = start_kvs_server(&["--addr", &addr, "--dir", dir]);
+    let mut client = kvs::KvsClient::connect(&*addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let killed = Command::new("kill")
+        .args(["-TERM", &server.id().to_string()])
+        .status()?;
+    assert!(killed.success());
+    assert!(server.wait()?.success());
+    // The connection left open is closed by the server.
+    assert!(client.get("key1".to_owned()).is_err());
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(store.verify()?.is_ok());
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Starts a RESP server for a new store, returning the store directory and server address.
+fn spawn_resp_server() -> (TempDir, std::net::SocketAddr) {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SharedKvStore::open(temp_dir.path()).unwrap();
+    let server = kvs::KvsServer::new(store, pool(4)).protocol(kvs::Protocol::Resp);
+    (temp_dir, spawn_server(server).unwrap())
+}
+
+// A RESP server should answer a Redis client.
+#[test]
+fn resp_redis_client() -> redis::RedisResult<()> {
+    use redis::Commands;
+    let (_temp_dir, addr) = spawn_resp_server();
+    let client = redis::Client::open(format!("redis://{}/", addr))?;
+    let mut con = client.get_connection()?;
+
+    let pong: String = redis::cmd("PING").query(&mut con)?;
+    assert_eq!(pong, "PONG");
+    let () = con.set("key1", "line1\r\nline2")?;
+    let () = con.set("ключ", "値 🦀")?;
+    let value: Option<String> = con.get("key1")?;
+    assert_eq!(value.as_deref(), Some("line1\r\nline2"));
+    let value: Option<String> = con.get("ключ")?;
+    assert_eq!(value.as_deref(), Some("値 🦀"));
+    let value: Option<String> = con.get("key2")?;
+    assert_eq!(value, None);
+    let exists: u64 = con.exists(&["key1", "key2", "ключ"])?;
+    assert_eq!(exists, 2);
+    let removed: u64 = con.del(&["key1", "key2"])?;
+    assert_eq!(removed, 1);
+    let exists: bool = con.exists("key1")?;
+    assert!(!exists);
+
+    let err = redis::cmd("FLUSHALL").query::<()>(&mut con).unwrap_err();
+    assert!(err.to_string().contains("unknown command"));
+    let err = redis::cmd("GET").query::<()>(&mut con).unwrap_err();
+    assert!(err.to_string().contains("wrong number of arguments"));
+    // The connection is still usable after errors.
+    let value: Option<String> = con.get("ключ")?;
+    assert_eq!(value.as_deref(), Some("値 🦀"));
+    Ok(())
+}
+
+// A RESP server should set and get values of any bytes, but only UTF-8 keys, and only
+// UTF-8 values on an engine storing strings.
+#[test]
+fn resp_binary_values() -> redis::RedisResult<()> {
+    use redis::Commands;
+    let (_temp_dir, addr) = spawn_resp_server();
+    let client = redis::Client::open(format!("redis://{}/", addr))?;
+    let mut con = client.get_connection()?;
+
+    let binary = vec![0u8, 0xff, 0xfe, b'\r', b'\n', 0xc3, 0x28];
+    let () = con.set("key1", &binary)?;
+    let value: Option<Vec<u8>> = con.get("key1")?;
+    assert_eq!(value, Some(binary.clone()));
+    let () = con.set("key2", "text")?;
+    let value: Option<String> = con.get("key2")?;
+    assert_eq!(value.as_deref(), Some("text"));
+    let err = con
+        .set::<_, _, ()>(&[0xffu8, 0xfe][..], "value")
+        .unwrap_err();
+    assert!(err.to_string().contains("keys must be UTF-8"));
+    drop(con);
+
+    // An engine storing strings takes UTF-8 values only.
+    let server =
+        kvs::KvsServer::new(kvs::MemKvsEngine::new(), pool(2)).protocol(kvs::Protocol::Resp);
+    let addr = spawn_server(server).unwrap();
+    let client = redis::Client::open(format!("redis://{}/", addr))?;
+    let mut con = client.get_connection()?;
+    let err = con.set::<_, _, ()>("key1", &binary).unwrap_err();
+    assert!(err.to_string().contains("UTF-8"));
+    let () = con.set("key2", "text")?;
+    Ok(())
+}
+
+// An engine whose reads fail, as if its disk were gone.
+#[derive(Clone)]
+struct BrokenEngine;
+
+impl KvsEngine for BrokenEngine {
+    fn set(&mut self, _: String, _: String) -> Result<()> {
+        Ok(())
+    }
+
+    fn get(&mut self, _: String) -> Result<Option<String>> {
+        Err(std::io::Error::other("disk gone").into())
+    }
+
+    fn remove(&mut self, _: String) -> Result<()> {
+        Err(KvsError::KeyNotFound)
+    }
+
+    fn keys(&mut self) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+}
+
+// `PING` should be answered without touching the engine, and `HEALTH` should report a
+// working engine as ok and a failing one as degraded, with `kvs-client ping` exiting
+// accordingly.
+#[test]
+fn server_ping_health() -> Result<()> {
+    use kvs::Health;
+    use predicates::prelude::PredicateBooleanExt;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SharedKvStore::open(temp_dir.path())?;
+    let addr = spawn_server(kvs::KvsServer::new(store.clone(), pool(4)))?;
+    // Each open connection holds a thread of the server.
+    let mut client = kvs::KvsClient::connect(addr)?;
+    client.ping()?;
+    assert_eq!(client.health()?, Health::Ok);
+    // The health check writes nothing, and its key is not one a client sees.
+    assert!(store.is_empty());
+    assert_eq!(client.keys("")?, Vec::<String>::new());
+    let mut stream = connect_v1(addr)?;
+    assert_eq!(
+        roundtrip(&mut stream, r#"{"V1":"Ping"}"#)?,
+        serde_json::json!({ "V1": "Pong" })
+    );
+    assert_eq!(
+        roundtrip(&mut stream, r#"{"V1":"Health"}"#)?,
+        serde_json::json!({ "V1": { "Health": "Ok" } })
+    );
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ping", "--addr", &addr.to_string()])
+        .assert()
+        .success()
+        .stdout(eq("PONG").trim());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ping", "--health", "--addr", &addr.to_string()])
+        .assert()
+        .success()
+        .stdout(eq("ok").trim());
+
+    let broken = spawn_server(kvs::KvsServer::new(BrokenEngine, pool(4)))?;
+    let mut client = kvs::KvsClient::connect(broken)?;
+    client.ping()?;
+    match client.health()? {
+        Health::Degraded(reason) => assert!(reason.contains("disk gone"), "{}", reason),
+        health => panic!("expected a degraded engine, got {:?}", health),
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ping", "--addr", &broken.to_string()])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ping", "--health", "--addr", &broken.to_string()])
+        .assert()
+        .failure()
+        .stdout(contains("degraded").and(contains("disk gone")));
+
+    // Nothing listening fails the ping.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let closed = listener.local_addr()?;
+    drop(listener);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["ping", "--addr", &closed.to_string()])
+        .assert()
+        .failure();
+    Ok(())
+}
+
+// A server should answer `PING` and `HEALTH` while a long compaction of its store runs.
+#[test]
+fn server_ping_during_compaction() -> Result<()> {
+    use kvs::Health;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder(temp_dir.path())
+        .compaction_threshold(u64::MAX)
+        .open_shared()?;
+    let value = "v".repeat(16 * 1024);
+    for round in 0..2 {
+        for i in 0..1_000 {
+            store.set(format!("key{}", i), format!("{}{}", value, round))?;
+        }
+    }
+    let addr = spawn_server(kvs::KvsServer::new(store.clone(), pool(2)))?;
+    let mut client = kvs::KvsClient::connect(addr)?;
+    client.ping()?;
+
+    let compacting = Arc::new(AtomicBool::new(true));
+    let answered = Arc::new(AtomicUsize::new(0));
+    let pinging = {
+        let (compacting, answered) = (Arc::clone(&compacting), Arc::clone(&answered));
+        std::thread::spawn(move || -> Result<()> {
+            while compacting.load(Ordering::SeqCst) {
+                client.ping()?;
+                assert_eq!(client.health()?, Health::Ok);
+                if compacting.load(Ordering::SeqCst) {
+                    answered.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+            Ok(())
+        })
+    };
+    let reclaimed = store.compact()?;
+    compacting.store(false, Ordering::SeqCst);
+    pinging.join().unwrap()?;
+    assert!(reclaimed > 0);
+    assert!(
+        answered.load(Ordering::SeqCst) > 0,
+        "no ping was answered during the compaction"
+    );
+    Ok(())
+}
+
+// A server requiring a token should serve a client sending it, and reject a wrong
+// token, a missing one, and any request before the token, after a delay.
+#[test]
+fn server_auth_token() -> Result<()> {
+    use std::time::{Duration, Instant};
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SharedKvStore::open(temp_dir.path())?;
+    let server = kvs::KvsServer::new(store, pool(4)).auth_token("s3cret".to_owned());
+    let addr = spawn_server(server)?;
+
+    let mut client = kvs::KvsClient::builder(addr)
+        .auth_token("s3cret".to_owned())
+        .connect()?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.ping()?;
+    drop(client);
+
+    let start = Instant::now();
+    for wrong in ["s3cre", "s3cret!", "S3CRET"] {
+        let connected = kvs::KvsClient::builder(addr)
+            .auth_token(wrong.to_owned())
+            .connect();
+        assert!(matches!(connected, Err(KvsError::AuthFailed)), "{}", wrong);
+    }
+    assert!(start.elapsed() >= Duration::from_secs(3));
+
+    let mut client = kvs::KvsClient::connect(addr)?;
+    assert!(matches!(
+        client.get("key1".to_owned()),
+        Err(KvsError::AuthRequired)
+    ));
+    drop(client);
+
+    // A request before the token is answered with an error, and the connection closed.
+    let mut stream = connect_v1(addr)?;
+    assert_eq!(
+        roundtrip(&mut stream, r#"{"V1":{"Get":{"key":"key1"}}}"#)?,
+        serde_json::json!({ "V1": "AuthRequired" })
+    );
+    assert!(roundtrip(&mut stream, r#"{"V1":"Ping"}"#).is_err());
+    let mut stream = connect_v1(addr)?;
+    assert_eq!(
+        roundtrip(&mut stream, r#"{"V1":{"Auth":{"token":"s3cret"}}}"#)?,
+        serde_json::json!({ "V1": { "Ok": null } })
+    );
+    assert_eq!(
+        roundtrip(&mut stream, r#"{"V1":{"Get":{"key":"key1"}}}"#)?,
+        serde_json::json!({ "V1": { "Ok": "value1" } })
+    );
+    Ok(())
+}
+
+// A RESP server requiring a token should take it with `AUTH`, like Redis.
+#[test]
+fn resp_auth_token() -> redis::RedisResult<()> {
+    use redis::Commands;
+    use std::io::{Read, Write};
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SharedKvStore::open(temp_dir.path()).unwrap();
+    let server = kvs::KvsServer::new(store, pool(4))
+        .protocol(kvs::Protocol::Resp)
+        .auth_token("s3cret".to_owned());
+    let addr = spawn_server(server).unwrap();
+
+    let client = redis::Client::open(format!("redis://:s3cret@{}/", addr))?;
+    let mut con = client.get_connection()?;
+    let () = con.set("key1", "value1")?;
+    let value: Option<String> = con.get("key1")?;
+    assert_eq!(value.as_deref(), Some("value1"));
+
+    let client = redis::Client::open(format!("redis://:wrong@{}/", addr))?;
+    assert!(client.get_connection().is_err());
+    // Each rejection is the last reply before the connection closes.
+    for (command, rejection) in [
+        (
+            "*2\r\n$4\r\nAUTH\r\n$5\r\nwrong\r\n",
+            "-WRONGPASS invalid auth token\r\n",
+        ),
+        (
+            "*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n",
+            "-NOAUTH Authentication required\r\n",
+        ),
+    ] {
+        let mut stream = std::net::TcpStream::connect(addr)?;
+        stream.write_all(command.as_bytes())?;
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply)?;
+        assert_eq!(reply, rejection);
+    }
+    Ok(())
+}
+
+// `kvs-server --auth-token-file` should require the token, which `kvs-client` reads
+// from `KVS_AUTH_TOKEN`.
+#[test]
+fn cli_auth_token() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let token_file = temp_dir.path().join("token");
+    std::fs::write(&token_file, "s3cret\n")?;
+    let store_dir = temp_dir.path().join("store");
+    std::fs::create_dir(&store_dir)?;
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")?
+        .local_addr()?
+        .to_string();
+    let (mut server, _) = start_kvs_server(&[
+        "--addr",
+        &addr,
+        "--dir",
+        store_dir.to_str().unwrap(),
+        "--auth-token-file",
+        token_file.to_str().unwrap(),
+    ]);
+    let client = |token: Option<&str>, args: &[&str]| {
+        let mut command = Command::cargo_bin("kvs-client").unwrap();
+        command.args(args).args(["--addr", &addr]);
+        match token {
+            Some(token) => command.env("KVS_AUTH_TOKEN", token),
+            None => command.env_remove("KVS_AUTH_TOKEN"),
+        };
+        command.assert()
+    };
+    client(Some("s3cret"), &["set", "key1", "value1"]).success();
+    client(Some("s3cret"), &["get", "key1"])
+        .success()
+        .stdout(eq("value1").trim());
+    client(Some("wrong"), &["get", "key1"])
+        .failure()
+        .stderr(contains("rejected the auth token"));
+    client(None, &["get", "key1"])
+        .failure()
+        .stderr(contains("requires an auth token"));
+    client(None, &["ping"])
+        .failure()
+        .stderr(contains("requires an auth token"));
+    server.kill()?;
+    server.wait()?;
+
+    // An empty token file is refused before serving.
+    std::fs::write(&token_file, "\n")?;
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", &addr, "--dir", store_dir.to_str().unwrap()])
+        .args(["--auth-token-file", token_file.to_str().unwrap()])
+        .assert()
+        .failure()
+        .stderr(contains("is empty"));
+    Ok(())
+}
+
+// A server serving TLS should answer a client trusting its certificate, pipelined
+// requests included, and the handshake should fail with `KvsError::Tls` for a client
+// trusting another authority or expecting another name, and against a server without
+// TLS.
+#[cfg(feature = "tls")]
+#[test]
+fn server_tls() -> Result<()> {
+    use kvs::{ClientTlsConfig, ServerTlsConfig};
+    use std::time::Duration;
+    let localhost = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    let cert = localhost.cert.pem().into_bytes();
+    let key = localhost.key_pair.serialize_pem().into_bytes();
+    let other = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    let trusting = |ca_cert: &[u8], server_name: &str| ClientTlsConfig {
+        ca_cert: Some(ca_cert.to_vec()),
+        server_name: Some(server_name.to_owned()),
+    };
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SharedKvStore::open(temp_dir.path())?;
+    let tls = ServerTlsConfig::from_pem(&cert, &key)?;
+    let addr = spawn_server(kvs::KvsServer::new(store, pool(4)).tls(tls))?;
+
+    let mut client = kvs::KvsClient::builder(addr)
+        .tls(trusting(&cert, "localhost"))
+        .connect()?;
+    client.set("greeting".to_owned(), "hello".to_owned())?;
+    assert_eq!(client.get("greeting".to_owned())?, Some("hello".to_owned()));
+    // Far more than the socket buffers hold, sent while the responses are read.
+    let value = "v".repeat(4096);
+    let mut pipeline = client.pipeline();
+    for i in 0..500 {
+        pipeline.set(format!("key{}", i), value.clone());
+    }
+    for i in 0..500 {
+        pipeline.get(format!("key{}", i));
+    }
+    let results = pipeline.execute()?;
+    assert!(results[500..]
+        .iter()
+        .all(|result| result.as_ref().unwrap().as_deref() == Some(&*value)));
+    assert_eq!(client.scan("key").count(), 500);
+    drop(client);
+    // A pooled connection is reused, so the server sends nothing unasked over TLS.
+    let clients = kvs::KvsClient::builder(addr)
+        .tls(trusting(&cert, "localhost"))
+        .pool(2)
+        .build()?;
+    for _ in 0..3 {
+        assert_eq!(
+            clients.get()?.get("greeting".to_owned())?,
+            Some("hello".to_owned())
+        );
+    }
+    assert_eq!(clients.connections(), 1);
+    drop(clients);
+
+    for config in [
+        trusting(other.cert.pem().as_bytes(), "localhost"),
+        trusting(&cert, "kvs.example"),
+    ] {
+        let connected = kvs::KvsClient::builder(addr).tls(config).connect();
+        assert!(matches!(connected, Err(KvsError::Tls(_))));
+    }
+    // A client without TLS gets no answer it understands.
+    let connected = kvs::KvsClient::builder(addr)
+        .timeout(Duration::from_secs(5))
+        .connect();
+    assert!(connected.is_err());
+
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let plain = spawn_server(kvs::KvsServer::new(
+        SharedKvStore::open(other_dir.path())?,
+        pool(2),
+    ))?;
+    let connected = kvs::KvsClient::builder(plain)
+        .tls(trusting(&cert, "localhost"))
+        .connect();
+    assert!(matches!(connected, Err(KvsError::Tls(_))));
+
+    assert!(matches!(
+        ServerTlsConfig::from_pem(b"not a certificate", &key),
+        Err(KvsError::TlsConfig(_))
+    ));
+    assert!(matches!(
+        ServerTlsConfig::from_pem(&cert, other.key_pair.serialize_pem().as_bytes()),
+        Err(KvsError::Tls(_))
+    ));
+    Ok(())
+}
+
+// `kvs-server --tls-cert --tls-key` should serve TLS, which `kvs-client` connects over
+// with `--tls-ca`.
+#[cfg(feature = "tls")]
+#[test]
+fn cli_tls() -> Result<()> {
+    let localhost =
+        rcgen::generate_simple_self_signed(vec!["localhost".to_owned(), "127.0.0.1".to_owned()])
+            .unwrap();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let cert = temp_dir.path().join("cert.pem");
+    let key = temp_dir.path().join("key.pem");
+    std::fs::write(&cert, localhost.cert.pem())?;
+    std::fs::write(&key, localhost.key_pair.serialize_pem())?;
+    let store_dir = temp_dir.path().join("store");
+    std::fs::create_dir(&store_dir)?;
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")?
+        .local_addr()?
+        .to_string();
+    let (mut server, _) = start_kvs_server(&[
+        "--addr",
+        &addr,
+        "--dir",
+        store_dir.to_str().unwrap(),
+        "--tls-cert",
+        cert.to_str().unwrap(),
+        "--tls-key",
+        key.to_str().unwrap(),
+    ]);
+    let client = |args: &[&str]| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", &addr])
+            .assert()
+    };
+    let ca = cert.to_str().unwrap();
+    client(&["set", "key1", "value1", "--tls-ca", ca]).success();
+    client(&["get", "key1", "--tls-ca", ca])
+        .success()
+        .stdout(eq("value1").trim());
+    client(&["ping", "--tls-ca", ca, "--tls-server-name", "localhost"])
+        .success()
+        .stdout(eq("PONG").trim());
+    client(&[
+        "get",
+        "key1",
+        "--tls-ca",
+        ca,
+        "--tls-server-name",
+        "kvs.example",
+    ])
+    .failure()
+    .stderr(contains("TLS error"));
+    client(&["get", "key1"]).failure();
+    server.kill()?;
+    server.wait()?;
+
+    // A key that cannot be read is refused before serving.
+    std::fs::write(&key, "not a key")?;
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", &addr, "--dir", store_dir.to_str().unwrap()])
+        .args(["--tls-cert", cert.to_str().unwrap()])
+        .args(["--tls-key", key.to_str().unwrap()])
+        .assert()
+        .failure()
+        .stderr(contains("Cannot serve TLS"));
+    Ok(())
+}
+
+// Scrapes `/metrics` of a server, returning the samples by name and labels.
+#[cfg(feature = "metrics")]
+fn scrape(addr: std::net::SocketAddr, path: &str) -> Result<(String, Vec<(String, u64)>)> {
+    use std::io::{Read, Write};
+    let mut stream = std::net::TcpStream::connect(addr)?;
+    write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, addr)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.lines().next().unwrap().to_owned();
+    let samples = body
+        .lines()
+        .filter(|line| status.ends_with("200 OK") && !line.starts_with('#'))
+        .map(|line| {
+            let (name, value) = line.rsplit_once(' ').unwrap();
+            (name.to_owned(), value.parse().unwrap())
+        })
+        .collect();
+    Ok((status, samples))
+}
+
+// A server should count the commands of both protocols and the errors by type, the
+// bytes it read, its connections and the statistics of its store, served in the
+// Prometheus text format until it shuts down.
+#[cfg(feature = "metrics")]
+#[test]
+fn server_metrics() -> Result<()> {
+    use std::io::{Read, Write};
+    let sample = |samples: &[(String, u64)], name: &str| {
+        samples
+            .iter()
+            .find(|(sample, _)| sample == name)
+            .map(|(_, value)| *value)
+    };
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let metrics = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let metrics_addr = metrics.local_addr()?;
+    let server = kvs::KvsServer::new(SharedKvStore::open(temp_dir.path())?, pool(2))
+        .metrics_listener(metrics);
+    let shutdown = server.shutdown_handle();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let serving = std::thread::spawn(move || server.serve(listener));
+
+    let mut stream = connect_v1(addr)?;
+    let mut sent = r#"{"magic":"kvs","version":1}"#.len();
+    for request in [
+        r#"{"V1":{"Set":{"key":"a","value":"1"}}}"#,
+        r#"{"V1":{"Set":{"key":"b","value":"2"}}}"#,
+        r#"{"V1":{"Get":{"key":"a"}}}"#,
+        r#"{"V1":{"Get":{"key":"missing"}}}"#,
+        r#"{"V1":{"Remove":{"key":"a"}}}"#,
+        r#"{"V1":{"Remove":{"key":"a"}}}"#,
+        r#"{"V1":"DbSize"}"#,
+        r#"{"V1":"Flush"}"#,
+    ] {
+        roundtrip(&mut stream, request)?;
+        sent += request.len();
+    }
+
+    let (status, samples) = scrape(metrics_addr, "/metrics")?;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    for (name, value) in [
+        (r#"kvs_commands_total{command="get"}"#, 2),
+        (r#"kvs_commands_total{command="set"}"#, 2),
+        (r#"kvs_commands_total{command="remove"}"#, 2),
+        (r#"kvs_commands_total{command="dbsize"}"#, 1),
+        (r#"kvs_commands_total{command="scan"}"#, 0),
+        (r#"kvs_errors_total{type="key_not_found"}"#, 1),
+        (r#"kvs_errors_total{type="unsupported"}"#, 1),
+        (r#"kvs_errors_total{type="failed"}"#, 0),
+        ("kvs_read_bytes_total", sent as u64),
+        ("kvs_connections_total", 1),
+        ("kvs_active_connections", 1),
+        ("kvs_live_keys", 1),
+        ("kvs_compactions_total", 0),
+    ] {
+        assert_eq!(sample(&samples, name), Some(value), "{}", name);
+    }
+    assert!(sample(&samples, "kvs_written_bytes_total").unwrap() > 0);
+    assert!(sample(&samples, "kvs_disk_bytes").unwrap() > 0);
+    assert!(sample(&samples, "kvs_uncompacted_bytes").unwrap() > 0);
+    assert_eq!(
+        scrape(metrics_addr, "/").unwrap().0,
+        "HTTP/1.1 404 Not Found"
+    );
+
+    // A malformed request ends its connection with an error.
+    stream.write_all(b"{oops")?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let _ = stream.read_to_end(&mut Vec::new());
+    let mut closed = false;
+    for _ in 0..100 {
+        let (_, samples) = scrape(metrics_addr, "/metrics")?;
+        if sample(&samples, "kvs_active_connections") == Some(0) {
+            let connection_errors = r#"kvs_errors_total{type="connection"}"#;
+            assert_eq!(sample(&samples, connection_errors), Some(1));
+            closed = true;
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    assert!(closed, "the connection should be counted as closed");
+
+    // Shutting down stops the endpoint, leaving the store to be reopened.
+    shutdown.shutdown();
+    serving.join().unwrap()?;
+    assert!(std::net::TcpStream::connect(metrics_addr).is_err());
+    assert_eq!(SharedKvStore::open(temp_dir.path())?.len(), 1);
+
+    // RESP commands count too, with unknown ones and wrong arguments as errors.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let metrics = std::net::TcpListener::bind("127.0.0.1:0")?;
+    let metrics_addr = metrics.local_addr()?;
+    let server = kvs::KvsServer::new(SharedKvStore::open(temp_dir.path())?, pool(2))
+        .protocol(kvs::Protocol::Resp)
+        .metrics_listener(metrics);
+    let addr = spawn_server(server)?;
+    let mut stream = std::net::TcpStream::connect(addr)?;
+    let commands = "*1\r\n$4\r\nPING\r\n*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n\
+                    *1\r\n$3\r\nGET\r\n*1\r\n$8\r\nFLUSHALL\r\n";
+    stream.write_all(commands.as_bytes())?;
+    let mut replies = Vec::new();
+    let mut buf = [0; 256];
+    while !String::from_utf8_lossy(&replies).contains("unknown command") {
+        let n = stream.read(&mut buf)?;
+        assert!(n > 0);
+        replies.extend_from_slice(&buf[..n]);
+    }
+    // The bytes written are counted once the write returns, which can be after the
+    // client reads them.
+    let mut samples = Vec::new();
+    for _ in 0..100 {
+        samples = scrape(metrics_addr, "/metrics")?.1;
+        if sample(&samples, "kvs_written_bytes_total") == Some(replies.len() as u64) {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    for (name, value) in [
+        (r#"kvs_commands_total{command="ping"}"#, 1),
+        (r#"kvs_commands_total{command="set"}"#, 1),
+        (r#"kvs_commands_total{command="get"}"#, 1),
+        (r#"kvs_errors_total{type="invalid"}"#, 1),
+        (r#"kvs_errors_total{type="unsupported"}"#, 1),
+        ("kvs_read_bytes_total", commands.len() as u64),
+        ("kvs_written_bytes_total", replies.len() as u64),
+    ] {
+        assert_eq!(sample(&samples, name), Some(value), "{}", name);
+    }
+    Ok(())
+}
+
+// A RESP server should accept inline commands, and hang up on broken input with an error.
+#[test]
+fn resp_inline_commands() -> Result<()> {
+    use std::io::{BufRead, BufReader, Write};
+    let (_temp_dir, addr) = spawn_resp_server();
+    let mut stream = std::net::TcpStream::connect(addr)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut reply = || -> Result<String> {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        Ok(line)
+    };
+
+    stream.write_all(b"PING\r\n")?;
+    assert_eq!(reply()?, "+PONG\r\n");
+    stream.write_all(b"set key1  value1\n\r\nGET key1\r\n")?;
+    assert_eq!(reply()?, "+OK\r\n");
+    assert_eq!(reply()?, "$6\r\n");
+    assert_eq!(reply()?, "value1\r\n");
+    stream.write_all(b"*2\r\n$3\r\nGET\r\n$4\r\nkey2\r\n")?;
+    assert_eq!(reply()?, "$-1\r\n");
+    stream.write_all(b"*2\r\n$3\r\nGET\r\n$x\r\n")?;
+    assert!(reply()?.starts_with("-ERR Protocol error"));
+    assert_eq!(reply()?, "");
+    Ok(())
+}
+
+// A `SharedQueueThreadPool` without threads could never run a job, so it should not
+// be created.
+#[test]
+fn thread_pool_needs_threads() {
+    assert!(matches!(
+        SharedQueueThreadPool::new(0),
+        Err(KvsError::NoThreads)
+    ));
+    pool(1);
+}
+
+// A job panicking on a `SharedQueueThreadPool` should not cost the pool a thread.
+#[test]
+fn thread_pool_recovers_from_panics() {
+    let pool = pool(4);
+    for _ in 0..8 {
+        pool.spawn(|| panic!("job panicked on purpose"));
+    }
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let barrier = std::sync::Arc::new(std::sync::Barrier::new(4));
+    // Every thread has to be alive to get past the barrier together.
+    for _ in 0..4 {
+        let sender = sender.clone();
+        let barrier = std::sync::Arc::clone(&barrier);
+        pool.spawn(move || {
+            barrier.wait();
+            sender.send(()).unwrap();
+        });
+    }
+    for _ in 0..4 {
+        receiver
+            .recv_timeout(std::time::Duration::from_secs(10))
+            .unwrap();
+    }
+}
+
+// The operations of a client in `server_concurrent_clients`.
+//
+// Shared keys only ever get one value and are never removed, and keys of a client
+// are only touched by it, so the outcome does not depend on how clients interleave.
+fn client_workload(client: usize) -> Vec<(String, Option<String>)> {
+    let mut ops = Vec::new();
+    for i in 0..50 {
+        let shared = format!("key{}", (client * 7 + i) % 40);
+        ops.push((shared.clone(), Some(format!("{}-value", shared))));
+        let own = format!("client{}-key{}", client, i % 10);
+        if i % 3 == 0 {
+            ops.push((own, None));
+        } else {
+            ops.push((own, Some(format!("value{}", i))));
+        }
+    }
+    ops
+}
+
+// Many clients writing overlapping keys at once should leave the state a single
+// client running all their operations would.
+#[test]
+fn server_concurrent_clients() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SharedKvStore::open(temp_dir.path())?;
+    let addr = spawn_server(kvs::KvsServer::new(store.clone(), pool(8)))?;
+
+    let handles: Vec<_> = (0..32)
+        .map(|client| {
+            std::thread::spawn(move || -> Result<()> {
+                let mut kvs_client = kvs::KvsClient::connect(addr)?;
+                for (key, value) in client_workload(client) {
+                    match value {
+                        Some(value) => kvs_client.set(key, value)?,
+                        None => match kvs_client.remove(key) {
+                            Ok(()) | Err(KvsError::KeyNotFound) => {}
+                            Err(e) => return Err(e),
+                        },
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    let mut oracle = kvs::MemKvsEngine::new();
+    for client in 0..32 {
+        for (key, value) in client_workload(client) {
+            match value {
+                Some(value) => oracle.set(key, value)?,
+                None => {
+                    let _ = oracle.remove(key);
+                }
+            }
+        }
+    }
+    let expected = oracle.snapshot();
+    assert_eq!(store.keys(), expected.keys().cloned().collect::<Vec<_>>());
+    let mut kvs_client = kvs::KvsClient::connect(addr)?;
+    for (key, value) in expected {
+        assert_eq!(kvs_client.get(key)?, Some(value));
+    }
+    Ok(())
+}
+
+// Gets on an `AsyncKvStore` should be served while another task streams sets.
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn async_store_concurrent_gets() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = kvs::AsyncKvStore::open(temp_dir.path()).await?;
+    for i in 0..10 {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .await?;
+    }
+
+    let writer = store.clone();
+    let sets = tokio::spawn(async move {
+        for i in 0..500 {
+            writer
+                .set(format!("new{}", i), format!("value{}", i))
+                .await?;
+        }
+        Ok::<_, KvsError>(())
+    });
+    let gets: Vec<_> = (0..8)
+        .map(|task| {
+            let reader = store.clone();
+            tokio::spawn(async move {
+                for i in 0..100 {
+                    let key = format!("key{}", (task + i) % 10);
+                    let expected = format!("value{}", (task + i) % 10);
+                    assert_eq!(reader.get(key).await?, Some(expected));
+                }
+                Ok::<_, KvsError>(())
+            })
+        })
+        .collect();
+    for get in gets {
+        get.await.unwrap()?;
+    }
+    sets.await.unwrap()?;
+
+    assert!(matches!(
+        store.remove("missing".to_owned()).await,
+        Err(KvsError::KeyNotFound)
+    ));
+    drop(store);
+    // The store thread closes the store once it has no handles left.
+    let mut store = loop {
+        match KvStore::open(temp_dir.path()) {
+            Err(KvsError::StoreLocked(_)) => std::thread::yield_now(),
+            store => break store?,
+        }
+    };
+    assert_eq!(store.get("new499")?, Some("value499".to_owned()));
+    Ok(())
+}
+
+// Cancelled writes on an `AsyncKvStore` should leave it consistent.
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn async_store_cancellation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = kvs::AsyncKvStore::open(temp_dir.path()).await?;
+    for i in 0..100 {
+        let writer = store.clone();
+        let set =
+            tokio::spawn(async move { writer.set("key1".to_owned(), format!("value{}", i)).await });
+        set.abort();
+    }
+    store.set("key2".to_owned(), "value2".to_owned()).await?;
+    let value = store.get("key1".to_owned()).await?;
+    assert!(value.is_none() || value.unwrap().starts_with("value"));
+    assert_eq!(
+        store.get("key2".to_owned()).await?,
+        Some("value2".to_owned())
+    );
+    Ok(())
+}
+
+// `AsyncKvsClient` should round-trip every command from concurrent tasks.
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn async_client_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(kvs::KvsServer::new(
+        SharedKvStore::open(temp_dir.path())?,
+        pool(4),
+    ))?;
+    let tasks: Vec<_> = (0..8)
+        .map(|task| {
+            tokio::spawn(async move {
+                let mut client = kvs::AsyncKvsClient::connect(addr).await?;
+                let key = format!("key{}", task);
+                client
+                    .set(key.clone(), format!("line1\nvalue{}", task))
+                    .await?;
+                let value = client.get(key.clone()).await?;
+                assert_eq!(value, Some(format!("line1\nvalue{}", task)));
+                client.remove(key.clone()).await?;
+                assert_eq!(client.get(key.clone()).await?, None);
+                assert!(matches!(
+                    client.remove(key).await,
+                    Err(KvsError::KeyNotFound)
+                ));
+                Ok::<_, KvsError>(())
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap()?;
+    }
+    Ok(())
+}
+
+// Total size of the log files in a store directory.
+fn logs_size(path: &std::path::Path) -> u64 {
+    std::fs::read_dir(path)
+        .expect("fail to read directory")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .map(|path| std::fs::metadata(path).unwrap().len())
+        .sum()
+}
+
+// `size_on_disk` and `live_size` should match the logs and the index summed from
+// scratch through random writes, compactions and reopens.
+#[test]
+fn incremental_sizes() -> Result<()> {
+    for disk_index in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let open = || {
+            KvStore::builder(temp_dir.path())
+                .max_segment_size(2048)
+                .compaction_threshold(8 * 1024)
+                .disk_index(disk_index)
+                .open()
+        };
+        let check = |store: &KvStore| {
+            assert_eq!(store.size_on_disk(), logs_size(temp_dir.path()));
+            assert_eq!(store.live_size(), store.stats().live_bytes);
+        };
+        let mut store = open()?;
+        check(&store);
+        // A fixed linear congruential sequence, so failures reproduce.
+        let mut seed: u64 = 7;
+        for step in 0..2000 {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+            let key = format!("key{}", (seed >> 33) % 50);
+            match (seed >> 40) % 8 {
+                0 | 1 => {
+                    let _ = store.remove(&key);
+                }
+                2 => {
+                    store.append(key, "+".repeat((seed >> 50) as usize % 20))?;
+                }
+                _ => store.set(key, "v".repeat((seed >> 50) as usize % 100))?,
+            }
+            if step % 500 == 100 {
+                store.compact_step(256)?;
+            }
+            if step % 500 == 250 {
+                store.compact()?;
+            }
+            if step % 500 == 499 {
+                drop(store);
+                store = open()?;
+            }
+            check(&store);
+        }
+    }
+    Ok(())
+}
+
+// Builds the distribution of the given sizes from scratch.
+fn size_stats<I: IntoIterator<Item = u64>>(sizes: I) -> kvs::SizeStats {
+    let mut stats = kvs::SizeStats::default();
+    for size in sizes {
+        stats.min = if stats.count == 0 {
+            size
+        } else {
+            stats.min.min(size)
+        };
+        stats.max = stats.max.max(size);
+        stats.count += 1;
+        stats.total += size;
+        stats.buckets[(64 - size.leading_zeros() as usize).min(31)] += 1;
+    }
+    stats
+}
+
+// The key and value size distributions of `stats` should match the ones rebuilt from
+// the keys and from the index loaded from scratch, through random writes, compactions
+// and reopens.
+#[test]
+fn size_distributions() -> Result<()> {
+    for disk_index in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let open = |path: &std::path::Path| {
+            KvStore::builder(path)
+                .max_segment_size(2048)
+                .compaction_threshold(8 * 1024)
+                .disk_index(disk_index)
+                .open()
+        };
+        let check = |store: &mut KvStore| -> Result<()> {
+            let stats = store.stats();
+            let keys: Vec<String> = store
+                .iter()
+                .map(|pair| pair.map(|(key, _)| key))
+                .collect::<Result<_>>()?;
+            assert_eq!(
+                stats.key_sizes,
+                size_stats(keys.iter().map(|key| key.len() as u64))
+            );
+            assert_eq!(stats.value_sizes.count, keys.len() as u64);
+            assert_eq!(stats.value_sizes.total, stats.live_bytes);
+            Ok(())
+        };
+        let mut store = open(temp_dir.path())?;
+        // A fixed linear congruential sequence, so failures reproduce.
+        let mut seed: u64 = 11;
+        for step in 0..2000 {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+            let key = "k".repeat(1 + (seed >> 33) as usize % 40);
+            match (seed >> 40) % 8 {
+                0 | 1 => {
+                    let _ = store.remove(&key);
+                }
+                2 => {
+                    store.append(key, "+".repeat((seed >> 50) as usize % 20))?;
+                }
+                _ => store.set(key, "v".repeat((seed >> 50) as usize % 3000))?,
+            }
+            if step % 500 == 100 {
+                store.compact_step(256)?;
+            }
+            if step % 500 == 250 {
+                store.compact()?;
+            }
+            if step % 500 == 499 {
+                drop(store);
+                store = open(temp_dir.path())?;
+            }
+            check(&mut store)?;
+            if step % 100 == 50 {
+                let crashed = crashed_copy(temp_dir.path());
+                let reopened = open(crashed.path())?;
+                assert_eq!(reopened.stats().key_sizes, store.stats().key_sizes);
+                assert_eq!(reopened.stats().value_sizes, store.stats().value_sizes);
+            }
+        }
+        let stats = store.stats();
+        assert!(stats.value_sizes.min <= stats.value_sizes.mean() as u64);
+        assert!(stats.value_sizes.mean() <= stats.value_sizes.max as f64);
+    }
+    Ok(())
+}
+
+// `stats` should track the store from memory, with live and stale bytes never
+// adding up to more than the logs hold.
+#[test]
+fn store_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder(temp_dir.path())
+        .compaction_threshold(u64::MAX)
+        .open()?;
+    let check = |store: &KvStore| {
+        let stats = store.stats();
+        assert!(stats.live_bytes + stats.uncompacted_bytes <= stats.disk_bytes);
+        assert_eq!(stats.disk_bytes, logs_size(temp_dir.path()));
+        assert_eq!(stats.generations, log_files(temp_dir.path()));
+        assert_eq!(stats.live_keys, store.len());
+        stats
+    };
+
+    let stats = check(&store);
+    assert_eq!(
+        (stats.live_keys, stats.live_bytes, stats.uncompacted_bytes),
+        (0, 0, 0)
+    );
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let stats = check(&store);
+    assert_eq!(stats.live_keys, 100);
+    assert_eq!(stats.uncompacted_bytes, 0);
+    assert_eq!(stats.live_bytes, stats.disk_bytes);
+
+    for i in 0..50 {
+        store.set(format!("key{}", i), format!("new{}", i))?;
+    }
+    for i in 50..80 {
+        store.remove(format!("key{}", i))?;
+    }
+    let before = check(&store);
+    assert_eq!(before.live_keys, 70);
+    assert!(before.uncompacted_bytes > 0);
+
+    store.compact()?;
+    let after = check(&store);
+    assert_eq!(after.live_keys, 70);
+    assert_eq!(after.uncompacted_bytes, 0);
+    assert_eq!(after.live_bytes, before.live_bytes);
+    assert!(after.current_gen > before.current_gen);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(check(&store), after);
+    let json = serde_json::to_value(after)?;
+    assert_eq!(json["live_keys"], 70);
+    Ok(())
+}
+
+// `stats` should not count keys that expired but are not evicted yet as live, also
+// once a compaction moved them, and count them as stale bytes instead.
+#[test]
+fn store_stats_expired() -> Result<()> {
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let builder = KvStore::builder(temp_dir.path()).compaction_threshold(u64::MAX);
+    // Compressed copies take fewer bytes than the records they copy.
+    #[cfg(feature = "compression")]
+    let builder = builder.compress_compacted(true);
+    let mut store = builder.open()?;
+    for i in 0..20 {
+        store.set_with_ttl(
+            format!("short{}", i),
+            format!("value{};", i).repeat(20),
+            Duration::from_millis(300),
+        )?;
+    }
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{};", i).repeat(20))?;
+    }
+    store.set("key0".to_owned(), "new".to_owned())?;
+    while store.compact_step(256)? {}
+    sleep(Duration::from_millis(400));
+
+    let stats = store.stats();
+    assert_eq!(stats.live_keys, 10);
+    assert_eq!(stats.live_keys, store.len());
+    assert_eq!(
+        stats.live_bytes + stats.uncompacted_bytes,
+        store.live_size()
+    );
+
+    // Evicting the expired keys leaves them stale.
+    assert_eq!(store.purge_expired()?.purged_keys, 20);
+    let purged = store.stats();
+    assert_eq!(
+        (
+            purged.live_keys,
+            purged.live_bytes,
+            purged.uncompacted_bytes
+        ),
+        (stats.live_keys, stats.live_bytes, stats.uncompacted_bytes)
+    );
+    Ok(())
+}
+
+// Fields recorded on spans, collected by `SpanFields`.
+#[cfg(feature = "tracing")]
+type RecordedFields = std::sync::Arc<std::sync::Mutex<Vec<(String, String, String)>>>;
+
+// A tracing layer collecting the name, field and value of every field recorded on a span.
+#[cfg(feature = "tracing")]
+struct SpanFields(RecordedFields);
+
+#[cfg(feature = "tracing")]
+impl SpanFields {
+    fn collect<S>(
+        &self,
+        span: &tracing::span::Id,
+        ctx: &tracing_subscriber::layer::Context<S>,
+        record: impl FnOnce(&mut dyn tracing_subscriber::field::Visit),
+    ) where
+        S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    {
+        struct Visitor<'a>(&'a str, &'a mut Vec<(String, String, String)>);
+        impl tracing_subscriber::field::Visit for Visitor<'_> {
+            fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+                let value = format!("{:?}", value);
+                self.1
+                    .push((self.0.to_owned(), field.name().to_owned(), value));
+            }
+        }
+        let name = ctx.span(span).unwrap().name();
+        record(&mut Visitor(name, &mut self.0.lock().unwrap()));
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl<S> tracing_subscriber::Layer<S> for SpanFields
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    fn on_new_span(
+        &self,
+        attrs: &tracing::span::Attributes<'_>,
+        id: &tracing::span::Id,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        self.collect(id, &ctx, |visitor| attrs.record(visitor));
+    }
+
+    fn on_record(
+        &self,
+        id: &tracing::span::Id,
+        values: &tracing::span::Record<'_>,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        self.collect(id, &ctx, |visitor| values.record(visitor));
+    }
+}
+
+// Compaction should run in a span recording the reclaimed bytes.
+#[cfg(feature = "tracing")]
+#[test]
+fn compaction_span() -> Result<()> {
+    use tracing_subscriber::layer::SubscriberExt;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let fields = RecordedFields::default();
+    let subscriber = tracing_subscriber::registry().with(SpanFields(fields.clone()));
+    let reclaimed = tracing::subscriber::with_default(subscriber, || -> Result<u64> {
+        let mut store = KvStore::open(temp_dir.path())?;
+        for i in 0..100 {
+            store.set("key1".to_owned(), format!("value{}", i))?;
+        }
+        Ok(store.compact()?.reclaimed_bytes)
+    })?;
+    assert!(reclaimed > 0);
+
+    let fields = fields.lock().unwrap();
+    assert!(fields
+        .iter()
+        .any(|(span, field, _)| span == "open" && field == "path"));
+    let recorded = (
+        "compact".to_owned(),
+        "reclaimed".to_owned(),
+        reclaimed.to_string(),
+    );
+    assert!(fields.contains(&recorded));
+    Ok(())
+}
+
+// Exporting a store and importing it into a fresh one should reproduce every pair.
+#[test]
+fn export_import_json() -> Result<()> {
+    let source_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut source = KvStore::open(source_dir.path())?;
+    let large = "ß".repeat(100_000);
+    source.set("key1".to_owned(), "line1\nline2".to_owned())?;
+    source.set("ключ".to_owned(), "値 🦀".to_owned())?;
+    source.set("large".to_owned(), large.clone())?;
+    source.set_bytes("bytes".to_owned(), vec![0, 159, 146, 150])?;
+    source.set_as("typed".to_owned(), &vec![1, 2, 3])?;
+    source.set_with_ttl(
+        "ttl".to_owned(),
+        "value".to_owned(),
+        std::time::Duration::from_secs(3600),
+    )?;
+    source.set("removed".to_owned(), "value".to_owned())?;
+    source.remove("removed")?;
+
+    let mut dump = Vec::new();
+    assert_eq!(source.export_json(&mut dump)?, 6);
+    assert_eq!(String::from_utf8(dump.clone()).unwrap().lines().count(), 6);
+
+    let target_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut target = KvStore::open(target_dir.path())?;
+    let report = target.import_json(&dump[..], false)?;
+    assert_eq!((report.imported, report.skipped, report.expired), (6, 0, 0));
+    assert!(source.keys().eq(target.keys()));
+    for key in ["key1", "ключ", "large", "ttl"] {
+        assert_eq!(target.get(key)?, source.get(key)?);
+    }
+    assert_eq!(target.get("large")?, Some(large));
+    assert_eq!(target.get_bytes("bytes")?, Some(vec![0, 159, 146, 150]));
+    assert_eq!(target.get_as::<_, Vec<i32>>("typed")?, Some(vec![1, 2, 3]));
+
+    // Existing keys are only replaced when overwriting.
+    target.set("key1".to_owned(), "changed".to_owned())?;
+    let report = target.import_json(&dump[..], false)?;
+    assert_eq!((report.imported, report.skipped), (0, 6));
+    assert_eq!(target.get("key1")?, Some("changed".to_owned()));
+    let report = target.import_json(&dump[..], true)?;
+    assert_eq!((report.imported, report.skipped), (6, 0));
+    assert_eq!(target.get("key1")?, Some("line1\nline2".to_owned()));
+
+    // Imported pairs survive reopening like any other write.
+    drop(target);
+    let mut target = KvStore::open(target_dir.path())?;
+    assert_eq!(target.get("ключ")?, Some("値 🦀".to_owned()));
+    Ok(())
+}
+
+// `export_csv` should write pairs that `import_csv` reads back as they were, whatever
+// the keys and values hold.
+#[test]
+fn export_import_csv() -> Result<()> {
+    use kvs::CsvOptions;
+
+    let source_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut source = KvStore::open(source_dir.path())?;
+    let nasty = [
+        ("plain", "value"),
+        ("comma,key", "a,b,,c"),
+        ("quote\"key", "say \"hi\" \"\""),
+        ("newlines", "line1\nline2\r\nline3\n"),
+        ("\"", ","),
+        ("empty", ""),
+        ("", "empty key"),
+        ("  spaces  ", " \t tabs "),
+        ("ключ", "値 🦀"),
+    ];
+    for (key, value) in nasty {
+        source.set(key.to_owned(), value.to_owned())?;
+    }
+    source.set_bytes("bytes".to_owned(), vec![0, 159])?;
+
+    let mut dump = Vec::new();
+    assert_eq!(source.export_csv(&mut dump)?, nasty.len() as u64);
+    let target_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut target = KvStore::open(target_dir.path())?;
+    let report = target.import_csv(&dump[..], CsvOptions::default())?;
+    assert_eq!(report.imported, nasty.len() as u64);
+    assert!(report.errors.is_empty());
+    assert_eq!(target.len(), nasty.len());
+    for (key, value) in nasty {
+        assert_eq!(target.get(key)?.as_deref(), Some(value));
+    }
+
+    // Another delimiter, a header and swapped columns read the same pairs.
+    let text = "value;key\n\"x;y\";a\n2;\"b\"\"\"\n";
+    let options = CsvOptions {
+        has_header: true,
+        delimiter: b';',
+        key_column: 1,
+        value_column: 0,
+        strict: true,
+    };
+    let report = target.import_csv(text.as_bytes(), options)?;
+    assert_eq!(report.imported, 2);
+    assert_eq!(target.get("a")?, Some("x;y".to_owned()));
+    assert_eq!(target.get("b\"")?, Some("2".to_owned()));
+    Ok(())
+}
+
+// `import_csv` should skip malformed rows, reporting the line each starts on, unless
+// it is strict, in which case the first one fails the import.
+#[test]
+fn import_csv_malformed_rows() -> Result<()> {
+    use kvs::{CsvOptions, CsvRowError};
+
+    let fixture = include_bytes!("fixtures/malformed.csv");
+    let options = CsvOptions {
+        has_header: true,
+        key_column: 1,
+        value_column: 2,
+        ..CsvOptions::default()
+    };
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let report = store.import_csv(&fixture[..], options)?;
+    assert_eq!(report.imported, 3);
+    let lines: Vec<u64> = report.errors.iter().map(|error| error.line).collect();
+    assert_eq!(lines, [5, 6, 7, 9]);
+    assert_eq!(
+        report.errors[0],
+        CsvRowError {
+            line: 5,
+            reason: "expected at least 3 columns, found 2".to_owned()
+        }
+    );
+    assert_eq!(store.get("alpha")?, Some("first".to_owned()));
+    assert_eq!(store.get("beta")?, Some("multi\nline".to_owned()));
+    assert_eq!(store.get("key, with comma")?, Some("say \"hi\"".to_owned()));
+    assert_eq!(store.len(), 3);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    let strict = CsvOptions {
+        strict: true,
+        ..options
+    };
+    match store.import_csv(&fixture[..], strict) {
+        Err(KvsError::MalformedCsv { line, .. }) => assert_eq!(line, 5),
+        other => panic!("expected a malformed row, got {:?}", other),
+    }
+    // The rows before the malformed one stay imported.
+    assert_eq!(store.len(), 2);
+    Ok(())
+}
+
+// A backup taken between writes should open as a store with exactly the earlier writes.
+#[test]
+fn online_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest = backup_dir.path().join("backup");
+    let mut store = KvStore::builder(temp_dir.path())
+        .max_segment_size(4 * 1024)
+        .compaction_threshold(8 * 1024)
+        .background_compaction(true)
+        .open()?;
+    for i in 0..2000 {
+        store.set(format!("key{}", i % 300), format!("value{}", i))?;
+        if i == 1200 {
+            store.backup_to(&dest)?;
+        }
+    }
+    assert!(matches!(
+        store.backup_to(&dest),
+        Err(KvsError::BackupNotEmpty(_))
+    ));
+    store.set("late".to_owned(), "value".to_owned())?;
+
+    let mut backup = KvStore::open(&dest)?;
+    assert_eq!(backup.len(), 300);
+    assert!(!backup.contains_key("late"));
+    for key in 0..300 {
+        // The last write of each key up to and including the 1201st.
+        let expected = (0..=1200).rev().find(|i| i % 300 == key).unwrap();
+        assert_eq!(
+            backup.get(format!("key{}", key))?,
+            Some(format!("value{}", expected))
+        );
+    }
+    // The original goes on untouched by the backup.
+    assert_eq!(store.get("key0")?, Some("value1800".to_owned()));
+    Ok(())
+}
+
+// Logs replaced by compactions should be archived, and a store restored from them.
+#[test]
+fn restore_archived_generation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::builder(temp_dir.path())
+            .compaction_threshold(u64::MAX)
+            .archive(kvs::Retention::Count(10))
+            .open()
+    };
+    let mut store = open()?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    let written_gen = store.stats().current_gen;
+    store.compact()?;
+    // Roll over right after the change, so its generation holds nothing else.
+    store.set_max_segment_size(1);
+    let changed_gen = store.stats().current_gen;
+    store.set("key0".to_owned(), "changed".to_owned())?;
+    store.set_max_segment_size(u64::MAX);
+    for i in 0..100 {
+        store.remove(format!("key{}", i))?;
+    }
+    store.compact()?;
+    assert!(store.is_empty());
+    drop(store);
+
+    // Archived logs are not replayed by a normal open.
+    let archive = temp_dir.path().join("archive");
+    assert!(log_files(&archive) >= 2);
+    let store = open()?;
+    assert!(store.is_empty());
+    drop(store);
+
+    let mut store = KvStore::restore_to_generation(temp_dir.path(), written_gen)?;
+    assert_eq!(store.len(), 100);
+    assert_eq!(store.get("key0")?, Some("value0".to_owned()));
+    assert_eq!(store.get("key99")?, Some("value99".to_owned()));
+    drop(store);
+    // The restore is a generation of its own, which survives reopening.
+    let mut store = open()?;
+    assert_eq!(store.get("key0")?, Some("value0".to_owned()));
+    drop(store);
+
+    // Restoring from the compacted generation on picks up later writes.
+    let mut store = KvStore::restore_to_generation(temp_dir.path(), changed_gen)?;
+    assert_eq!(store.len(), 100);
+    assert_eq!(store.get("key0")?, Some("changed".to_owned()));
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    drop(store);
+
+    assert!(matches!(
+        KvStore::restore_to_generation(temp_dir.path(), 1000),
+        Err(KvsError::GenerationNotFound(1000))
+    ));
+    Ok(())
+}
+
+// Only as many archived logs as the retention keeps should stay around.
+#[test]
+fn archive_retention() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder(temp_dir.path())
+        .compaction_threshold(u64::MAX)
+        .archive(kvs::Retention::Count(2))
+        .open()?;
+    for round in 0..5 {
+        store.set("key1".to_owned(), format!("value{}", round))?;
+        store.set("key1".to_owned(), format!("value{}", round))?;
+        store.compact()?;
+    }
+    let archive = temp_dir.path().join("archive");
+    assert_eq!(log_files(&archive), 2);
+    drop(store);
+    // The first generation is gone, and nothing left can stand in for it.
+    assert!(matches!(
+        KvStore::restore_to_generation(temp_dir.path(), 1),
+        Err(KvsError::GenerationNotFound(1))
+    ));
+
+    let mut store = KvStore::builder(temp_dir.path())
+        .compaction_threshold(u64::MAX)
+        .archive(kvs::Retention::Age(std::time::Duration::ZERO))
+        .open()?;
+    store.set("key1".to_owned(), "value".to_owned())?;
+    store.compact()?;
+    assert_eq!(log_files(&archive), 0);
+    Ok(())
+}
+
+// `clear` should empty the store at once, and stay cleared however a crash interrupts it.
+#[test]
+fn clear_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder(temp_dir.path())
+        .max_segment_size(1024)
+        .open()?;
+    for i in 0..200 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert!(log_files(temp_dir.path()) > 1);
+    let before_clear = crashed_copy(temp_dir.path());
+
+    store.clear()?;
+    assert!(store.is_empty());
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(log_files(temp_dir.path()), 1);
+    assert_eq!(store.stats().uncompacted_bytes, 0);
+    store.set("key1".to_owned(), "new".to_owned())?;
+    assert_eq!(store.get("key1")?, Some("new".to_owned()));
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 1);
+    assert_eq!(store.get("key1")?, Some("new".to_owned()));
+    assert_eq!(store.stats().uncompacted_bytes, 0);
+    drop(store);
+
+    // A crash after the clear record is synced but before any old log is removed.
+    let clear_log = newest_log(temp_dir.path());
+    std::fs::copy(
+        &clear_log,
+        before_clear.path().join(clear_log.file_name().unwrap()),
+    )?;
+    let mut store = KvStore::open(before_clear.path())?;
+    assert_eq!(store.len(), 1);
+    assert_eq!(store.get("key2")?, None);
+    Ok(())
+}
+
+// Dropping a store should keep its writes, and `close` should save its index.
+#[test]
+fn drop_and_close() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.close()?;
+    assert!(temp_dir.path().join("index.snapshot").exists());
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    drop(store);
+
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    store.close()?;
+    Ok(())
+}
+
+// Compactions should write compressed logs that read back the same, including after
+// the logs are replayed and compacted again.
+#[cfg(feature = "compression")]
+#[test]
+fn compressed_compaction() -> Result<()> {
+    fn fill(store: &mut KvStore) -> Result<()> {
+        for i in 0..200 {
+            store.set(format!("key{}", i), format!("value-{};", i).repeat(20))?;
+        }
+        for i in (0..200).step_by(2) {
+            store.set(format!("key{}", i), format!("other-{};", i).repeat(20))?;
+        }
+        Ok(())
+    }
+    fn expected(i: usize) -> String {
+        if i.is_multiple_of(2) {
+            format!("other-{};", i).repeat(20)
+        } else {
+            format!("value-{};", i).repeat(20)
+        }
+    }
+
+    for format in [LogFormat::Json, LogFormat::Bincode] {
+        let plain_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut plain = KvStore::builder(plain_dir.path()).format(format).open()?;
+        fill(&mut plain)?;
+        plain.compact()?;
+
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let open = || {
+            KvStore::builder(temp_dir.path())
+                .format(format)
+                .compress_compacted(true)
+                .open()
+        };
+        let mut store = open()?;
+        fill(&mut store)?;
+        store.compact()?;
+        assert!(logs_size(temp_dir.path()) * 2 < logs_size(plain_dir.path()));
+        // The active log is not compressed.
+        store.set("key0".to_owned(), "new".to_owned())?;
+        assert!(std::fs::read(newest_log(temp_dir.path()))?
+            .windows(3)
+            .any(|window| window == b"new"));
+        for i in 1..200 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(expected(i)));
+        }
+        drop(store);
+
+        // Replay the compressed log without its hint or the index snapshot.
+        for entry in std::fs::read_dir(temp_dir.path())? {
+            let path = entry?.path();
+            if path.extension() == Some("hint".as_ref())
+                || path.file_name() == Some("index.snapshot".as_ref())
+            {
+                std::fs::remove_file(path)?;
+            }
+        }
+        let mut store = open()?;
+        store.set_verify_checksums(true);
+        assert_eq!(store.get("key0")?, Some("new".to_owned()));
+        for i in 1..200 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(expected(i)));
+        }
+        store.compact()?;
+        let scanned: Vec<_> = store.iter().collect::<Result<_>>()?;
+        assert_eq!(scanned.len(), 200);
+        drop(store);
+
+        let mut store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key0")?, Some("new".to_owned()));
+        for i in 1..200 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(expected(i)));
+        }
+    }
+    Ok(())
+}
+
+// Values streamed in and out should round-trip, a short reader should leave the
+// previous value in place, and a torn stream should be dropped on open.
+#[test]
+fn streamed_values() -> Result<()> {
+    let value: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+    for format in [LogFormat::Json, LogFormat::Bincode] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_format(temp_dir.path(), format)?;
+        store.set_from_reader("big".to_owned(), &value[..], value.len() as u64)?;
+        store.set_from_reader("empty".to_owned(), std::io::empty(), 0)?;
+        store.set("small".to_owned(), "value".to_owned())?;
+
+        let mut copied = Vec::new();
+        assert_eq!(
+            store.get_to_writer("big", &mut copied)?,
+            Some(value.len() as u64)
+        );
+        assert_eq!(copied, value);
+        assert_eq!(store.get_bytes("big")?, Some(value.clone()));
+        copied.clear();
+        assert_eq!(store.get_to_writer("empty", &mut copied)?, Some(0));
+        assert!(copied.is_empty());
+        assert_eq!(store.get_bytes("empty")?, Some(Vec::new()));
+        copied.clear();
+        assert_eq!(store.get_to_writer("small", &mut copied)?, Some(5));
+        assert_eq!(copied, b"value");
+        assert_eq!(store.get_to_writer("missing", &mut copied)?, None);
+
+        // A reader ending early writes nothing.
+        let log_len = logs_size(temp_dir.path());
+        let short = &value[..100_000];
+        assert!(matches!(
+            store.set_from_reader("small".to_owned(), short, value.len() as u64),
+            Err(KvsError::Io(ref e)) if e.kind() == std::io::ErrorKind::UnexpectedEof
+        ));
+        assert_eq!(logs_size(temp_dir.path()), log_len);
+        assert_eq!(store.get("small")?, Some("value".to_owned()));
+        store.set("after".to_owned(), "value".to_owned())?;
+
+        // A crash midway through a stream leaves a torn write the next open drops.
+        let log = newest_log(temp_dir.path());
+        let before = std::fs::read(&log)?.len();
+        store.set_from_reader("small".to_owned(), &value[..], value.len() as u64)?;
+        let after = std::fs::read(&log)?;
+        let crashed_dir = crashed_copy(temp_dir.path());
+        let torn_len = before + (after.len() - before) / 2;
+        std::fs::write(
+            crashed_dir.path().join(log.file_name().unwrap()),
+            &after[..torn_len],
+        )?;
+        let mut crashed = KvStore::open(crashed_dir.path())?;
+        assert_eq!(crashed.get("small")?, Some("value".to_owned()));
+        assert_eq!(crashed.get_bytes("big")?, Some(value.clone()));
+        assert_eq!(crashed.get("after")?, Some("value".to_owned()));
+        drop(crashed);
+
+        drop(store);
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set_verify_checksums(true);
+        assert_eq!(store.get_bytes("small")?, Some(value.clone()));
+        assert_eq!(store.get_bytes("big")?, Some(value.clone()));
+        assert_eq!(store.get_bytes("empty")?, Some(Vec::new()));
+    }
+    Ok(())
+}
+
+// Streamed values larger than the compaction threshold should survive the
+// compactions they trigger.
+#[test]
+fn streamed_values_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder(temp_dir.path())
+        .compaction_threshold(64 * 1024)
+        .open()?;
+    let value = |i: u8| vec![i; 200_000];
+    for i in 0..4 {
+        store.set_from_reader("big".to_owned(), &value(i)[..], 200_000)?;
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    // Only the latest value is left after the automatic compactions.
+    assert!(logs_size(temp_dir.path()) < 2 * 300_000);
+    assert_eq!(store.get_bytes("big")?, Some(value(3)));
+    store.compact()?;
+    let mut copied = Vec::new();
+    assert_eq!(store.get_to_writer("big", &mut copied)?, Some(200_000));
+    assert_eq!(copied, value(3));
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes("big")?, Some(value(3)));
+    for i in 0..4 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// Reads through memory maps should return what buffered reads do, across rollovers,
+// compactions and reopening, and on the shared store.
+#[cfg(all(feature = "mmap", unix))]
+#[test]
+fn mmap_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::builder(temp_dir.path())
+            .max_segment_size(4 * 1024)
+            .mmap_reads(true)
+    };
+    let mut store = open().open()?;
+    for i in 0..500 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set_bytes("bytes".to_owned(), vec![0, 1, 2, 255])?;
+    for i in 0..500 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    for i in (0..500).step_by(3) {
+        store.set(format!("key{}", i), format!("other{}", i))?;
+    }
+    store.remove("key1")?;
+    store.compact()?;
+    let expected = |i: usize| match i {
+        1 => None,
+        _ if i.is_multiple_of(3) => Some(format!("other{}", i)),
+        _ => Some(format!("value{}", i)),
+    };
+    for i in 0..500 {
+        assert_eq!(store.get(format!("key{}", i))?, expected(i));
+    }
+    assert_eq!(store.get_bytes("bytes")?, Some(vec![0, 1, 2, 255]));
+    drop(store);
+
+    let mut store = open().open()?;
+    for i in 0..500 {
+        assert_eq!(store.get(format!("key{}", i))?, expected(i));
+    }
+    drop(store);
+
+    let store = open().open_shared()?;
+    let clone = store.clone();
+    for i in 0..500 {
+        assert_eq!(clone.get(format!("key{}", i))?, expected(i));
+    }
+    store.compact()?;
+    for i in 0..500 {
+        assert_eq!(clone.get(format!("key{}", i))?, expected(i));
+    }
+    Ok(())
+}
+
+// A disk-resident index should read like the in-memory one while its changes are
+// merged into its file, across compactions, reopening, and a damaged or missing file.
+#[test]
+fn disk_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::builder(temp_dir.path())
+            .disk_index(true)
+            .index_cache_entries(16)
+            .open()
+    };
+    let expected = |i: usize| match i % 5 {
+        0 => None,
+        1 => Some(format!("other{}", i)),
+        _ => Some(format!("value{}", i)),
+    };
+    let check = |store: &mut KvStore| -> Result<()> {
+        for i in 0..300 {
+            assert_eq!(store.get(format!("key{:03}", i))?, expected(i));
+        }
+        let mut keys: Vec<String> = (0..300)
+            .filter(|&i| expected(i).is_some())
+            .map(|i| format!("key{:03}", i))
+            .collect();
+        keys.sort();
+        assert!(KvStore::keys(store).eq(keys.iter().map(|key| key.as_str())));
+        assert_eq!(store.len(), keys.len());
+        let scanned: Vec<_> = store.scan_prefix("key01").collect::<Result<_>>()?;
+        assert_eq!(
+            scanned.len(),
+            keys.iter().filter(|key| key.starts_with("key01")).count()
+        );
+        Ok(())
+    };
+
+    let mut store = open()?;
+    for i in 0..300 {
+        store.set(format!("key{:03}", i), format!("value{}", i))?;
+    }
+    for i in 0..300 {
+        match i % 5 {
+            0 => store.remove(format!("key{:03}", i))?,
+            1 => store.set(format!("key{:03}", i), format!("other{}", i))?,
+            _ => {}
+        }
+    }
+    assert!(temp_dir.path().join("index.sorted").exists());
+    check(&mut store)?;
+    drop(store);
+
+    let mut store = open()?;
+    check(&mut store)?;
+    assert!(store.compact()?.reclaimed_bytes > 0);
+    check(&mut store)?;
+    store.set("key999".to_owned(), "last".to_owned())?;
+    drop(store);
+
+    let mut store = open()?;
+    assert_eq!(store.get("key999")?, Some("last".to_owned()));
+    store.remove("key999")?;
+    check(&mut store)?;
+    drop(store);
+
+    // A damaged index file is dropped and rebuilt from the logs.
+    let index_file = temp_dir.path().join("index.sorted");
+    let mut bytes = std::fs::read(&index_file)?;
+    bytes[10] ^= 0xff;
+    std::fs::write(&index_file, bytes)?;
+    let mut store = open()?;
+    check(&mut store)?;
+    drop(store);
+
+    // Opening with the in-memory index drops the file, which would miss its writes.
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(!index_file.exists());
+    store.set("key000".to_owned(), "back".to_owned())?;
+    drop(store);
+    let mut store = open()?;
+    assert_eq!(store.get("key000")?, Some("back".to_owned()));
+    store.remove("key000")?;
+    check(&mut store)?;
+    store.clear()?;
+    assert_eq!(store.len(), 0);
+    drop(store);
+    let store = open()?;
+    assert_eq!(store.len(), 0);
+    Ok(())
+}
+
+// A store with millions of keys should stay within a memory ceiling with a disk-resident
+// index, across compaction and reopening.
+#[cfg(target_os = "linux")]
+#[test]
+fn disk_index_memory_ceiling() -> Result<()> {
+    const KEYS: usize = 2_000_000;
+    // Run in a child process, so the peak memory use measured is that of the store alone.
+    if std::env::var_os("KVS_MEMORY_CEILING_CHILD").is_none() {
+        let status = Command::new(std::env::current_exe()?)
+            .args(["--exact", "disk_index_memory_ceiling", "--test-threads=1"])
+            .env("KVS_MEMORY_CEILING_CHILD", "1")
+            .status()?;
+        assert!(status.success());
+        return Ok(());
+    }
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::builder(temp_dir.path())
+            .disk_index(true)
+            .compaction_threshold(u64::MAX)
+            .open()
+    };
+    let key = |i: usize| format!("key{:08}", i);
+    let mut store = open()?;
+    store.extend((0..KEYS).map(|i| (key(i), format!("value{}", i))));
+    store.extend(
+        (0..KEYS)
+            .step_by(10)
+            .map(|i| (key(i), format!("other{}", i))),
+    );
+    let expected = |i: usize| match i % 10 {
+        0 => format!("other{}", i),
+        _ => format!("value{}", i),
+    };
+    for i in (0..KEYS).step_by(9_973) {
+        assert_eq!(store.get(key(i))?, Some(expected(i)));
+    }
+    drop(store);
+
+    let mut store = open()?;
+    assert!(store.compact()?.reclaimed_bytes > 0);
+    for i in (1..KEYS).step_by(9_967) {
+        assert_eq!(store.get(key(i))?, Some(expected(i)));
+    }
+    drop(store);
+
+    let store = open()?;
+    assert_eq!(store.len(), KEYS);
+    drop(store);
+
+    let status = std::fs::read_to_string("/proc/self/status")?;
+    let peak_kb: u64 = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))
+        .and_then(|peak| peak.trim().trim_end_matches("kB").trim().parse().ok())
+        .expect("no peak memory use in /proc/self/status");
+    // An in-memory index of this many keys takes well over 100 MiB on its own.
+    assert!(peak_kb < 64 * 1024, "peak memory use of {} kB", peak_kb);
+    Ok(())
+}
+
+// Reads through the value cache should always see the latest write of a key, whichever
+// way it was written, while a skewed read pattern is mostly answered from the cache.
+#[test]
+fn read_cache() -> Result<()> {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder(temp_dir.path())
+        .read_cache_bytes(64 << 20)
+        .compaction_threshold(4096)
+        .open()?;
+    let mut oracle: HashMap<String, String> = HashMap::new();
+    let mut seed: u32 = 0x2545_f491;
+    let mut next = || {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        seed
+    };
+    for step in 0..5000 {
+        let key = format!("key{}", next() % 8);
+        match next() % 20 {
+            0..=2 => {
+                store.set(key.clone(), format!("set{}", step))?;
+                oracle.insert(key, format!("set{}", step));
+            }
+            3 => {
+                let _ = store.remove(key.clone());
+                oracle.remove(&key);
+            }
+            4 => {
+                let mut batch = WriteBatch::new();
+                batch.put(key.clone(), format!("batch{}", step));
+                batch.delete(format!("key{}", step % 8));
+                store.write_batch(batch)?;
+                oracle.insert(key, format!("batch{}", step));
+                oracle.remove(&format!("key{}", step % 8));
+            }
+            5 => {
+                store.set_bytes(key.clone(), format!("bytes{}", step).into_bytes())?;
+                oracle.insert(key, format!("bytes{}", step));
+            }
+            6 if step % 500 == 6 => {
+                store.clear()?;
+                oracle.clear();
+            }
+            7 if step % 100 == 7 => {
+                store.compact()?;
+            }
+            _ => assert_eq!(store.get(&key)?, oracle.get(&key).cloned()),
+        }
+    }
+    // Once the writes stop, every live key is read from the log at most once more.
+    store.set("key0".to_owned(), "last".to_owned())?;
+    oracle.insert("key0".to_owned(), "last".to_owned());
+    let before = store.stats();
+    for i in 0..1000 {
+        let key = format!("key{}", i % 8);
+        assert_eq!(store.get(&key)?, oracle.get(&key).cloned());
+    }
+    let after = store.stats();
+    let live = oracle.len() as u64;
+    assert!(after.cache_hits - before.cache_hits >= live * 125 - live);
+
+    // An expired value is never returned from the cache.
+    store.set_with_ttl(
+        "ttl".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(50),
+    )?;
+    assert_eq!(store.get("ttl")?, Some("value".to_owned()));
+    assert_eq!(store.get("ttl")?, Some("value".to_owned()));
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(store.get("ttl")?, None);
+
+    // Without a budget nothing is cached.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    store.get("key")?;
+    store.get("key")?;
+    let stats = store.stats();
+    assert_eq!(
+        (stats.cache_hits, stats.cache_misses, stats.cache_bytes),
+        (0, 0, 0)
+    );
+    Ok(())
+}
+
+// The value cache should stay within its byte and entry budgets, evicting the least
+// recently used values.
+#[test]
+fn read_cache_budget() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder(temp_dir.path())
+        .read_cache_bytes(16 * 1024)
+        .open()?;
+    for i in 0..500 {
+        store.set(format!("key{:03}", i), "v".repeat(200))?;
+    }
+    for i in 0..500 {
+        assert_eq!(store.get(format!("key{:03}", i))?, Some("v".repeat(200)));
+        assert!(store.stats().cache_bytes <= 16 * 1024);
+    }
+    assert!(store.stats().cache_bytes > 12 * 1024);
+
+    // The most recently read values are the ones still cached.
+    let before = store.stats();
+    for i in 450..500 {
+        store.get(format!("key{:03}", i))?;
+    }
+    let after = store.stats();
+    assert_eq!(after.cache_hits - before.cache_hits, 50);
+    store.get("key000")?;
+    assert_eq!(store.stats().cache_misses, after.cache_misses + 1);
+
+    // A value larger than the whole budget is read but never cached.
+    store.set("large".to_owned(), "v".repeat(32 * 1024))?;
+    let before = store.stats();
+    assert_eq!(store.get("large")?, Some("v".repeat(32 * 1024)));
+    assert_eq!(store.get("large")?, Some("v".repeat(32 * 1024)));
+    let after = store.stats();
+    assert_eq!(after.cache_misses - before.cache_misses, 2);
+    assert_eq!(after.cache_bytes, before.cache_bytes);
+    drop(store);
+
+    let mut store = KvStore::builder(temp_dir.path())
+        .read_cache_bytes(u64::MAX)
+        .read_cache_entries(10)
+        .open()?;
+    for i in 0..20 {
+        store.get(format!("key{:03}", i))?;
+    }
+    for i in 10..20 {
+        store.get(format!("key{:03}", i))?;
+    }
+    let stats = store.stats();
+    assert_eq!((stats.cache_hits, stats.cache_misses), (10, 20));
+    Ok(())
+}
+
+// A store with hundreds of logs should read from all of them while keeping only a few
+// open, across reopening and compaction.
+#[test]
+fn max_open_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::builder(temp_dir.path())
+            .max_segment_size(1)
+            .max_open_logs(4)
+            .compaction_threshold(u64::MAX)
+            .open()
+    };
+    let mut store = open()?;
+    for i in 0..300 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    assert!(log_files(temp_dir.path()) >= 300);
+    let check = |store: &mut KvStore| -> Result<()> {
+        // Reading in a stride keeps evicting the readers of the logs read before.
+        for round in 0..3 {
+            for i in (round..300).step_by(7) {
+                assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+            }
+        }
+        Ok(())
+    };
+    check(&mut store)?;
+    drop(store);
+
+    let mut store = open()?;
+    check(&mut store)?;
+    assert_eq!(store.iter().count(), 300);
+    store.compact()?;
+    check(&mut store)?;
+    drop(store);
+
+    let shared = KvStore::builder(temp_dir.path())
+        .max_open_logs(1)
+        .open_shared()?;
+    for i in 0..300 {
+        assert_eq!(
+            shared.get(format!("key{}", i))?,
+            Some(format!("value{}", i))
+        );
+    }
+    Ok(())
+}
+
+// An incremental compaction should take bounded steps while other writes go on between
+// them, with reads, reopening and a crash at any step seeing the latest values.
+#[test]
+fn compact_step() -> Result<()> {
+    use std::collections::BTreeMap;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = |path: &std::path::Path, disk_index: bool| {
+        KvStore::builder(path)
+            .compaction_threshold(u64::MAX)
+            .disk_index(disk_index)
+            .index_cache_entries(32)
+            .open()
+    };
+    for disk_index in [false, true] {
+        let mut store = open(temp_dir.path(), disk_index)?;
+        store.clear()?;
+        let mut oracle = BTreeMap::new();
+        for round in 0..3 {
+            for i in 0..200 {
+                let value = format!("value{}-{}", i, round);
+                store.set(format!("key{:03}", i), value.clone())?;
+                oracle.insert(format!("key{:03}", i), value);
+            }
+        }
+        let check = |store: &mut KvStore, oracle: &BTreeMap<String, String>| -> Result<()> {
+            for i in 0..220 {
+                let key = format!("key{:03}", i);
+                assert_eq!(store.get(&key)?, oracle.get(&key).cloned());
+            }
+            assert_eq!(store.len(), oracle.len());
+            Ok(())
+        };
+        let before = logs_size(temp_dir.path());
+
+        let mut steps = 0;
+        while store.compact_step(256)? {
+            steps += 1;
+            // Writes on both sides of where the compaction has got to.
+            let i = steps * 7 % 200;
+            let value = format!("step{}", steps);
+            store.set(format!("key{:03}", i), value.clone())?;
+            oracle.insert(format!("key{:03}", i), value);
+            let removed = format!("key{:03}", (steps * 13 + 100) % 200);
+            if oracle.remove(&removed).is_some() {
+                store.remove(&removed)?;
+            }
+            store.set(format!("key{:03}", 200 + steps % 20), "new".to_owned())?;
+            oracle.insert(format!("key{:03}", 200 + steps % 20), "new".to_owned());
+            check(&mut store, &oracle)?;
+            assert_eq!(store.stats().disk_bytes, logs_size(temp_dir.path()));
+
+            if steps % 5 == 0 {
+                let crashed = crashed_copy(temp_dir.path());
+                let mut store = open(crashed.path(), disk_index)?;
+                check(&mut store, &oracle)?;
+                store.compact()?;
+                check(&mut store, &oracle)?;
+            }
+        }
+        assert!(steps > 10);
+        check(&mut store, &oracle)?;
+        assert!(logs_size(temp_dir.path()) < before / 2);
+        assert_eq!(store.stats().disk_bytes, logs_size(temp_dir.path()));
+        drop(store);
+
+        // Closing midway leaves the compacted log as just another log.
+        let mut store = open(temp_dir.path(), disk_index)?;
+        check(&mut store, &oracle)?;
+        for i in 0..200 {
+            store.set(format!("key{:03}", i), format!("last{}", i))?;
+            oracle.insert(format!("key{:03}", i), format!("last{}", i));
+        }
+        assert!(store.compact_step(0)?);
+        assert!(store.compact_step(0)?);
+        drop(store);
+        let mut store = open(temp_dir.path(), disk_index)?;
+        check(&mut store, &oracle)?;
+        assert!(store.compact()?.reclaimed_bytes > 0);
+        check(&mut store, &oracle)?;
+        assert!(!store.compact_step(0)?);
+    }
+    Ok(())
+}
+
+// A compaction should report exactly what it read, wrote and dropped, with the stale
+// records counted across reopening, replaying the logs and incremental steps.
+#[test]
+fn compaction_report() -> Result<()> {
+    use std::time::Instant;
+
+    let open = |path: &std::path::Path, disk_index: bool| {
+        KvStore::builder(path)
+            .compaction_threshold(u64::MAX)
+            .disk_index(disk_index)
+            .open()
+    };
+    let hints_size = |path: &std::path::Path| -> u64 {
+        std::fs::read_dir(path)
+            .expect("fail to read directory")
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension() == Some("hint".as_ref()))
+            .map(|path| std::fs::metadata(path).unwrap().len())
+            .sum()
+    };
+    for disk_index in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = open(temp_dir.path(), disk_index)?;
+        // 100 values are set, 50 of them overwritten twice, and 20 removed.
+        for round in 0..3 {
+            let count = if round == 0 { 100 } else { 50 };
+            for i in 0..count {
+                store.set(format!("key{:03}", i), format!("value{}", round))?;
+            }
+        }
+        for i in 80..100 {
+            store.remove(format!("key{:03}", i))?;
+        }
+        let stats = store.stats();
+        let start = Instant::now();
+        let report = store.compact()?;
+        assert!(report.duration <= start.elapsed());
+        assert_eq!(report.live_records, 80);
+        assert_eq!(report.stale_records, 120);
+        assert_eq!(report.tombstones, 20);
+        assert_eq!(report.bytes_read, stats.live_bytes);
+        assert_eq!(
+            report.bytes_written,
+            logs_size(temp_dir.path()) + hints_size(temp_dir.path())
+        );
+        assert_eq!(report.removed_gens, vec![stats.current_gen]);
+        assert!(report.reclaimed_bytes > 0);
+        assert_eq!(store.last_compaction(), Some(&report));
+        assert_eq!(store.compact()?, Default::default());
+        assert_eq!(store.last_compaction(), Some(&report));
+
+        // The stale records are counted again from the index saved on close, and from
+        // the logs replayed after a crash.
+        for i in 0..10 {
+            store.set(format!("key{:03}", i), "value3".to_owned())?;
+        }
+        store.remove("key010")?;
+        let crashed = crashed_copy(temp_dir.path());
+        drop(store);
+        for path in [temp_dir.path(), crashed.path()] {
+            let mut store = open(path, disk_index)?;
+            let report = store.compact()?;
+            assert_eq!(report.live_records, 79);
+            assert_eq!(report.stale_records, 11);
+            assert_eq!(report.tombstones, 1);
+        }
+
+        // A value replaced between steps is dropped only if it was not copied yet, and
+        // a tombstone written between them is left for the next compaction.
+        let mut store = open(temp_dir.path(), disk_index)?;
+        for i in 0..40 {
+            store.set(format!("new{:02}", i), "value".to_owned())?;
+        }
+        for i in 20..30 {
+            store.set(format!("key{:03}", i), "value4".to_owned())?;
+        }
+        assert!(store.compact_step(1)?);
+        store.set("key000".to_owned(), "value5".to_owned())?;
+        store.set("key079".to_owned(), "value5".to_owned())?;
+        store.remove("new39")?;
+        while store.compact_step(1024)? {}
+        let report = store.last_compaction().unwrap().clone();
+        assert_eq!(report.live_records, 117);
+        assert_eq!(report.stale_records, 12);
+        assert_eq!(report.tombstones, 0);
+        assert_eq!(report.bytes_read, report.bytes_written);
+        let report = store.compact()?;
+        assert_eq!(report.live_records, 118);
+        assert_eq!(report.stale_records, 1);
+        assert_eq!(report.tombstones, 1);
+    }
+    Ok(())
+}
+
+// Merged values should resolve over long chains across logs, survive reopening and
+// replaying the logs, and read the same once compactions collapse the chains, after
+// which no merge operator is needed to open the store.
+#[test]
+fn merge_operator() -> Result<()> {
+    fn append(existing: Option<&str>, operands: &[String]) -> String {
+        let mut value = existing.unwrap_or_default().to_owned();
+        for operand in operands {
+            if !value.is_empty() {
+                value.push(',');
+            }
+            value.push_str(operand);
+        }
+        value
+    }
+    let open = |path: &std::path::Path, disk_index: bool, merge: bool| {
+        let builder = KvStore::builder(path)
+            .compaction_threshold(u64::MAX)
+            .max_segment_size(512)
+            .disk_index(disk_index);
+        if merge {
+            builder.merge_operator(append).open()
+        } else {
+            builder.open()
+        }
+    };
+    let check = |store: &mut KvStore, expected: &[(&str, String)]| -> Result<()> {
+        for (key, value) in expected {
+            assert_eq!(store.get(key)?.as_ref(), Some(value));
+        }
+        Ok(())
+    };
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert!(matches!(
+        store.merge("key".to_owned(), "a".to_owned()),
+        Err(KvsError::MissingMergeOperator)
+    ));
+    drop(store);
+
+    for disk_index in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = open(temp_dir.path(), disk_index, true)?;
+        store.set("list".to_owned(), "base".to_owned())?;
+        store.set("gone".to_owned(), "old".to_owned())?;
+        store.remove("gone")?;
+        let mut list = "base".to_owned();
+        let mut fresh = String::new();
+        for i in 0..200 {
+            store.merge("list".to_owned(), i.to_string())?;
+            list = append(Some(&list), &[i.to_string()]);
+            if i % 2 == 0 {
+                store.merge("fresh".to_owned(), i.to_string())?;
+                fresh = append(Some(&fresh), &[i.to_string()]);
+            }
+        }
+        store.merge("gone".to_owned(), "new".to_owned())?;
+        assert!(store.stats().generations > 10);
+        let mut expected = vec![("list", list), ("fresh", fresh), ("gone", "new".to_owned())];
+        check(&mut store, &expected)?;
+
+        // Reopening needs the merge operator, whether from the saved index or after a crash.
+        let crashed = crashed_copy(temp_dir.path());
+        drop(store);
+        for path in [temp_dir.path(), crashed.path()] {
+            assert!(matches!(
+                open(path, disk_index, false),
+                Err(KvsError::MissingMergeOperator)
+            ));
+            check(&mut open(path, disk_index, true)?, &expected)?;
+        }
+
+        let mut store = open(temp_dir.path(), disk_index, true)?;
+        store.compact()?;
+        check(&mut store, &expected)?;
+        drop(store);
+        let mut store = open(temp_dir.path(), disk_index, false)?;
+        check(&mut store, &expected)?;
+        drop(store);
+
+        // Merges between the steps of a compaction are resolved as they are written.
+        let mut store = open(temp_dir.path(), disk_index, true)?;
+        for i in 0..20 {
+            store.merge("list".to_owned(), format!("more{}", i))?;
+            expected[0].1 = append(Some(&expected[0].1), &[format!("more{}", i)]);
+        }
+        assert!(store.compact_step(1)?);
+        for i in 0..5 {
+            store.merge("list".to_owned(), format!("step{}", i))?;
+            expected[0].1 = append(Some(&expected[0].1), &[format!("step{}", i)]);
+        }
+        while store.compact_step(1)? {}
+        check(&mut store, &expected)?;
+        drop(store);
+        check(&mut open(temp_dir.path(), disk_index, false)?, &expected)?;
+    }
+    Ok(())
+}
+
+// Counters should start from 0, go negative, refuse values that are not integers and
+// results that overflow, and keep their values across reopening and compaction.
+#[test]
+fn increment_decrement() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.increment("hits".to_owned(), 5)?, 5);
+    assert_eq!(store.increment("hits".to_owned(), 10)?, 15);
+    assert_eq!(store.decrement("hits".to_owned(), 20)?, -5);
+    assert_eq!(store.get("hits")?, Some("-5".to_owned()));
+    assert_eq!(store.decrement("misses".to_owned(), 3)?, -3);
+    assert_eq!(store.increment("zero".to_owned(), 0)?, 0);
+    assert_eq!(store.get("zero")?, Some("0".to_owned()));
+
+    store.set("name".to_owned(), "bob".to_owned())?;
+    assert!(matches!(
+        store.increment("name".to_owned(), 1),
+        Err(KvsError::NotAnInteger { key }) if key == "name"
+    ));
+    assert_eq!(store.get("name")?, Some("bob".to_owned()));
+
+    store.set("max".to_owned(), i64::MAX.to_string())?;
+    assert!(matches!(
+        store.increment("max".to_owned(), 1),
+        Err(KvsError::IntegerOverflow { key }) if key == "max"
+    ));
+    assert_eq!(store.get("max")?, Some(i64::MAX.to_string()));
+    assert_eq!(store.decrement("max".to_owned(), i64::MAX)?, 0);
+    assert!(matches!(
+        store.decrement("min".to_owned(), i64::MIN),
+        Err(KvsError::IntegerOverflow { .. })
+    ));
+    assert_eq!(store.decrement("min".to_owned(), i64::MAX)?, -i64::MAX);
+    assert_eq!(store.decrement("min".to_owned(), 1)?, i64::MIN);
+
+    for _ in 0..1000 {
+        store.increment("count".to_owned(), 1)?;
+    }
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("count")?, Some("1000".to_owned()));
+    assert_eq!(store.increment("count".to_owned(), 1)?, 1001);
+    store.compact()?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.increment("count".to_owned(), -1001)?, 0);
+    assert_eq!(store.get("hits")?, Some("-5".to_owned()));
+    assert_eq!(store.get("min")?, Some(i64::MIN.to_string()));
+    Ok(())
+}
+
+// Appended values should grow by their suffixes only, read back whole across logs,
+// reopening and replaying the logs, and read the same once compactions collapse them.
+#[test]
+fn append_values() -> Result<()> {
+    let open = |path: &std::path::Path, disk_index: bool| {
+        KvStore::builder(path)
+            .compaction_threshold(u64::MAX)
+            .max_segment_size(1024)
+            .disk_index(disk_index)
+            .merge_operator(|existing, operands| {
+                let mut value = existing.unwrap_or_default().to_uppercase();
+                value.extend(operands.iter().map(|operand| operand.to_uppercase()));
+                value
+            })
+            .open()
+    };
+    for disk_index in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = open(temp_dir.path(), disk_index)?;
+        let mut events = String::new();
+        for i in 0..500 {
+            let line = format!("event {}\n", i);
+            events.push_str(&line);
+            assert_eq!(
+                store.append("events".to_owned(), line)?,
+                events.len() as u64
+            );
+        }
+        // Rewriting the value on every append would take hundreds of kilobytes.
+        assert!(logs_size(temp_dir.path()) < 200 * 500);
+        assert!(store.stats().generations > 10);
+        store.set("name".to_owned(), "b".to_owned())?;
+        store.append("name".to_owned(), "ob".to_owned())?;
+        store.merge("name".to_owned(), "by".to_owned())?;
+        assert_eq!(store.append("name".to_owned(), "!".to_owned())?, 6);
+        let mut expected = vec![("events", events), ("name", "BOBBY!".to_owned())];
+        let check = |store: &mut KvStore, expected: &[(&str, String)]| -> Result<()> {
+            for (key, value) in expected {
+                assert_eq!(store.get(key)?.as_ref(), Some(value));
+            }
+            Ok(())
+        };
+        check(&mut store, &expected)?;
+
+        let crashed = crashed_copy(temp_dir.path());
+        drop(store);
+        check(&mut open(crashed.path(), disk_index)?, &expected)?;
+        let mut store = open(temp_dir.path(), disk_index)?;
+        check(&mut store, &expected)?;
+        store.compact()?;
+        check(&mut store, &expected)?;
+        assert!(logs_size(temp_dir.path()) < 2 * expected[0].1.len() as u64);
+
+        // Appends between the steps of a compaction write the whole value.
+        for i in 0..10 {
+            store.append("events".to_owned(), format!("more {}\n", i))?;
+            expected[0].1.push_str(&format!("more {}\n", i));
+        }
+        assert!(store.compact_step(1)?);
+        store.append("events".to_owned(), "step\n".to_owned())?;
+        expected[0].1.push_str("step\n");
+        while store.compact_step(1)? {}
+        check(&mut store, &expected)?;
+        store.remove("events")?;
+        assert_eq!(store.append("events".to_owned(), "new".to_owned())?, 3);
+        drop(store);
+        let mut store = open(temp_dir.path(), disk_index)?;
+        assert_eq!(store.get("events")?, Some("new".to_owned()));
+        check(&mut store, &expected[1..])?;
+    }
+    Ok(())
+}
+
+// Popping a key should return its latest value and leave it removed, with the same
+// stale bytes as a get and a remove, and nothing written for a missing key.
+#[test]
+fn pop_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.pop("missing".to_owned())?, None);
+    assert_eq!(logs_size(temp_dir.path()), 0);
+    store.set("key".to_owned(), "value".to_owned())?;
+    assert_eq!(store.pop("key".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key")?, None);
+    assert_eq!(store.pop("key".to_owned())?, None);
+    assert!(matches!(store.remove("key"), Err(KvsError::KeyNotFound)));
+
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut other = KvStore::open(other_dir.path())?;
+    other.set("key".to_owned(), "value".to_owned())?;
+    other.get("key")?;
+    other.remove("key")?;
+    assert_eq!(
+        store.stats().uncompacted_bytes,
+        other.stats().uncompacted_bytes
+    );
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key")?, None);
+    drop(store);
+
+    // Every pop sees the latest overwrite, through compactions and the value cache.
+    let mut store = KvStore::builder(temp_dir.path())
+        .compaction_threshold(4096)
+        .read_cache_bytes(1024)
+        .open()?;
+    for i in 0..2000 {
+        let key = format!("key{}", i % 7);
+        store.set(key.clone(), format!("value{}", i))?;
+        if i % 3 == 0 {
+            store.get(&key)?;
+        }
+        if i % 5 == 0 {
+            assert_eq!(store.pop(key.clone())?, Some(format!("value{}", i)));
+            assert_eq!(store.pop(key)?, None);
+        }
+    }
+    Ok(())
+}
+
+// A memoized value should only be computed for a missing key, and a computation that
+// panics or fails should leave nothing behind.
+#[test]
+fn get_or_insert_with() -> Result<()> {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("hit".to_owned(), "cached".to_owned())?;
+    let value = store.get_or_insert_with("hit".to_owned(), || panic!("computed a hit"))?;
+    assert_eq!(value, "cached");
+    let mut calls = 0;
+    for _ in 0..3 {
+        let value = store.get_or_insert_with("miss".to_owned(), || {
+            calls += 1;
+            "computed".to_owned()
+        })?;
+        assert_eq!(value, "computed");
+    }
+    assert_eq!(calls, 1);
+
+    let len = logs_size(temp_dir.path());
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        store.get_or_insert_with("panic".to_owned(), || panic!("computation failed"))
+    }));
+    assert!(result.is_err());
+    let result: std::result::Result<String, KvsError> =
+        store.get_or_try_insert_with("fail".to_owned(), || Err(KvsError::KeyNotFound));
+    assert!(matches!(result, Err(KvsError::KeyNotFound)));
+    assert_eq!(logs_size(temp_dir.path()), len);
+    assert_eq!(store.get("panic")?, None);
+    assert_eq!(store.get("fail")?, None);
+    let value =
+        store.get_or_try_insert_with("fail".to_owned(), || Ok::<_, KvsError>("ok".to_owned()))?;
+    assert_eq!(value, "ok");
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("miss")?, Some("computed".to_owned()));
+    assert_eq!(store.get("fail")?, Some("ok".to_owned()));
+    assert_eq!(store.get("panic")?, None);
+    Ok(())
+}
+
+// Entries should read a value once and write every mutation right away, with an
+// entry that is made and dropped writing nothing.
+#[test]
+fn entry_api() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    match store.entry("key".to_owned())? {
+        Entry::Vacant(entry) => {
+            assert_eq!(entry.key(), "key");
+            entry.insert("value1".to_owned())?;
+        }
+        Entry::Occupied(_) => panic!("missing key is occupied"),
+    }
+    assert_eq!(store.get("key")?, Some("value1".to_owned()));
+
+    let len = logs_size(temp_dir.path());
+    let entry = store.entry("key".to_owned())?;
+    assert_eq!(entry.key(), "key");
+    drop(entry);
+    drop(store.entry("missing".to_owned())?);
+    assert_eq!(logs_size(temp_dir.path()), len);
+    assert_eq!(store.get("missing")?, None);
+
+    match store.entry("key".to_owned())? {
+        Entry::Occupied(mut entry) => {
+            assert_eq!(entry.get(), "value1");
+            assert_eq!(entry.insert("value2".to_owned())?, "value1");
+            assert_eq!(entry.get(), "value2");
+            assert_eq!(entry.insert("value3".to_owned())?, "value2");
+        }
+        Entry::Vacant(_) => panic!("existing key is vacant"),
+    }
+    assert_eq!(store.get("key")?, Some("value3".to_owned()));
+    store.set("other".to_owned(), "value".to_owned())?;
+    match store.entry("other".to_owned())? {
+        Entry::Occupied(entry) => assert_eq!(entry.remove()?, "value"),
+        Entry::Vacant(_) => panic!("existing key is vacant"),
+    }
+    assert_eq!(store.get("other")?, None);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key")?, Some("value3".to_owned()));
+    assert_eq!(store.get("other")?, None);
+    assert!(matches!(store.entry("other".to_owned())?, Entry::Vacant(_)));
+    Ok(())
+}
+
+// Renaming should move a value, streamed or not, to its new key, with the stale bytes
+// of a set and a remove, and a crash before the tombstone should lose no value.
+#[test]
+fn rename_keys() -> Result<()> {
+    use std::time::Duration;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder(temp_dir.path())
+        .compaction_threshold(u64::MAX)
+        .open()?;
+    assert!(matches!(
+        store.rename("missing".to_owned(), "new".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    store.set("old".to_owned(), "value".to_owned())?;
+    let (len, uncompacted) = (logs_size(temp_dir.path()), store.stats().uncompacted_bytes);
+    store.rename("old".to_owned(), "old".to_owned())?;
+    assert_eq!(logs_size(temp_dir.path()), len);
+    assert_eq!(store.get("old")?, Some("value".to_owned()));
+
+    store.rename("old".to_owned(), "new".to_owned())?;
+    assert_eq!(store.get("old")?, None);
+    assert_eq!(store.get("new")?, Some("value".to_owned()));
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut other = KvStore::open(other_dir.path())?;
+    other.set("old".to_owned(), "value".to_owned())?;
+    let other_uncompacted = other.stats().uncompacted_bytes;
+    other.set("new".to_owned(), "value".to_owned())?;
+    other.remove("old")?;
+    assert_eq!(
+        store.stats().uncompacted_bytes - uncompacted,
+        other.stats().uncompacted_bytes - other_uncompacted
+    );
+
+    // Renaming onto an existing key overwrites it, and the expiry moves with the value.
+    store.set_with_ttl(
+        "ttl".to_owned(),
+        "short".to_owned(),
+        Duration::from_millis(300),
+    )?;
+    store.rename("ttl".to_owned(), "new".to_owned())?;
+    assert_eq!(store.get("new")?, Some("short".to_owned()));
+    std::thread::sleep(Duration::from_millis(400));
+    assert_eq!(store.get("new")?, None);
+    let value: Vec<u8> = (0..200_000).map(|i| (i % 251) as u8).collect();
+    store.set_from_reader("stream".to_owned(), &value[..], value.len() as u64)?;
+    store.rename("stream".to_owned(), "moved".to_owned())?;
+    assert_eq!(store.get_bytes("moved")?, Some(value.clone()));
+    assert_eq!(store.get_bytes("stream")?, None);
+
+    // A crash right before the tombstone of the old key leaves both keys.
+    let tombstone = {
+        let before = logs_size(other_dir.path());
+        other.remove("new")?;
+        logs_size(other_dir.path()) - before
+    };
+    store.set("key".to_owned(), "value".to_owned())?;
+    store.rename("key".to_owned(), "yek".to_owned())?;
+    let crashed = crashed_copy(temp_dir.path());
+    let log = std::fs::OpenOptions::new()
+        .write(true)
+        .open(newest_log(crashed.path()))
+        .expect("fail to open log");
+    let log_len = log.metadata().expect("fail to read log length").len();
+    log.set_len(log_len - tombstone)
+        .expect("fail to truncate log");
+    let mut crashed = KvStore::open(crashed.path())?;
+    assert_eq!(crashed.get("key")?, Some("value".to_owned()));
+    assert_eq!(crashed.get("yek")?, Some("value".to_owned()));
+
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.compact()?;
+    assert_eq!(store.get("key")?, None);
+    assert_eq!(store.get("yek")?, Some("value".to_owned()));
+    assert_eq!(store.get("new")?, None);
+    assert_eq!(store.get_bytes("moved")?, Some(value));
+    Ok(())
+}
+
+// A transaction should see its own writes, hide them from the store until it commits,
+// write nothing if dropped, and be replayed entirely or not at all after a crash.
+#[test]
+fn transactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("object".to_owned(), "v1".to_owned())?;
+    store.set("index1".to_owned(), "v1".to_owned())?;
+    store.set("stale".to_owned(), "v1".to_owned())?;
+
+    let len = logs_size(temp_dir.path());
+    let mut tx = store.begin();
+    tx.put("object".to_owned(), "v2".to_owned());
+    tx.delete("object".to_owned());
+    tx.put("index2".to_owned(), "v2".to_owned());
+    assert_eq!(tx.get("object")?, None);
+    assert_eq!(tx.get("index1")?, Some("v1".to_owned()));
+    assert_eq!(tx.get("index2")?, Some("v2".to_owned()));
+    drop(tx);
+    store.begin().abort();
+    assert_eq!(logs_size(temp_dir.path()), len);
+    assert_eq!(store.get("object")?, Some("v1".to_owned()));
+    assert_eq!(store.get("index2")?, None);
+
+    let mut tx = store.begin();
+    tx.put("object".to_owned(), "v2".to_owned());
+    tx.put("index1".to_owned(), "v2".to_owned());
+    tx.put("index2".to_owned(), "v2".to_owned());
+    tx.delete("stale".to_owned());
+    assert_eq!(tx.get("object")?, Some("v2".to_owned()));
+    tx.commit()?;
+    let committed = |store: &mut KvStore| -> Result<()> {
+        assert_eq!(store.get("object")?, Some("v2".to_owned()));
+        assert_eq!(store.get("index1")?, Some("v2".to_owned()));
+        assert_eq!(store.get("index2")?, Some("v2".to_owned()));
+        assert_eq!(store.get("stale")?, None);
+        Ok(())
+    };
+    committed(&mut store)?;
+
+    // A crash anywhere before the last byte of the transaction drops all of it.
+    let crashed = crashed_copy(temp_dir.path());
+    let log = newest_log(crashed.path());
+    let full_log = std::fs::read(&log)?;
+    for cut in len..full_log.len() as u64 {
+        std::fs::write(&log, &full_log[..cut as usize])?;
+        let mut store = KvStore::open(crashed.path())?;
+        assert_eq!(store.get("object")?, Some("v1".to_owned()));
+        assert_eq!(store.get("index1")?, Some("v1".to_owned()));
+        assert_eq!(store.get("index2")?, None);
+        assert_eq!(store.get("stale")?, Some("v1".to_owned()));
+    }
+    std::fs::write(&log, &full_log)?;
+    committed(&mut KvStore::open(crashed.path())?)?;
+    drop(store);
+    committed(&mut KvStore::open(temp_dir.path())?)?;
+    Ok(())
+}
+
+// The same key in two namespaces and the default one should name three values, across
+// compaction and reopening, with listing and clearing scoped to a namespace.
+#[test]
+fn namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    for name in ["", "a\0b", "\0"] {
+        assert!(matches!(
+            store.namespace(name),
+            Err(KvsError::InvalidNamespace(invalid)) if invalid == name
+        ));
+    }
+    store.set("key".to_owned(), "default".to_owned())?;
+    for name in ["sessions", "counters"] {
+        let mut namespace = store.namespace(name)?;
+        assert_eq!(namespace.name(), name);
+        assert!(namespace.is_empty());
+        assert_eq!(namespace.get("key")?, None);
+        for i in 0..3 {
+            namespace.set(format!("key{}", i), format!("{}{}", name, i))?;
+        }
+        namespace.set("key".to_owned(), name.to_owned())?;
+        namespace.set("key".to_owned(), format!("{}!", name))?;
+    }
+    store.namespace("sessions")?.remove("key2")?;
+
+    let check = |store: &mut KvStore| -> Result<()> {
+        assert_eq!(store.get("key")?, Some("default".to_owned()));
+        let mut sessions = store.namespace("sessions")?;
+        assert_eq!(sessions.get("key")?, Some("sessions!".to_owned()));
+        assert_eq!(sessions.get("key2")?, None);
+        assert!(sessions.contains_key("key0"));
+        assert_eq!(sessions.keys().collect::<Vec<_>>(), ["key", "key0", "key1"]);
+        assert_eq!(sessions.len(), 3);
+        assert_eq!(
+            sessions
+                .scan_prefix("key")
+                .skip(1)
+                .collect::<Result<Vec<_>>>()?,
+            [
+                ("key0".to_owned(), "sessions0".to_owned()),
+                ("key1".to_owned(), "sessions1".to_owned())
+            ]
+        );
+        let mut counters = store.namespace("counters")?;
+        assert_eq!(counters.get("key")?, Some("counters!".to_owned()));
+        assert_eq!(counters.iter().count(), 4);
+        let stats = store.stats();
+        assert_eq!(stats.live_keys, 8);
+        assert_eq!(
+            stats.namespace_keys.into_iter().collect::<Vec<_>>(),
+            [("counters".to_owned(), 4), ("sessions".to_owned(), 3)]
+        );
+        Ok(())
+    };
+    check(&mut store)?;
+    store.compact()?;
+    check(&mut store)?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    check(&mut store)?;
+
+    store.namespace("counters")?.clear()?;
+    assert!(store.namespace("counters")?.is_empty());
+    assert_eq!(store.namespace("sessions")?.len(), 3);
+    assert_eq!(store.get("key")?, Some("default".to_owned()));
+    assert!(!store.stats().namespace_keys.contains_key("counters"));
+    Ok(())
+}
+
+// Byte keys with NULs, 0xFF and invalid UTF-8 should round-trip and sort by their bytes
+// in either log format, apart from string keys, across compaction and reopening.
+#[test]
+fn raw_keys() -> Result<()> {
+    let keys: Vec<Vec<u8>> = vec![
+        vec![],
+        vec![0],
+        vec![0, 0, 1],
+        b"abc".to_vec(),
+        vec![0x7F],
+        vec![0x80],
+        vec![0xC3, 0x28],
+        vec![0xE2, 0x82],
+        vec![0xFF],
+        vec![0xFF, 0x00],
+        vec![0xFF, 0xFE, 0xFD],
+    ];
+    for format in [LogFormat::Json, LogFormat::Bincode] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_format(temp_dir.path(), format)?;
+        store.set("abc".to_owned(), "string".to_owned())?;
+        for (i, key) in keys.iter().rev().enumerate() {
+            store.set_raw(key.clone(), vec![i as u8; 3])?;
+            store.set_raw(key.clone(), key.iter().rev().copied().collect())?;
+        }
+        store.set_raw(b"gone".to_vec(), b"value".to_vec())?;
+        store.remove_raw(b"gone")?;
+        assert!(matches!(
+            store.remove_raw(b"gone"),
+            Err(KvsError::KeyNotFound)
+        ));
+
+        let check = |store: &mut KvStore| -> Result<()> {
+            assert_eq!(store.get("abc")?, Some("string".to_owned()));
+            for key in &keys {
+                let value: Vec<u8> = key.iter().rev().copied().collect();
+                assert_eq!(store.get_raw(key)?, Some(value));
+                assert!(store.contains_key_raw(key));
+            }
+            assert_eq!(store.get_raw(b"gone")?, None);
+            let scanned: Vec<Vec<u8>> = store
+                .range_raw::<&[u8], _>(..)
+                .map(|entry| entry.map(|(key, _)| key))
+                .collect::<Result<_>>()?;
+            assert_eq!(scanned, keys);
+            let high: Vec<Vec<u8>> = store
+                .range_raw(&[0x80][..]..&[0xFF, 0x00][..])
+                .map(|entry| entry.map(|(key, _)| key))
+                .collect::<Result<_>>()?;
+            assert_eq!(high, keys[5..9]);
+            let prefixed: Vec<(Vec<u8>, Vec<u8>)> =
+                store.scan_prefix_raw(&[0xFF]).collect::<Result<_>>()?;
+            assert_eq!(
+                prefixed,
+                [
+                    (vec![0xFF], vec![0xFF]),
+                    (vec![0xFF, 0x00], vec![0x00, 0xFF]),
+                    (vec![0xFF, 0xFE, 0xFD], vec![0xFD, 0xFE, 0xFF])
+                ]
+            );
+            Ok(())
+        };
+        check(&mut store)?;
+        store.compact()?;
+        check(&mut store)?;
+        drop(store);
+        check(&mut KvStore::open(temp_dir.path())?)?;
+    }
+    Ok(())
+}
+
+// Reverse scans should yield exactly the forward scans reversed over logs of several
+// generations, with either index, and meet forward scans without skipping or repeating.
+#[test]
+fn reverse_iteration() -> Result<()> {
+    for disk_index in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let open = || {
+            KvStore::builder(temp_dir.path())
+                .max_segment_size(1024)
+                .disk_index(disk_index)
+                .index_cache_entries(16)
+                .open()
+        };
+        let mut store = open()?;
+        for i in 0..300 {
+            store.set(format!("key{:03}", i), format!("value{}", i))?;
+        }
+        for i in (0..300).step_by(7) {
+            store.remove(format!("key{:03}", i))?;
+        }
+        store.set("other".to_owned(), "value".to_owned())?;
+        assert!(log_files(temp_dir.path()) > 3);
+
+        let check = |store: &mut KvStore| -> Result<()> {
+            let mut keys: Vec<String> = KvStore::keys(store).map(|key| key.into_owned()).collect();
+            keys.reverse();
+            let keys_rev: Vec<String> = store.keys_rev().map(|key| key.into_owned()).collect();
+            assert_eq!(keys_rev, keys);
+
+            let mut forward: Vec<(String, String)> =
+                store.range("key050".."key250").collect::<Result<_>>()?;
+            forward.reverse();
+            let reverse: Vec<(String, String)> =
+                store.range_rev("key050".."key250").collect::<Result<_>>()?;
+            assert_eq!(reverse, forward);
+            assert_eq!(forward.first().map(|(key, _)| key.as_str()), Some("key249"));
+
+            let mut forward: Vec<(String, String)> =
+                store.range("key100"..="key200").collect::<Result<_>>()?;
+            forward.reverse();
+            let reverse: Vec<(String, String)> = store
+                .range("key100"..="key200")
+                .rev()
+                .collect::<Result<_>>()?;
+            assert_eq!(reverse, forward);
+            assert_eq!(forward.first().map(|(key, _)| key.as_str()), Some("key200"));
+
+            let mut forward: Vec<(String, String)> =
+                store.scan_prefix("key1").collect::<Result<_>>()?;
+            forward.reverse();
+            let reverse: Vec<(String, String)> =
+                store.scan_prefix("key1").rev().collect::<Result<_>>()?;
+            assert_eq!(reverse, forward);
+            assert!(reverse.iter().all(|(key, _)| key.starts_with("key1")));
+
+            let all: Vec<(String, String)> = store.iter().collect::<Result<_>>()?;
+            let mut scan = store.iter();
+            let mut front = Vec::new();
+            let mut back = Vec::new();
+            for step in 0.. {
+                let entry = if step % 3 == 0 {
+                    scan.next_back().map(|entry| (&mut back, entry))
+                } else {
+                    scan.next().map(|entry| (&mut front, entry))
+                };
+                match entry {
+                    Some((side, entry)) => side.push(entry?),
+                    None => break,
+                }
+            }
+            assert!(scan.next().is_none() && scan.next_back().is_none());
+            front.extend(back.into_iter().rev());
+            assert_eq!(front, all);
+            Ok(())
+        };
+        check(&mut store)?;
+        store.compact()?;
+        for i in (0..300).step_by(11) {
+            store.set(format!("key{:03}", i), format!("other{}", i))?;
+        }
+        check(&mut store)?;
+        drop(store);
+        check(&mut open()?)?;
+    }
+    Ok(())
+}
+
+// A cursor should seek to either side of a key, stop past both ends and come back from
+// them, skip removed keys over logs of several generations, and move on past a value
+// that fails to read.
+#[test]
+fn cursor_api() -> Result<()> {
+    for disk_index in [false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::builder(temp_dir.path())
+            .max_segment_size(1024)
+            .disk_index(disk_index)
+            .index_cache_entries(16)
+            .open()?;
+        let mut cursor = store.cursor();
+        assert!(!cursor.next()? && !cursor.prev()? && !cursor.seek("key")?);
+        assert_eq!(cursor.value()?, None);
+        drop(cursor);
+        for i in (0..200).step_by(2) {
+            store.set(format!("key{:03}", i), format!("value{}", i))?;
+        }
+        for i in (0..200).step_by(6) {
+            store.remove(format!("key{:03}", i))?;
+        }
+        store.compact()?;
+        store.set("key100".to_owned(), "other".to_owned())?;
+        assert!(log_files(temp_dir.path()) > 1);
+
+        let mut cursor = store.cursor();
+        assert!(cursor.seek("key001")?);
+        assert_eq!(cursor.key(), Some("key002"));
+        assert_eq!(cursor.value()?, Some("value2"));
+        assert!(cursor.seek("key012")?);
+        assert_eq!(cursor.key(), Some("key014"));
+        assert!(cursor.seek_for_prev("key013")?);
+        assert_eq!(cursor.key(), Some("key010"));
+        assert!(cursor.seek_for_prev("key100")?);
+        assert_eq!(cursor.value()?, Some("other"));
+        assert!(cursor.prev()? && cursor.prev()?);
+        assert_eq!(cursor.key(), Some("key094"));
+        assert!(cursor.next()?);
+        assert_eq!(cursor.key(), Some("key098"));
+
+        // Past the last key, and back.
+        assert!(!cursor.seek("key999")?);
+        assert_eq!((cursor.key(), cursor.valid()), (None, false));
+        assert!(!cursor.next()?);
+        assert!(cursor.prev()?);
+        assert_eq!(cursor.key(), Some("key196"));
+        assert!(!cursor.next()?);
+        assert!(cursor.prev()?);
+        assert_eq!(cursor.key(), Some("key196"));
+
+        // Before the first key, and back.
+        assert!(!cursor.seek_for_prev("a")?);
+        assert_eq!(cursor.value()?, None);
+        assert!(!cursor.prev()?);
+        assert!(cursor.next()?);
+        assert_eq!(cursor.key(), Some("key002"));
+        assert!(!cursor.prev()?);
+        assert!(cursor.next()?);
+        assert_eq!(cursor.key(), Some("key002"));
+
+        // Walking the whole store either way.
+        let mut forward = Vec::new();
+        let mut valid = cursor.seek_to_first()?;
+        while valid {
+            forward.push(cursor.key().unwrap().to_owned());
+            valid = cursor.next()?;
+        }
+        let mut reverse = Vec::new();
+        let mut valid = cursor.seek_to_last()?;
+        while valid {
+            reverse.push(cursor.key().unwrap().to_owned());
+            valid = cursor.prev()?;
+        }
+        drop(cursor);
+        reverse.reverse();
+        assert_eq!(forward, reverse);
+        let keys: Vec<String> = KvStore::keys(&store).map(|key| key.into_owned()).collect();
+        assert_eq!(forward, keys);
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "abcdefgh".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    let log = newest_log(temp_dir.path());
+    let value_at = std::fs::read(&log)?
+        .windows(8)
+        .position(|window| window == b"abcdefgh")
+        .expect("value not found in log");
+    flip_byte(&log, value_at);
+    store.set_verify_checksums(true);
+    let mut cursor = store.cursor();
+    assert!(cursor.seek("key2")?);
+    assert!(matches!(cursor.value(), Err(KvsError::Corruption { .. })));
+    assert!(cursor.next()?);
+    assert_eq!(cursor.value()?, Some("value3"));
+    assert!(cursor.prev()? && cursor.prev()?);
+    assert_eq!(cursor.value()?, Some("value1"));
+    Ok(())
+}
+
+// Watchers should see exactly the changes to keys under their prefix, each once per
+// matching watcher, none after their handle is dropped or from compactions, and a
+// watcher that panics should leave the store and the other watchers working.
+#[test]
+fn watch_keys() -> Result<()> {
+    use std::sync::{Arc, Mutex};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder(temp_dir.path())
+        .max_segment_size(1024)
+        .open()?;
+    let recorder = || {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&events);
+        let record = move |event: &WatchEvent| recorded.lock().unwrap().push(event.clone());
+        (events, record)
+    };
+    let (config, record) = recorder();
+    let config_handle = store.watch("cfg/".to_owned(), record);
+    let (db, record) = recorder();
+    let db_handle = store.watch("cfg/db/".to_owned(), record);
+    let _panicking = store.watch("cfg/".to_owned(), |_| panic!("watcher failed"));
+
+    store.set("cfg/name".to_owned(), "kvs".to_owned())?;
+    store.set("other".to_owned(), "value".to_owned())?;
+    store.set("cfg/db/host".to_owned(), "localhost".to_owned())?;
+    store.append("cfg/name".to_owned(), "-1".to_owned())?;
+    store.increment("cfg/db/port".to_owned(), 5432)?;
+    store.remove("cfg/name")?;
+    let mut batch = WriteBatch::new();
+    batch.put("cfg/db/user".to_owned(), "admin".to_owned());
+    batch.delete("cfg/missing".to_owned());
+    batch.delete("cfg/db/host".to_owned());
+    store.write_batch(batch)?;
+    let set = |key: &str, value: &str| WatchEvent::Set(key.to_owned(), value.to_owned());
+    let remove = |key: &str| WatchEvent::Remove(key.to_owned());
+    assert_eq!(
+        *db.lock().unwrap(),
+        [
+            set("cfg/db/host", "localhost"),
+            set("cfg/db/port", "5432"),
+            set("cfg/db/user", "admin"),
+            remove("cfg/db/host"),
+        ]
+    );
+    assert_eq!(
+        *config.lock().unwrap(),
+        [
+            set("cfg/name", "kvs"),
+            set("cfg/db/host", "localhost"),
+            set("cfg/name", "kvs-1"),
+            set("cfg/db/port", "5432"),
+            remove("cfg/name"),
+            set("cfg/db/user", "admin"),
+            remove("cfg/db/host"),
+        ]
+    );
+
+    // Compactions rewrite records without changing values.
+    for i in 0..200 {
+        store.set("other".to_owned(), format!("value{}", i))?;
+    }
+    store.compact()?;
+    assert_eq!(store.get("cfg/db/user")?, Some("admin".to_owned()));
+    assert_eq!(config.lock().unwrap().len(), 7);
+
+    drop(db_handle);
+    store.set("cfg/db/user".to_owned(), "root".to_owned())?;
+    assert_eq!(db.lock().unwrap().len(), 4);
+    assert_eq!(config.lock().unwrap().len(), 8);
+    store.clear()?;
+    assert_eq!(
+        config.lock().unwrap()[8..],
+        [remove("cfg/db/port"), remove("cfg/db/user")]
+    );
+    drop(config_handle);
+    store.set("cfg/name".to_owned(), "kvs".to_owned())?;
+    assert_eq!(config.lock().unwrap().len(), 10);
+    assert_eq!(store.get("cfg/name")?, Some("kvs".to_owned()));
+    Ok(())
+}
+
+// Every change is numbered, and the changes after a number are read back in order,
+// unless a compaction dropped some of them.
+#[test]
+fn change_feed() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::builder(temp_dir.path())
+        .max_segment_size(1024)
+        .compaction_threshold(u64::MAX)
+        .open()?;
+    assert_eq!(store.current_seq(), 0);
+    assert!(store.changes_since(0).next().is_none());
+
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("b".to_owned(), "2".to_owned())?;
+    store.append("a".to_owned(), "x".to_owned())?;
+    store.remove("b")?;
+    let mut batch = WriteBatch::new();
+    batch.put("c".to_owned(), "3".to_owned());
+    batch.put("d".to_owned(), "4".to_owned());
+    store.write_batch(batch)?;
+    store.set_from_reader("s".to_owned(), &b"streamed"[..], 8)?;
+    assert_eq!(store.current_seq(), 7);
+    let set = |seq, key: &str, value: &str| ChangeEvent {
+        seq,
+        change: Change::Set(key.to_owned(), value.to_owned()),
+    };
+    let feed = |store: &mut KvStore, seq| store.changes_since(seq).collect::<Result<Vec<_>>>();
+    assert_eq!(
+        feed(&mut store, 0)?,
+        [
+            set(1, "a", "1"),
+            set(2, "b", "2"),
+            set(3, "a", "1x"),
+            ChangeEvent {
+                seq: 4,
+                change: Change::Remove("b".to_owned()),
+            },
+            set(5, "c", "3"),
+            set(6, "d", "4"),
+            set(7, "s", "streamed"),
+        ]
+    );
+    assert_eq!(
+        feed(&mut store, 5)?,
+        [set(6, "d", "4"), set(7, "s", "streamed")]
+    );
+    assert!(feed(&mut store, 7)?.is_empty());
+
+    // A checkpoint taken before a compaction is behind the changes it drops.
+    let before = store.current_seq();
+    for i in 0..200 {
+        store.set("a".to_owned(), format!("value{}", i))?;
+    }
+    store.compact()?;
+    let after = store.current_seq();
+    assert_eq!(after, 207);
+    match store.changes_since(before).next() {
+        Some(Err(KvsError::CompactedAway { since, horizon })) => {
+            assert_eq!((since, horizon), (before, 206));
+        }
+        other => panic!("expected CompactedAway, got {:?}", other),
+    }
+    // The changes the compaction kept are still read, with the ones written after it.
+    store.set("e".to_owned(), "5".to_owned())?;
+    let expected = [set(207, "a", "value199"), set(208, "e", "5")];
+    assert_eq!(feed(&mut store, 206)?, expected);
+    assert_eq!(feed(&mut store, after)?, expected[1..]);
+
+    // The numbers go on from where they were after a reopen and after a crash.
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.current_seq(), 208);
+    assert_eq!(feed(&mut store, 206)?, expected);
+    let crashed = crashed_copy(temp_dir.path());
+    let mut copy = KvStore::open(crashed.path())?;
+    assert_eq!(copy.current_seq(), 208);
+    copy.set("f".to_owned(), "6".to_owned())?;
+    assert_eq!(
+        feed(&mut copy, after)?,
+        [set(208, "e", "5"), set(209, "f", "6")]
+    );
+
+    store.clear()?;
+    assert_eq!(
+        feed(&mut store, 208)?,
+        [ChangeEvent {
+            seq: 209,
+            change: Change::Clear,
+        }]
+    );
+    assert!(matches!(
+        store.changes_since(after).next(),
+        Some(Err(KvsError::CompactedAway { horizon: 208, .. }))
+    ));
+    Ok(())
+}
+
+// The commands read out of the logs of one store, applied to another, leave it with
+// the same contents, before and after the first store is compacted.
+#[test]
+fn replicate_commands() -> Result<()> {
+    use std::time::Duration;
+
+    fn join(existing: Option<&str>, operands: &[String]) -> String {
+        let mut value = existing.unwrap_or_default().to_owned();
+        for operand in operands {
+            value.push_str(operand);
+        }
+        value
+    }
+    let open = |path: &std::path::Path| {
+        KvStore::builder(path)
+            .max_segment_size(1024)
+            .compaction_threshold(u64::MAX)
+            .merge_operator(join)
+            .open()
+    };
+    let contents = |store: &mut KvStore| -> Result<Vec<(String, Vec<u8>)>> {
+        let keys: Vec<String> = KvStore::keys(store).map(Cow::into_owned).collect();
+        keys.into_iter()
+            .map(|key| Ok((key.clone(), store.get_bytes(&key)?.unwrap())))
+            .collect()
+    };
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut primary = open(primary_dir.path())?;
+    for i in 0..50 {
+        primary.set(format!("key{}", i % 20), format!("value{}", i))?;
+    }
+    primary.set_bytes("bytes".to_owned(), vec![0, 159, 146, 150])?;
+    primary.set_as("json".to_owned(), &vec![1, 2, 3])?;
+    primary.set_with_ttl(
+        "ttl".to_owned(),
+        "soon".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    primary.set_from_reader("stream".to_owned(), &b"streamed value"[..], 14)?;
+    primary.append("key1".to_owned(), "+suffix".to_owned())?;
+    primary.merge("key2".to_owned(), "+operand".to_owned())?;
+    primary.rename("key3".to_owned(), "renamed".to_owned())?;
+    primary.remove("key4")?;
+    let mut batch = WriteBatch::new();
+    batch.put("batched".to_owned(), "value".to_owned());
+    batch.delete("key5".to_owned());
+    primary.write_batch(batch)?;
+    assert!(primary.stats().generations > 1);
+
+    let standby_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut standby = open(standby_dir.path())?;
+    let cmds = primary.raw_commands(..).collect::<Result<Vec<_>>>()?;
+    standby.apply_all(cmds)?;
+    assert_eq!(contents(&mut standby)?, contents(&mut primary)?);
+
+    // Removing a key the standby does not have is not an error.
+    for i in 0..50 {
+        primary.set("churn".to_owned(), format!("value{}", i))?;
+    }
+    primary.remove("churn")?;
+    primary.remove("key6")?;
+    standby.remove("key6")?;
+    let tail = primary.stats().current_gen;
+    primary.set("after".to_owned(), "tail".to_owned())?;
+    let cmds = primary.raw_commands(tail..).collect::<Result<Vec<_>>>()?;
+    standby.apply_all(cmds)?;
+    assert_eq!(standby.get("after")?, Some("tail".to_owned()));
+    assert_eq!(standby.get("key6")?, None);
+    assert!(matches!(
+        standby.apply(kvs::Command::Checksum(0)),
+        Err(KvsError::NotAChange)
+    ));
+
+    // A compacted log holds every live key, so replaying it from scratch matches too.
+    primary.compact()?;
+    let fresh_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut fresh = open(fresh_dir.path())?;
+    let cmds = primary.raw_commands(..).collect::<Result<Vec<_>>>()?;
+    assert!(cmds.len() < 100);
+    fresh.apply_all(cmds.clone())?;
+    assert_eq!(contents(&mut fresh)?, contents(&mut primary)?);
+    standby.apply_all(cmds)?;
+    assert_eq!(contents(&mut standby)?, contents(&mut primary)?);
+    assert_eq!(fresh.get_bytes("stream")?, Some(b"streamed value".to_vec()));
+    Ok(())
+}
+
+// `verify` should find nothing wrong with a healthy store, and report every damaged
+// record, mismatched entry and stray file of a damaged one.
+#[test]
+fn verify_store() -> Result<()> {
+    use kvs::VerifyProblem;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::builder(temp_dir.path())
+            .format(LogFormat::Json)
+            .max_segment_size(512)
+            .compaction_threshold(u64::MAX)
+            .open()
+    };
+    let mut store = open()?;
+    for i in 0..40 {
+        store.set(format!("key{:02}", i % 30), format!("value{}", i))?;
+    }
+    store.remove("key29")?;
+    store.set_from_reader("stream".to_owned(), &b"streamed"[..], 8)?;
+    let report = store.verify()?;
+    assert!(report.is_ok(), "{:?}", report.problems);
+    assert_eq!(report.entries_checked, 30);
+    assert_eq!(report.logs_checked, store.stats().generations);
+    assert!(report.garbage_bytes > 0);
+    store.compact()?;
+    let report = store.verify()?;
+    assert!(report.is_ok(), "{:?}", report.problems);
+    assert!(report.garbage_bytes < 100);
+
+    for i in 0..30 {
+        store.set(format!("key{:02}", i), format!("new value{}", i))?;
+    }
+    let first_log = std::fs::read_dir(temp_dir.path())
+        .expect("fail to read directory")
+        .map(|entry| entry.unwrap().path())
+        .find(|path| std::fs::read_to_string(path).is_ok_and(|text| text.contains("new value0")))
+        .expect("no log file found");
+    let log = std::fs::read(&first_log).expect("fail to read log file");
+    let text = String::from_utf8(log.clone()).unwrap();
+
+    // An entry pointing at a remove, which has no checksum to fail first.
+    let start = text.find(r#"{"N""#).unwrap();
+    let end = text.find(r#"{"S":["key00","#).unwrap();
+    let end = end + text[end..].find('}').unwrap() + 1;
+    let mut damaged = log.clone();
+    let remove = r#"{"R":"key00"}"#;
+    damaged[start..start + remove.len()].copy_from_slice(remove.as_bytes());
+    damaged[start + remove.len()..end].fill(b' ');
+    // A flipped byte in a value.
+    let value = text.find("new value5").unwrap();
+    damaged[value] ^= 0x01;
+    std::fs::write(&first_log, &damaged).expect("fail to write log file");
+    std::fs::write(temp_dir.path().join("stray.tmp"), "").expect("fail to write file");
+
+    let report = store.verify()?;
+    let gen: u64 = first_log
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .and_then(|stem| stem.parse().ok())
+        .unwrap();
+    let bad_keys: Vec<&str> = report
+        .problems
+        .iter()
+        .filter_map(|problem| match problem {
+            VerifyProblem::BadEntry { key, gen: bad, .. } if *bad == gen => Some(key.as_str()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(bad_keys, ["key00", "key05"]);
+    assert!(report.problems.iter().any(|problem| matches!(
+        problem,
+        VerifyProblem::BadLog { gen: bad, offset, .. } if *bad == gen && *offset > 0
+    )));
+    assert!(report
+        .problems
+        .contains(&VerifyProblem::IndexMismatch("key00".to_owned())));
+    assert!(report.problems.contains(&VerifyProblem::OrphanFile(
+        temp_dir.path().join("stray.tmp")
+    )));
+    assert!(matches!(
+        store.verify_with(true),
+        Err(KvsError::VerifyFailed(VerifyProblem::BadEntry { .. }))
+    ));
+
+    // A truncated log loses the records past its end, and a removed one is missing.
+    std::fs::write(&first_log, &log[..log.len() - 10]).expect("fail to write log file");
+    std::fs::remove_file(temp_dir.path().join(format!("{}.log", gen + 1)))
+        .expect("fail to remove log file");
+    let report = store.verify()?;
+    assert!(report
+        .problems
+        .contains(&VerifyProblem::MissingGeneration(gen + 1)));
+    assert!(report.problems.iter().any(|problem| matches!(
+        problem,
+        VerifyProblem::BadLog { gen: bad, .. } if *bad == gen
+    )));
+    assert!(report.problems.iter().any(|problem| matches!(
+        problem,
+        VerifyProblem::BadEntry { gen: bad, .. } if *bad == gen
+    )));
+    Ok(())
+}
+
+// `repair` should skip the damaged byte ranges of a log, keep every key written outside
+// them, drop a batch cut by one whole, and move the damaged log aside.
+#[test]
+fn repair_store() -> Result<()> {
+    for format in [LogFormat::Json, LogFormat::Bincode] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_format(temp_dir.path(), format)?;
+        for i in 0..10 {
+            store.set(format!("key{:02}", i), format!("value{}", i))?;
+        }
+        store.remove("key01")?;
+        let mut batch = WriteBatch::new();
+        batch.put("key10".to_owned(), "value10".to_owned());
+        batch.put("key11".to_owned(), "value11".to_owned());
+        store.write_batch(batch)?;
+        store.set("key02".to_owned(), "new value2".to_owned())?;
+        assert!(matches!(
+            KvStore::repair(temp_dir.path()),
+            Err(KvsError::StoreLocked(_))
+        ));
+        drop(store);
+
+        let log_path = newest_log(temp_dir.path());
+        let mut log = std::fs::read(&log_path).expect("fail to read log file");
+        let log_len = log.len() as u64;
+        for key in ["key04", "key11"] {
+            let at = log
+                .windows(key.len())
+                .position(|window| window == key.as_bytes())
+                .unwrap();
+            log[at - 1..at + key.len() + 3].fill(0xff);
+        }
+        std::fs::write(&log_path, &log).expect("fail to write log file");
+
+        let report = KvStore::repair(temp_dir.path())?;
+        let gen: u64 = log_path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse().ok())
+            .unwrap();
+        assert_eq!(report.damaged_gens, [gen]);
+        assert_eq!(report.gen, Some(gen + 1));
+        // The batch header and `key10` are intact, but dropped with `key11`.
+        assert_eq!(report.records_lost, 2);
+        assert_eq!(report.bytes_recovered + report.bytes_lost, log_len);
+        assert!(temp_dir
+            .path()
+            .join("damaged")
+            .join(format!("{}.log", gen))
+            .exists());
+
+        let mut store = KvStore::open(temp_dir.path())?;
+        let keys: Vec<String> = KvStore::keys(&store).map(|key| key.into_owned()).collect();
+        assert_eq!(
+            keys,
+            ["key00", "key02", "key03", "key05", "key06", "key07", "key08", "key09"]
+        );
+        assert_eq!(store.get("key02")?, Some("new value2".to_owned()));
+        assert_eq!(store.get("key09")?, Some("value9".to_owned()));
+        // The lost changes leave a gap in the change feed rather than being reused.
+        assert_eq!(store.current_seq(), 15);
+        store.set("key04".to_owned(), "value4".to_owned())?;
+        drop(store);
+        let mut store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key04")?, Some("value4".to_owned()));
+        assert!(store.verify()?.is_ok());
+    }
+    Ok(())
+}
+
+// A new store should record its format version, `open` should refuse a newer version,
+// and `migrate` should upgrade a store from before the manifest once, backing it up.
+#[test]
+fn manifest_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    let manifest = temp_dir.path().join("MANIFEST");
+    let text = std::fs::read_to_string(&manifest).expect("fail to read manifest");
+    assert_eq!(text, "version 1\nengine kvs\n");
+
+    // A store from before the manifest still opens, and is left without one.
+    std::fs::remove_file(&manifest).expect("fail to remove manifest");
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    drop(store);
+    assert!(!manifest.exists());
+
+    KvStore::migrate(temp_dir.path())?;
+    assert_eq!(
+        std::fs::read_to_string(&manifest).expect("fail to read manifest"),
+        text
+    );
+    let backups = temp_dir.path().join("migration");
+    let backup = backups.join("v0");
+    assert_eq!(log_files(&backup), log_files(temp_dir.path()));
+    assert!(backup.join("engine").exists());
+    assert!(!backup.join("MANIFEST").exists());
+    KvStore::migrate(temp_dir.path())?;
+    assert_eq!(
+        std::fs::read_dir(&backups)
+            .expect("fail to read directory")
+            .count(),
+        1
+    );
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert!(store.verify()?.is_ok());
+    drop(store);
+
+    std::fs::write(&manifest, "version 2\nengine kvs\n").expect("fail to write manifest");
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::UnsupportedVersion {
+            found: 2,
+            supported: 1
+        })
+    ));
+    assert!(matches!(
+        KvStore::migrate(temp_dir.path()),
+        Err(KvsError::UnsupportedVersion { found: 2, .. })
+    ));
+    Ok(())
+}
+
+// An encrypted store should never write a value in plaintext, read its values back
+// after a compaction and a torn tail, and refuse to open without its key.
+#[cfg(feature = "encryption")]
+#[test]
+fn encrypted_store() -> Result<()> {
+    for format in [LogFormat::Json, LogFormat::Bincode] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let open = |key| {
+            KvStore::builder(temp_dir.path())
+                .format(format)
+                .compaction_threshold(u64::MAX)
+                .encryption_key(key)
+                .open()
+        };
+        let mut store = open([7; 32])?;
+        for i in 0..20 {
+            store.set(format!("key{}", i % 10), format!("secret value {}", i))?;
+        }
+        store.set_from_reader("stream".to_owned(), &b"streamed secret"[..], 15)?;
+        store.compact()?;
+        store.set("after".to_owned(), "secret after compaction".to_owned())?;
+        drop(store);
+
+        let mut logs = Vec::new();
+        for entry in std::fs::read_dir(temp_dir.path()).expect("fail to read directory") {
+            let path = entry.unwrap().path();
+            if path.extension() == Some("log".as_ref()) {
+                logs.extend(std::fs::read(path).expect("fail to read log file"));
+            }
+        }
+        assert!(logs.windows(6).all(|window| window != b"secret"));
+
+        // The newest log only holds the last write, a torn copy of which is truncated as
+        // in a plain store.
+        let log_path = newest_log(temp_dir.path());
+        let log = std::fs::read(&log_path).expect("fail to read log file");
+        let mut torn = log.clone();
+        torn.extend_from_slice(&log[..log.len() - 3]);
+        std::fs::write(&log_path, &torn).expect("fail to write log file");
+        let mut store = open([7; 32])?;
+        assert_eq!(
+            std::fs::read(&log_path).expect("fail to read log file"),
+            log
+        );
+        assert_eq!(store.get("key3")?, Some("secret value 13".to_owned()));
+        assert_eq!(store.get("stream")?, Some("streamed secret".to_owned()));
+        assert_eq!(
+            store.get("after")?,
+            Some("secret after compaction".to_owned())
+        );
+        drop(store);
+
+        assert!(matches!(open([8; 32]), Err(KvsError::WrongEncryptionKey)));
+        assert!(matches!(
+            KvStore::open(temp_dir.path()),
+            Err(KvsError::EncryptionKeyRequired)
+        ));
+        assert!(matches!(
+            KvStore::repair(temp_dir.path()),
+            Err(KvsError::EncryptionKeyRequired)
+        ));
+
+        let plain_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open_with_format(plain_dir.path(), format)?;
+        store.set("key".to_owned(), "value".to_owned())?;
+        drop(store);
+        assert!(matches!(
+            KvStore::builder(plain_dir.path())
+                .encryption_key([7; 32])
+                .open(),
+            Err(KvsError::NotEncrypted)
+        ));
+    }
+    Ok(())
+}
+
+// A workload should generate the same operations for the same seed, split them and the
+// keys loaded between its threads, keep to its sizes and read ratio, and pick keys in
+// turn in sequential order.
+#[test]
+fn bench_workload() -> Result<()> {
+    use kvs::{KeyOrder, Op, SizeDistribution, Workload};
+    assert_eq!(
+        "16".parse::<SizeDistribution>()?,
+        SizeDistribution::Fixed(16)
+    );
+    assert_eq!(
+        "8-64".parse::<SizeDistribution>()?,
+        SizeDistribution::Uniform { min: 8, max: 64 }
+    );
+    for invalid in ["", "big", "9-3", "1-"] {
+        assert!(matches!(
+            invalid.parse::<SizeDistribution>(),
+            Err(KvsError::InvalidWorkload(_))
+        ));
+    }
+
+    let workload = Workload {
+        keys: 1_000,
+        operations: 10_001,
+        key_size: SizeDistribution::Uniform { min: 6, max: 12 },
+        value_size: SizeDistribution::Uniform { min: 1, max: 50 },
+        read_ratio: 0.8,
+        threads: 3,
+        ..Workload::default()
+    };
+    workload.validate()?;
+    let ops: Vec<Vec<Op>> = (0..3)
+        .map(|thread| workload.ops(thread).collect())
+        .collect();
+    assert_eq!(
+        ops.iter().map(Vec::len).collect::<Vec<_>>(),
+        vec![3_334, 3_334, 3_333]
+    );
+    assert_eq!(workload.ops(2).len(), 3_333);
+    assert_eq!(ops[1], workload.clone().ops(1).collect::<Vec<_>>());
+    let reseeded = Workload {
+        seed: 1,
+        ..workload.clone()
+    };
+    assert_ne!(ops[1], reseeded.ops(1).collect::<Vec<_>>());
+    let reads = ops
+        .iter()
+        .flatten()
+        .filter(|op| matches!(op, Op::Get(_)))
+        .count();
+    assert!((7_600..8_400).contains(&reads), "{} reads", reads);
+    let keys: std::collections::BTreeSet<String> = (0..1_000).map(|n| workload.key(n)).collect();
+    assert_eq!(keys.len(), 1_000);
+    for op in ops.iter().flatten() {
+        let key = match op {
+            Op::Get(key) => key,
+            Op::Set(key, value) => {
+                assert!((1..=50).contains(&value.len()));
+                key
+            }
+        };
+        assert!(keys.contains(key));
+        assert!((6..=12).contains(&key.len()));
+    }
+
+    // Loading sets every key once over the threads.
+    let mut loaded: Vec<String> = (0..3)
+        .flat_map(|thread| workload.load_ops(thread))
+        .map(|op| match op {
+            Op::Set(key, _) => key,
+            op => panic!("loading should only set keys, not {:?}", op),
+        })
+        .collect();
+    loaded.sort();
+    assert_eq!(loaded, keys.iter().cloned().collect::<Vec<_>>());
+
+    let sequential = Workload {
+        keys: 4,
+        operations: 10,
+        order: KeyOrder::Sequential,
+        read_ratio: 1.0,
+        threads: 2,
+        ..Workload::default()
+    };
+    let picked: Vec<Op> = sequential.ops(1).collect();
+    let expected: Vec<Op> = [1, 3, 1, 3, 1]
+        .iter()
+        .map(|&n| Op::Get(sequential.key(n)))
+        .collect();
+    assert_eq!(picked, expected);
+
+    for invalid in [
+        Workload {
+            keys: 0,
+            ..Workload::default()
+        },
+        Workload {
+            threads: 0,
+            ..Workload::default()
+        },
+        Workload {
+            read_ratio: 1.5,
+            ..Workload::default()
+        },
+    ] {
+        assert!(matches!(
+            invalid.validate(),
+            Err(KvsError::InvalidWorkload(_))
+        ));
+    }
+    Ok(())
+}
+
+// Latencies should give exact nearest-rank percentiles, whether recorded in one set or
+// merged from several, and zero when there are none.
+#[test]
+fn bench_latencies() {
+    use kvs::Latencies;
+    use std::time::Duration;
+    let micros = Duration::from_micros;
+    let mut empty = Latencies::new();
+    assert!(empty.is_empty());
+    assert_eq!(empty.percentile(50.0), Duration::ZERO);
+    assert_eq!(empty.summary().count, 0);
+
+    let mut odd = Latencies::new();
+    let mut even = Latencies::new();
+    for n in (1..=100).rev() {
+        if n % 2 == 0 {
+            even.record(micros(n));
+        } else {
+            odd.record(micros(n));
+        }
+    }
+    assert_eq!(even.percentile(50.0), micros(50));
+    let mut all = Latencies::new();
+    all.merge(odd);
+    all.merge(even);
+    assert_eq!(all.len(), 100);
+    assert_eq!(all.percentile(0.0), micros(1));
+    assert_eq!(all.percentile(1.0), micros(1));
+    assert_eq!(all.percentile(50.0), micros(50));
+    assert_eq!(all.percentile(99.5), micros(100));
+    all.record(micros(1_000));
+    let summary = all.summary();
+    assert_eq!(summary.count, 101);
+    assert_eq!(summary.p50, micros(51));
+    assert_eq!(summary.p90, micros(91));
+    assert_eq!(summary.p99, micros(100));
+    assert_eq!(summary.p999, micros(1_000));
+    assert_eq!(summary.max, micros(1_000));
+    // The 6050us of the latencies over 101 of them, rounded down to the nanosecond.
+    assert_eq!(summary.mean, Duration::from_nanos(59_900));
+}
+
+// A benchmark should run against a store directory and a server, count the compactions
+// it triggers locally, and print its report as JSON with `kvs bench --json`.
+#[test]
+fn bench_run() -> Result<()> {
+    use kvs::{BenchTarget, Workload};
+    use predicates::prelude::PredicateBooleanExt;
+    let workload = Workload {
+        keys: 200,
+        operations: 20_000,
+        read_ratio: 0.1,
+        threads: 2,
+        ..Workload::default()
+    };
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let report = workload.run(&BenchTarget::Local(temp_dir.path().to_owned()))?;
+    assert_eq!(report.latency.count, 20_000);
+    assert_eq!(
+        report.read_latency.count + report.write_latency.count,
+        20_000
+    );
+    assert!(report.throughput > 0.0);
+    assert!(report.latency.p50 <= report.latency.p99);
+    assert!(report.compactions.unwrap() > 0);
+    assert!(report.dir_bytes.unwrap() > 0);
+    let store = SharedKvStore::open(temp_dir.path())?;
+    assert_eq!(store.len(), 200);
+    assert_eq!(store.compactions(), 0);
+    drop(store);
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set(workload.key(0), "overwritten".to_owned())?;
+    store.compact()?;
+    assert_eq!(store.compactions(), 1);
+    drop(store);
+
+    let server_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = spawn_server(kvs::KvsServer::new(
+        SharedKvStore::open(server_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+    ))?;
+    let report = workload.run(&BenchTarget::Server {
+        addr: addr.to_string(),
+        dir: Some(server_dir.path().to_owned()),
+    })?;
+    assert_eq!(report.latency.count, 20_000);
+    assert_eq!(report.compactions, None);
+    assert!(report.dir_bytes.unwrap() > 0);
+
+    let output = Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["bench", "--keys", "10", "--ops", "100", "--json"])
+        .current_dir(&temp_dir)
+        .output()?;
+    assert!(output.status.success());
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    assert_eq!(report["workload"]["operations"], 100);
+    assert_eq!(report["latency"]["count"], 100);
+    assert!(report["latency"]["p99_us"].as_f64().unwrap() > 0.0);
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["bench", "--keys", "10", "--ops", "100"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("throughput").and(contains("compactions")));
+    Ok(())
+}